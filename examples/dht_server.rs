@@ -68,12 +68,15 @@ fn main() {
     let socket = bind_socket(local_addr);
     let stats = Stats::new();
 
-    let lan_discovery_sender = LanDiscoverySender::new(tx.clone(), server_pk, local_addr.is_ipv6());
+    let mut lan_discovery_sender = LanDiscoverySender::new(tx.clone(), server_pk, local_addr.is_ipv6());
 
     let mut server = Server::new(tx, server_pk, server_sk);
     server.set_bootstrap_info(07032018, Box::new(|_| "This is tox-rs".as_bytes().to_owned()));
     server.enable_lan_discovery(true);
     server.enable_ipv6_mode(local_addr.is_ipv6());
+    lan_discovery_sender.set_quiet_period(server.lan_discovery_quiet_period());
+    lan_discovery_sender.set_ports(server.lan_discovery_ports());
+    lan_discovery_sender.set_filter(server.send_filter());
 
     // Bootstrap from nodes
     for &(pk, saddr) in &BOOTSTRAP_NODES {
@@ -0,0 +1,85 @@
+// a benchmark of handling a flood of PingRequest packets sent from a small
+// set of nodes
+//
+// Run it with `cargo run --release --example ping_flood`.
+
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use futures::*;
+use futures::sync::mpsc;
+
+use tox::toxcore::crypto_core::*;
+use tox::toxcore::dht::packet::*;
+use tox::toxcore::dht::server::*;
+
+/// Number of nodes that send `PingRequest` packets.
+const NODES_NUMBER: usize = 4;
+/// Number of `PingRequest` packets sent by every node.
+const PINGS_PER_NODE: usize = 2500;
+
+fn per_packet(elapsed: Duration, packets: usize) -> Duration {
+    elapsed / packets as u32
+}
+
+fn main() {
+    if crypto_init().is_err() {
+        panic!("Crypto initialization failed.");
+    }
+
+    let (server_pk, server_sk) = gen_keypair();
+    let packets_number = NODES_NUMBER * PINGS_PER_NODE;
+
+    // The channel is big enough to hold all responses so that sending never
+    // blocks
+    let (tx, rx) = mpsc::channel(packets_number);
+    let mut server = Server::new(tx, server_pk, server_sk.clone());
+    // Measure the response path, so requests must not be dropped by the
+    // per node limit
+    server.set_max_requests_per_pk(None);
+
+    let nodes = (0 .. NODES_NUMBER).map(|i| {
+        let (pk, sk) = gen_keypair();
+        let addr: SocketAddr = format!("127.0.0.1:{}", 33445 + i).parse().unwrap();
+        (pk, precompute(&server_pk, &sk), addr)
+    }).collect::<Vec<_>>();
+
+    let packets = (0 .. PINGS_PER_NODE).flat_map(|id| {
+        nodes.iter().map(move |&(pk, ref precomputed_key, addr)| {
+            let payload = PingRequestPayload { id: id as u64 };
+            (Packet::PingRequest(PingRequest::new(precomputed_key, &pk, &payload)), addr)
+        })
+    }).collect::<Vec<_>>();
+
+    // Reference: what handling would cost if every packet required a fresh
+    // precomputation of the shared key
+    let start = Instant::now();
+    for (packet, _) in &packets {
+        if let Packet::PingRequest(packet) = packet {
+            let _ = precompute(&packet.pk, &server_sk);
+        }
+    }
+    let precompute_elapsed = start.elapsed();
+
+    let start = Instant::now();
+    for (packet, addr) in packets {
+        server.handle_packet(packet, addr).wait().unwrap();
+    }
+    let handle_elapsed = start.elapsed();
+
+    drop(server);
+    let responses = rx.collect().wait().unwrap().into_iter()
+        .filter_map(|(packet, _)| match packet {
+            Packet::PingResponse(response) => Some(response),
+            _ => None,
+        })
+        .count();
+    assert_eq!(responses, packets_number, "Not every PingRequest was answered");
+
+    println!("Handled {} PingRequest packets from {} nodes, sent {} responses",
+        packets_number, NODES_NUMBER, responses);
+    println!("Handling with cached precomputed keys: {:?} per packet",
+        per_packet(handle_elapsed, packets_number));
+    println!("Key precomputation alone:              {:?} per packet",
+        per_packet(precompute_elapsed, packets_number));
+}
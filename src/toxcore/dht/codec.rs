@@ -176,6 +176,36 @@ pub enum EncodeErrorKind {
     Io,
 }
 
+/** Decode DHT `Packet` from raw bytes.
+
+This is the same parsing that is done by `DhtCodec` and can be used when
+packets are received by some transport other than UDP socket.
+*/
+pub fn decode(bytes: &[u8]) -> Result<Packet, DecodeError> {
+    let len = bytes.len();
+    if len > MAX_DHT_PACKET_SIZE {
+        return Err(DecodeError::too_big_packet(len))
+    }
+
+    match Packet::from_bytes(bytes) {
+        IResult::Incomplete(needed) => Err(DecodeError::incomplete_packet(needed, bytes.to_vec())),
+        IResult::Error(error) => Err(DecodeError::deserialize(error, bytes.to_vec())),
+        IResult::Done(_, packet) => Ok(packet),
+    }
+}
+
+/** Encode DHT `Packet` to raw bytes.
+
+This is the same serialization that is done by `DhtCodec` and can be used
+when packets are sent by some transport other than UDP socket.
+*/
+pub fn encode(packet: &Packet) -> Result<Vec<u8>, EncodeError> {
+    let mut packet_buf = [0; MAX_DHT_PACKET_SIZE];
+    packet.to_bytes((&mut packet_buf, 0))
+        .map(|(packet_buf, size)| packet_buf[..size].to_vec())
+        .map_err(EncodeError::serialize)
+}
+
 /// Struct to use for {de-,}serializing DHT UDP packets.
 #[derive(Clone)]
 pub struct DhtCodec {
@@ -196,21 +226,12 @@ impl Decoder for DhtCodec {
     type Error = DecodeError;
 
     fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        let len = buf.len();
-        if len > MAX_DHT_PACKET_SIZE {
-            return Err(DecodeError::too_big_packet(len))
-        }
+        let packet = decode(buf)?;
 
-        match Packet::from_bytes(buf) {
-            IResult::Incomplete(needed) => Err(DecodeError::incomplete_packet(needed, buf.to_vec())),
-            IResult::Error(error) => Err(DecodeError::deserialize(error, buf.to_vec())),
-            IResult::Done(_, packet) => {
-                // Add 1 to incoming counter
-                self.stats.counters.increase_incoming();
+        // Add 1 to incoming counter
+        self.stats.counters.increase_incoming();
 
-                Ok(Some(packet))
-            }
-        }
+        Ok(Some(packet))
     }
 }
 
@@ -219,17 +240,13 @@ impl Encoder for DhtCodec {
     type Error = EncodeError;
 
     fn encode(&mut self, packet: Self::Item, buf: &mut BytesMut) -> Result<(), Self::Error> {
-        let mut packet_buf = [0; MAX_DHT_PACKET_SIZE];
-        packet.to_bytes((&mut packet_buf, 0))
-            .map(|(packet_buf, size)| {
-                // Add 1 to outgoing counter
-                self.stats.counters.increase_outgoing();
-
-                buf.extend(&packet_buf[..size]);
-            })
-            .map_err(|error|
-                EncodeError::serialize(error)
-            )
+        let packet_buf = encode(&packet)?;
+
+        // Add 1 to outgoing counter
+        self.stats.counters.increase_outgoing();
+
+        buf.extend(&packet_buf);
+        Ok(())
     }
 }
 
@@ -243,10 +260,8 @@ mod tests {
     const ONION_RETURN_2_PAYLOAD_SIZE: usize = ONION_RETURN_2_SIZE - secretbox::NONCEBYTES;
     const ONION_RETURN_3_PAYLOAD_SIZE: usize = ONION_RETURN_3_SIZE - secretbox::NONCEBYTES;
 
-    #[test]
-    fn encode_decode() {
-        crypto_init().unwrap();
-        let test_packets = vec![
+    fn test_packets() -> Vec<Packet> {
+        vec![
             Packet::PingRequest(PingRequest {
                 pk: gen_keypair().0,
                 nonce: gen_nonce(),
@@ -374,7 +389,29 @@ mod tests {
                 version: 42,
                 motd: vec![1, 2, 3, 4]
             }),
-        ];
+            Packet::CookieResponse(CookieResponse {
+                nonce: gen_nonce(),
+                payload: vec![42; 136],
+            }),
+            Packet::CryptoHandshake(CryptoHandshake {
+                cookie: EncryptedCookie {
+                    nonce: secretbox::gen_nonce(),
+                    payload: vec![42; 88],
+                },
+                nonce: gen_nonce(),
+                payload: vec![42; 248],
+            }),
+            Packet::CryptoData(CryptoData {
+                nonce_last_bytes: 42,
+                payload: vec![42; 123],
+            }),
+        ]
+    }
+
+    #[test]
+    fn encode_decode() {
+        crypto_init().unwrap();
+        let test_packets = test_packets();
 
         let stats = Stats::new();
         let mut codec = DhtCodec::new(stats);
@@ -387,6 +424,24 @@ mod tests {
         }
     }
 
+    #[test]
+    fn encode_decode_bytes() {
+        crypto_init().unwrap();
+        for packet in test_packets() {
+            let bytes = encode(&packet).expect("Packet should be encoded");
+            let res = decode(&bytes).expect("Packet should be decoded");
+            assert_eq!(packet, res);
+        }
+    }
+
+    #[test]
+    fn decode_bytes_too_big() {
+        let bytes = vec![42; MAX_DHT_PACKET_SIZE + 1];
+        let res = decode(&bytes);
+        assert!(res.is_err());
+        assert_eq!(*res.err().unwrap().kind(), DecodeErrorKind::TooBigPacket { len: MAX_DHT_PACKET_SIZE + 1 });
+    }
+
     #[test]
     fn decode_encrypted_packet_incomplete() {
         crypto_init().unwrap();
@@ -2,7 +2,7 @@
 Module for friend.
 */
 
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use std::net::SocketAddr;
 
 use crate::toxcore::time::*;
@@ -16,6 +16,8 @@ use crate::toxcore::dht::server::hole_punching::*;
 pub const FRIEND_BOOTSTRAP_NODES_COUNT: u8 = 4;
 /// Maximum close nodes friend can have.
 pub const FRIEND_CLOSE_NODES_COUNT: u8 = 8;
+/// Interval in seconds for random `NodesRequest`.
+pub const NODES_REQ_INTERVAL: u64 = 20;
 
 /// Hold friend related info.
 #[derive(Clone, Debug)]
@@ -32,6 +34,9 @@ pub struct DhtFriend {
     /// How many times we sent `NodesRequest` packet to a random node from close
     /// nodes list.
     pub random_requests_count: u32,
+    /// Interval between `NodesRequest` packets sent to a random node from
+    /// close nodes list to search for the friend.
+    pub search_interval: Duration,
     /// List of nodes to send `NodesRequest` packet.
     pub nodes_to_bootstrap: NodesQueue,
     /// Struct for hole punching.
@@ -46,11 +51,22 @@ impl DhtFriend {
             close_nodes: Kbucket::new(FRIEND_CLOSE_NODES_COUNT),
             last_nodes_req_time: clock_now(),
             random_requests_count: 0,
+            search_interval: Duration::from_secs(NODES_REQ_INTERVAL),
             nodes_to_bootstrap: NodesQueue::new(FRIEND_BOOTSTRAP_NODES_COUNT),
             hole_punch: HolePunching::new(),
         }
     }
 
+    /// Create new `DhtFriend` with close nodes list filled with given nodes,
+    /// e.g. restored from cache. Nodes that don't fit the list are skipped.
+    pub fn with_nodes(pk: PublicKey, nodes: &[PackedNode]) -> Self {
+        let mut friend = DhtFriend::new(pk);
+        for node in nodes {
+            friend.try_add_to_close(node);
+        }
+        friend
+    }
+
     /// IP address is known when `DhtFriend` has node in close nodes list with
     /// the same `PublicKey`.
     pub fn is_addr_known(&self) -> bool {
@@ -105,6 +121,22 @@ mod tests {
     use crate::toxcore::dht::dht_node::*;
     use crate::toxcore::time::ConstNow;
 
+    #[test]
+    fn with_nodes() {
+        crypto_init().unwrap();
+        let pk = gen_keypair().0;
+        let nodes = [
+            PackedNode::new("192.168.1.1:12345".parse().unwrap(), &gen_keypair().0),
+            PackedNode::new("192.168.1.2:12345".parse().unwrap(), &gen_keypair().0),
+        ];
+        let friend = DhtFriend::with_nodes(pk, &nodes);
+
+        assert_eq!(friend.pk, pk);
+        for node in &nodes {
+            assert!(friend.close_nodes.contains(&pk, &node.pk));
+        }
+    }
+
     #[test]
     fn addr_is_unknown() {
         crypto_init().unwrap();
@@ -22,6 +22,24 @@ pub const BAD_NODE_TIMEOUT: u64 = PING_INTERVAL * 2 + 2;
 /// The timeout after which a node is discarded completely.
 pub const KILL_NODE_TIMEOUT: u64 = BAD_NODE_TIMEOUT + PING_INTERVAL;
 
+/// Capability bit of a node that can relay TCP connections.
+pub const CAPABILITY_TCP_RELAY: u8 = 1;
+
+/// Capability bit of a node that relays onion packets.
+pub const CAPABILITY_ONION: u8 = 2;
+
+/// Address family that is preferred when a node has both IPv4 and IPv6
+/// addresses.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AddrPreference {
+    /// Prefer the address the node responded from most recently.
+    Recent,
+    /// Prefer IPv4 address.
+    Ipv4,
+    /// Prefer IPv6 address.
+    Ipv6,
+}
+
 /// Struct conatains SocketAddrs and timestamps for sending and receiving packet
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct SockAndTime<T: Into<SocketAddr> + Copy> {
@@ -70,6 +88,11 @@ impl<T: Into<SocketAddr> + Copy> SockAndTime<T> {
         self.last_ping_req_time.map_or(true, |time| clock_elapsed(time) >= Duration::from_secs(PING_INTERVAL))
     }
 
+    /// Update the time of the last response.
+    pub fn update_resp_time(&mut self) {
+        self.last_resp_time = Some(clock_now());
+    }
+
     /// Get address if it should be pinged and update `last_ping_req_time`.
     pub fn ping_addr(&mut self) -> Option<T> {
         if let Some(saddr) = self.saddr {
@@ -99,6 +122,13 @@ pub struct DhtNode {
     pub assoc6: SockAndTime<SocketAddrV6>,
     /// Public Key of the node.
     pub pk: PublicKey,
+    /// Capability bits of the node. Empty until we learn which protocols the
+    /// node supports.
+    pub capabilities: u8,
+    /// Whether the node was learned from a trusted origin, i.e. from our
+    /// configured bootstrap list. Trusted nodes are preferred over untrusted
+    /// ones when kbucket is full.
+    pub is_trusted: bool,
 }
 
 impl DhtNode {
@@ -113,9 +143,16 @@ impl DhtNode {
             pk: pn.pk,
             assoc4: SockAndTime::new(saddr_v4),
             assoc6: SockAndTime::new(saddr_v6),
+            capabilities: 0,
+            is_trusted: false,
         }
     }
 
+    /// Check if the node is known to have all given capability bits.
+    pub fn has_capabilities(&self, capabilities: u8) -> bool {
+        self.capabilities & capabilities == capabilities
+    }
+
     /// Check if the node is considered bad i.e. it does not answer both on IPv4
     /// and IPv6 addresses for `BAD_NODE_TIMEOUT` seconds.
     pub fn is_bad(&self) -> bool {
@@ -128,12 +165,35 @@ impl DhtNode {
         self.assoc4.is_discarded() && self.assoc6.is_discarded()
     }
 
+    /// Check if the node answered on IPv4 or IPv6 address within the given
+    /// duration.
+    pub fn is_fresh(&self, max_age: Duration) -> bool {
+        [self.assoc4.last_resp_time, self.assoc6.last_resp_time].iter()
+            .any(|time| time.map_or(false, |time| clock_elapsed(time) <= max_age))
+    }
+
     /// Return `SocketAddr` for `DhtNode` based on the last response time.
     pub fn get_socket_addr(&self) -> Option<SocketAddr> {
-        let addr = if self.assoc4.last_resp_time >= self.assoc6.last_resp_time {
-            self.assoc4.saddr.map(Into::into)
-        } else {
-            self.assoc6.saddr.map(Into::into)
+        self.get_preferred_socket_addr(AddrPreference::Recent)
+    }
+
+    /// Return `SocketAddr` for `DhtNode` based on the address preference.
+    /// The address of the other family is returned if the node doesn't have
+    /// the preferred one or if the preferred one is bad while the other one
+    /// is not.
+    pub fn get_preferred_socket_addr(&self, preference: AddrPreference) -> Option<SocketAddr> {
+        let addr4 = self.assoc4.saddr.map(SocketAddr::V4);
+        let addr6 = self.assoc6.saddr.map(SocketAddr::V6);
+        let good_addr4 = addr4.filter(|_| !self.assoc4.is_bad());
+        let good_addr6 = addr6.filter(|_| !self.assoc6.is_bad());
+        let addr = match preference {
+            AddrPreference::Recent => if self.assoc4.last_resp_time >= self.assoc6.last_resp_time {
+                addr4
+            } else {
+                addr6
+            },
+            AddrPreference::Ipv4 => good_addr4.or(good_addr6).or(addr4).or(addr6),
+            AddrPreference::Ipv6 => good_addr6.or(good_addr4).or(addr6).or(addr4),
         };
 
         if addr.is_none() {
@@ -159,10 +219,30 @@ impl DhtNode {
     /// Convert `DhtNode` to `PackedNode`. The address is chosen based on the
     /// last response time.
     pub fn to_packed_node(&self) -> Option<PackedNode> {
-        self.get_socket_addr()
+        self.to_preferred_packed_node(AddrPreference::Recent)
+    }
+
+    /// Convert `DhtNode` to `PackedNode`. The address is chosen based on the
+    /// address preference.
+    pub fn to_preferred_packed_node(&self, preference: AddrPreference) -> Option<PackedNode> {
+        self.get_preferred_socket_addr(preference)
             .map(|addr| PackedNode::new(addr, &self.pk))
     }
 
+    /// Convert `DhtNode` to `PackedNode` with IPv4 address. Returns `None` if
+    /// the node doesn't have IPv4 address.
+    pub fn to_packed_node_ipv4(&self) -> Option<PackedNode> {
+        self.assoc4.saddr
+            .map(|addr| PackedNode::new(SocketAddr::V4(addr), &self.pk))
+    }
+
+    /// Convert `DhtNode` to `PackedNode` with IPv6 address. Returns `None` if
+    /// the node doesn't have IPv6 address.
+    pub fn to_packed_node_ipv6(&self) -> Option<PackedNode> {
+        self.assoc6.saddr
+            .map(|addr| PackedNode::new(SocketAddr::V6(addr), &self.pk))
+    }
+
     /// Convert `DhtNode` to list of `PackedNode` which can contain IPv4 and
     /// IPv6 addresses.
     pub fn to_all_packed_nodes(&self) -> Vec<PackedNode> {
@@ -172,6 +252,21 @@ impl DhtNode {
             .collect()
     }
 
+    /// Update the address of the node for the family of the given address and
+    /// the time of the last response.
+    pub fn update_addr(&mut self, addr: SocketAddr) {
+        match addr {
+            SocketAddr::V4(v4) => {
+                self.assoc4.saddr = Some(v4);
+                self.assoc4.update_resp_time();
+            },
+            SocketAddr::V6(v6) => {
+                self.assoc6.saddr = Some(v6);
+                self.assoc6.update_resp_time();
+            },
+        }
+    }
+
     /// Update returned socket address and time of receiving packet
     pub fn update_returned_addr(&mut self, addr: SocketAddr) {
         match addr {
@@ -191,6 +286,59 @@ impl DhtNode {
 mod tests {
     use super::*;
 
+    use tokio_executor;
+    use tokio_timer::clock::*;
+
+    use crate::toxcore::time::ConstNow;
+
+    #[test]
+    fn is_fresh() {
+        crypto_init().unwrap();
+        let pn = PackedNode::new("127.0.0.1:33445".parse().unwrap(), &gen_keypair().0);
+        let dht_node = DhtNode::new(pn);
+
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(
+            Instant::now() + Duration::from_secs(10)
+        ));
+
+        with_default(&clock, &mut enter, |_| {
+            assert!(dht_node.is_fresh(Duration::from_secs(20)));
+            assert!(!dht_node.is_fresh(Duration::from_secs(5)));
+        });
+    }
+
+    #[test]
+    fn get_preferred_socket_addr() {
+        crypto_init().unwrap();
+        let addr4 = "127.0.0.1:33445".parse().unwrap();
+        let addr6 = "[::1]:33445".parse().unwrap();
+        let mut dht_node = DhtNode::new(PackedNode::new(addr4, &gen_keypair().0));
+
+        assert_eq!(dht_node.get_preferred_socket_addr(AddrPreference::Ipv6), Some(addr4));
+
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(Instant::now() + Duration::from_secs(1)));
+
+        with_default(&clock, &mut enter, |_| {
+            dht_node.update_addr(addr6);
+        });
+
+        // IPv6 address responded more recently
+        assert_eq!(dht_node.get_socket_addr(), Some(addr6));
+        assert_eq!(dht_node.get_preferred_socket_addr(AddrPreference::Recent), Some(addr6));
+        assert_eq!(dht_node.get_preferred_socket_addr(AddrPreference::Ipv4), Some(addr4));
+        assert_eq!(dht_node.get_preferred_socket_addr(AddrPreference::Ipv6), Some(addr6));
+
+        // IPv4 address doesn't respond anymore
+        let clock = Clock::new_with_now(ConstNow(Instant::now() + Duration::from_secs(BAD_NODE_TIMEOUT + 1)));
+
+        with_default(&clock, &mut enter, |_| {
+            dht_node.update_addr(addr6);
+            assert_eq!(dht_node.get_preferred_socket_addr(AddrPreference::Ipv4), Some(addr6));
+        });
+    }
+
     #[test]
     fn dht_node_clonable() {
         crypto_init().unwrap();
@@ -201,4 +349,36 @@ mod tests {
         let dht_node = DhtNode::new(pn);
         let _ = dht_node.clone();
     }
+
+    #[test]
+    fn has_capabilities() {
+        crypto_init().unwrap();
+        let saddr = "127.0.0.1:33445".parse().unwrap();
+        let mut dht_node = DhtNode::new(PackedNode::new(saddr, &gen_keypair().0));
+
+        assert!(!dht_node.has_capabilities(CAPABILITY_TCP_RELAY));
+
+        dht_node.capabilities = CAPABILITY_TCP_RELAY;
+        assert!(dht_node.has_capabilities(CAPABILITY_TCP_RELAY));
+        assert!(!dht_node.has_capabilities(CAPABILITY_ONION));
+        assert!(!dht_node.has_capabilities(CAPABILITY_TCP_RELAY | CAPABILITY_ONION));
+    }
+
+    #[test]
+    fn update_addr() {
+        crypto_init().unwrap();
+        let saddr_v4 = "127.0.0.1:33445".parse().unwrap();
+        let saddr_v6: SocketAddrV6 = "[2001:db8::1]:33445".parse().unwrap();
+        let mut dht_node = DhtNode::new(PackedNode::new(saddr_v4, &gen_keypair().0));
+
+        dht_node.update_addr("127.0.0.2:33446".parse().unwrap());
+        assert_eq!(dht_node.assoc4.saddr, Some("127.0.0.2:33446".parse().unwrap()));
+
+        // address of the other family is kept
+        dht_node.update_addr(SocketAddr::V6(saddr_v6));
+        assert_eq!(dht_node.assoc4.saddr, Some("127.0.0.2:33446".parse().unwrap()));
+        assert_eq!(dht_node.assoc6.saddr, Some(saddr_v6));
+        assert!(dht_node.assoc6.last_resp_time.is_some());
+    }
+
 }
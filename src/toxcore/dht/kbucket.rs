@@ -13,8 +13,11 @@ PK; and additionally used to store nodes closest to friends.
 */
 
 use std::cmp::{Ord, Ordering};
+use std::collections::HashMap;
 use std::convert::Into;
-use std::net::SocketAddr;
+use std::mem;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
 
 use crate::toxcore::crypto_core::*;
 use crate::toxcore::dht::dht_node::*;
@@ -47,6 +50,57 @@ pub fn kbucket_index(&PublicKey(ref own_pk): &PublicKey,
     None  // PKs are equal
 }
 
+/// Create `PublicKey` that gets into the kbucket with given index relative to
+/// `base_pk`. Different suffixes give different keys of the same kbucket.
+#[cfg(test)]
+pub(crate) fn pk_in_kbucket(base_pk: &PublicKey, index: usize, suffix: u8) -> PublicKey {
+    let mut pk = base_pk.0;
+    pk[index / 8] ^= 0x80 >> (index % 8);
+    pk[PUBLICKEYBYTES - 1] ^= suffix;
+    PublicKey(pk)
+}
+
+/// Check if addresses belong to the same /24 IPv4 or /48 IPv6 subnet.
+pub(crate) fn is_same_subnet(addr_1: &SocketAddr, addr_2: &SocketAddr) -> bool {
+    match (addr_1.ip(), addr_2.ip()) {
+        (IpAddr::V4(ip_1), IpAddr::V4(ip_2)) => ip_1.octets()[.. 3] == ip_2.octets()[.. 3],
+        (IpAddr::V6(ip_1), IpAddr::V6(ip_2)) => ip_1.segments()[.. 3] == ip_2.segments()[.. 3],
+        _ => false,
+    }
+}
+
+/// Subnet of an address used to limit the number of nodes from the same
+/// network in `Ktree`: /24 for IPv4 and /64 for IPv6.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+enum Subnet {
+    V4([u8; 3]),
+    V6([u16; 4]),
+}
+
+impl Subnet {
+    fn new(addr: &SocketAddr) -> Subnet {
+        match addr.ip() {
+            IpAddr::V4(ip) => {
+                let o = ip.octets();
+                Subnet::V4([o[0], o[1], o[2]])
+            },
+            IpAddr::V6(ip) => {
+                let s = ip.segments();
+                Subnet::V6([s[0], s[1], s[2], s[3]])
+            },
+        }
+    }
+}
+
+/// Check if the node has an address of IPv4 or IPv6 family.
+fn has_family(node: &DhtNode, ipv4: bool) -> bool {
+    if ipv4 {
+        node.assoc4.saddr.is_some()
+    } else {
+        node.assoc6.saddr.is_some()
+    }
+}
+
 impl Into<DhtNode> for PackedNode {
     fn into(self) -> DhtNode {
         DhtNode::new(self)
@@ -100,11 +154,34 @@ pub struct Kbucket {
     pub capacity: u8,
     /// Nodes that kbucket has, sorted by distance to PK.
     pub nodes: Vec<DhtNode>,
+    /// `PublicKey`s of nodes that were removed from the kbucket for being bad
+    /// with the time of removal. Such nodes can't be added back until
+    /// `quarantine_timeout` is passed so that flapping nodes don't occupy
+    /// slots of the kbucket over and over again.
+    quarantine: HashMap<PublicKey, Instant>,
+    /// Time during which a node removed for being bad can't be added back.
+    quarantine_timeout: Duration,
+    /// Whether a full kbucket should keep nodes of both IPv4 and IPv6 address
+    /// families so that one family doesn't crowd out the other one.
+    balance_families: bool,
+    /// Maximum number of nodes from the same /24 IPv4 or /48 IPv6 subnet.
+    /// `None` means that the number is not limited.
+    max_nodes_per_subnet: Option<u8>,
+    /// Whether `PublicKey`s of nodes evicted to make room for new nodes should
+    /// be stored until they are taken with `take_evicted`.
+    track_evictions: bool,
+    /// `PublicKey`s of evicted nodes paired with `PublicKey`s of nodes that
+    /// took their place when `track_evictions` is enabled.
+    evicted: Vec<(PublicKey, PublicKey)>,
 }
 
 /// Default number of nodes that kbucket can hold.
 pub const KBUCKET_DEFAULT_SIZE: u8 = 8;
 
+/// Default number of seconds during which a node removed from kbucket for
+/// being bad can't be added back.
+pub const QUARANTINE_TIMEOUT: u64 = 60;
+
 impl Kbucket {
     /** Create a new `Kbucket` to store nodes close to the `PublicKey`.
 
@@ -115,6 +192,131 @@ impl Kbucket {
         Kbucket {
             capacity,
             nodes: Vec::with_capacity(capacity as usize),
+            quarantine: HashMap::new(),
+            quarantine_timeout: Duration::from_secs(QUARANTINE_TIMEOUT),
+            balance_families: false,
+            max_nodes_per_subnet: None,
+            track_evictions: false,
+            evicted: Vec::new(),
+        }
+    }
+
+    /// Set whether a full kbucket should keep nodes of both IPv4 and IPv6
+    /// address families. It should be enabled for dual-stack nodes.
+    pub fn set_balance_families(&mut self, balance_families: bool) {
+        self.balance_families = balance_families;
+    }
+
+    /// Number of slots of a full kbucket that nodes of the address family are
+    /// entitled to when families are balanced. IPv6 gets the extra slot of an
+    /// odd capacity so that a single-slot kbucket doesn't flap between
+    /// families.
+    fn family_share(&self, ipv4: bool) -> usize {
+        let capacity = self.capacity();
+        if ipv4 {
+            capacity / 2
+        } else {
+            capacity - capacity / 2
+        }
+    }
+
+    /// Number of nodes having an address of the family. Nodes with both
+    /// addresses are counted for both families.
+    fn family_count(&self, ipv4: bool) -> usize {
+        self.nodes.iter().filter(|node| has_family(node, ipv4)).count()
+    }
+
+    /// Find the farthest node that the full kbucket should evict to make room
+    /// for a node with under-represented address family.
+    fn family_balance_index(&self, new_node: &PackedNode) -> Option<usize> {
+        let ipv4 = new_node.saddr.is_ipv4();
+        if !self.balance_families ||
+            self.family_count(ipv4) >= self.family_share(ipv4) ||
+            self.family_count(!ipv4) <= self.family_share(!ipv4) {
+            return None;
+        }
+        self.nodes.iter().rposition(|node| !has_family(node, ipv4))
+    }
+
+    /// Find the farthest node starting from `from` index that the full
+    /// kbucket can evict for a closer node. A node without the family of the
+    /// new node can be evicted only if its family keeps more than its share.
+    fn eviction_index(&self, new_node: &PackedNode, from: usize) -> Option<usize> {
+        if !self.balance_families {
+            return Some(self.nodes.len() - 1);
+        }
+        let ipv4 = new_node.saddr.is_ipv4();
+        let can_evict_other = self.family_count(!ipv4) > self.family_share(!ipv4);
+        self.nodes[from ..].iter()
+            .rposition(|node| has_family(node, ipv4) || can_evict_other)
+            .map(|index| index + from)
+    }
+
+    /// Set time during which a node removed for being bad can't be added back.
+    pub fn set_quarantine_timeout(&mut self, timeout: Duration) {
+        self.quarantine_timeout = timeout;
+    }
+
+    /// Set maximum number of nodes from the same /24 IPv4 or /48 IPv6 subnet.
+    /// It prevents an attacker controlling many nodes in one subnet from
+    /// filling the kbucket. `None` means that the number is not limited.
+    pub fn set_max_nodes_per_subnet(&mut self, max_nodes_per_subnet: Option<u8>) {
+        self.max_nodes_per_subnet = max_nodes_per_subnet;
+    }
+
+    /// Check if the kbucket already has the maximum number of nodes from the
+    /// subnet of the new node.
+    fn is_subnet_full(&self, new_node: &PackedNode) -> bool {
+        match self.max_nodes_per_subnet {
+            Some(max_nodes_per_subnet) => self.nodes.iter()
+                .filter(|node| node.get_all_addrs().iter().any(|addr| is_same_subnet(addr, &new_node.saddr)))
+                .count() >= max_nodes_per_subnet as usize,
+            None => false,
+        }
+    }
+
+    /// Set whether `PublicKey`s of nodes evicted to make room for new nodes
+    /// should be stored. They should be taken with `take_evicted` regularly.
+    pub fn set_track_evictions(&mut self, track_evictions: bool) {
+        self.track_evictions = track_evictions;
+        if !track_evictions {
+            self.evicted.clear();
+        }
+    }
+
+    /// Take `PublicKey`s of nodes evicted since the previous call paired with
+    /// `PublicKey`s of nodes that took their place. It's always empty unless
+    /// eviction tracking is enabled.
+    pub fn take_evicted(&mut self) -> Vec<(PublicKey, PublicKey)> {
+        mem::replace(&mut self.evicted, Vec::new())
+    }
+
+    /// Check if node with given `PublicKey` was removed for being bad recently
+    /// and therefore can't be added back.
+    pub fn is_quarantined(&self, pk: &PublicKey) -> bool {
+        match self.quarantine.get(pk) {
+            Some(&time) => clock_elapsed(time) < self.quarantine_timeout,
+            None => false,
+        }
+    }
+
+    /// Remove nodes which quarantine is over from quarantine list.
+    pub fn clear_quarantine(&mut self) {
+        let timeout = self.quarantine_timeout;
+        self.quarantine.retain(|_, &mut time|
+            clock_elapsed(time) < timeout
+        );
+    }
+
+    /// Remove the node with given index to make room for the new node and put
+    /// it to quarantine if it's bad.
+    fn remove_with_quarantine(&mut self, index: usize, new_pk: &PublicKey) {
+        let node = self.nodes.remove(index);
+        if node.is_bad() {
+            self.quarantine.insert(node.pk, clock_now());
+        }
+        if self.track_evictions {
+            self.evicted.push((node.pk, *new_pk));
         }
     }
 
@@ -139,10 +341,16 @@ impl Kbucket {
 
     - If the [`PackedNode`] with given `PublicKey` is already in the `Kbucket`,
       the [`DhtNode`] is updated (since its `SocketAddr` can differ).
+    - If the [`PackedNode`] with given `PublicKey` was recently removed from
+      the `Kbucket` for being bad, it's not added.
     - If kbucket is not full, node is appended.
     - If kbucket is full and `evict` is `true`, node's closeness is compared to
       nodes already in kbucket, and if it's closer than some node, it prepends
       that node, and last node is removed from the list.
+    - If address families are balanced, a full kbucket evicts a node of the
+      family that has more than its half of slots for a node of the other
+      family, and a closer node never evicts a node of the family that has
+      no more than its half of slots.
     - If the node being added is farther away than the nodes in the kbucket or
       `evict` is `false`, it isn't added and `false` is returned.
 
@@ -167,18 +375,19 @@ impl Kbucket {
             Ok(index) => {
                 debug!(target: "Kbucket",
                     "Updated: the node was already in the kbucket.");
-                match new_node.saddr {
-                    SocketAddr::V4(sock_v4) => {
-                        self.nodes[index].assoc4.saddr = Some(sock_v4);
-                        self.nodes[index].assoc4.last_resp_time = Some(clock_now());
-                    },
-                    SocketAddr::V6(sock_v6) => {
-                        self.nodes[index].assoc6.saddr = Some(sock_v6);
-                        self.nodes[index].assoc6.last_resp_time = Some(clock_now());
-                    }
-                }
+                self.nodes[index].update_addr(new_node.saddr);
                 true
             },
+            Err(_) if self.is_quarantined(&new_node.pk) => {
+                debug!(target: "Kbucket",
+                    "Node can't be added to the kbucket since it's quarantined.");
+                false
+            },
+            Err(_) if self.is_subnet_full(new_node) => {
+                debug!(target: "Kbucket",
+                    "Node can't be added to the kbucket since there are too many nodes from its subnet.");
+                false
+            },
             Err(index) if !evict || index == self.nodes.len() => {
                 // index is pointing past the end
                 // we are not going to evict the farthest node or the current
@@ -192,14 +401,25 @@ impl Kbucket {
                             debug!(target: "Kbucket",
                                 "No free space left in the kbucket, the last bad node removed.");
                             // replace the farthest bad node
-                            self.nodes.remove(index);
+                            self.remove_with_quarantine(index, &new_node.pk);
                             self.nodes.push((*new_node).into());
                             true
                         },
-                        None => {
-                            debug!(target: "Kbucket",
-                                "Node can't be added to the kbucket.");
-                            false
+                        None => match self.family_balance_index(new_node) {
+                            Some(index) => {
+                                debug!(target: "Kbucket",
+                                    "No free space left in the kbucket, the last node of over-represented address family removed.");
+                                self.remove_with_quarantine(index, &new_node.pk);
+                                let index = self.nodes.binary_search_by(|n| base_pk.distance(&n.pk, &new_node.pk))
+                                    .unwrap_or_else(|index| index);
+                                self.nodes.insert(index, (*new_node).into());
+                                true
+                            },
+                            None => {
+                                debug!(target: "Kbucket",
+                                    "Node can't be added to the kbucket.");
+                                false
+                            },
                         },
                     }
                 } else {
@@ -214,11 +434,25 @@ impl Kbucket {
             Err(index) => {
                 // index is pointing inside the list
                 // we are going to evict the farthest node if the kbucket is full
-                if self.is_full() {
-                    debug!(target: "Kbucket",
-                        "No free space left in the kbucket, the last node removed.");
-                    self.nodes.pop();
-                }
+                let index = if self.is_full() {
+                    match self.eviction_index(new_node, index) {
+                        Some(evict_index) => {
+                            debug!(target: "Kbucket",
+                                "No free space left in the kbucket, the farther node removed.");
+                            self.remove_with_quarantine(evict_index, &new_node.pk);
+                            // the evicted node is not closer than the new one
+                            // so the insertion index is still valid
+                            index
+                        },
+                        None => {
+                            debug!(target: "Kbucket",
+                                "Node can't be added to the kbucket without leaving address family under-represented.");
+                            return false;
+                        },
+                    }
+                } else {
+                    index
+                };
                 debug!(target: "Kbucket", "Node inserted inside the kbucket.");
                 self.nodes.insert(index, (*new_node).into());
                 true
@@ -226,6 +460,42 @@ impl Kbucket {
         }
     }
 
+    /** Try to add [`PackedNode`] from a trusted origin to the kbucket.
+
+    It works like `try_add` without eviction but if the kbucket is full and
+    doesn't have bad nodes the farthest untrusted node is replaced. Nodes of
+    the same kbucket are considered equally distant so a trusted node is
+    preferred over an untrusted one. It makes it harder to eclipse us by
+    flooding with attacker-controlled close nodes.
+
+    Returns `true` if node was added or updated, `false` otherwise.
+
+    [`PackedNode`]: ../packed_node/struct.PackedNode.html
+    */
+    pub fn try_add_trusted(&mut self, base_pk: &PublicKey, new_node: &PackedNode) -> bool {
+        if !self.try_add(base_pk, new_node, /* evict */ false) {
+            if self.is_quarantined(&new_node.pk) || self.is_subnet_full(new_node) {
+                return false;
+            }
+            match self.nodes.iter().rposition(|n| !n.is_trusted) {
+                Some(index) => {
+                    debug!(target: "Kbucket",
+                        "No free space left in the kbucket, the last untrusted node removed.");
+                    self.remove_with_quarantine(index, &new_node.pk);
+                    let index = self.nodes.binary_search_by(|n| base_pk.distance(&n.pk, &new_node.pk))
+                        .unwrap_or_else(|index| index);
+                    self.nodes.insert(index, (*new_node).into());
+                },
+                None => return false,
+            }
+        }
+
+        if let Some(node) = self.get_node_mut(base_pk, &new_node.pk) {
+            node.is_trusted = true;
+        }
+        true
+    }
+
     /** Remove [`DhtNode`](./struct.DhtNode.html) with given PK from the
     `Kbucket`.
 
@@ -285,7 +555,8 @@ impl Kbucket {
       - Node is already in the [`Kbucket`] but has different address or in a bad
         state
 
-    Otherwise `false` is returned.
+    Otherwise `false` is returned. Also `false` is returned if node was recently
+    removed from the [`Kbucket`] for being bad.
 
     Note that the result of this function doesn't always match the result of
     `try_add` function. `try_add` will always return `true` when node is already
@@ -301,15 +572,21 @@ impl Kbucket {
                 self.nodes[index].is_bad() ||
                     self.nodes[index].assoc4.saddr.map(SocketAddr::V4) != Some(new_node.saddr) &&
                         self.nodes[index].assoc6.saddr.map(SocketAddr::V6) != Some(new_node.saddr),
+            Err(_) if self.is_quarantined(&new_node.pk) =>
+                // node was recently removed for being bad
+                false,
+            Err(_) if self.is_subnet_full(new_node) =>
+                // too many nodes from the same subnet
+                false,
             Err(index) if !evict || index == self.nodes.len() =>
                 // can't find node in the kbucket
                 // we are not going to evict the farthest node or the current
                 // node is the farthest one
-                !self.is_full() || self.nodes.iter().any(|n| n.is_bad()),
-            Err(_index) =>
+                !self.is_full() || self.nodes.iter().any(|n| n.is_bad()) || self.family_balance_index(new_node).is_some(),
+            Err(index) =>
                 // can't find node in the kbucket
-                // we are going to evict the farthest node if the kbucket is full
-                true,
+                // we are going to evict a farther node if the kbucket is full
+                !self.is_full() || self.eviction_index(new_node, index).is_some(),
         }
     }
 
@@ -347,6 +624,18 @@ pub struct Ktree {
     pk: PublicKey,
     /// List of [`Kbucket`](./struct.Kbucket.html)s.
     pub kbuckets: Vec<Kbucket>,
+    /// Address family preferred when nodes with both IPv4 and IPv6 addresses
+    /// are returned by `get_closest`.
+    addr_preference: AddrPreference,
+    /// Whether kbuckets and the result of `get_closest` should keep nodes of
+    /// both IPv4 and IPv6 address families.
+    balance_families: bool,
+    /// Maximum number of nodes from the same /24 IPv4 or /64 IPv6 subnet in
+    /// all kbuckets together. `None` means that the number is not limited.
+    max_nodes_per_subnet: Option<u8>,
+    /// Number of node addresses from every /24 IPv4 or /64 IPv6 subnet in all
+    /// kbuckets together.
+    subnets: HashMap<Subnet, usize>,
 }
 
 /** Maximum number of [`Kbucket`](./struct.Kbucket.html)s that [`Ktree`]
@@ -365,7 +654,11 @@ impl Ktree {
         trace!(target: "Ktree", "Creating new Ktree with PK: {:?}", pk);
         Ktree {
             pk: *pk,
-            kbuckets: vec![Kbucket::new(KBUCKET_DEFAULT_SIZE); KBUCKET_MAX_ENTRIES as usize]
+            kbuckets: vec![Kbucket::new(KBUCKET_DEFAULT_SIZE); KBUCKET_MAX_ENTRIES as usize],
+            addr_preference: AddrPreference::Recent,
+            balance_families: false,
+            max_nodes_per_subnet: None,
+            subnets: HashMap::new(),
         }
     }
 
@@ -417,6 +710,8 @@ impl Ktree {
       number of kbuckets.
     * [`Kbucket`](./struct.Kbucket.html) to which it is added has free space
       or added node is closer to the PK than other node in the kbucket.
+    * the limit of nodes from its subnet in the whole `Ktree` is not reached
+      if it's set.
 
     Returns `true` if node was added successfully, `false` otherwise.
     */
@@ -424,8 +719,33 @@ impl Ktree {
         debug!(target: "Ktree", "Trying to add PackedNode.");
         trace!(target: "Ktree", "With PN: {:?}; and self: {:?}", node, self);
 
+        if self.is_subnet_full(node) {
+            debug!(target: "Ktree",
+                "Node can't be added to the Ktree since there are too many nodes from its subnet.");
+            return false;
+        }
+
         match self.kbucket_index(&node.pk) {
-            Some(index) => self.kbuckets[index].try_add(&self.pk, node, /* evict */ false),
+            Some(index) => self.update_kbucket(index, |kbucket, pk| kbucket.try_add(pk, node, /* evict */ false)),
+            None => {
+                trace!("Failed to add node: {:?}", node);
+                false
+            }
+        }
+    }
+
+    /// Add [`PackedNode`](./struct.PackedNode.html) from a trusted origin to
+    /// `Ktree`. Unlike `try_add` it can replace an untrusted node when
+    /// kbucket is full.
+    pub fn try_add_trusted(&mut self, node: &PackedNode) -> bool {
+        if self.is_subnet_full(node) {
+            debug!(target: "Ktree",
+                "Node can't be added to the Ktree since there are too many nodes from its subnet.");
+            return false;
+        }
+
+        match self.kbucket_index(&node.pk) {
+            Some(index) => self.update_kbucket(index, |kbucket, pk| kbucket.try_add_trusted(pk, node)),
             None => {
                 trace!("Failed to add node: {:?}", node);
                 false
@@ -440,7 +760,7 @@ impl Ktree {
                 self);
 
         match self.kbucket_index(node_pk) {
-            Some(index) => self.kbuckets[index].remove(&self.pk, node_pk),
+            Some(index) => self.update_kbucket(index, |kbucket, pk| kbucket.remove(pk, node_pk)),
             None => {
                 trace!("Failed to remove PK: {:?}", node_pk);
                 None
@@ -448,6 +768,116 @@ impl Ktree {
         }
     }
 
+    /// Set time during which a node removed for being bad can't be added back
+    /// to `Ktree`.
+    pub fn set_quarantine_timeout(&mut self, timeout: Duration) {
+        for kbucket in &mut self.kbuckets {
+            kbucket.set_quarantine_timeout(timeout);
+        }
+    }
+
+    /// Change the kbucket with given index keeping counts of nodes from every
+    /// subnet up to date. Only nodes of this kbucket can be added, removed or
+    /// get new addresses.
+    fn update_kbucket<F, R>(&mut self, index: usize, f: F) -> R
+        where F: FnOnce(&mut Kbucket, &PublicKey) -> R
+    {
+        let subnets_before = Ktree::kbucket_subnets(&self.kbuckets[index]);
+        let result = f(&mut self.kbuckets[index], &self.pk);
+        let subnets_after = Ktree::kbucket_subnets(&self.kbuckets[index]);
+
+        for subnet in subnets_before {
+            let is_empty = match self.subnets.get_mut(&subnet) {
+                Some(count) => {
+                    *count -= 1;
+                    *count == 0
+                },
+                None => false,
+            };
+            if is_empty {
+                self.subnets.remove(&subnet);
+            }
+        }
+        for subnet in subnets_after {
+            *self.subnets.entry(subnet).or_insert(0) += 1;
+        }
+
+        result
+    }
+
+    /// Get subnets of all addresses of nodes in the kbucket.
+    fn kbucket_subnets(kbucket: &Kbucket) -> Vec<Subnet> {
+        kbucket.iter()
+            .flat_map(|node| node.get_all_addrs())
+            .map(|addr| Subnet::new(&addr))
+            .collect()
+    }
+
+    /// Set maximum number of nodes from the same /24 IPv4 or /64 IPv6 subnet
+    /// in all kbuckets of `Ktree` together. It also works when nodes of the
+    /// subnet get into different kbuckets. `None` means that the number is
+    /// not limited.
+    pub fn set_max_nodes_per_subnet(&mut self, max_nodes_per_subnet: Option<u8>) {
+        self.max_nodes_per_subnet = max_nodes_per_subnet;
+    }
+
+    /// Check if `Ktree` already has the maximum number of nodes from the
+    /// subnet of the new node. Nodes that are already in `Ktree` can always
+    /// be updated.
+    fn is_subnet_full(&self, new_node: &PackedNode) -> bool {
+        match self.max_nodes_per_subnet {
+            Some(max_nodes_per_subnet) if !self.contains(&new_node.pk) => self.subnets
+                .get(&Subnet::new(&new_node.saddr))
+                .map_or(false, |&count| count >= max_nodes_per_subnet as usize),
+            _ => false,
+        }
+    }
+
+    /// Set address family preferred when nodes with both IPv4 and IPv6
+    /// addresses are returned by `get_closest`.
+    pub fn set_addr_preference(&mut self, addr_preference: AddrPreference) {
+        self.addr_preference = addr_preference;
+    }
+
+    /// Get address family preferred when nodes with both IPv4 and IPv6
+    /// addresses are returned by `get_closest`.
+    pub fn addr_preference(&self) -> AddrPreference {
+        self.addr_preference
+    }
+
+    /// Set whether full kbuckets and the result of `get_closest` should keep
+    /// nodes of both IPv4 and IPv6 address families.
+    pub fn set_balance_families(&mut self, balance_families: bool) {
+        self.balance_families = balance_families;
+        for kbucket in &mut self.kbuckets {
+            kbucket.set_balance_families(balance_families);
+        }
+    }
+
+    /// Set whether `PublicKey`s of nodes evicted from kbuckets to make room
+    /// for new nodes should be stored.
+    pub fn set_track_evictions(&mut self, track_evictions: bool) {
+        for kbucket in &mut self.kbuckets {
+            kbucket.set_track_evictions(track_evictions);
+        }
+    }
+
+    /// Take `PublicKey`s of nodes evicted from all kbuckets since the
+    /// previous call paired with `PublicKey`s of nodes that took their place.
+    pub fn take_evicted(&mut self) -> Vec<(PublicKey, PublicKey)> {
+        self.kbuckets.iter_mut()
+            .flat_map(|kbucket| kbucket.take_evicted())
+            .collect()
+    }
+
+    /// Remove nodes which quarantine is over from quarantine lists of all
+    /// kbuckets.
+    pub fn clear_quarantine(&mut self) {
+        for kbucket in &mut self.kbuckets {
+            kbucket.clear_quarantine();
+        }
+    }
+
     /** Get (up to) 4 closest nodes to given PK.
 
     Functionality for [`SendNodes`](./struct.SendNodes.html).
@@ -455,17 +885,57 @@ impl Ktree {
     Returns less than 4 nodes only if `Ktree` contains less than 4
     nodes.
 
-    It should not contain LAN ip node if the request is from global ip.
+    It should not contain LAN ip node if the request is from global ip. It
+    should contain only IPv4 nodes if the request is from IPv4 address.
+    If address families are balanced and all closest nodes are of the same
+    family, the farthest of them is replaced by the closest node of the
+    other family.
     */
-    pub fn get_closest(&self, pk: &PublicKey, only_global: bool) -> NodesQueue {
+    pub fn get_closest(&self, pk: &PublicKey, only_global: bool, only_ipv4: bool) -> NodesQueue {
+        self.get_closest_fresh(pk, only_global, only_ipv4, None)
+    }
+
+    /// Get (up to) 4 closest nodes to given PK like `get_closest` but only
+    /// from nodes that answered within `max_age` if it's set.
+    pub fn get_closest_fresh(&self, pk: &PublicKey, only_global: bool, only_ipv4: bool, max_age: Option<Duration>) -> NodesQueue {
         debug!(target: "Ktree", "Getting closest nodes.");
         trace!(target: "Ktree", "With PK: {:?} and self: {:?}", pk, self);
 
         let mut queue = NodesQueue::new(4);
-        for node in self.iter().filter(|node| !node.is_bad()) {
-            if let Some(pn) = node.to_packed_node() {
-                if !only_global || IsGlobal::is_global(&pn.saddr.ip()) {
-                    queue.try_add(pk, &pn);
+        // closest nodes of each address family used to balance the result
+        let mut closest_v4 = NodesQueue::new(1);
+        let mut closest_v6 = NodesQueue::new(1);
+        let balance_families = self.balance_families && !only_ipv4;
+        let is_fresh = |node: &DhtNode| max_age.map_or(true, |max_age| node.is_fresh(max_age));
+        let is_allowed = |pn: &PackedNode| !only_global || IsGlobal::is_global(&pn.saddr.ip());
+        for node in self.iter().filter(|node| !node.is_bad() && is_fresh(node)) {
+            let pn = if only_ipv4 {
+                node.to_packed_node_ipv4()
+            } else {
+                node.to_preferred_packed_node(self.addr_preference)
+            };
+            if let Some(pn) = pn.filter(|pn| is_allowed(pn)) {
+                queue.try_add(pk, &pn);
+            }
+            if balance_families {
+                if let Some(pn) = node.to_packed_node_ipv4().filter(|pn| is_allowed(pn)) {
+                    closest_v4.try_add(pk, &pn);
+                }
+                if let Some(pn) = node.to_packed_node_ipv6().filter(|pn| is_allowed(pn)) {
+                    closest_v6.try_add(pk, &pn);
+                }
+            }
+        }
+        if balance_families && queue.is_full() {
+            for (ipv4, closest) in &[(true, closest_v4), (false, closest_v6)] {
+                let is_present = queue.iter().any(|pn| pn.saddr.is_ipv4() == *ipv4);
+                let closest = closest.iter().next();
+                if let Some(closest) = closest.filter(|closest| !is_present && !queue.contains(pk, &closest.pk)) {
+                    let farthest_pk = queue.iter().last().map(|pn| pn.pk);
+                    if let Some(farthest_pk) = farthest_pk {
+                        queue.remove(pk, &farthest_pk);
+                    }
+                    queue.try_add(pk, closest);
                 }
             }
         }
@@ -497,6 +967,10 @@ impl Ktree {
     [`PackedNode`]: ./struct.PackedNode.html
     */
     pub fn can_add(&self, new_node: &PackedNode) -> bool {
+        if self.is_subnet_full(new_node) {
+            return false;
+        }
+
         match self.kbucket_index(&new_node.pk) {
             None => false,
             Some(i) =>
@@ -536,6 +1010,18 @@ impl Ktree {
         self.iter()
             .all(|node| node.is_discarded())
     }
+
+    /// Get number of distinct /24 IPv4 and /48 IPv6 subnets addresses of
+    /// nodes in `Ktree` belong to.
+    pub fn subnets_count(&self) -> usize {
+        let mut subnets: Vec<SocketAddr> = Vec::new();
+        for addr in self.iter().flat_map(|node| node.get_all_addrs()) {
+            if !subnets.iter().any(|subnet| is_same_subnet(subnet, &addr)) {
+                subnets.push(addr);
+            }
+        }
+        subnets.len()
+    }
 }
 
 #[cfg(test)]
@@ -626,6 +1112,35 @@ mod tests {
         assert!(kbucket.try_add(&pk, &existing_node, /* evict */ false));
     }
 
+    #[test]
+    fn kbucket_try_add_track_evictions() {
+        let pk = PublicKey([0; PUBLICKEYBYTES]);
+        let mut kbucket = Kbucket::new(KBUCKET_DEFAULT_SIZE);
+
+        for i in 0 .. 8 {
+            let addr = SocketAddr::new("1.2.3.4".parse().unwrap(), 12345 + u16::from(i));
+            let node = PackedNode::new(addr, &PublicKey([i + 2; PUBLICKEYBYTES]));
+            assert!(kbucket.try_add(&pk, &node, /* evict */ false));
+        }
+
+        let closer_node = PackedNode::new(
+            "1.2.3.5:12345".parse().unwrap(),
+            &PublicKey([1; PUBLICKEYBYTES])
+        );
+
+        // evictions are not tracked by default
+        let mut untracked = kbucket.clone();
+        assert!(untracked.try_add(&pk, &closer_node, /* evict */ true));
+        assert!(untracked.take_evicted().is_empty());
+
+        kbucket.set_track_evictions(true);
+        assert!(kbucket.try_add(&pk, &closer_node, /* evict */ true));
+
+        // the farthest node is evicted
+        assert_eq!(kbucket.take_evicted(), vec![(PublicKey([9; PUBLICKEYBYTES]), closer_node.pk)]);
+        assert!(kbucket.take_evicted().is_empty());
+    }
+
     #[test]
     fn kbucket_try_add_should_replace_bad_nodes() {
         let pk = PublicKey([0; PUBLICKEYBYTES]);
@@ -682,6 +1197,324 @@ mod tests {
         });
     }
 
+    #[test]
+    fn kbucket_try_add_should_not_add_quarantined_nodes() {
+        let pk = PublicKey([0; PUBLICKEYBYTES]);
+        let mut kbucket = Kbucket::new(1);
+        let quarantine_timeout = Duration::from_secs(BAD_NODE_TIMEOUT * 2);
+        kbucket.set_quarantine_timeout(quarantine_timeout);
+
+        let node_1 = PackedNode::new(
+            "1.2.3.4:12345".parse().unwrap(),
+            &PublicKey([1; PUBLICKEYBYTES])
+        );
+        let node_2 = PackedNode::new(
+            "1.2.3.4:12346".parse().unwrap(),
+            &PublicKey([2; PUBLICKEYBYTES])
+        );
+
+        assert!(kbucket.try_add(&pk, &node_2, /* evict */ false));
+
+        let now = Instant::now();
+        let mut enter = tokio_executor::enter().unwrap();
+
+        // bad node is replaced and quarantined
+        let clock = Clock::new_with_now(ConstNow(
+            now + Duration::from_secs(BAD_NODE_TIMEOUT + 1)
+        ));
+        with_default(&clock, &mut enter, |_| {
+            assert!(kbucket.try_add(&pk, &node_1, /* evict */ false));
+            assert!(kbucket.is_quarantined(&node_2.pk));
+        });
+
+        // node_1 is bad now but node_2 can't replace it during quarantine
+        let clock = Clock::new_with_now(ConstNow(
+            now + Duration::from_secs(BAD_NODE_TIMEOUT * 2 + 2)
+        ));
+        with_default(&clock, &mut enter, |_| {
+            assert!(!kbucket.can_add(&pk, &node_2, /* evict */ false));
+            assert!(!kbucket.try_add(&pk, &node_2, /* evict */ false));
+        });
+
+        // quarantine is over
+        let clock = Clock::new_with_now(ConstNow(
+            now + Duration::from_secs(BAD_NODE_TIMEOUT + 1) + quarantine_timeout
+        ));
+        with_default(&clock, &mut enter, |_| {
+            assert!(kbucket.can_add(&pk, &node_2, /* evict */ false));
+            assert!(kbucket.try_add(&pk, &node_2, /* evict */ false));
+        });
+    }
+
+    #[test]
+    fn kbucket_try_add_balance_families() {
+        let pk = PublicKey([0; PUBLICKEYBYTES]);
+        let mut kbucket = Kbucket::new(2);
+        kbucket.set_balance_families(true);
+
+        let node_v4_1 = PackedNode::new(
+            "1.2.3.4:12345".parse().unwrap(),
+            &PublicKey([1; PUBLICKEYBYTES])
+        );
+        let node_v4_2 = PackedNode::new(
+            "1.2.3.4:12346".parse().unwrap(),
+            &PublicKey([2; PUBLICKEYBYTES])
+        );
+        let node_v6_1 = PackedNode::new(
+            "[2001:db8::1]:12345".parse().unwrap(),
+            &PublicKey([3; PUBLICKEYBYTES])
+        );
+        let node_v6_2 = PackedNode::new(
+            "[2001:db8::1]:12346".parse().unwrap(),
+            &PublicKey([4; PUBLICKEYBYTES])
+        );
+
+        assert!(kbucket.try_add(&pk, &node_v4_1, /* evict */ false));
+        assert!(kbucket.try_add(&pk, &node_v4_2, /* evict */ false));
+
+        // IPv6 node replaces the farthest IPv4 node since there are no IPv6
+        // nodes in the kbucket
+        assert!(kbucket.can_add(&pk, &node_v6_1, /* evict */ false));
+        assert!(kbucket.try_add(&pk, &node_v6_1, /* evict */ false));
+        assert!(kbucket.contains(&pk, &node_v4_1.pk));
+        assert!(kbucket.contains(&pk, &node_v6_1.pk));
+
+        // both families are present now so the kbucket is just full
+        assert!(!kbucket.can_add(&pk, &node_v6_2, /* evict */ false));
+        assert!(!kbucket.try_add(&pk, &node_v6_2, /* evict */ false));
+        assert!(!kbucket.try_add(&pk, &node_v4_2, /* evict */ false));
+    }
+
+    #[test]
+    fn kbucket_try_add_balance_families_under_represented() {
+        let pk = PublicKey([0; PUBLICKEYBYTES]);
+        let mut kbucket = Kbucket::new(4);
+        kbucket.set_balance_families(true);
+
+        for i in 0 .. 3 {
+            let addr = SocketAddr::new("1.2.3.4".parse().unwrap(), 12345 + u16::from(i));
+            let node = PackedNode::new(addr, &PublicKey([i + 1; PUBLICKEYBYTES]));
+            assert!(kbucket.try_add(&pk, &node, /* evict */ false));
+        }
+        let node_v6_1 = PackedNode::new("[2001:db8::1]:12345".parse().unwrap(), &PublicKey([4; PUBLICKEYBYTES]));
+        assert!(kbucket.try_add(&pk, &node_v6_1, /* evict */ false));
+
+        // IPv6 family has less than half of slots so the farthest IPv4 node
+        // is replaced
+        let node_v6_2 = PackedNode::new("[2001:db8::2]:12345".parse().unwrap(), &PublicKey([5; PUBLICKEYBYTES]));
+        assert!(kbucket.can_add(&pk, &node_v6_2, /* evict */ false));
+        assert!(kbucket.try_add(&pk, &node_v6_2, /* evict */ false));
+        assert!(!kbucket.contains(&pk, &PublicKey([3; PUBLICKEYBYTES])));
+
+        // both families have half of slots now
+        let node_v6_3 = PackedNode::new("[2001:db8::3]:12345".parse().unwrap(), &PublicKey([6; PUBLICKEYBYTES]));
+        assert!(!kbucket.can_add(&pk, &node_v6_3, /* evict */ false));
+        assert!(!kbucket.try_add(&pk, &node_v6_3, /* evict */ false));
+    }
+
+    #[test]
+    fn kbucket_try_add_balance_families_evict() {
+        let pk = PublicKey([0; PUBLICKEYBYTES]);
+        let mut kbucket = Kbucket::new(2);
+        kbucket.set_balance_families(true);
+
+        let node_v4_1 = PackedNode::new("1.2.3.4:12345".parse().unwrap(), &PublicKey([1; PUBLICKEYBYTES]));
+        let node_v4_2 = PackedNode::new("1.2.3.4:12346".parse().unwrap(), &PublicKey([2; PUBLICKEYBYTES]));
+        let node_v4_3 = PackedNode::new("1.2.3.4:12347".parse().unwrap(), &PublicKey([3; PUBLICKEYBYTES]));
+        let node_v6 = PackedNode::new("[2001:db8::1]:12345".parse().unwrap(), &PublicKey([4; PUBLICKEYBYTES]));
+
+        assert!(kbucket.try_add(&pk, &node_v4_2, /* evict */ false));
+        assert!(kbucket.try_add(&pk, &node_v6, /* evict */ false));
+
+        // closer IPv4 node can't evict the only IPv6 node
+        assert!(!kbucket.can_add(&pk, &node_v4_3, /* evict */ true));
+        assert!(!kbucket.try_add(&pk, &node_v4_3, /* evict */ true));
+        assert!(kbucket.contains(&pk, &node_v6.pk));
+
+        // but it can evict a farther IPv4 node
+        assert!(kbucket.can_add(&pk, &node_v4_1, /* evict */ true));
+        assert!(kbucket.try_add(&pk, &node_v4_1, /* evict */ true));
+        assert!(!kbucket.contains(&pk, &node_v4_2.pk));
+        assert!(kbucket.contains(&pk, &node_v6.pk));
+
+        let pks = kbucket.iter().map(|node| node.pk).collect::<Vec<_>>();
+        assert_eq!(pks, vec![node_v4_1.pk, node_v6.pk]);
+    }
+
+    #[test]
+    fn kbucket_try_add_balance_families_single_slot() {
+        let pk = PublicKey([0; PUBLICKEYBYTES]);
+        let mut kbucket = Kbucket::new(1);
+        kbucket.set_balance_families(true);
+
+        let node_v4_1 = PackedNode::new("1.2.3.4:12345".parse().unwrap(), &PublicKey([1; PUBLICKEYBYTES]));
+        let node_v4_2 = PackedNode::new("1.2.3.4:12346".parse().unwrap(), &PublicKey([2; PUBLICKEYBYTES]));
+        let node_v6 = PackedNode::new("[2001:db8::1]:12345".parse().unwrap(), &PublicKey([3; PUBLICKEYBYTES]));
+
+        assert!(kbucket.try_add(&pk, &node_v4_2, /* evict */ false));
+
+        // IPv6 node takes the single slot
+        assert!(kbucket.try_add(&pk, &node_v6, /* evict */ false));
+        assert!(!kbucket.contains(&pk, &node_v4_2.pk));
+
+        // and IPv4 nodes can't take it back even if they are closer
+        assert!(!kbucket.try_add(&pk, &node_v4_2, /* evict */ false));
+        assert!(!kbucket.try_add(&pk, &node_v4_1, /* evict */ true));
+        assert!(kbucket.contains(&pk, &node_v6.pk));
+    }
+
+    #[test]
+    fn is_same_subnet_test() {
+        let addr = "1.2.3.4:12345".parse().unwrap();
+        assert!(is_same_subnet(&addr, &"1.2.3.5:12346".parse().unwrap()));
+        assert!(!is_same_subnet(&addr, &"1.2.4.4:12345".parse().unwrap()));
+        assert!(!is_same_subnet(&addr, &"[::ffff:1.2.3.4]:12345".parse().unwrap()));
+
+        let addr = "[2001:db8:1:2::1]:12345".parse().unwrap();
+        assert!(is_same_subnet(&addr, &"[2001:db8:1:3::2]:12345".parse().unwrap()));
+        assert!(!is_same_subnet(&addr, &"[2001:db8:2:2::1]:12345".parse().unwrap()));
+    }
+
+    #[test]
+    fn kbucket_try_add_max_nodes_per_subnet() {
+        let pk = PublicKey([0; PUBLICKEYBYTES]);
+        let mut kbucket = Kbucket::new(8);
+        kbucket.set_max_nodes_per_subnet(Some(2));
+
+        for i in 0 .. 8 {
+            let addr = SocketAddr::new("1.2.3.4".parse().unwrap(), 12345 + u16::from(i));
+            let node = PackedNode::new(addr, &PublicKey([i + 2; PUBLICKEYBYTES]));
+            assert_eq!(kbucket.can_add(&pk, &node, /* evict */ false), i < 2);
+            assert_eq!(kbucket.try_add(&pk, &node, /* evict */ false), i < 2);
+        }
+
+        // even closer node from the same subnet can't be added
+        let closer_node = PackedNode::new("1.2.3.5:12345".parse().unwrap(), &PublicKey([1; PUBLICKEYBYTES]));
+        assert!(!kbucket.try_add(&pk, &closer_node, /* evict */ true));
+        assert!(!kbucket.try_add_trusted(&pk, &closer_node));
+
+        // nodes from other subnets can be added
+        let node = PackedNode::new("1.2.4.4:12345".parse().unwrap(), &PublicKey([10; PUBLICKEYBYTES]));
+        assert!(kbucket.try_add(&pk, &node, /* evict */ false));
+
+        // existing nodes still can be updated
+        let node = PackedNode::new("1.2.3.6:12345".parse().unwrap(), &PublicKey([2; PUBLICKEYBYTES]));
+        assert!(kbucket.try_add(&pk, &node, /* evict */ false));
+
+        assert_eq!(kbucket.nodes.len(), 3);
+    }
+
+    #[test]
+    fn kbucket_try_add_trusted() {
+        let pk = PublicKey([0; PUBLICKEYBYTES]);
+        let mut kbucket = Kbucket::new(2);
+
+        let node_1 = PackedNode::new("1.2.3.4:12345".parse().unwrap(), &PublicKey([1; PUBLICKEYBYTES]));
+        let node_2 = PackedNode::new("1.2.3.4:12346".parse().unwrap(), &PublicKey([2; PUBLICKEYBYTES]));
+        let trusted_1 = PackedNode::new("1.2.3.4:12347".parse().unwrap(), &PublicKey([3; PUBLICKEYBYTES]));
+        let trusted_2 = PackedNode::new("1.2.3.4:12348".parse().unwrap(), &PublicKey([4; PUBLICKEYBYTES]));
+        let trusted_3 = PackedNode::new("1.2.3.4:12349".parse().unwrap(), &PublicKey([5; PUBLICKEYBYTES]));
+
+        assert!(kbucket.try_add(&pk, &node_1, /* evict */ false));
+        assert!(kbucket.try_add(&pk, &node_2, /* evict */ false));
+
+        // trusted node replaces the farthest untrusted node even though it's
+        // farther itself
+        assert!(kbucket.try_add_trusted(&pk, &trusted_1));
+        assert!(kbucket.contains(&pk, &node_1.pk));
+        assert!(!kbucket.contains(&pk, &node_2.pk));
+        assert!(kbucket.get_node(&pk, &trusted_1.pk).unwrap().is_trusted);
+
+        assert!(kbucket.try_add_trusted(&pk, &trusted_2));
+        assert!(!kbucket.contains(&pk, &node_1.pk));
+
+        // untrusted node can't replace trusted ones
+        assert!(!kbucket.try_add(&pk, &node_1, /* evict */ false));
+        // and trusted node can't replace trusted ones either
+        assert!(!kbucket.try_add_trusted(&pk, &trusted_3));
+
+        // nodes are still sorted
+        let pks = kbucket.iter().map(|node| node.pk).collect::<Vec<_>>();
+        assert_eq!(pks, vec![trusted_1.pk, trusted_2.pk]);
+    }
+
+    #[test]
+    fn kbucket_try_add_trusted_existing_node() {
+        let pk = PublicKey([0; PUBLICKEYBYTES]);
+        let mut kbucket = Kbucket::new(2);
+
+        let node = PackedNode::new("1.2.3.4:12345".parse().unwrap(), &PublicKey([1; PUBLICKEYBYTES]));
+
+        assert!(kbucket.try_add(&pk, &node, /* evict */ false));
+        assert!(!kbucket.get_node(&pk, &node.pk).unwrap().is_trusted);
+
+        assert!(kbucket.try_add_trusted(&pk, &node));
+        assert!(kbucket.get_node(&pk, &node.pk).unwrap().is_trusted);
+
+        // adding as untrusted again doesn't reset the flag
+        assert!(kbucket.try_add(&pk, &node, /* evict */ false));
+        assert!(kbucket.get_node(&pk, &node.pk).unwrap().is_trusted);
+    }
+
+    #[test]
+    fn kbucket_try_add_without_balance_families() {
+        let pk = PublicKey([0; PUBLICKEYBYTES]);
+        let mut kbucket = Kbucket::new(2);
+
+        let node_v4_1 = PackedNode::new(
+            "1.2.3.4:12345".parse().unwrap(),
+            &PublicKey([1; PUBLICKEYBYTES])
+        );
+        let node_v4_2 = PackedNode::new(
+            "1.2.3.4:12346".parse().unwrap(),
+            &PublicKey([2; PUBLICKEYBYTES])
+        );
+        let node_v6 = PackedNode::new(
+            "[2001:db8::1]:12345".parse().unwrap(),
+            &PublicKey([3; PUBLICKEYBYTES])
+        );
+
+        assert!(kbucket.try_add(&pk, &node_v4_1, /* evict */ false));
+        assert!(kbucket.try_add(&pk, &node_v4_2, /* evict */ false));
+        assert!(!kbucket.try_add(&pk, &node_v6, /* evict */ false));
+    }
+
+    #[test]
+    fn kbucket_clear_quarantine() {
+        let pk = PublicKey([0; PUBLICKEYBYTES]);
+        let mut kbucket = Kbucket::new(1);
+        kbucket.set_quarantine_timeout(Duration::from_secs(10));
+
+        let node_1 = PackedNode::new(
+            "1.2.3.4:12345".parse().unwrap(),
+            &PublicKey([1; PUBLICKEYBYTES])
+        );
+        let node_2 = PackedNode::new(
+            "1.2.3.4:12346".parse().unwrap(),
+            &PublicKey([2; PUBLICKEYBYTES])
+        );
+
+        assert!(kbucket.try_add(&pk, &node_2, /* evict */ false));
+
+        let now = Instant::now() + Duration::from_secs(BAD_NODE_TIMEOUT + 1);
+        let mut enter = tokio_executor::enter().unwrap();
+
+        let clock = Clock::new_with_now(ConstNow(now));
+        with_default(&clock, &mut enter, |_| {
+            assert!(kbucket.try_add(&pk, &node_1, /* evict */ false));
+            kbucket.clear_quarantine();
+        });
+
+        assert!(!kbucket.quarantine.is_empty());
+
+        let clock = Clock::new_with_now(ConstNow(now + Duration::from_secs(10)));
+        with_default(&clock, &mut enter, |_| {
+            kbucket.clear_quarantine();
+        });
+
+        assert!(kbucket.quarantine.is_empty());
+    }
+
     // Kbucket::remove()
 
     #[test]
@@ -815,6 +1648,53 @@ mod tests {
         assert!(ktree.try_add(&node));
     }
 
+    #[test]
+    fn ktree_try_add_max_nodes_per_subnet() {
+        crypto_init().unwrap();
+        let pk = gen_keypair().0;
+        let mut ktree = Ktree::new(&pk);
+        ktree.set_max_nodes_per_subnet(Some(2));
+
+        for i in 0 .. 4 {
+            let node = PackedNode::new(SocketAddr::new("1.2.3.4".parse().unwrap(), 10000 + i as u16), &pk_in_kbucket(&pk, i, 0));
+            assert_eq!(ktree.can_add(&node), i < 2);
+            assert_eq!(ktree.try_add(&node), i < 2);
+        }
+
+        // existing node can be updated
+        assert!(ktree.try_add(&PackedNode::new("1.2.3.5:12345".parse().unwrap(), &pk_in_kbucket(&pk, 0, 0))));
+        assert!(!ktree.try_add_trusted(&PackedNode::new("1.2.3.6:12345".parse().unwrap(), &pk_in_kbucket(&pk, 4, 0))));
+        // but nodes from other subnets still can be added
+        assert!(ktree.try_add(&PackedNode::new("1.2.4.4:12345".parse().unwrap(), &pk_in_kbucket(&pk, 5, 0))));
+
+        // IPv6 nodes are limited by /64 subnet
+        for i in 0 .. 3 {
+            let node = PackedNode::new(format!("[2001:db8:1:2::{}]:12345", i + 1).parse().unwrap(), &pk_in_kbucket(&pk, 6 + i, 0));
+            assert_eq!(ktree.try_add(&node), i < 2);
+        }
+        assert!(ktree.try_add(&PackedNode::new("[2001:db8:1:3::1]:12345".parse().unwrap(), &pk_in_kbucket(&pk, 9, 0))));
+
+        // removed nodes free their slots
+        assert!(ktree.remove(&pk_in_kbucket(&pk, 0, 0)).is_some());
+        assert!(ktree.try_add(&PackedNode::new("1.2.3.4:12345".parse().unwrap(), &pk_in_kbucket(&pk, 10, 0))));
+    }
+
+    #[test]
+    fn ktree_subnets_count() {
+        crypto_init().unwrap();
+        let pk = gen_keypair().0;
+        let mut ktree = Ktree::new(&pk);
+
+        assert_eq!(ktree.subnets_count(), 0);
+
+        let addrs = ["1.2.3.4:12345", "1.2.3.5:12345", "1.2.4.4:12345", "[2001:db8:1:2::1]:12345", "[2001:db8:1:3::1]:12345"];
+        for addr in addrs.iter() {
+            assert!(ktree.try_add(&PackedNode::new(addr.parse().unwrap(), &gen_keypair().0)));
+        }
+
+        assert_eq!(ktree.subnets_count(), 3);
+    }
+
     #[test]
     fn ktree_try_add_self() {
         let pk = PublicKey([0; PUBLICKEYBYTES]);
@@ -869,15 +1749,62 @@ mod tests {
             assert!(ktree.try_add(&node_by_idx(i)));
         }
 
-        let closest: Vec<_> = ktree.get_closest(&PublicKey([0; PUBLICKEYBYTES]), true).into();
+        let closest: Vec<_> = ktree.get_closest(&PublicKey([0; PUBLICKEYBYTES]), true, false).into();
         let should_be = (0 .. 4).map(node_by_idx).collect::<Vec<_>>();
         assert_eq!(closest, should_be);
 
-        let closest: Vec<_> = ktree.get_closest(&PublicKey([255; PUBLICKEYBYTES]), true).into();
+        let closest: Vec<_> = ktree.get_closest(&PublicKey([255; PUBLICKEYBYTES]), true, false).into();
         let should_be = (4 .. 8).rev().map(node_by_idx).collect::<Vec<_>>();
         assert_eq!(closest, should_be);
     }
 
+    #[test]
+    fn ktree_get_closest_only_ipv4() {
+        let pk = PublicKey([0; PUBLICKEYBYTES]);
+        let mut ktree = Ktree::new(&pk);
+
+        let node_v4 = PackedNode::new("1.2.3.4:12345".parse().unwrap(), &PublicKey([1; PUBLICKEYBYTES]));
+        let node_v6 = PackedNode::new("[2001:db8::1]:12345".parse().unwrap(), &PublicKey([2; PUBLICKEYBYTES]));
+
+        assert!(ktree.try_add(&node_v4));
+        assert!(ktree.try_add(&node_v6));
+
+        let closest: Vec<_> = ktree.get_closest(&pk, false, false).into();
+        assert_eq!(closest, vec![node_v4, node_v6]);
+
+        let closest: Vec<_> = ktree.get_closest(&pk, false, true).into();
+        assert_eq!(closest, vec![node_v4]);
+    }
+
+    #[test]
+    fn ktree_get_closest_balance_families() {
+        let pk = PublicKey([0; PUBLICKEYBYTES]);
+        let mut ktree = Ktree::new(&pk);
+
+        let nodes_v4 = (0 .. 5).map(|i| {
+            let addr = SocketAddr::new("1.2.3.4".parse().unwrap(), 12345 + u16::from(i));
+            PackedNode::new(addr, &PublicKey([i + 1; PUBLICKEYBYTES]))
+        }).collect::<Vec<_>>();
+        let node_v6 = PackedNode::new("[2001:db8::1]:12345".parse().unwrap(), &PublicKey([6; PUBLICKEYBYTES]));
+
+        for node in &nodes_v4 {
+            assert!(ktree.try_add(node));
+        }
+        assert!(ktree.try_add(&node_v6));
+
+        let closest: Vec<_> = ktree.get_closest(&pk, false, false).into();
+        assert_eq!(closest, nodes_v4[.. 4].to_vec());
+
+        // the farthest IPv4 node is replaced by the IPv6 node
+        ktree.set_balance_families(true);
+        let closest: Vec<_> = ktree.get_closest(&pk, false, false).into();
+        assert_eq!(closest, vec![nodes_v4[0], nodes_v4[1], nodes_v4[2], node_v6]);
+
+        // but not when only IPv4 nodes are requested
+        let closest: Vec<_> = ktree.get_closest(&pk, false, true).into();
+        assert_eq!(closest, nodes_v4[.. 4].to_vec());
+    }
+
     // Ktree::position()
 
     fn position_test_data() -> (Ktree, PackedNode, PackedNode, PackedNode) {
@@ -2,6 +2,7 @@
 
 use std::iter;
 use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use std::fmt;
@@ -13,6 +14,7 @@ use futures::{future, stream, Future, Stream};
 use futures::sync::mpsc;
 use get_if_addrs;
 use get_if_addrs::IfAddr;
+use parking_lot::RwLock;
 use tokio::timer::Interval;
 use tokio::timer::Error as TimerError;
 use tokio::timer::timeout::Error as TimeoutError;
@@ -20,6 +22,8 @@ use tokio::timer::timeout::Error as TimeoutError;
 use crate::toxcore::crypto_core::*;
 use crate::toxcore::io_tokio::*;
 use crate::toxcore::dht::packet::*;
+use crate::toxcore::dht::server::PacketFilter;
+use crate::toxcore::time::*;
 
 /// Error that can happen during lan discovery
 #[derive(Debug)]
@@ -118,6 +122,89 @@ pub const LAN_DISCOVERY_SEND_TIMEOUT: u64 = 1;
 /// Shorthand for the transmit half of the message channel.
 type Tx = mpsc::Sender<(Packet, SocketAddr)>;
 
+/// Quiet period during which LAN discovery is suppressed, e.g. because we are
+/// on a metered connection. Clones share the same period so it can be set by
+/// DHT `Server` and checked by `LanDiscoverySender`.
+#[derive(Clone, Debug, Default)]
+pub struct QuietPeriod {
+    /// Time until which LAN discovery is suppressed.
+    until: Arc<RwLock<Option<Instant>>>,
+}
+
+impl QuietPeriod {
+    /// Create new `QuietPeriod` that doesn't suppress anything.
+    pub fn new() -> QuietPeriod {
+        QuietPeriod::default()
+    }
+
+    /// Suppress LAN discovery for the given duration starting from now. It
+    /// resumes automatically after that.
+    pub fn suppress(&self, duration: Duration) {
+        *self.until.write() = Some(clock_now() + duration);
+    }
+
+    /// Check if LAN discovery is suppressed now.
+    pub fn is_active(&self) -> bool {
+        match *self.until.read() {
+            Some(until) => clock_now() < until,
+            None => false,
+        }
+    }
+}
+
+/// Window of ports `LanDiscovery` packets are sent to. It advances every time
+/// packets are sent. Clones share the same window so that packets sent by
+/// DHT `Server` and by `LanDiscoverySender` continue each other's ports.
+#[derive(Clone, Debug)]
+pub struct LanPorts {
+    /// Start port for the next iteration of `LanDiscovery` packets sending
+    next_port: Arc<RwLock<u16>>,
+}
+
+impl Default for LanPorts {
+    fn default() -> Self {
+        LanPorts {
+            next_port: Arc::new(RwLock::new(START_PORT)),
+        }
+    }
+}
+
+impl LanPorts {
+    /// Create new `LanPorts` starting from `START_PORT`.
+    pub fn new() -> LanPorts {
+        LanPorts::default()
+    }
+
+    /// Get ports of the current iteration and advance the window. The
+    /// `DEFAULT_PORT` is always included.
+    fn next_ports(&self) -> Vec<u16> {
+        fn cycle(port: u16) -> u16 {
+            (port - START_PORT) % (END_PORT - START_PORT) + START_PORT
+        }
+        let mut next_port = self.next_port.write();
+        // range of ports to send discovery packet to
+        let ports_range = (*next_port .. *next_port + PORTS_PER_DISCOVERY).map(cycle);
+        // always send discovery packet to default port
+        let ports = iter::once(DEFAULT_PORT).chain(ports_range).collect();
+        // update port for next iteration
+        *next_port = cycle(*next_port + PORTS_PER_DISCOVERY);
+        ports
+    }
+}
+
+/// Get broadcast addresses to send `LanDiscovery` packets to during the
+/// current iteration and advance the ports window.
+///
+/// This function returns Cartesian product of broadcast addresses of host's
+/// network interfaces with ports list of current iteration.
+pub(crate) fn broadcast_socket_addrs(ipv6: bool, ports: &LanPorts) -> Vec<SocketAddr> {
+    let ip_addrs = LanDiscoverySender::get_broadcast_addrs(ipv6);
+    let ports = ports.next_ports();
+    ip_addrs.into_iter().flat_map(|ip_addr|
+        ports.iter().map(move |&port| SocketAddr::new(ip_addr, port))
+    ).collect()
+}
+
 /// LAN discovery struct
 pub struct LanDiscoverySender {
     /// Sink to send packet to UDP socket
@@ -126,8 +213,12 @@ pub struct LanDiscoverySender {
     dht_pk: PublicKey,
     /// Whether our UDP socket is IPv6
     ipv6: bool,
-    /// Start port for the next iteration of `LanDiscovery` packets sending
-    next_port: u16,
+    /// Ports window of `LanDiscovery` packets sending
+    ports: LanPorts,
+    /// Quiet period during which `LanDiscovery` packets are not sent
+    quiet_period: QuietPeriod,
+    /// Filter that is called for every packet before sending it
+    filter: Option<Arc<PacketFilter>>,
 }
 
 impl LanDiscoverySender {
@@ -137,10 +228,33 @@ impl LanDiscoverySender {
             tx,
             dht_pk,
             ipv6,
-            next_port: START_PORT,
+            ports: LanPorts::new(),
+            quiet_period: QuietPeriod::new(),
+            filter: None,
         }
     }
 
+    /// Set quiet period during which `LanDiscovery` packets are not sent.
+    /// Usually it's shared with DHT `Server` so that LAN discovery can be
+    /// suppressed with `Server::suppress_lan_discovery`.
+    pub fn set_quiet_period(&mut self, quiet_period: QuietPeriod) {
+        self.quiet_period = quiet_period;
+    }
+
+    /// Set ports window of `LanDiscovery` packets sending. Usually it's
+    /// shared with DHT `Server` so that `Server::trigger_lan_discovery`
+    /// doesn't repeat ports that were just used.
+    pub fn set_ports(&mut self, ports: LanPorts) {
+        self.ports = ports;
+    }
+
+    /// Set filter that is called for every packet before sending it. If the
+    /// filter returns `false` the packet is not sent. Usually it's
+    /// `Server::send_filter` so that LAN discovery respects allowed networks.
+    pub fn set_filter(&mut self, filter: Arc<PacketFilter>) {
+        self.filter = Some(filter);
+    }
+
     /// Get broadcast addresses for host's network interfaces.
     fn get_ipv4_broadcast_addrs() -> Vec<IpAddr> {
         let ifs = get_if_addrs::get_if_addrs().expect("no network interface");
@@ -157,9 +271,9 @@ impl LanDiscoverySender {
     }
 
     /// Get broadcast addresses depending on IP version.
-    fn get_broadcast_addrs(&self) -> Vec<IpAddr> {
+    fn get_broadcast_addrs(ipv6: bool) -> Vec<IpAddr> {
         let mut ip_addrs = LanDiscoverySender::get_ipv4_broadcast_addrs();
-        if self.ipv6 {
+        if ipv6 {
             // IPv6 broadcast address
             ip_addrs.push("FF02::1".parse().unwrap());
             // IPv4 global broadcast address
@@ -171,34 +285,21 @@ impl LanDiscoverySender {
         ip_addrs
     }
 
-    /// Get broadcast addresses to send `LanDiscovery` packet.
-    ///
-    /// This function returns Cartesian product of addresses from
-    /// `get_broadcast_addrs` function with ports list of current iteration.
-    fn get_broadcast_socket_addrs(&mut self) -> Vec<SocketAddr> {
-        fn cycle(port: u16) -> u16 {
-            (port - START_PORT) % (END_PORT - START_PORT) + START_PORT
-        }
-        let ip_addrs = self.get_broadcast_addrs();
-        // range of ports to send discovery packet to
-        let ports_range = (self.next_port .. self.next_port + PORTS_PER_DISCOVERY).map(cycle);
-        // always send discovery packet to default port
-        let ports_range = iter::once(DEFAULT_PORT).chain(ports_range);
-        // add ports to ip addrs
-        let socket_addrs = ip_addrs.into_iter().flat_map(move |ip_addr| {
-            ports_range.clone().map(move |port| SocketAddr::new(ip_addr, port))
-        }).collect();
-        // update port for next iteration
-        self.next_port = cycle(self.next_port + PORTS_PER_DISCOVERY);
-        socket_addrs
-    }
-
-    /// Send `LanDiscovery` packets.
-    fn send(&mut self) -> impl Future<Item=(), Error=TimeoutError<mpsc::SendError<(Packet, SocketAddr)>>> + Send {
-        let addrs = self.get_broadcast_socket_addrs();
+    /// Send `LanDiscovery` packets unless quiet period is active.
+    pub(crate) fn send(&mut self) -> impl Future<Item=(), Error=TimeoutError<mpsc::SendError<(Packet, SocketAddr)>>> + Send {
+        let addrs = if self.quiet_period.is_active() {
+            trace!("LAN discovery is suppressed, packets are not sent");
+            Vec::new()
+        } else {
+            broadcast_socket_addrs(self.ipv6, &self.ports)
+        };
         let lan_packet = Packet::LanDiscovery(LanDiscovery {
             pk: self.dht_pk,
         });
+        let addrs = match self.filter {
+            Some(ref filter) => addrs.into_iter().filter(|&addr| filter(&lan_packet, addr)).collect(),
+            None => addrs,
+        };
 
         let stream = stream::iter_ok(
             addrs.into_iter().map(move |addr| (lan_packet.clone(), addr))
@@ -234,6 +335,8 @@ mod tests {
     use super::*;
 
     use futures::{Future, Stream};
+    use tokio_executor;
+    use tokio_timer::clock::*;
 
     fn broadcast_addrs_count() -> usize {
         get_if_addrs::get_if_addrs().expect("no network interface").iter().filter_map(|interface|
@@ -258,7 +361,7 @@ mod tests {
 
         assert!(lan_discovery.send().wait().is_ok());
 
-        assert_eq!(lan_discovery.next_port, START_PORT + PORTS_PER_DISCOVERY);
+        assert_eq!(*lan_discovery.ports.next_port.read(), START_PORT + PORTS_PER_DISCOVERY);
 
         for _i in 0 .. packets_count {
             let (received, rx1) = rx.into_future().wait().unwrap();
@@ -284,7 +387,7 @@ mod tests {
 
         assert!(lan_discovery.send().wait().is_ok());
 
-        assert_eq!(lan_discovery.next_port, START_PORT + PORTS_PER_DISCOVERY);
+        assert_eq!(*lan_discovery.ports.next_port.read(), START_PORT + PORTS_PER_DISCOVERY);
 
         for _i in 0 .. packets_count {
             let (received, rx1) = rx.into_future().wait().unwrap();
@@ -298,6 +401,59 @@ mod tests {
         }
     }
 
+    #[test]
+    fn send_suppressed() {
+        crypto_init().unwrap();
+        // `+1` for 255.255.255.255
+        let packets_count = (broadcast_addrs_count() + 1) * (PORTS_PER_DISCOVERY + 1) as usize;
+
+        let (tx, rx) = mpsc::channel(packets_count);
+        let (dht_pk, _dht_sk) = gen_keypair();
+        let mut lan_discovery = LanDiscoverySender::new(tx, dht_pk, /* ipv6 */ false);
+        let quiet_period = QuietPeriod::new();
+        lan_discovery.set_quiet_period(quiet_period.clone());
+
+        let now = Instant::now();
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(now));
+        with_default(&clock, &mut enter, |_| {
+            quiet_period.suppress(Duration::from_secs(10));
+            assert!(lan_discovery.send().wait().is_ok());
+            // ports are not advanced since nothing is sent
+            assert_eq!(*lan_discovery.ports.next_port.read(), START_PORT);
+        });
+
+        let clock = Clock::new_with_now(ConstNow(now + Duration::from_secs(10)));
+        with_default(&clock, &mut enter, |_| {
+            assert!(lan_discovery.send().wait().is_ok());
+            assert_eq!(*lan_discovery.ports.next_port.read(), START_PORT + PORTS_PER_DISCOVERY);
+        });
+
+        drop(lan_discovery);
+
+        assert_eq!(rx.collect().wait().unwrap().len(), packets_count);
+    }
+
+    #[test]
+    fn send_filtered() {
+        crypto_init().unwrap();
+        let packets_count = (broadcast_addrs_count() + 1) * (PORTS_PER_DISCOVERY + 1) as usize;
+
+        let (tx, rx) = mpsc::channel(packets_count);
+        let (dht_pk, _dht_sk) = gen_keypair();
+        let mut lan_discovery = LanDiscoverySender::new(tx, dht_pk, /* ipv6 */ false);
+        lan_discovery.set_filter(Arc::new(|_packet: &Packet, addr: SocketAddr| addr.port() == DEFAULT_PORT));
+
+        assert!(lan_discovery.send().wait().is_ok());
+
+        drop(lan_discovery);
+
+        let addrs = rx.map(|(_packet, addr)| addr).collect().wait().unwrap();
+        // `+1` for 255.255.255.255
+        assert_eq!(addrs.len(), broadcast_addrs_count() + 1);
+        assert!(addrs.iter().all(|addr| addr.port() == DEFAULT_PORT));
+    }
+
     #[test]
     fn cycle_around_ports() {
         crypto_init().unwrap();
@@ -308,11 +464,11 @@ mod tests {
         let (dht_pk, _dht_sk) = gen_keypair();
         let mut lan_discovery = LanDiscoverySender::new(tx, dht_pk, /* ipv6 */ false);
 
-        lan_discovery.next_port = END_PORT - 1;
+        *lan_discovery.ports.next_port.write() = END_PORT - 1;
 
         assert!(lan_discovery.send().wait().is_ok());
 
-        assert_eq!(lan_discovery.next_port, START_PORT + PORTS_PER_DISCOVERY - 1);
+        assert_eq!(*lan_discovery.ports.next_port.read(), START_PORT + PORTS_PER_DISCOVERY - 1);
 
         for _i in 0 .. packets_count {
             let (received, rx1) = rx.into_future().wait().unwrap();
@@ -12,6 +12,7 @@ pub mod daemon_state;
 pub mod lan_discovery;
 pub mod ip_port;
 pub mod request_queue;
+pub mod requests_limiter;
 pub mod nodes_queue;
 pub mod precomputed_cache;
 pub mod server_ext;
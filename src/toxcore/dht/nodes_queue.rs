@@ -106,6 +106,11 @@ impl NodesQueue {
         }
     }
 
+    /// Remove all nodes from the queue.
+    pub fn clear(&mut self) {
+        self.nodes.clear();
+    }
+
     /// Check if node with given PK is in the queue.
     pub fn contains(&self, base_pk: &PublicKey, pk: &PublicKey) -> bool {
         self.nodes.binary_search_by(|n| base_pk.distance(&n.pk, pk)).is_ok()
@@ -116,6 +121,11 @@ impl NodesQueue {
         self.capacity as usize
     }
 
+    /// Get the number of nodes in the queue.
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
     /** Check if the queue is empty.
 
     Returns `true` if there are no nodes in the queue, `false`
@@ -157,6 +167,9 @@ impl NodesQueue {
 
     /// Create iterator over `PackedNode`s. Nodes that this iterator produces
     /// are sorted by distance to a base `PublicKey` (in ascending order).
+    /// Since distance is XOR of keys, different `PublicKey`s are never
+    /// equidistant to the base one, so the order doesn't depend on the order
+    /// in which nodes were added.
     pub fn iter(&self) -> impl Iterator<Item = &PackedNode> {
         self.nodes.iter()
     }
@@ -242,6 +255,31 @@ mod tests {
         assert!(queue.can_add(&pk, &existing_node_2));
     }
 
+    #[test]
+    fn iter_order_does_not_depend_on_insertion_order() {
+        let pk = PublicKey([0; PUBLICKEYBYTES]);
+        let nodes = (0 .. 8).map(|i| {
+            let addr = SocketAddr::new("1.2.3.4".parse().unwrap(), 12345 + u16::from(i));
+            let mut node_pk = [0; PUBLICKEYBYTES];
+            // keys differ only in the last byte so they are as close to
+            // each other as possible
+            node_pk[PUBLICKEYBYTES - 1] = i + 1;
+            PackedNode::new(addr, &PublicKey(node_pk))
+        }).collect::<Vec<_>>();
+
+        let mut queue_1 = NodesQueue::new(8);
+        for node in &nodes {
+            assert!(queue_1.try_add(&pk, node));
+        }
+        let mut queue_2 = NodesQueue::new(8);
+        for node in nodes.iter().rev() {
+            assert!(queue_2.try_add(&pk, node));
+        }
+
+        assert_eq!(queue_1.iter().collect::<Vec<_>>(), nodes.iter().collect::<Vec<_>>());
+        assert_eq!(queue_1, queue_2);
+    }
+
     #[test]
     fn remove() {
         let pk = PublicKey([0; PUBLICKEYBYTES]);
@@ -265,6 +303,25 @@ mod tests {
         assert!(queue.is_empty());
     }
 
+    #[test]
+    fn clear() {
+        let pk = PublicKey([0; PUBLICKEYBYTES]);
+        let mut queue = NodesQueue::new(8);
+
+        for i in 0 .. 4 {
+            let node = PackedNode::new(
+                "1.2.3.4:12345".parse().unwrap(),
+                &PublicKey([i + 1; PUBLICKEYBYTES])
+            );
+            assert!(queue.try_add(&pk, &node));
+        }
+
+        queue.clear();
+
+        assert!(queue.is_empty());
+        assert_eq!(queue.capacity(), 8);
+    }
+
     #[test]
     fn is_empty() {
         let pk = PublicKey([0; PUBLICKEYBYTES]);
@@ -39,4 +39,10 @@ impl PrecomputedCache {
         keys.put(pk, precomputed_key.clone());
         precomputed_key
     }
+
+    /// Put `PrecomputedKey` for the given `PublicKey` to the cache.
+    #[cfg(test)]
+    pub(crate) fn insert(&self, pk: PublicKey, precomputed_key: PrecomputedKey) {
+        self.precomputed_keys.lock().put(pk, precomputed_key);
+    }
 }
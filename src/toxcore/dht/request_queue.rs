@@ -1,11 +1,15 @@
 //! Managing requests IDs and timeouts.
 
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::time::{Duration, Instant};
 
 use crate::toxcore::crypto_core::*;
 use crate::toxcore::time::*;
 
+/// Default maximum number of request IDs that `RequestQueue` can hold. When
+/// the limit is reached the oldest request ID is evicted.
+pub const REQUEST_QUEUE_MAX_SIZE: usize = 4096;
+
 /** Struct that stores and manages requests IDs and timeouts.
 
 Request ID is tied to `PublicKey` of node to which a request is supposed to be
@@ -15,8 +19,14 @@ made.
 pub struct RequestQueue {
     /// Map that stores requests IDs with time when they were generated.
     ping_map: HashMap<(PublicKey, u64), Instant>,
+    /// Requests IDs in order they were generated so that the oldest one can
+    /// be found without scanning the map. It might contain IDs that were
+    /// already removed from the map, they are skipped on eviction.
+    order: VecDeque<((PublicKey, u64), Instant)>,
     /// Timeout when requests IDs are considered invalid.
     timeout: Duration,
+    /// Maximum number of request IDs that can be stored.
+    max_size: usize,
 }
 
 impl RequestQueue {
@@ -24,7 +34,38 @@ impl RequestQueue {
     pub fn new(timeout: Duration) -> RequestQueue {
         RequestQueue {
             ping_map: HashMap::new(),
+            order: VecDeque::new(),
             timeout,
+            max_size: REQUEST_QUEUE_MAX_SIZE,
+        }
+    }
+
+    /// Set maximum number of request IDs that can be stored. Excess request
+    /// IDs are evicted starting from the oldest ones.
+    pub fn set_max_size(&mut self, max_size: usize) {
+        self.max_size = max_size;
+        while self.ping_map.len() > self.max_size {
+            self.evict_oldest();
+        }
+    }
+
+    /// Number of stored request IDs.
+    pub fn len(&self) -> usize {
+        self.ping_map.len()
+    }
+
+    /// Check if there are no stored request IDs.
+    pub fn is_empty(&self) -> bool {
+        self.ping_map.is_empty()
+    }
+
+    /// Remove the oldest request ID.
+    fn evict_oldest(&mut self) {
+        while let Some((key, time)) = self.order.pop_front() {
+            if self.ping_map.get(&key) == Some(&time) {
+                self.ping_map.remove(&key);
+                return;
+            }
         }
     }
 
@@ -42,7 +83,12 @@ impl RequestQueue {
     /// be verified with `check_ping_id` function.
     pub fn new_ping_id(&mut self, pk: PublicKey) -> u64 {
         let ping_id = self.generate_ping_id(pk);
-        self.ping_map.insert((pk, ping_id), clock_now());
+        if self.ping_map.len() >= self.max_size {
+            self.evict_oldest();
+        }
+        let time = clock_now();
+        self.ping_map.insert((pk, ping_id), time);
+        self.order.push_back(((pk, ping_id), time));
         ping_id
     }
 
@@ -61,10 +107,25 @@ impl RequestQueue {
 
     /// Remove timed out request IDs.
     pub fn clear_timed_out(&mut self) {
+        self.take_timed_out();
+    }
+
+    /// Remove timed out request IDs and return `PublicKey`s of nodes these
+    /// requests were made to. A `PublicKey` is returned as many times as many
+    /// requests to the node timed out.
+    pub fn take_timed_out(&mut self) -> Vec<PublicKey> {
         let timeout = self.timeout;
-        self.ping_map.retain(|&_, &mut time|
-            clock_elapsed(time) <= timeout
-        );
+        let mut timed_out = Vec::new();
+        self.ping_map.retain(|&(pk, _), &mut time| {
+            let is_alive = clock_elapsed(time) <= timeout;
+            if !is_alive {
+                timed_out.push(pk);
+            }
+            is_alive
+        });
+        let ping_map = &self.ping_map;
+        self.order.retain(|&(key, time)| ping_map.get(&key) == Some(&time));
+        timed_out
     }
 }
 
@@ -173,4 +234,103 @@ mod tests {
             assert!(queue.ping_map.contains_key(&(pk, ping_id_2)));
         });
     }
+
+    #[test]
+    fn take_timed_out() {
+        crypto_init().unwrap();
+        let mut queue = RequestQueue::new(Duration::from_secs(42));
+        let (pk_1, _sk) = gen_keypair();
+        let (pk_2, _sk) = gen_keypair();
+
+        queue.new_ping_id(pk_1);
+
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(Instant::now() + Duration::from_secs(43)));
+        let ping_id_2 = with_default(&clock, &mut enter, |_| queue.new_ping_id(pk_2));
+
+        let timed_out = with_default(&clock, &mut enter, |_| queue.take_timed_out());
+
+        assert_eq!(timed_out, vec![pk_1]);
+        assert_eq!(queue.len(), 1);
+        assert!(queue.ping_map.contains_key(&(pk_2, ping_id_2)));
+    }
+
+    #[test]
+    fn new_ping_id_evicts_oldest() {
+        crypto_init().unwrap();
+        let mut queue = RequestQueue::new(Duration::from_secs(42));
+        queue.set_max_size(2);
+        let (pk, _sk) = gen_keypair();
+
+        let now = Instant::now();
+        let mut enter = tokio_executor::enter().unwrap();
+        let ping_ids = (0 .. 3).map(|i| {
+            let clock = Clock::new_with_now(ConstNow(now + Duration::from_secs(i)));
+            with_default(&clock, &mut enter, |_| queue.new_ping_id(pk))
+        }).collect::<Vec<_>>();
+
+        assert_eq!(queue.len(), 2);
+        assert!(!queue.ping_map.contains_key(&(pk, ping_ids[0])));
+        assert!(queue.ping_map.contains_key(&(pk, ping_ids[1])));
+        assert!(queue.ping_map.contains_key(&(pk, ping_ids[2])));
+    }
+
+    #[test]
+    fn set_max_size_evicts_oldest() {
+        crypto_init().unwrap();
+        let mut queue = RequestQueue::new(Duration::from_secs(42));
+        let (pk, _sk) = gen_keypair();
+
+        let now = Instant::now();
+        let mut enter = tokio_executor::enter().unwrap();
+        let ping_ids = (0 .. 3).map(|i| {
+            let clock = Clock::new_with_now(ConstNow(now + Duration::from_secs(i)));
+            with_default(&clock, &mut enter, |_| queue.new_ping_id(pk))
+        }).collect::<Vec<_>>();
+
+        queue.set_max_size(1);
+
+        assert_eq!(queue.len(), 1);
+        assert!(queue.ping_map.contains_key(&(pk, ping_ids[2])));
+    }
+
+    #[test]
+    fn new_ping_id_evicts_oldest_skipping_checked() {
+        crypto_init().unwrap();
+        let mut queue = RequestQueue::new(Duration::from_secs(42));
+        queue.set_max_size(2);
+        let (pk, _sk) = gen_keypair();
+
+        let ping_id_1 = queue.new_ping_id(pk);
+        let ping_id_2 = queue.new_ping_id(pk);
+        assert!(queue.check_ping_id(pk, ping_id_1));
+        let ping_id_3 = queue.new_ping_id(pk);
+
+        // the queue isn't full after ping_id_1 was checked
+        assert_eq!(queue.len(), 2);
+        assert!(queue.ping_map.contains_key(&(pk, ping_id_2)));
+        assert!(queue.ping_map.contains_key(&(pk, ping_id_3)));
+
+        let ping_id_4 = queue.new_ping_id(pk);
+
+        assert_eq!(queue.len(), 2);
+        assert!(!queue.ping_map.contains_key(&(pk, ping_id_2)));
+        assert!(queue.ping_map.contains_key(&(pk, ping_id_3)));
+        assert!(queue.ping_map.contains_key(&(pk, ping_id_4)));
+    }
+
+    #[test]
+    fn clear_timed_out_drops_stale_order() {
+        crypto_init().unwrap();
+        let mut queue = RequestQueue::new(Duration::from_secs(42));
+        let (pk, _sk) = gen_keypair();
+
+        let ping_id = queue.new_ping_id(pk);
+        assert!(queue.check_ping_id(pk, ping_id));
+        queue.new_ping_id(pk);
+
+        queue.clear_timed_out();
+
+        assert_eq!(queue.order.len(), 1);
+    }
 }
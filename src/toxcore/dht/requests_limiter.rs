@@ -0,0 +1,147 @@
+//! Limiting the rate of requests per sender's `PublicKey` or address.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+use crate::toxcore::crypto_core::*;
+use crate::toxcore::time::*;
+
+/** Struct that counts requests received from every `PublicKey` and decides
+whether a new request should be handled.
+
+Requests are counted per interval. If node with some `PublicKey` sends more
+than `max_requests` requests during one interval then the rest of its requests
+received during that interval should be dropped. Since the counting is done
+per `PublicKey` it works regardless of the address the requests were sent
+from. Requests can be counted per other key, e.g. per address, as well.
+*/
+#[derive(Clone, Debug)]
+pub struct RequestsLimiter<K: Eq + Hash = PublicKey> {
+    /// Map that stores time when the current interval started and the number
+    /// of requests received during this interval.
+    requests: HashMap<K, (Instant, u32)>,
+    /// Maximum number of requests that can be handled per interval.
+    max_requests: u32,
+    /// Length of the interval.
+    interval: Duration,
+}
+
+impl<K: Eq + Hash> RequestsLimiter<K> {
+    /// Create new `RequestsLimiter`.
+    pub fn new(max_requests: u32, interval: Duration) -> RequestsLimiter<K> {
+        RequestsLimiter {
+            requests: HashMap::new(),
+            max_requests,
+            interval,
+        }
+    }
+
+    /// Count a request from the node with given key. Returns `true` if the
+    /// request should be handled and `false` if this node exceeded the limit
+    /// and the request should be dropped.
+    pub fn check(&mut self, key: K) -> bool {
+        let interval = self.interval;
+        let entry = self.requests.entry(key).or_insert_with(|| (clock_now(), 0));
+
+        if clock_elapsed(entry.0) >= interval {
+            *entry = (clock_now(), 0);
+        }
+
+        entry.1 = entry.1.saturating_add(1);
+        entry.1 <= self.max_requests
+    }
+
+    /// Remove entries which interval is over.
+    pub fn clear_expired(&mut self) {
+        let interval = self.interval;
+        self.requests.retain(|_, &mut (time, _)|
+            clock_elapsed(time) < interval
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::SocketAddr;
+
+    use tokio_executor;
+    use tokio_timer::clock::*;
+
+    use crate::toxcore::time::ConstNow;
+
+    #[test]
+    fn clone() {
+        let limiter: RequestsLimiter = RequestsLimiter::new(2, Duration::from_secs(1));
+        let _ = limiter.clone();
+    }
+
+    #[test]
+    fn check() {
+        crypto_init().unwrap();
+        let mut limiter = RequestsLimiter::new(2, Duration::from_secs(1));
+        let pk_1 = gen_keypair().0;
+        let pk_2 = gen_keypair().0;
+
+        assert!(limiter.check(pk_1));
+        assert!(limiter.check(pk_1));
+        assert!(!limiter.check(pk_1));
+
+        // other keys are not affected
+        assert!(limiter.check(pk_2));
+    }
+
+    #[test]
+    fn check_by_addr() {
+        let mut limiter = RequestsLimiter::new(1, Duration::from_secs(1));
+        let addr_1: SocketAddr = "127.0.0.1:33445".parse().unwrap();
+        let addr_2: SocketAddr = "127.0.0.1:33446".parse().unwrap();
+
+        assert!(limiter.check(addr_1));
+        assert!(!limiter.check(addr_1));
+
+        // other addresses are not affected
+        assert!(limiter.check(addr_2));
+    }
+
+    #[test]
+    fn check_after_interval() {
+        crypto_init().unwrap();
+        let mut limiter = RequestsLimiter::new(1, Duration::from_secs(1));
+        let pk = gen_keypair().0;
+
+        assert!(limiter.check(pk));
+        assert!(!limiter.check(pk));
+
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(
+            Instant::now() + Duration::from_secs(1)
+        ));
+
+        with_default(&clock, &mut enter, |_| {
+            assert!(limiter.check(pk));
+        });
+    }
+
+    #[test]
+    fn clear_expired() {
+        crypto_init().unwrap();
+        let mut limiter = RequestsLimiter::new(1, Duration::from_secs(1));
+        let pk = gen_keypair().0;
+
+        assert!(limiter.check(pk));
+
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(
+            Instant::now() + Duration::from_secs(1)
+        ));
+
+        with_default(&clock, &mut enter, |_| {
+            limiter.clear_expired();
+        });
+
+        assert!(limiter.requests.is_empty());
+    }
+}
@@ -0,0 +1,229 @@
+/*! Estimation of the connectivity of DHT node.
+
+Other nodes tell us which address they see us from when they include our own
+node in `NodesResponse` packets. Comparing these addresses lets us guess which
+kind of NAT we are behind and whether UDP hole punching is likely to work.
+*/
+
+use std::net::SocketAddr;
+
+use crate::toxcore::dht::kbucket::is_same_subnet;
+
+/// Minimum number of nodes from distinct subnets that have to report the same
+/// external address before we trust it.
+pub const MIN_EXTERNAL_ADDR_REPORTERS: usize = 2;
+
+/// Type of NAT inferred from our external addresses reported by other nodes.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum NatType {
+    /// There are not enough reports to infer the type of NAT.
+    Unknown,
+    /// All nodes see the same port for our IP address. We are either not
+    /// behind NAT at all or behind a NAT that keeps the same mapping for all
+    /// destinations.
+    Cone,
+    /// Different nodes see different ports for the same IP address. Such NAT
+    /// creates a new mapping for every destination so hole punching is
+    /// unlikely to work.
+    Symmetric,
+}
+
+/// Reports about our external address collected from returned addresses of
+/// close nodes. Every report is identified by the address of the reporting
+/// node so that a bunch of nodes from the same subnet counts as a single one.
+#[derive(Clone, Debug, Default)]
+pub struct ExternalAddrs {
+    /// List of addresses of the reporting node and the address it sees us
+    /// from.
+    reports: Vec<(SocketAddr, SocketAddr)>,
+}
+
+impl ExternalAddrs {
+    /// Create new empty `ExternalAddrs`.
+    pub fn new() -> ExternalAddrs {
+        ExternalAddrs::default()
+    }
+
+    /// Store the address the node with `reporter_addr` sees us from.
+    pub fn add(&mut self, reporter_addr: SocketAddr, addr: SocketAddr) {
+        self.reports.push((reporter_addr, addr));
+    }
+
+    /// Distinct external addresses that were reported.
+    pub fn addrs(&self) -> Vec<SocketAddr> {
+        let mut addrs: Vec<SocketAddr> = Vec::new();
+        for &(_, addr) in &self.reports {
+            if !addrs.contains(&addr) {
+                addrs.push(addr);
+            }
+        }
+        addrs
+    }
+
+    /// Number of distinct subnets the nodes reported `addr` are from.
+    fn reporters_count(&self, addr: SocketAddr) -> usize {
+        let mut reporters: Vec<SocketAddr> = Vec::new();
+        for &(reporter_addr, reported) in &self.reports {
+            if reported == addr && !reporters.iter().any(|other| is_same_subnet(other, &reporter_addr)) {
+                reporters.push(reporter_addr);
+            }
+        }
+        reporters.len()
+    }
+
+    /// Distinct external addresses that were reported by at least
+    /// `MIN_EXTERNAL_ADDR_REPORTERS` nodes from distinct subnets. The most
+    /// confirmed addresses come first.
+    pub fn confirmed_addrs(&self) -> Vec<SocketAddr> {
+        let mut addrs = self.addrs().into_iter()
+            .map(|addr| (self.reporters_count(addr), addr))
+            .filter(|&(count, _)| count >= MIN_EXTERNAL_ADDR_REPORTERS)
+            .collect::<Vec<_>>();
+        addrs.sort_by(|&(count_1, _), &(count_2, _)| count_2.cmp(&count_1));
+        addrs.into_iter().map(|(_, addr)| addr).collect()
+    }
+
+    /// Infer the type of NAT from the confirmed addresses. A single node
+    /// reporting a different port is not enough to consider NAT symmetric.
+    pub fn nat_type(&self) -> NatType {
+        let addrs = self.confirmed_addrs();
+        if addrs.is_empty() {
+            return NatType::Unknown;
+        }
+        for (i, addr) in addrs.iter().enumerate() {
+            if addrs[i + 1 ..].iter().any(|other| other.ip() == addr.ip()) {
+                return NatType::Symmetric;
+            }
+        }
+        NatType::Cone
+    }
+}
+
+/// Summary of what we know about the connectivity of DHT node.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ConnectivityReport {
+    /// Our external addresses as other nodes see them.
+    pub external_addrs: Vec<SocketAddr>,
+    /// Inferred type of NAT we are behind.
+    pub nat_type: NatType,
+    /// Whether we have ever received a valid response via UDP.
+    pub udp_works: bool,
+    /// Whether it's better to connect to friends via TCP relays since direct
+    /// UDP connections are unlikely to work.
+    pub tcp_relay_recommended: bool,
+}
+
+impl ConnectivityReport {
+    /// Create new `ConnectivityReport` from observed signals.
+    pub fn new(external_addrs: &ExternalAddrs, udp_works: bool) -> ConnectivityReport {
+        let nat_type = external_addrs.nat_type();
+        ConnectivityReport {
+            external_addrs: external_addrs.addrs(),
+            nat_type,
+            udp_works,
+            tcp_relay_recommended: !udp_works || nat_type == NatType::Symmetric,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report_without_signals() {
+        let report = ConnectivityReport::new(&ExternalAddrs::new(), false);
+
+        assert!(report.external_addrs.is_empty());
+        assert_eq!(report.nat_type, NatType::Unknown);
+        assert!(!report.udp_works);
+        assert!(report.tcp_relay_recommended);
+    }
+
+    #[test]
+    fn report_single_addr() {
+        let addr = "1.2.3.4:33445".parse().unwrap();
+        let mut external_addrs = ExternalAddrs::new();
+        external_addrs.add("5.6.7.8:33445".parse().unwrap(), addr);
+
+        let report = ConnectivityReport::new(&external_addrs, true);
+
+        assert_eq!(report.external_addrs, vec![addr]);
+        assert_eq!(report.nat_type, NatType::Unknown);
+        assert!(!report.tcp_relay_recommended);
+    }
+
+    #[test]
+    fn report_cone() {
+        let addr = "1.2.3.4:33445".parse().unwrap();
+        let mut external_addrs = ExternalAddrs::new();
+        external_addrs.add("5.6.7.8:33445".parse().unwrap(), addr);
+        external_addrs.add("9.10.11.12:33445".parse().unwrap(), addr);
+
+        let report = ConnectivityReport::new(&external_addrs, true);
+
+        assert_eq!(report.external_addrs, vec![addr]);
+        assert_eq!(report.nat_type, NatType::Cone);
+        assert!(!report.tcp_relay_recommended);
+    }
+
+    #[test]
+    fn report_symmetric() {
+        let addr_1 = "1.2.3.4:33445".parse().unwrap();
+        let addr_2 = "1.2.3.4:33446".parse().unwrap();
+        let mut external_addrs = ExternalAddrs::new();
+        external_addrs.add("5.6.7.8:33445".parse().unwrap(), addr_1);
+        external_addrs.add("9.10.11.12:33445".parse().unwrap(), addr_1);
+        external_addrs.add("13.14.15.16:33445".parse().unwrap(), addr_2);
+        external_addrs.add("17.18.19.20:33445".parse().unwrap(), addr_2);
+
+        let report = ConnectivityReport::new(&external_addrs, true);
+
+        assert_eq!(report.external_addrs, vec![addr_1, addr_2]);
+        assert_eq!(report.nat_type, NatType::Symmetric);
+        assert!(report.udp_works);
+        assert!(report.tcp_relay_recommended);
+    }
+
+    #[test]
+    fn single_different_port_is_not_symmetric() {
+        let addr_1 = "1.2.3.4:33445".parse().unwrap();
+        let addr_2 = "1.2.3.4:33446".parse().unwrap();
+        let mut external_addrs = ExternalAddrs::new();
+        external_addrs.add("5.6.7.8:33445".parse().unwrap(), addr_1);
+        external_addrs.add("9.10.11.12:33445".parse().unwrap(), addr_1);
+        external_addrs.add("13.14.15.16:33445".parse().unwrap(), addr_2);
+
+        assert_eq!(external_addrs.confirmed_addrs(), vec![addr_1]);
+        assert_eq!(external_addrs.nat_type(), NatType::Cone);
+    }
+
+    #[test]
+    fn reporters_from_same_subnet_count_once() {
+        let addr_1 = "1.2.3.4:33445".parse().unwrap();
+        let addr_2 = "1.2.3.4:33446".parse().unwrap();
+        let mut external_addrs = ExternalAddrs::new();
+        external_addrs.add("5.6.7.8:33445".parse().unwrap(), addr_1);
+        external_addrs.add("9.10.11.12:33445".parse().unwrap(), addr_1);
+        for i in 0 .. 8 {
+            external_addrs.add(format!("13.14.15.{}:33445", i).parse().unwrap(), addr_2);
+        }
+
+        assert_eq!(external_addrs.confirmed_addrs(), vec![addr_1]);
+        assert_eq!(external_addrs.nat_type(), NatType::Cone);
+    }
+
+    #[test]
+    fn confirmed_addrs_most_confirmed_first() {
+        let addr_1 = "1.2.3.4:33445".parse().unwrap();
+        let addr_2 = "1.2.3.4:33446".parse().unwrap();
+        let mut external_addrs = ExternalAddrs::new();
+        external_addrs.add("5.6.7.8:33445".parse().unwrap(), addr_1);
+        external_addrs.add("9.10.11.12:33445".parse().unwrap(), addr_1);
+        external_addrs.add("13.14.15.16:33445".parse().unwrap(), addr_2);
+        external_addrs.add("17.18.19.20:33445".parse().unwrap(), addr_2);
+        external_addrs.add("21.22.23.24:33445".parse().unwrap(), addr_2);
+
+        assert_eq!(external_addrs.confirmed_addrs(), vec![addr_2, addr_1]);
+    }
+}
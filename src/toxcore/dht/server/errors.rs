@@ -0,0 +1,98 @@
+/*! Errors enum for DHT server.
+*/
+
+use std::fmt;
+
+use failure::{Backtrace, Context, Fail};
+
+use std::convert::From;
+use std::io::Error as IoError;
+use std::io::ErrorKind as IoErrorKind;
+
+/// Error that can happen when sending a request packet.
+#[derive(Debug)]
+pub struct SendError {
+    ctx: Context<SendErrorKind>,
+}
+
+impl SendError {
+    /// Return the kind of this error.
+    pub fn kind(&self) -> &SendErrorKind {
+        self.ctx.get_context()
+    }
+
+    pub(crate) fn target_is_self() -> SendError {
+        SendError::from(SendErrorKind::TargetIsSelf)
+    }
+
+    pub(crate) fn send_to(error: IoError) -> SendError {
+        SendError::from(error.context(SendErrorKind::SendTo))
+    }
+}
+
+impl Fail for SendError {
+    fn cause(&self) -> Option<&dyn Fail> {
+        self.ctx.cause()
+    }
+
+    fn backtrace(&self) -> Option<&Backtrace> {
+        self.ctx.backtrace()
+    }
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.ctx.fmt(f)
+    }
+}
+
+/// The specific kind of error that can occur.
+#[derive(Clone, Debug, Eq, PartialEq, Fail)]
+pub enum SendErrorKind {
+    /// Error indicates that the packet was going to be sent to ourselves.
+    #[fail(display = "Attempt to send a request to ourselves")]
+    TargetIsSelf,
+    /// Error indicates that the packet can't be sent.
+    #[fail(display = "Send packet error")]
+    SendTo,
+}
+
+impl From<SendErrorKind> for SendError {
+    fn from(kind: SendErrorKind) -> SendError {
+        SendError::from(Context::new(kind))
+    }
+}
+
+impl From<Context<SendErrorKind>> for SendError {
+    fn from(ctx: Context<SendErrorKind>) -> SendError {
+        SendError { ctx }
+    }
+}
+
+/// From trait for temporary use during transition from io:Error to custom enum error of failure crate
+impl From<SendError> for IoError {
+    fn from(item: SendError) -> Self {
+        IoError::new(IoErrorKind::Other, format!("SendError occured. error: {:?}", item))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn send_error() {
+        let error = SendError::send_to(IoError::new(IoErrorKind::Other, "test error"));
+        assert!(error.cause().is_some());
+        assert_eq!(format!("{}", error), "Send packet error".to_owned());
+    }
+
+    #[test]
+    fn send_error_kind() {
+        let target_is_self = SendErrorKind::TargetIsSelf;
+        assert_eq!(format!("{}", target_is_self), "Attempt to send a request to ourselves".to_owned());
+
+        let send_to = SendErrorKind::SendTo;
+        assert_eq!(format!("{}", send_to), "Send packet error".to_owned());
+    }
+}
@@ -53,6 +53,10 @@ pub struct HolePunching {
     /// Ping id that is used to send `NatPingRequest` packets. It's refreshed
     /// every time we receive valid `NatPingResponse` packet.
     pub ping_id: u64,
+    /// Number of ports following the highest observed port that are predicted
+    /// to be allocated by a symmetric NAT next. Zero disables port
+    /// prediction.
+    pub port_prediction_range: u16,
 }
 
 impl Default for HolePunching {
@@ -73,6 +77,7 @@ impl HolePunching {
             first_punching_index: 0,
             last_punching_index: 0,
             ping_id: gen_ping_id(),
+            port_prediction_range: 0,
         }
     }
 
@@ -178,6 +183,21 @@ impl HolePunching {
         }).collect()
     }
 
+    /// Port prediction algorithm for symmetric NAT. Such NAT usually
+    /// allocates ports sequentially so the next mappings are likely to follow
+    /// the highest port returned by close nodes of a friend.
+    fn predicted_hole_punching(&self, ports: &[u16], ip: IpAddr) -> Vec<SocketAddr> {
+        let max_port = match ports.iter().max() {
+            Some(&max_port) => max_port,
+            None => return Vec::new(),
+        };
+
+        (1 ..= self.port_prediction_range)
+            .filter_map(|i| max_port.checked_add(i))
+            .map(|port| SocketAddr::new(ip, port))
+            .collect()
+    }
+
     /// Get addresses for hole punching using different port guessing
     /// algorithms.
     ///
@@ -195,8 +215,9 @@ impl HolePunching {
         let mut addrs = if num_same_port == num_ports {
             vec![SocketAddr::new(ip, first_port)]
         } else {
-            let addrs = self.first_hole_punching(ports, ip);
+            let mut addrs = self.first_hole_punching(ports, ip);
             self.first_punching_index += MAX_PORTS_TO_PUNCH;
+            addrs.append(&mut self.predicted_hole_punching(ports, ip));
             addrs
         };
 
@@ -283,6 +304,44 @@ mod tests {
         assert!(!hole_punch.next_punch_addrs(&addrs).is_empty());
     }
 
+    #[test]
+    fn hole_punch_predicted_ports() {
+        let addrs = vec![
+            "127.0.0.1:55555".parse().unwrap(),
+            "127.0.0.1:55556".parse().unwrap(),
+            "127.0.0.1:55557".parse().unwrap(),
+            "127.0.0.1:55558".parse().unwrap(),
+            "127.0.0.1:55556".parse().unwrap(),
+        ];
+
+        let mut hole_punch = HolePunching::new();
+        hole_punch.is_punching_done = false;
+        hole_punch.port_prediction_range = 10;
+
+        let punch_addrs = hole_punch.next_punch_addrs(&addrs);
+        for port in 55559 ..= 55568 {
+            assert!(punch_addrs.contains(&SocketAddr::new("127.0.0.1".parse().unwrap(), port)));
+        }
+        assert!(!punch_addrs.contains(&"127.0.0.1:55569".parse().unwrap()));
+    }
+
+    #[test]
+    fn hole_punch_predicted_ports_disabled() {
+        let addrs = vec![
+            "127.0.0.1:55555".parse().unwrap(),
+            "127.0.0.1:55556".parse().unwrap(),
+            "127.0.0.1:55557".parse().unwrap(),
+            "127.0.0.1:55558".parse().unwrap(),
+            "127.0.0.1:55556".parse().unwrap(),
+        ];
+
+        let mut hole_punch = HolePunching::new();
+        hole_punch.is_punching_done = false;
+
+        let punch_addrs = hole_punch.next_punch_addrs(&addrs);
+        assert!(!punch_addrs.contains(&"127.0.0.1:55568".parse().unwrap()));
+    }
+
     #[test]
     fn hole_punch_lash_punch() {
         let addrs = vec![
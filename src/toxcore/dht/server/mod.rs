@@ -4,16 +4,24 @@ This module works on top of other modules.
 */
 
 pub mod hole_punching;
+pub mod errors;
+pub mod connectivity;
 
-use futures::{Future, Sink, Stream, future, stream};
+use futures::{Async, Future, Sink, Stream, future};
+use futures::task::{self, Task};
 use futures::future::{Either, join_all};
 use futures::sync::mpsc;
+use ipnet::IpNet;
+use lru::LruCache;
 use parking_lot::RwLock;
 use tokio::timer::Interval;
 
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
 use std::io::{ErrorKind, Error};
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::time::{Duration, Instant};
 use std::{iter, mem};
 
@@ -26,36 +34,70 @@ use crate::toxcore::dht::nodes_queue::*;
 use crate::toxcore::dht::precomputed_cache::*;
 use crate::toxcore::onion::packet::*;
 use crate::toxcore::onion::onion_announce::*;
+use crate::toxcore::onion::replay_cache::*;
 use crate::toxcore::dht::request_queue::*;
+use crate::toxcore::dht::requests_limiter::*;
 use crate::toxcore::io_tokio::*;
 use crate::toxcore::dht::dht_friend::*;
 use crate::toxcore::dht::dht_node::*;
+use crate::toxcore::dht::lan_discovery::{LanPorts, QuietPeriod, broadcast_socket_addrs};
 use crate::toxcore::dht::server::hole_punching::*;
+use crate::toxcore::dht::server::errors::*;
+use crate::toxcore::dht::server::connectivity::*;
 use crate::toxcore::tcp::packet::OnionRequest;
 use crate::toxcore::net_crypto::*;
 use crate::toxcore::dht::ip_port::IsGlobal;
 use crate::toxcore::utils::*;
 
+pub use crate::toxcore::dht::dht_friend::NODES_REQ_INTERVAL;
+
 /// Shorthand for the transmit half of the message channel.
 type Tx = mpsc::Sender<(Packet, SocketAddr)>;
 
 /// Shorthand for the transmit half of the TCP onion channel.
 type TcpOnionTx = mpsc::Sender<(InnerOnionResponse, SocketAddr)>;
 
-/// Number of random `NodesRequest` packet to send every second one per second.
-/// After random requests count exceeds this number `NODES_REQ_INTERVAL` will be
-/// used.
+/// Shorthand for the transmit half of the health snapshots channel.
+type HealthTx = mpsc::UnboundedSender<HealthSnapshot>;
+
+/// Shorthand for the transmit half of the DHT events channel.
+type EventTx = mpsc::UnboundedSender<DhtEvent>;
+
+/// Shorthand for the transmit half of the channel of packets that were not
+/// queued for sending.
+type QueueErrorTx = mpsc::UnboundedSender<(SocketAddr, Error)>;
+
+/// Shorthand for the transmit half of the path nodes channel.
+type PathNodeTx = mpsc::UnboundedSender<PackedNode>;
+
+/// Shorthand for the transmit half of the local onion responses channel.
+type OnionResponseTx = mpsc::UnboundedSender<InnerOnionResponse>;
+
+/// Target address, target `PublicKey` and search `PublicKey` of `NodesRequest`.
+type NodesReqKey = (SocketAddr, PublicKey, PublicKey);
+
+/// Default number of random `NodesRequest` packet to send every second one per
+/// second. After random requests count exceeds this number `NODES_REQ_INTERVAL`
+/// will be used.
 pub const MAX_BOOTSTRAP_TIMES: u32 = 5;
 /// How often onion key should be refreshed.
 pub const ONION_REFRESH_KEY_INTERVAL: u64 = 7200;
-/// Interval in seconds for random `NodesRequest`.
-pub const NODES_REQ_INTERVAL: u64 = 20;
+/// Maximum power of 2 by which `NODES_REQ_INTERVAL` is multiplied when random
+/// requests tapering is enabled and close nodes list is stable.
+pub const MAX_RANDOM_REQUESTS_BACKOFF_EXP: u32 = 3;
 /// Ping timeout in seconds.
 pub const PING_TIMEOUT: u64 = 5;
+/// Maximum power of 2 by which `PING_INTERVAL` is multiplied for a node that
+/// didn't answer our previous requests. Longer intervals are pointless since
+/// such node is discarded after `KILL_NODE_TIMEOUT` seconds.
+pub const MAX_PING_BACKOFF_EXP: u32 = 1;
 /// Maximum newly announced nodes to ping per `TIME_TO_PING` seconds.
 pub const MAX_TO_PING: u8 = 32;
 /// Maximum nodes to send `NodesRequest` packet.
 pub const MAX_TO_BOOTSTRAP: u8 = 8;
+/// Maximum number of nodes deferred to be added to close nodes list during the
+/// next DHT main loop iterations.
+pub const MAX_DEFERRED_CLOSE_NODES: u8 = 16;
 /// How often in seconds to ping newly announced nodes.
 pub const TIME_TO_PING: u64 = 2;
 /// How often in seconds to ping initial bootstrap nodes.
@@ -69,6 +111,37 @@ pub const PRECOMPUTED_LRU_CACHE_SIZE: usize = KBUCKET_DEFAULT_SIZE as usize * KB
 pub const DHT_SEND_TIMEOUT: u64 = 1;
 /// How often DHT main loop should be called.
 const MAIN_LOOP_INTERVAL: u64 = 1;
+/// Maximum number of `PingRequest` and `NodesRequest` packets that we handle
+/// from one `PublicKey` per `REQUESTS_LIMIT_INTERVAL` seconds.
+pub const MAX_REQUESTS_PER_PK: u32 = 16;
+/// Interval in seconds for counting requests from one `PublicKey`.
+pub const REQUESTS_LIMIT_INTERVAL: u64 = 1;
+/// Maximum number of `OnionAnnounceResponse` packets that we send to one
+/// IP address per `REQUESTS_LIMIT_INTERVAL` seconds.
+pub const MAX_ANNOUNCE_RESPONSES_PER_ADDR: u32 = 64;
+/// Number of the last DHT main loop executions the moving average of its
+/// duration is approximately calculated over.
+const LOOP_DURATION_AVERAGE_WINDOW: u32 = 8;
+/// Default interval in seconds for sending `HealthSnapshot` to subscribers.
+pub const HEALTH_SNAPSHOT_INTERVAL: u64 = 60;
+/// Interval in seconds for warning that `Server` doesn't have any nodes to
+/// bootstrap from.
+const NEEDS_BOOTSTRAP_WARN_INTERVAL: u64 = 60;
+/// Ports below this value are reserved for well-known services.
+pub const WELL_KNOWN_PORTS_END: u16 = 1024;
+/// Size of the channel of packets handled by task spawned with
+/// `spawn_handler`.
+pub const HANDLER_CHANNEL_SIZE: usize = 64;
+/// Time in seconds during which nodes sent in `NodesResponse` are reused for
+/// identical `NodesRequest` packets from the same node.
+pub const NODES_RESP_CACHE_TIMEOUT: u64 = 1;
+/// Maximum number of nodes we cache sent `NodesResponse` nodes for.
+pub const NODES_RESP_CACHE_SIZE: usize = 64;
+/// Maximum number of nodes that `OnionAnnounceResponse` packet can hold.
+pub const MAX_ONION_ANNOUNCE_NODES: usize = 4;
+/// Number of consecutive sends failed because the receiver of outgoing
+/// packets is dropped after which the channel is considered closed.
+pub const MAX_CONSECUTIVE_SEND_FAILURES: usize = 8;
 
 /// Struct that contains necessary data for `BootstrapInfo` packet.
 #[derive(Clone)]
@@ -80,6 +153,266 @@ struct ServerBootstrapInfo {
     motd_cb: Arc<Fn(&Server) -> Vec<u8> + Send + Sync>,
 }
 
+/// Nodes sent in `NodesResponse` that can be reused for identical
+/// `NodesRequest` packets from the same node.
+#[derive(Clone)]
+struct CachedNodesResp {
+    /// `PublicKey` the requester searched for.
+    search_pk: PublicKey,
+    /// Whether only nodes with global addresses were selected.
+    only_global: bool,
+    /// Whether only nodes with IPv4 addresses were selected.
+    only_ipv4: bool,
+    /// Nodes that were sent.
+    nodes: Vec<PackedNode>,
+    /// Time when nodes were selected.
+    time: Instant,
+}
+
+/// Events that DHT server sends to interested modules through the events sink.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DhtEvent {
+    /// Onion symmetric key was rotated so all onion returns created with the
+    /// previous key became invalid. Onion client should announce itself again.
+    OnionKeyRotated,
+    /// Receiver of outgoing packets is dropped so packets can't be sent
+    /// anymore. Usually it means that UDP writer task has died. `Server`
+    /// stops its periodical tasks after this event.
+    OutgoingChannelClosed,
+    /// Node with the given `PublicKey` was evicted from close nodes list to
+    /// make room for another node. Sent only when eviction tracking is
+    /// enabled.
+    NodeEvicted(PublicKey),
+}
+
+/// Snapshot of DHT server health that is periodically sent to subscribers.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HealthSnapshot {
+    /// Number of nodes in close nodes list.
+    pub close_nodes: usize,
+    /// Number of friends excluding fake ones.
+    pub friends: usize,
+    /// Number of nodes waiting to be sent `NodesRequest` packet.
+    pub nodes_to_bootstrap: usize,
+    /// Number of nodes waiting to be sent `PingRequest` packet.
+    pub nodes_to_ping: usize,
+    /// Number of requests waiting for response.
+    pub pending_requests: usize,
+    /// Number of nodes announced to us via onion.
+    pub announced_nodes: usize,
+    /// Time passed since the server was created.
+    pub uptime: Duration,
+    /// Time when the last packet was received.
+    pub last_packet_at: Option<Instant>,
+}
+
+/// Role of DHT node that defines which packets it handles.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum NodeRole {
+    /// Node only helps other nodes to bootstrap. It answers ping, nodes and
+    /// bootstrap info requests but doesn't relay onion packets, doesn't
+    /// handle net crypto packets and doesn't have friends.
+    BootstrapOnly,
+    /// Node is used by a client. It handles net crypto packets and has friends
+    /// but doesn't relay onion packets.
+    Client,
+    /// Node relays onion packets but doesn't handle net crypto packets and
+    /// doesn't have friends.
+    Relay,
+    /// Node does everything.
+    Full,
+}
+
+/// Strategy of filling close nodes list while bootstrapping.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FillStrategy {
+    /// Nodes learned from `NodesResponse` packets are queried during the
+    /// next DHT main loop iterations like any other nodes.
+    Passive,
+    /// Nodes learned from `NodesResponse` packets are queried for nodes
+    /// close to us immediately so that the search iterates towards our key
+    /// and kbuckets are filled quickly. It lasts until the number of nodes in
+    /// kbuckets stops changing between DHT main loop iterations and starts
+    /// again when the sink for outgoing packets is replaced.
+    Active,
+}
+
+/// Description of what `Server` did with a handled packet.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum RoutingDecision {
+    /// Response was sent back to the sender of the packet. Other packets
+    /// might be sent as well.
+    RepliedTo(SocketAddr),
+    /// The packet or its content was sent to other nodes with the given
+    /// addresses.
+    ForwardedTo(Vec<SocketAddr>),
+    /// The packet was handled without sending anything, e.g. it updated our
+    /// state or was passed to another module. Modules like `NetCrypto` send
+    /// their responses on their own so such packets end up here as well as
+    /// requests that are silently dropped by rate limiting.
+    DeliveredLocally,
+    /// The packet was dropped for the given reason.
+    Dropped(String),
+}
+
+/// Number of DHT packet types that are counted separately.
+const PACKET_TYPES_COUNT: usize = 21;
+
+/// Names of DHT packet types used as labels of metrics. They are indexed by
+/// `Server::packet_type_index`.
+const PACKET_TYPE_NAMES: [&str; PACKET_TYPES_COUNT] = [
+    "PingRequest",
+    "PingResponse",
+    "NodesRequest",
+    "NodesResponse",
+    "CookieRequest",
+    "CookieResponse",
+    "CryptoHandshake",
+    "CryptoData",
+    "DhtRequest",
+    "LanDiscovery",
+    "OnionRequest0",
+    "OnionRequest1",
+    "OnionRequest2",
+    "OnionAnnounceRequest",
+    "OnionAnnounceResponse",
+    "OnionDataRequest",
+    "OnionDataResponse",
+    "OnionResponse3",
+    "OnionResponse2",
+    "OnionResponse1",
+    "BootstrapInfo",
+];
+
+/// Statistics of DHT server work.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ServerStats {
+    /// Duration of the last DHT main loop execution.
+    pub last_loop_duration: Option<Duration>,
+    /// Exponential moving average of DHT main loop executions durations.
+    pub average_loop_duration: Option<Duration>,
+}
+
+impl ServerStats {
+    /// Record the duration of DHT main loop execution.
+    fn update_loop_duration(&mut self, duration: Duration) {
+        self.last_loop_duration = Some(duration);
+        self.average_loop_duration = Some(match self.average_loop_duration {
+            Some(average) => (average * (LOOP_DURATION_AVERAGE_WINDOW - 1) + duration) / LOOP_DURATION_AVERAGE_WINDOW,
+            None => duration,
+        });
+    }
+}
+
+/// Guard of a send that is not completed yet. When it's dropped the send is
+/// considered completed and `drain` futures are woken up if there are no more
+/// pending sends.
+struct PendingSend {
+    /// Number of sends that are not completed yet.
+    pending_sends: Arc<AtomicUsize>,
+    /// Tasks of `drain` futures waiting for pending sends to complete.
+    drain_tasks: Arc<RwLock<Vec<Task>>>,
+}
+
+impl Drop for PendingSend {
+    fn drop(&mut self) {
+        if self.pending_sends.fetch_sub(1, Ordering::SeqCst) == 1 {
+            for task in self.drain_tasks.write().drain(..) {
+                task.notify();
+            }
+        }
+    }
+}
+
+/// Set of ports stored as a bitset so that checking a port takes the same time
+/// regardless of the number of ports in the set.
+#[derive(Clone)]
+struct PortSet {
+    /// One bit for every port.
+    bits: Arc<Vec<u64>>,
+}
+
+impl PortSet {
+    /// Create a new `PortSet` with given ports.
+    fn new<I: IntoIterator<Item = u16>>(ports: I) -> PortSet {
+        let mut bits = vec![0; (usize::from(u16::max_value()) + 1) / 64];
+        for port in ports {
+            bits[usize::from(port) / 64] |= 1 << (port % 64);
+        }
+        PortSet {
+            bits: Arc::new(bits),
+        }
+    }
+
+    /// Check if the set contains given port.
+    fn contains(&self, port: u16) -> bool {
+        self.bits[usize::from(port) / 64] & (1 << (port % 64)) != 0
+    }
+}
+
+/// Guard of an onion packet that is being forwarded to the next hop. When it's
+/// dropped the forward is considered completed even if its future was dropped
+/// before completion.
+struct OnionForward {
+    /// Number of onion packets that are being forwarded to the next hop.
+    onion_forwards_in_flight: Arc<AtomicUsize>,
+}
+
+impl OnionForward {
+    /// Count a new onion forward as in flight until the guard is dropped.
+    fn new(onion_forwards_in_flight: Arc<AtomicUsize>) -> OnionForward {
+        onion_forwards_in_flight.fetch_add(1, Ordering::Relaxed);
+        OnionForward {
+            onion_forwards_in_flight,
+        }
+    }
+}
+
+impl Drop for OnionForward {
+    fn drop(&mut self) {
+        self.onion_forwards_in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Count the result of sending a packet to the outgoing channel. When the
+/// channel is considered closed `OutgoingChannelClosed` event is sent.
+fn count_send_result(consecutive_send_failures: &AtomicUsize, event_sink: Option<&EventTx>, is_closed: bool) {
+    if !is_closed {
+        consecutive_send_failures.store(0, Ordering::SeqCst);
+        return;
+    }
+
+    let failures = consecutive_send_failures.fetch_add(1, Ordering::SeqCst) + 1;
+    if failures == MAX_CONSECUTIVE_SEND_FAILURES {
+        error!("Receiver of outgoing packets is dropped, packets can't be sent anymore");
+        if let Some(event_sink) = event_sink {
+            if let Err(e) = event_sink.unbounded_send(DhtEvent::OutgoingChannelClosed) {
+                warn!("Failed to send OutgoingChannelClosed event: {}", e);
+            }
+        }
+    }
+}
+
+/// Check if the address belongs to one of allowed networks. IPv4-mapped IPv6
+/// addresses are checked as IPv4 ones since they come from dual-stack sockets.
+/// `None` means that all networks are allowed.
+fn is_addr_allowed(nets: &Option<Vec<IpNet>>, addr: &SocketAddr) -> bool {
+    let ipv4 = match addr.ip() {
+        IpAddr::V6(ip) => ip.to_ipv4().map(IpAddr::V4),
+        IpAddr::V4(_) => None,
+    };
+    match *nets {
+        Some(ref nets) => nets.iter().any(|net|
+            net.contains(&addr.ip()) || ipv4.map_or(false, |ip| net.contains(&ip))
+        ),
+        None => true,
+    }
+}
+
+/// Filter that is called for every received or sent packet with its address.
+/// If it returns `false` the packet is dropped.
+pub type PacketFilter = dyn Fn(&Packet, SocketAddr) -> bool + Send + Sync;
+
 /**
 Own DHT node data.
 
@@ -111,14 +444,17 @@ pub struct Server {
     pub sk: SecretKey,
     /// DHT `PublicKey`.
     pub pk: PublicKey,
-    /// Tx split of a channel to send packets to this peer via UDP socket.
-    pub tx: Tx,
+    /// Tx split of a channel to send packets to this peer via UDP socket. It
+    /// can be replaced at runtime with `replace_tx`.
+    tx: Arc<RwLock<Tx>>,
     /// Struct that stores and manages requests IDs and timeouts.
     pub request_queue: Arc<RwLock<RequestQueue>>,
     /// Close nodes list which contains nodes close to own DHT `PublicKey`.
     pub close_nodes: Arc<RwLock<Ktree>>,
     /// Symmetric key used for onion return encryption.
     onion_symmetric_key: Arc<RwLock<secretbox::Key>>,
+    /// Time when onion symmetric key was generated.
+    onion_symmetric_key_time: Arc<RwLock<Instant>>,
     /// Onion announce struct to handle `OnionAnnounce` and `OnionData` packets.
     onion_announce: Arc<RwLock<OnionAnnounce>>,
     /// Friends list used to store friends related data like close nodes per
@@ -135,9 +471,30 @@ pub struct Server {
     /// How many times we sent `NodesRequest` packet to a random node from close
     /// nodes list.
     random_requests_count: Arc<RwLock<u32>>,
+    /// Number of random `NodesRequest` packets to send every second before
+    /// relaxing to the steady-state interval.
+    max_bootstrap_times: u32,
     /// Time when we sent `NodesRequest` packet to a random node from close
     /// nodes list.
     last_nodes_req_time: Arc<RwLock<Instant>>,
+    /// Targets and search keys of `NodesRequest` packets sent during the
+    /// current DHT main loop iteration. It's used to avoid sending identical
+    /// requests from different loop stages. `None` outside of the iteration.
+    tick_nodes_reqs: Arc<RwLock<Option<HashSet<NodesReqKey>>>>,
+    /// Number of consecutive DHT main loop iterations that found timed out
+    /// requests to the node with given `PublicKey`. Such nodes are pinged
+    /// less often until they answer.
+    unanswered_pings: Arc<RwLock<HashMap<PublicKey, u32>>>,
+    /// Nodes recently sent in `NodesResponse` packets by `PublicKey` of the
+    /// requester. It saves us from selecting closest nodes again when a node
+    /// repeats its request. Cleared when close nodes lists change.
+    nodes_resp_cache: Arc<RwLock<LruCache<PublicKey, CachedNodesResp>>>,
+    /// If random requests tapering is enabled the interval between random
+    /// `NodesRequest` packets grows while close nodes list is stable.
+    random_requests_tapering_enabled: bool,
+    /// Power of 2 by which `NODES_REQ_INTERVAL` is multiplied for the next
+    /// random `NodesRequest` packet.
+    random_requests_backoff: Arc<RwLock<u32>>,
     /// List of nodes to send `PingRequest`. When we receive `PingRequest` or
     /// `NodesRequest` packet from a new node we should send `PingRequest` to
     /// this node to check if it's capable of handling our requests. But instead
@@ -145,12 +502,185 @@ pub struct Server {
     /// is processed every `TIME_TO_PING` seconds. The purpose of this is to
     /// prevent amplification attacks.
     nodes_to_ping: Arc<RwLock<NodesQueue>>,
+    /// Maximum number of friends processed during one DHT main loop iteration.
+    /// If there are more friends the rest of them will be processed during the
+    /// next iterations. `None` means that all friends are processed every
+    /// iteration.
+    max_friends_per_tick: Option<usize>,
+    /// Index of the friend from which the next DHT main loop iteration starts
+    /// processing friends when `max_friends_per_tick` is set.
+    friends_offset: Arc<RwLock<usize>>,
+    /// Maximum number of new nodes added to close nodes list from
+    /// `NodesResponse` packets during one DHT main loop iteration. Other nodes
+    /// are deferred to the next iterations so that the list can't be changed
+    /// quickly by many responding nodes. `None` means that it's not limited.
+    max_close_admissions_per_tick: Option<usize>,
+    /// Number of new nodes added to close nodes list from `NodesResponse`
+    /// packets during the current DHT main loop iteration.
+    close_admissions: Arc<AtomicUsize>,
+    /// Nodes that sent `NodesResponse` but weren't added to close nodes list
+    /// because `max_close_admissions_per_tick` was reached.
+    deferred_close_nodes: Arc<RwLock<NodesQueue>>,
+    /// Maximum number of friends for which nodes from `NodesResponse` packets
+    /// are checked as candidates to their close nodes. With more friends
+    /// every response is checked only for some of them in turn. `None` means
+    /// that it's not limited.
+    max_friends_fan_out: Option<usize>,
+    /// Index of the friend from which nodes from the next `NodesResponse` are
+    /// checked when `max_friends_fan_out` is exceeded.
+    friends_fan_out_offset: Arc<RwLock<usize>>,
+    /// Counter of requests received from every `PublicKey`. It's used to drop
+    /// requests from a node that floods us with them even if it rotates its
+    /// addresses. `None` means that requests are not limited.
+    requests_limiter: Option<Arc<RwLock<RequestsLimiter>>>,
+    /// Counter of `OnionAnnounceRequest` packets handled from every IP
+    /// address. Since responses are bigger than requests it's used to prevent
+    /// using our node for traffic amplification.
+    announce_responses_limiter: Arc<RwLock<RequestsLimiter<IpAddr>>>,
+    /// Counter of handshake related packets passed to net crypto. Handshakes
+    /// are relatively expensive so the total number of them is limited
+    /// regardless of the sender. `None` means that it's not limited.
+    handshakes_limiter: Option<Arc<RwLock<RequestsLimiter<()>>>>,
+    /// Counter of handshake related packets passed to net crypto from every
+    /// IP address so that a single host can't use up the total limit. `None`
+    /// means that it's not limited.
+    handshakes_per_addr_limiter: Option<Arc<RwLock<RequestsLimiter<IpAddr>>>>,
+    /// If `Server` is paused it doesn't send periodical requests but still
+    /// handles incoming packets.
+    is_paused: Arc<AtomicBool>,
+    /// If onion relay is paused onion packets are dropped. Unlike
+    /// `onion_relay_enabled` it can be changed after `Server` is started.
+    is_onion_relay_paused: Arc<AtomicBool>,
+    /// If NAT traversal is paused `NatPingRequest` packets are not sent and
+    /// received NAT ping packets are ignored.
+    is_nat_paused: Arc<AtomicBool>,
+    /// If `Server` is draining it doesn't send new packets anymore.
+    is_draining: Arc<AtomicBool>,
+    /// Number of sends that are not completed yet.
+    pending_sends: Arc<AtomicUsize>,
+    /// Number of consecutive sends that failed because the receiver of
+    /// outgoing packets is dropped.
+    consecutive_send_failures: Arc<AtomicUsize>,
+    /// Tasks of `drain` futures waiting for pending sends to complete.
+    drain_tasks: Arc<RwLock<Vec<Task>>>,
+    /// Time when `Server` was created.
+    start_time: Instant,
+    /// Milliseconds passed since `start_time` when the last packet was
+    /// received plus one. Zero means that no packets were received yet. It's
+    /// stored as an offset so that it can be updated without locking on every
+    /// received packet.
+    last_packet_offset: Arc<AtomicUsize>,
+    /// Interval for sending `HealthSnapshot` to subscribers.
+    health_interval: Duration,
+    /// Time when the last `HealthSnapshot` was sent.
+    last_health_time: Arc<RwLock<Option<Instant>>>,
+    /// Sinks of subscribers that receive `HealthSnapshot` periodically.
+    health_subscribers: Arc<RwLock<Vec<HealthTx>>>,
+    /// List of networks `Server` is allowed to communicate with. Packets from
+    /// other addresses are dropped and packets to other addresses are not
+    /// sent. `None` means that communication is unrestricted.
+    allowed_networks: Arc<RwLock<Option<Vec<IpNet>>>>,
+    /// Time during which a node removed from close nodes lists for being bad
+    /// can't be added back.
+    quarantine_timeout: Duration,
+    /// Maximum time since the last response of a node to send it in
+    /// `NodesResponse`. Nodes that didn't answer for longer are not sent to
+    /// other nodes even if they are not bad yet. `None` means that all good
+    /// nodes are sent.
+    nodes_resp_max_age: Option<Duration>,
+    /// Whether our own node should be included in `NodesResponse` when we
+    /// are among the nodes closest to the searched key.
+    self_in_nodes_resp_enabled: bool,
+    /// Strategy of filling close nodes list while bootstrapping.
+    fill_strategy: FillStrategy,
+    /// Number of nodes in every kbucket of close nodes list observed by the
+    /// previous DHT main loop iteration while close nodes list is actively
+    /// filled. `None` means that active filling is finished.
+    active_fill: Arc<RwLock<Option<Vec<(usize, usize)>>>>,
+    /// Number of ports following the highest observed port of a friend
+    /// behind symmetric NAT that are tried during hole punching.
+    port_prediction_range: u16,
+    /// Number of onion packets that were dropped because their payload
+    /// exceeded maximum size.
+    oversized_onion_packets: Arc<AtomicUsize>,
+    /// Whether nodes evicted from close nodes list to make room for other
+    /// nodes are counted and reported.
+    eviction_tracking: bool,
+    /// Number of nodes evicted from close nodes list to make room for other
+    /// nodes. Counted only when eviction tracking is enabled.
+    evicted_close_nodes: Arc<AtomicUsize>,
+    /// Number of `OnionRequest2` packets that were dropped because their
+    /// payload couldn't be decrypted or decoded.
+    malformed_onion_packets: Arc<AtomicUsize>,
+    /// Number of `BootstrapInfo` requests that were sent with a version
+    /// different from ours.
+    incompatible_version_packets: Arc<AtomicUsize>,
+    /// Maximum number of onion packets that are being forwarded to the next
+    /// hop at the same time. New onion requests are dropped when this limit
+    /// is reached. `None` means that the number is not limited.
+    max_onion_forwards: Option<usize>,
+    /// Ports onion requests are not forwarded to. It prevents using our node
+    /// to probe or attack services on other hosts. `None` means that onion
+    /// requests can be forwarded to any port.
+    denied_onion_ports: Option<PortSet>,
+    /// Number of nodes closest to the searched `PublicKey` that are sent in
+    /// `OnionAnnounceResponse` so that the searcher can continue the search.
+    onion_announce_nodes_count: usize,
+    /// Number of onion packets that are being forwarded to the next hop.
+    onion_forwards_in_flight: Arc<AtomicUsize>,
+    /// Number of onion requests that were dropped because too many onion
+    /// packets were being forwarded.
+    shed_onion_packets: Arc<AtomicUsize>,
+    /// Number of received packets by packet type.
+    received_packets: Arc<[AtomicUsize; PACKET_TYPES_COUNT]>,
+    /// Number of received packets that were dropped before handling.
+    dropped_packets: Arc<AtomicUsize>,
+    /// Number of `PingRequest` and `NodesRequest` packets that were dropped
+    /// because they were sent from our own `PublicKey`.
+    self_requests: Arc<AtomicUsize>,
+    /// Number of `NatPingResponse` packets that were dropped because they
+    /// were sent from a node that is not our friend.
+    unknown_nat_ping_responses: Arc<AtomicUsize>,
+    /// Number of handshake related packets that were dropped because of
+    /// handshakes limit.
+    dropped_handshake_packets: Arc<AtomicUsize>,
+    /// Number of onion packets that were forwarded to the next hop.
+    relayed_onion_packets: Arc<AtomicUsize>,
+    /// Cache of recently forwarded onion requests used to drop replayed
+    /// requests. None if replay detection is disabled.
+    onion_replay_cache: Option<Arc<RwLock<OnionReplayCache>>>,
+    /// Statistics of DHT server work.
+    stats: Arc<RwLock<ServerStats>>,
+    /// Filter that is called for every received packet before handling it.
+    inbound_filter: Arc<RwLock<Option<Arc<PacketFilter>>>>,
+    /// Filter that is called for every packet before sending it.
+    outbound_filter: Arc<RwLock<Option<Arc<PacketFilter>>>>,
     /// Info used to respond to `BootstrapInfo` packets.
     bootstrap_info: Option<ServerBootstrapInfo>,
     /// `OnionResponse1` packets that have TCP protocol kind inside onion return
     /// should be redirected to TCP sender trough this sink
     /// None if there is no TCP relay
     tcp_onion_sink: Option<TcpOnionTx>,
+    /// Addresses packets were sent to while handling a packet. It's set only
+    /// for the copy of `Server` that handles a packet in
+    /// `handle_packet_traced`.
+    send_trace: Option<Arc<RwLock<Vec<SocketAddr>>>>,
+    /// Onion responses addressed to our own node are delivered to a local
+    /// onion client through this sink instead of being sent over the
+    /// network. None if there is no local onion client.
+    onion_response_sink: Option<OnionResponseTx>,
+    /// Addresses our UDP socket is reachable on locally. Together with
+    /// confirmed external addresses reported by other nodes they are used to
+    /// recognize packets addressed to ourselves.
+    local_addrs: Vec<SocketAddr>,
+    /// Sink for DHT events. None if nobody is interested in them.
+    event_sink: Option<EventTx>,
+    /// Sink for destinations of packets that were not queued to the outgoing
+    /// channel along with the error. None if nobody is interested in them.
+    queue_error_sink: Option<QueueErrorTx>,
+    /// Sink for verified nodes with global addresses that can be used as
+    /// onion path nodes. None if there is no onion client.
+    path_node_sink: Option<PathNodeTx>,
     /// Net crypto module that handles `CookieRequest`, `CookieResponse`,
     /// `CryptoHandshake` and `CryptoData` packets. It can be `None` in case of
     /// pure bootstrap server when we don't have friends and therefore don't
@@ -159,16 +689,56 @@ pub struct Server {
     /// If LAN discovery is enabled `Server` will handle `LanDiscovery` packets
     /// and send `NodesRequest` packets in reply.
     lan_discovery_enabled: bool,
+    /// Quiet period during which LAN discovery is suppressed. It's shared with
+    /// `LanDiscoverySender` so that sending is suppressed as well.
+    lan_discovery_quiet_period: QuietPeriod,
+    /// Ports window of `LanDiscovery` packets. It's shared with
+    /// `LanDiscoverySender` so that ports continue each other.
+    lan_discovery_ports: LanPorts,
+    /// If onion relay is enabled `Server` will handle onion packets.
+    onion_relay_enabled: bool,
+    /// If net crypto is enabled `Server` will pass net crypto packets to
+    /// `net_crypto` module.
+    net_crypto_enabled: bool,
+    /// If friends are enabled `Server` will search for friends added with
+    /// `add_friend`.
+    friends_enabled: bool,
+    /// If enabled `Server` will respond to `OnionDataRequest` packets for
+    /// destinations that are not announced with `OnionDataResponse` packet
+    /// with empty payload so that sender learns that delivery failed.
+    /// Otherwise such requests are dropped.
+    onion_data_failure_response_enabled: bool,
+    /// If enabled `Server` will respond to `BootstrapInfo` requests sent with
+    /// a version different from ours. Otherwise such requests are dropped.
+    incompatible_version_response_enabled: bool,
+    /// If enabled `Server` will respond to `NodesRequest` packets even if it
+    /// doesn't know any nodes to put into `NodesResponse`. Otherwise the
+    /// requester is only added to the list of nodes to ping.
+    empty_nodes_response_enabled: bool,
+    /// If respond only mode is enabled `Server` answers `PingRequest` packets
+    /// but doesn't consider adding their senders to close nodes list.
+    respond_only_mode: bool,
     /// If IPv6 mode is enabled `Server` will send packets to IPv6 addresses. If
     /// it's disabled such packets will be dropped.
     is_ipv6_enabled: bool,
     /// Initial bootstrap nodes list. We send `NodesRequest` packet to each node
     /// from this list if Ktree doesn't have good (or bad but not discarded)
     /// nodes.
-    initial_bootstrap: Vec<PackedNode>,
+    initial_bootstrap: Arc<RwLock<Vec<PackedNode>>>,
+    /// Node used as a last resort to bootstrap from when there are neither
+    /// close nodes nor initial bootstrap nodes.
+    fallback_bootstrap_node: Option<PackedNode>,
+    /// Time when the warning that `Server` doesn't have any nodes to bootstrap
+    /// from was logged.
+    last_needs_bootstrap_warn_time: Arc<RwLock<Option<Instant>>>,
     /// Lru cache for precomputed keys. It stores precomputed keys to avoid
     /// redundant calculations.
     precomputed_keys: PrecomputedCache,
+    /// Whether we have ever received a valid response via UDP.
+    udp_works: Arc<AtomicBool>,
+    /// The first node that was added to empty close nodes list after it sent
+    /// us `NodesResponse`, i.e. the node that got us onto the network.
+    first_bootstrap: Arc<RwLock<Option<PackedNode>>>,
 }
 
 impl Server {
@@ -195,29 +765,170 @@ impl Server {
         Server {
             sk,
             pk,
-            tx,
+            tx: Arc::new(RwLock::new(tx)),
             request_queue: Arc::new(RwLock::new(RequestQueue::new(Duration::from_secs(PING_TIMEOUT)))),
             close_nodes: Arc::new(RwLock::new(Ktree::new(&pk))),
             onion_symmetric_key: Arc::new(RwLock::new(secretbox::gen_key())),
+            onion_symmetric_key_time: Arc::new(RwLock::new(clock_now())),
             onion_announce: Arc::new(RwLock::new(OnionAnnounce::new(pk))),
             friends: Arc::new(RwLock::new(friends)),
             nodes_to_bootstrap: Arc::new(RwLock::new(NodesQueue::new(MAX_TO_BOOTSTRAP))),
             random_requests_count: Arc::new(RwLock::new(0)),
+            max_bootstrap_times: MAX_BOOTSTRAP_TIMES,
             last_nodes_req_time: Arc::new(RwLock::new(clock_now())),
+            tick_nodes_reqs: Arc::new(RwLock::new(None)),
+            unanswered_pings: Arc::new(RwLock::new(HashMap::new())),
+            nodes_resp_cache: Arc::new(RwLock::new(LruCache::new(NODES_RESP_CACHE_SIZE))),
+            random_requests_tapering_enabled: false,
+            random_requests_backoff: Arc::new(RwLock::new(0)),
             nodes_to_ping: Arc::new(RwLock::new(NodesQueue::new(MAX_TO_PING))),
+            max_friends_per_tick: None,
+            friends_offset: Arc::new(RwLock::new(0)),
+            max_close_admissions_per_tick: None,
+            max_friends_fan_out: None,
+            friends_fan_out_offset: Arc::new(RwLock::new(0)),
+            close_admissions: Arc::new(AtomicUsize::new(0)),
+            deferred_close_nodes: Arc::new(RwLock::new(NodesQueue::new(MAX_DEFERRED_CLOSE_NODES))),
+            requests_limiter: Some(Arc::new(RwLock::new(RequestsLimiter::new(
+                MAX_REQUESTS_PER_PK,
+                Duration::from_secs(REQUESTS_LIMIT_INTERVAL)
+            )))),
+            handshakes_limiter: None,
+            handshakes_per_addr_limiter: None,
+            announce_responses_limiter: Arc::new(RwLock::new(RequestsLimiter::new(
+                MAX_ANNOUNCE_RESPONSES_PER_ADDR,
+                Duration::from_secs(REQUESTS_LIMIT_INTERVAL)
+            ))),
+            is_paused: Arc::new(AtomicBool::new(false)),
+            is_onion_relay_paused: Arc::new(AtomicBool::new(false)),
+            is_nat_paused: Arc::new(AtomicBool::new(false)),
+            is_draining: Arc::new(AtomicBool::new(false)),
+            pending_sends: Arc::new(AtomicUsize::new(0)),
+            consecutive_send_failures: Arc::new(AtomicUsize::new(0)),
+            drain_tasks: Arc::new(RwLock::new(Vec::new())),
+            start_time: clock_now(),
+            health_interval: Duration::from_secs(HEALTH_SNAPSHOT_INTERVAL),
+            last_packet_offset: Arc::new(AtomicUsize::new(0)),
+            last_health_time: Arc::new(RwLock::new(None)),
+            health_subscribers: Arc::new(RwLock::new(Vec::new())),
+            allowed_networks: Arc::new(RwLock::new(None)),
+            quarantine_timeout: Duration::from_secs(QUARANTINE_TIMEOUT),
+            nodes_resp_max_age: None,
+            self_in_nodes_resp_enabled: true,
+            fill_strategy: FillStrategy::Passive,
+            active_fill: Arc::new(RwLock::new(Some(Vec::new()))),
+            port_prediction_range: 0,
+            oversized_onion_packets: Arc::new(AtomicUsize::new(0)),
+            eviction_tracking: false,
+            evicted_close_nodes: Arc::new(AtomicUsize::new(0)),
+            malformed_onion_packets: Arc::new(AtomicUsize::new(0)),
+            incompatible_version_packets: Arc::new(AtomicUsize::new(0)),
+            max_onion_forwards: None,
+            denied_onion_ports: None,
+            onion_announce_nodes_count: MAX_ONION_ANNOUNCE_NODES,
+            onion_forwards_in_flight: Arc::new(AtomicUsize::new(0)),
+            shed_onion_packets: Arc::new(AtomicUsize::new(0)),
+            received_packets: Arc::new(Default::default()),
+            dropped_packets: Arc::new(AtomicUsize::new(0)),
+            self_requests: Arc::new(AtomicUsize::new(0)),
+            unknown_nat_ping_responses: Arc::new(AtomicUsize::new(0)),
+            dropped_handshake_packets: Arc::new(AtomicUsize::new(0)),
+            relayed_onion_packets: Arc::new(AtomicUsize::new(0)),
+            onion_replay_cache: None,
+            stats: Arc::new(RwLock::new(ServerStats::default())),
+            inbound_filter: Arc::new(RwLock::new(None)),
+            outbound_filter: Arc::new(RwLock::new(None)),
             bootstrap_info: None,
             tcp_onion_sink: None,
+            send_trace: None,
+            onion_response_sink: None,
+            local_addrs: Vec::new(),
+            queue_error_sink: None,
+            path_node_sink: None,
+            event_sink: None,
             net_crypto: None,
             lan_discovery_enabled: true,
+            lan_discovery_quiet_period: QuietPeriod::new(),
+            lan_discovery_ports: LanPorts::new(),
+            onion_relay_enabled: true,
+            net_crypto_enabled: true,
+            friends_enabled: true,
+            onion_data_failure_response_enabled: false,
+            incompatible_version_response_enabled: true,
+            empty_nodes_response_enabled: true,
+            respond_only_mode: false,
             is_ipv6_enabled: false,
-            initial_bootstrap: Vec::new(),
+            initial_bootstrap: Arc::new(RwLock::new(Vec::new())),
+            fallback_bootstrap_node: None,
+            last_needs_bootstrap_warn_time: Arc::new(RwLock::new(None)),
             precomputed_keys,
+            udp_works: Arc::new(AtomicBool::new(false)),
+            first_bootstrap: Arc::new(RwLock::new(None)),
+        }
+    }
+
+    /// Create new `Server` with handlers preset according to the `NodeRole`.
+    pub fn with_role(tx: Tx, pk: PublicKey, sk: SecretKey, role: NodeRole) -> Server {
+        let mut server = Server::new(tx, pk, sk);
+        server.set_role(role);
+        server
+    }
+
+    /// Enable or disable onion relay, net crypto and friends according to the
+    /// `NodeRole`.
+    pub fn set_role(&mut self, role: NodeRole) {
+        let (onion_relay, net_crypto, friends) = match role {
+            NodeRole::BootstrapOnly => (false, false, false),
+            NodeRole::Client => (false, true, true),
+            NodeRole::Relay => (true, false, false),
+            NodeRole::Full => (true, true, true),
+        };
+        self.enable_onion_relay(onion_relay);
+        self.enable_net_crypto(net_crypto);
+        self.enable_friends(friends);
+    }
+
+    /// Enable/disable onion packets handling.
+    pub fn enable_onion_relay(&mut self, enable: bool) {
+        self.onion_relay_enabled = enable;
+    }
+
+    /// Enable/disable passing net crypto packets to `net_crypto` module.
+    pub fn enable_net_crypto(&mut self, enable: bool) {
+        self.net_crypto_enabled = enable;
+    }
+
+    /// Enable/disable friends. When friends are disabled `add_friend` does
+    /// nothing.
+    pub fn enable_friends(&mut self, enable: bool) {
+        self.friends_enabled = enable;
+    }
+
+    /// Check if the packet should be handled according to enabled handlers.
+    fn is_packet_enabled(&self, packet: &Packet) -> bool {
+        match *packet {
+            Packet::CookieRequest(_) |
+            Packet::CookieResponse(_) |
+            Packet::CryptoHandshake(_) |
+            Packet::CryptoData(_) => self.net_crypto_enabled,
+            Packet::OnionRequest0(_) |
+            Packet::OnionRequest1(_) |
+            Packet::OnionRequest2(_) |
+            Packet::OnionAnnounceRequest(_) |
+            Packet::OnionDataRequest(_) |
+            Packet::OnionResponse3(_) |
+            Packet::OnionResponse2(_) |
+            Packet::OnionResponse1(_) => self.onion_relay_enabled && !self.is_onion_relay_paused(),
+            _ => true,
         }
     }
 
-    /// Enable/disable IPv6 mode of DHT server.
+    /// Enable/disable IPv6 mode of DHT server. In IPv6 mode the server is
+    /// dual-stack so the close nodes list keeps nodes of both address
+    /// families.
     pub fn enable_ipv6_mode(&mut self, enable: bool) {
         self.is_ipv6_enabled = enable;
+        self.close_nodes.write().set_balance_families(enable);
     }
 
     /// Get is_ipv6_enabled member variable
@@ -225,1869 +936,6591 @@ impl Server {
         self.is_ipv6_enabled
     }
 
-    /// Enable/disable `LanDiscovery` packets handling.
-    pub fn enable_lan_discovery(&mut self, enable: bool) {
-        self.lan_discovery_enabled = enable;
+    /// Check if packets can be sent to the address in the current net mode.
+    /// IPv6 addresses are reachable only in IPv6 mode unless they are
+    /// IPv4-compatible or IPv4-mapped. The address also has to belong to
+    /// allowed networks.
+    pub fn can_reach(&self, addr: SocketAddr) -> bool {
+        let is_ipv4 = match addr.ip() {
+            IpAddr::V4(_) => true,
+            IpAddr::V6(ip) => ip.to_ipv4().is_some(),
+        };
+        (self.is_ipv6_enabled || is_ipv4) && self.is_addr_allowed(&addr)
     }
 
-    /// Get closest nodes from both close_nodes and friend's close_nodes
-    fn get_closest(&self, base_pk: &PublicKey, only_global: bool) -> NodesQueue {
-        let close_nodes = self.close_nodes.read();
-        let friends = self.friends.read();
+    /// Get number of onion packets that were dropped because their payload
+    /// exceeded maximum size.
+    pub fn oversized_onion_packets(&self) -> usize {
+        self.oversized_onion_packets.load(Ordering::Relaxed)
+    }
 
-        let mut queue = close_nodes.get_closest(base_pk, only_global);
+    /// Enable/disable tracking of nodes evicted from close nodes list to make
+    /// room for other nodes. Every eviction is counted and reported as
+    /// `DhtEvent::NodeEvicted` which helps to diagnose close list churn.
+    pub fn enable_eviction_tracking(&mut self, enable: bool) {
+        self.eviction_tracking = enable;
+        self.close_nodes.write().set_track_evictions(enable);
+    }
 
-        for node in friends.iter().flat_map(|friend| friend.close_nodes.iter()) {
-            if let Some(pn) = node.to_packed_node() {
-                if !only_global || IsGlobal::is_global(&pn.saddr.ip()) {
-                    queue.try_add(base_pk, &pn);
-                }
-            }
-        }
+    /// Get number of nodes evicted from close nodes list to make room for
+    /// other nodes while eviction tracking was enabled.
+    pub fn evicted_close_nodes(&self) -> usize {
+        self.evicted_close_nodes.load(Ordering::Relaxed)
+    }
 
-        queue
+    /// Get number of `OnionRequest2` packets that were dropped because their
+    /// payload couldn't be decrypted or decoded.
+    pub fn malformed_onion_packets(&self) -> usize {
+        self.malformed_onion_packets.load(Ordering::Relaxed)
     }
 
-    /// Add a friend.
-    /// `node_to_bootstrap` of new friend is filled with close nodes for fast bootstrapping.
-    pub fn add_friend(&self, friend_pk: PublicKey) {
-        let mut friend = DhtFriend::new(friend_pk);
-        let close_nodes = self.get_closest(&friend.pk, true);
+    /// Get number of onion requests that were dropped because too many onion
+    /// packets were being forwarded.
+    pub fn shed_onion_packets(&self) -> usize {
+        self.shed_onion_packets.load(Ordering::Relaxed)
+    }
 
-        for node in close_nodes.iter() {
-            friend.nodes_to_bootstrap.try_add(&friend.pk, &node);
-        }
+    /// Set maximum number of onion packets that are being forwarded to the
+    /// next hop at the same time. It protects DHT work from being starved by
+    /// onion relay load. `None` means that the number is not limited.
+    pub fn set_max_onion_forwards(&mut self, max_onion_forwards: Option<usize>) {
+        self.max_onion_forwards = max_onion_forwards;
+    }
 
-        self.friends.write().push(friend);
+    /// Set ports onion requests are not forwarded to. `None` means that onion
+    /// requests can be forwarded to any port.
+    pub fn set_denied_onion_ports(&mut self, denied_onion_ports: Option<Vec<u16>>) {
+        self.denied_onion_ports = denied_onion_ports.map(PortSet::new);
     }
 
-    /// The main loop of DHT server which should be called every second. This
-    /// method iterates over all nodes from close nodes list, close nodes of
-    /// friends and bootstrap nodes and sends `NodesRequest` packets if
-    /// necessary.
-    fn dht_main_loop(&self) -> impl Future<Item = (), Error = Error> + Send {
-        // Check if we should send `NodesRequest` packet to a random node. This
-        // request is sent every second 5 times and then every 20 seconds.
-        fn send_random_request(last_nodes_req_time: &mut Instant, random_requests_count: &mut u32) -> bool {
-            if clock_elapsed(*last_nodes_req_time) > Duration::from_secs(NODES_REQ_INTERVAL) || *random_requests_count < MAX_BOOTSTRAP_TIMES {
-                *random_requests_count = random_requests_count.saturating_add(1);
-                *last_nodes_req_time = clock_now();
-                true
-            } else {
-                false
-            }
-        }
+    /// Deny forwarding onion requests to well-known service ports, i.e. ports
+    /// below `WELL_KNOWN_PORTS_END`.
+    pub fn deny_well_known_onion_ports(&mut self) {
+        self.denied_onion_ports = Some(PortSet::new(0 .. WELL_KNOWN_PORTS_END));
+    }
 
-        let mut request_queue = self.request_queue.write();
-        let mut nodes_to_bootstrap = self.nodes_to_bootstrap.write();
-        let mut close_nodes = self.close_nodes.write();
-        let mut friends = self.friends.write();
+    /// Set maximum number of `OnionAnnounceResponse` packets that we send to
+    /// one IP address per `REQUESTS_LIMIT_INTERVAL` seconds. Default is
+    /// `MAX_ANNOUNCE_RESPONSES_PER_ADDR`.
+    pub fn set_max_announce_responses_per_addr(&self, max_announce_responses: u32) {
+        *self.announce_responses_limiter.write() = RequestsLimiter::new(
+            max_announce_responses,
+            Duration::from_secs(REQUESTS_LIMIT_INTERVAL)
+        );
+    }
 
-        request_queue.clear_timed_out();
+    /// Set number of closest nodes that are sent in `OnionAnnounceResponse`.
+    /// It can't exceed `MAX_ONION_ANNOUNCE_NODES` which is the default.
+    pub fn set_onion_announce_nodes_count(&mut self, onion_announce_nodes_count: usize) {
+        self.onion_announce_nodes_count = onion_announce_nodes_count.min(MAX_ONION_ANNOUNCE_NODES);
+    }
 
-        // Send NodesRequest packets to nodes from the Server
-        let ping_nodes_to_bootstrap = self.ping_nodes_to_bootstrap(&mut request_queue, &mut nodes_to_bootstrap, self.pk);
-        let ping_close_nodes = self.ping_close_nodes(&mut request_queue, close_nodes.iter_mut(), self.pk);
-        let send_nodes_req_random = if send_random_request(&mut self.last_nodes_req_time.write(), &mut self.random_requests_count.write()) {
-            Either::A(self.send_nodes_req_random(&mut request_queue, close_nodes.iter(), self.pk))
-        } else {
-            Either::B(future::ok(()))
-        };
+    /// Set number of random `NodesRequest` packets that are sent every second
+    /// during initial bootstrap before `NODES_REQ_INTERVAL` is used. Default is
+    /// `MAX_BOOTSTRAP_TIMES`.
+    pub fn set_max_bootstrap_times(&mut self, max_bootstrap_times: u32) {
+        self.max_bootstrap_times = max_bootstrap_times;
+    }
 
-        // Send NodesRequest packets to nodes from every DhtFriend
-        let send_nodes_req_to_friends = friends.iter_mut().map(|friend| {
-            let ping_nodes_to_bootstrap = self.ping_nodes_to_bootstrap(&mut request_queue, &mut friend.nodes_to_bootstrap, friend.pk);
-            let ping_close_nodes = self.ping_close_nodes(&mut request_queue, friend.close_nodes.nodes.iter_mut(), friend.pk);
-            let send_nodes_req_random = if send_random_request(&mut friend.last_nodes_req_time, &mut friend.random_requests_count) {
-                Either::A(self.send_nodes_req_random(&mut request_queue, friend.close_nodes.nodes.iter(), friend.pk))
-            } else {
-                Either::B(future::ok(()))
-            };
-            ping_nodes_to_bootstrap.join3(ping_close_nodes, send_nodes_req_random)
-        }).collect::<Vec<_>>();
+    /// Get number of `BootstrapInfo` requests that were sent with a version
+    /// different from ours. Other DHT packets don't carry protocol version so
+    /// packets of incompatible versions can't be distinguished from malformed
+    /// ones.
+    pub fn incompatible_version_packets(&self) -> usize {
+        self.incompatible_version_packets.load(Ordering::Relaxed)
+    }
 
-        let send_nat_ping_req = self.send_nat_ping_req(&mut request_queue, &mut friends);
+    /// Get number of `PingRequest` and `NodesRequest` packets that were
+    /// dropped because they were sent from our own `PublicKey`. Such requests
+    /// may loop back to us through NAT quirks.
+    pub fn self_requests(&self) -> usize {
+        self.self_requests.load(Ordering::Relaxed)
+    }
 
-        ping_nodes_to_bootstrap.join5(
-            ping_close_nodes,
-            send_nodes_req_random,
-            future::join_all(send_nodes_req_to_friends),
-            send_nat_ping_req
-        ).map(|_| ())
+    /// Get number of `NatPingResponse` packets that were dropped because they
+    /// were sent from a node that is not our friend.
+    pub fn unknown_nat_ping_responses(&self) -> usize {
+        self.unknown_nat_ping_responses.load(Ordering::Relaxed)
     }
 
-    /// Run DHT periodical tasks. Result future will never be completed
-    /// successfully.
-    pub fn run(self) -> impl Future<Item = (), Error = Error> + Send {
-        self.clone().run_pings_sending().join4(
-            self.clone().run_onion_key_refresing(),
-            self.clone().run_main_loop(),
-            self.run_bootstrap_requests_sending()
-        ).map(|_| ())
+    /// Set maximum number of `PingRequest` and `NodesRequest` packets that we
+    /// handle from one `PublicKey` per `REQUESTS_LIMIT_INTERVAL` seconds.
+    /// Excess requests are dropped. `None` means that the number is not
+    /// limited. Default is `MAX_REQUESTS_PER_PK`.
+    pub fn set_max_requests_per_pk(&mut self, max_requests: Option<u32>) {
+        self.requests_limiter = max_requests.map(|max_requests|
+            Arc::new(RwLock::new(RequestsLimiter::new(
+                max_requests,
+                Duration::from_secs(REQUESTS_LIMIT_INTERVAL)
+            )))
+        );
     }
 
-    /// Store bootstap nodes
-    pub fn add_initial_bootstrap(&mut self, pn: PackedNode) {
-        self.initial_bootstrap.push(pn);
+    /// Check that requests limit is not exceeded for the given `PublicKey`.
+    fn check_requests_limit(&self, pk: PublicKey) -> bool {
+        self.requests_limiter.as_ref().map_or(true, |requests_limiter|
+            requests_limiter.write().check(pk)
+        )
     }
 
-    /// Run initial bootstrapping. It sends `NodesRequest` packet to bootstrap
-    /// nodes periodically if all nodes in Ktree are discarded (including the
-    /// case when it's empty). It has to be an endless loop because we might
-    /// loose the network connection and thereby loose all nodes in Ktree.
-    fn run_bootstrap_requests_sending(self) -> impl Future<Item = (), Error = Error> + Send {
-        let interval = Duration::from_secs(BOOTSTRAP_INTERVAL);
-        let wakeups = Interval::new(Instant::now(), interval);
+    /// Set maximum number of `CookieRequest`, `CookieResponse` and
+    /// `CryptoHandshake` packets passed to net crypto per
+    /// `REQUESTS_LIMIT_INTERVAL` seconds. Excess packets are dropped. Packets
+    /// from addresses of connections we started are not limited. `None`
+    /// means that the number is not limited which is the default.
+    pub fn set_max_handshakes(&mut self, max_handshakes: Option<u32>) {
+        self.handshakes_limiter = max_handshakes.map(|max_handshakes|
+            Arc::new(RwLock::new(RequestsLimiter::new(
+                max_handshakes,
+                Duration::from_secs(REQUESTS_LIMIT_INTERVAL)
+            )))
+        );
+    }
 
-        wakeups
-            .map_err(|e| Error::new(ErrorKind::Other, format!("Bootstrap timer error: {:?}", e)))
-            .for_each(move |_instant| {
-                trace!("Bootstrap wake up");
-                self.send_bootstrap_requests()
-            })
+    /// Set maximum number of handshake related packets passed to net crypto
+    /// from one IP address per `REQUESTS_LIMIT_INTERVAL` seconds. Packets
+    /// dropped by this limit are not counted towards `set_max_handshakes`
+    /// limit, so a single host can't use it up. `None` means that the number
+    /// is not limited which is the default.
+    pub fn set_max_handshakes_per_addr(&mut self, max_handshakes: Option<u32>) {
+        self.handshakes_per_addr_limiter = max_handshakes.map(|max_handshakes|
+            Arc::new(RwLock::new(RequestsLimiter::new(
+                max_handshakes,
+                Duration::from_secs(REQUESTS_LIMIT_INTERVAL)
+            )))
+        );
     }
 
-    /// Check if all nodes in Ktree are discarded (including the case when
-    /// it's empty) and if so then send `NodesRequest` packet to nodes from
-    /// initial bootstrap list and from Ktree.
-    fn send_bootstrap_requests(&self) -> impl Future<Item = (), Error = Error> + Send {
-        let mut request_queue = self.request_queue.write();
-        let close_nodes = self.close_nodes.read();
+    /// Get number of handshake related packets that were dropped because of
+    /// handshakes limit.
+    pub fn dropped_handshake_packets(&self) -> usize {
+        self.dropped_handshake_packets.load(Ordering::Relaxed)
+    }
 
-        if !close_nodes.is_all_discarded() {
-            return Either::A(future::ok(()));
+    /// Check that handshakes limits are not exceeded for the packet from the
+    /// given address and count the dropped packet otherwise. Packets from
+    /// addresses of connections we started are responses to our handshakes
+    /// and they are not limited.
+    fn check_handshakes_limit(&self, net_crypto: &NetCrypto, addr: SocketAddr) -> bool {
+        if net_crypto.has_connection_by_addr(addr) {
+            return true;
         }
 
-        let futures = close_nodes
-            .iter()
-            .flat_map(|node| node.to_all_packed_nodes())
-            .chain(self.initial_bootstrap.iter().cloned())
-            .map(|node| self.send_nodes_req(&node, &mut request_queue, self.pk))
-            .collect::<Vec<_>>();
+        let is_allowed = self.handshakes_per_addr_limiter.as_ref().map_or(true, |limiter|
+            limiter.write().check(addr.ip())
+        ) && self.handshakes_limiter.as_ref().map_or(true, |limiter|
+            limiter.write().check(())
+        );
+        if !is_allowed {
+            trace!("Too many handshake packets, dropping packet from {}", addr);
+            self.dropped_handshake_packets.fetch_add(1, Ordering::Relaxed);
+        }
+        is_allowed
+    }
 
-        Either::B(join_all(futures).map(|_| ()))
+    /// Check that the request is not sent from our own `PublicKey` and count
+    /// it otherwise.
+    fn check_not_self(&self, pk: &PublicKey) -> bool {
+        if *pk == self.pk {
+            self.self_requests.fetch_add(1, Ordering::Relaxed);
+            false
+        } else {
+            true
+        }
     }
 
-    /// Run DHT main loop periodically. Result future will never be completed
-    /// successfully.
-    fn run_main_loop(self) -> impl Future<Item = (), Error = Error> + Send {
-        let interval = Duration::from_secs(MAIN_LOOP_INTERVAL);
-        let wakeups = Interval::new(Instant::now(), interval);
-        wakeups
-            .map_err(|e| Error::new(ErrorKind::Other, format!("DHT server timer error: {:?}", e)))
-            .for_each(move |_instant| {
-                trace!("DHT server wake up");
-                self.dht_main_loop().then(|res| {
-                    if let Err(e) = res {
-                        warn!("Failed to send DHT periodical packets: {}", e);
-                    }
-                    future::ok(())
-                })
-            })
+    /// Get statistics of DHT server work.
+    pub fn stats(&self) -> ServerStats {
+        *self.stats.read()
     }
 
-    /// Refresh onion symmetric key periodically. Result future will never be
-    /// completed successfully.
-    fn run_onion_key_refresing(self) -> impl Future<Item = (), Error = Error> + Send {
-        let interval = Duration::from_secs(ONION_REFRESH_KEY_INTERVAL);
-        let wakeups = Interval::new(Instant::now() + interval, interval);
-        wakeups
-            .map_err(|e| Error::new(ErrorKind::Other, format!("DHT server timer error: {:?}", e)))
-            .for_each(move |_instant| {
-                trace!("Refreshing onion key");
-                self.refresh_onion_key();
-                future::ok(())
-            })
+    /// Get duration of the last DHT main loop execution.
+    pub fn last_loop_duration(&self) -> Option<Duration> {
+        self.stats.read().last_loop_duration
     }
 
-    /// Run ping sending periodically. Result future will never be completed
-    /// successfully.
-    fn run_pings_sending(self) -> impl Future<Item = (), Error = Error> + Send {
-        let interval = Duration::from_secs(TIME_TO_PING);
-        let wakeups = Interval::new(Instant::now() + interval, interval);
-        wakeups
-            .map_err(|e| Error::new(ErrorKind::Other, format!("Ping timer error: {:?}", e)))
-            .for_each(move |_instant| {
-                trace!("Pings sending wake up");
-                self.send_pings()
-            })
+    /// Render counters of DHT server in Prometheus exposition format so that
+    /// they can be exposed by an HTTP endpoint for scraping.
+    pub fn metrics_prometheus(&self) -> String {
+        fn duration_millis(duration: Duration) -> u64 {
+            duration.as_secs() * 1000 + u64::from(duration.subsec_millis())
+        }
+        fn metric(out: &mut String, name: &str, kind: &str, help: &str, values: &[(Option<&str>, u64)]) {
+            writeln!(out, "# HELP {} {}", name, help).unwrap();
+            writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+            for &(packet_type, value) in values {
+                match packet_type {
+                    Some(packet_type) => writeln!(out, "{}{{type=\"{}\"}} {}", name, packet_type, value).unwrap(),
+                    None => writeln!(out, "{} {}", name, value).unwrap(),
+                }
+            }
+        }
+
+        let received_packets = PACKET_TYPE_NAMES.iter()
+            .zip(self.received_packets.iter())
+            .map(|(&packet_type, count)| (Some(packet_type), count.load(Ordering::Relaxed) as u64))
+            .collect::<Vec<_>>();
+        let stats = self.stats();
+        let close_nodes = self.close_nodes.read().iter().count();
+        let friends = self.friends.read().len().saturating_sub(FAKE_FRIENDS_NUMBER);
+
+        let mut out = String::new();
+        metric(&mut out, "tox_dht_packets_received_total", "counter",
+            "Number of received DHT packets by type.", &received_packets);
+        metric(&mut out, "tox_dht_packets_dropped_total", "counter",
+            "Number of received DHT packets dropped before handling.",
+            &[(None, self.dropped_packets.load(Ordering::Relaxed) as u64)]);
+        metric(&mut out, "tox_dht_onion_packets_relayed_total", "counter",
+            "Number of onion packets forwarded to the next hop.",
+            &[(None, self.relayed_onion_packets.load(Ordering::Relaxed) as u64)]);
+        metric(&mut out, "tox_dht_onion_packets_shed_total", "counter",
+            "Number of onion requests dropped because too many were being forwarded.",
+            &[(None, self.shed_onion_packets() as u64)]);
+        metric(&mut out, "tox_dht_onion_packets_oversized_total", "counter",
+            "Number of onion packets dropped because their payload exceeded maximum size.",
+            &[(None, self.oversized_onion_packets() as u64)]);
+        metric(&mut out, "tox_dht_onion_packets_malformed_total", "counter",
+            "Number of onion packets dropped because their payload couldn't be decrypted or decoded.",
+            &[(None, self.malformed_onion_packets() as u64)]);
+        metric(&mut out, "tox_dht_incompatible_version_packets_total", "counter",
+            "Number of BootstrapInfo requests sent with a different protocol version.",
+            &[(None, self.incompatible_version_packets() as u64)]);
+        metric(&mut out, "tox_dht_self_requests_total", "counter",
+            "Number of requests dropped because they were sent from our own public key.",
+            &[(None, self.self_requests() as u64)]);
+        metric(&mut out, "tox_dht_unknown_nat_ping_responses_total", "counter",
+            "Number of NAT ping responses dropped because they were sent from unknown friends.",
+            &[(None, self.unknown_nat_ping_responses() as u64)]);
+        metric(&mut out, "tox_dht_handshake_packets_dropped_total", "counter",
+            "Number of handshake packets dropped because of handshakes limit.",
+            &[(None, self.dropped_handshake_packets() as u64)]);
+        metric(&mut out, "tox_dht_close_nodes", "gauge",
+            "Number of nodes in close nodes list.", &[(None, close_nodes as u64)]);
+        metric(&mut out, "tox_dht_friends", "gauge",
+            "Number of DHT friends.", &[(None, friends as u64)]);
+        if let Some(duration) = stats.last_loop_duration {
+            metric(&mut out, "tox_dht_last_loop_duration_milliseconds", "gauge",
+                "Duration of the last DHT main loop execution.",
+                &[(None, duration_millis(duration))]);
+        }
+        if let Some(duration) = stats.average_loop_duration {
+            metric(&mut out, "tox_dht_average_loop_duration_milliseconds", "gauge",
+                "Moving average of DHT main loop executions durations.",
+                &[(None, duration_millis(duration))]);
+        }
+        out
+    }
+
+    /// Get the index of the packet type in `PACKET_TYPE_NAMES`. It's used to
+    /// count received packets by type without locking.
+    fn packet_type_index(packet: &Packet) -> usize {
+        match *packet {
+            Packet::PingRequest(_) => 0,
+            Packet::PingResponse(_) => 1,
+            Packet::NodesRequest(_) => 2,
+            Packet::NodesResponse(_) => 3,
+            Packet::CookieRequest(_) => 4,
+            Packet::CookieResponse(_) => 5,
+            Packet::CryptoHandshake(_) => 6,
+            Packet::CryptoData(_) => 7,
+            Packet::DhtRequest(_) => 8,
+            Packet::LanDiscovery(_) => 9,
+            Packet::OnionRequest0(_) => 10,
+            Packet::OnionRequest1(_) => 11,
+            Packet::OnionRequest2(_) => 12,
+            Packet::OnionAnnounceRequest(_) => 13,
+            Packet::OnionAnnounceResponse(_) => 14,
+            Packet::OnionDataRequest(_) => 15,
+            Packet::OnionDataResponse(_) => 16,
+            Packet::OnionResponse3(_) => 17,
+            Packet::OnionResponse2(_) => 18,
+            Packet::OnionResponse1(_) => 19,
+            Packet::BootstrapInfo(_) => 20,
+        }
     }
 
-    /// Send `PingRequest` packets to nodes from `nodes_to_ping` list.
-    fn send_pings(&self) -> impl Future<Item = (), Error = Error> + Send {
-        let nodes_to_ping = mem::replace(
-            &mut *self.nodes_to_ping.write(),
-            NodesQueue::new(MAX_TO_PING)
-        );
+    /// Set capability bits of the node with given `PublicKey` in close nodes
+    /// lists. Returns `false` if the node is not in any close nodes list.
+    pub fn set_node_capabilities(&self, pk: &PublicKey, capabilities: u8) -> bool {
+        let mut found = false;
+        if let Some(node) = self.close_nodes.write().get_node_mut(pk) {
+            node.capabilities = capabilities;
+            found = true;
+        }
+        for friend in self.friends.write().iter_mut() {
+            if let Some(node) = friend.close_nodes.get_node_mut(&friend.pk, pk) {
+                node.capabilities = capabilities;
+                found = true;
+            }
+        }
+        found
+    }
 
-        if nodes_to_ping.is_empty() {
-            return Either::A(future::ok(()))
+    /// Get nodes from close nodes list that are known to be capable of
+    /// relaying TCP connections.
+    pub fn relay_candidates(&self) -> Vec<PackedNode> {
+        let close_nodes = self.close_nodes.read();
+        close_nodes.iter()
+            .filter(|node| node.has_capabilities(CAPABILITY_TCP_RELAY))
+            .flat_map(|node| node.to_preferred_packed_node(close_nodes.addr_preference()))
+            .collect()
+    }
+
+    /// Get the number of nodes in every kbucket of the close nodes list as
+    /// pairs of kbucket index and nodes count.
+    pub fn bucket_fill(&self) -> Vec<(usize, usize)> {
+        Server::kbuckets_fill(&self.close_nodes.read())
+    }
+
+    /// Get the number of nodes in every kbucket of the given close nodes list
+    /// as pairs of kbucket index and nodes count.
+    fn kbuckets_fill(close_nodes: &Ktree) -> Vec<(usize, usize)> {
+        close_nodes.kbuckets.iter()
+            .map(|kbucket| kbucket.nodes.len())
+            .enumerate()
+            .collect()
+    }
+
+    /// Finish active filling of close nodes list if the number of nodes in
+    /// kbuckets didn't change since the previous DHT main loop iteration.
+    /// Empty close nodes list is never considered filled.
+    fn update_active_fill(&self, close_nodes: &Ktree) {
+        let mut active_fill = self.active_fill.write();
+        let is_stable = match *active_fill {
+            Some(ref previous_fill) => {
+                let fill = Server::kbuckets_fill(close_nodes);
+                let is_stable = !close_nodes.is_empty() && *previous_fill == fill;
+                if !is_stable {
+                    *active_fill = Some(fill);
+                }
+                is_stable
+            },
+            None => false,
+        };
+        if is_stable {
+            debug!("Close nodes list is filled, finishing active filling");
+            *active_fill = None;
         }
+    }
 
-        let mut request_queue = self.request_queue.write();
+    /// Get a score from 0 to 1 summarizing how well the close nodes list is
+    /// populated and how many of its nodes are not bad. Kbuckets closer to us
+    /// than the closest non-empty one are not expected to be filled so they
+    /// don't lower the score.
+    pub fn coverage_score(&self) -> f32 {
+        let fill = self.bucket_fill();
+        let last = match fill.iter().rposition(|&(_, count)| count > 0) {
+            Some(last) => last,
+            None => return 0.0,
+        };
 
-        let futures = nodes_to_ping.iter().map(|node|
-            self.send_ping_req(node, &mut request_queue)
-        ).collect::<Vec<_>>();
+        let nodes = fill[..= last].iter().map(|&(_, count)| count).sum::<usize>();
+        let capacity = (last + 1) * KBUCKET_DEFAULT_SIZE as usize;
+        let good = self.close_nodes.read().iter().filter(|node| !node.is_bad()).count();
 
-        Either::B(future::join_all(futures).map(|_| ()))
+        let fill = nodes.min(capacity) as f32 / capacity as f32;
+        let freshness = good as f32 / nodes as f32;
+        fill * freshness
     }
 
-    /// Add node to a `nodes_to_ping` list to send ping later. If node is
-    /// a friend and we don't know it's address then this method will send
-    /// `PingRequest` immediately instead of adding to a `nodes_to_ping`
-    /// list.
-    fn ping_add(&self, node: &PackedNode) -> impl Future<Item = (), Error = Error> + Send {
-        let close_nodes = self.close_nodes.read();
+    /// Check if onion packet payload doesn't exceed maximum size. Count the
+    /// packet as dropped otherwise.
+    fn check_onion_payload_size(&self, len: usize, max_len: usize) -> bool {
+        if len > max_len {
+            trace!("Dropping onion packet with too big payload: {} bytes", len);
+            self.oversized_onion_packets.fetch_add(1, Ordering::Relaxed);
+            false
+        } else {
+            true
+        }
+    }
 
-        if !close_nodes.can_add(node) {
-            return Either::A(future::ok(()))
+    /// Check if a new onion request can be forwarded without exceeding maximum
+    /// number of onion forwards in flight. Count the request as shed
+    /// otherwise.
+    fn check_onion_forwards_limit(&self) -> bool {
+        match self.max_onion_forwards {
+            Some(max_onion_forwards) if self.onion_forwards_in_flight.load(Ordering::Relaxed) >= max_onion_forwards => {
+                trace!("Dropping onion request since too many onion packets are being forwarded");
+                self.shed_onion_packets.fetch_add(1, Ordering::Relaxed);
+                false
+            },
+            _ => true,
         }
+    }
 
-        let friends = self.friends.read();
+    /// Check if onion request can be forwarded to the next hop with given
+    /// port.
+    fn check_onion_next_hop_port(&self, port: u16) -> bool {
+        match self.denied_onion_ports {
+            Some(ref denied_onion_ports) if denied_onion_ports.contains(port) => {
+                trace!("Dropping onion request to denied port {}", port);
+                false
+            },
+            _ => true,
+        }
+    }
 
-        // If node is friend and we don't know friend's IP address yet then send
-        // PingRequest immediately and unconditionally
-        if friends.iter().any(|friend| friend.pk == node.pk && !friend.is_addr_known()) {
-            return Either::B(self.send_ping_req(node, &mut self.request_queue.write()))
+    /// Send onion packet to the next hop counting it as in flight until the
+    /// returned future is completed or dropped.
+    fn forward_onion_packet(&self, addr: SocketAddr, packet: Packet) -> impl Future<Item = (), Error = Error> + Send {
+        let onion_forward = OnionForward::new(self.onion_forwards_in_flight.clone());
+        self.relayed_onion_packets.fetch_add(1, Ordering::Relaxed);
+        self.send_to_direct(addr, packet).then(move |res| {
+            drop(onion_forward);
+            res
+        })
+    }
+
+    /// Check if temporary `PublicKey` that should be passed to the next onion
+    /// hop is sane, i.e. it's not all zeros and it's not our own DHT key.
+    fn check_onion_temporary_pk(&self, temporary_pk: &PublicKey) -> Result<(), Error> {
+        if temporary_pk.0.iter().all(|&b| b == 0) || *temporary_pk == self.pk {
+            Err(Error::new(ErrorKind::Other,
+                format!("Onion request has malformed inner temporary_pk: {:?}", temporary_pk)
+            ))
+        } else {
+            Ok(())
         }
+    }
 
-        self.nodes_to_ping.write().try_add(&self.pk, node);
+    /// Set filter that is called for every received packet before handling it.
+    /// If the filter returns `false` the packet is dropped.
+    pub fn set_inbound_filter(&self, filter: Box<PacketFilter>) {
+        *self.inbound_filter.write() = Some(filter.into());
+    }
 
-        Either::A(future::ok(()))
+    /// Set filter that is called for every packet before sending it. If the
+    /// filter returns `false` the packet is not sent.
+    pub fn set_outbound_filter(&self, filter: Box<PacketFilter>) {
+        *self.outbound_filter.write() = Some(filter.into());
     }
 
-    /// Send `NodesRequest` packets to nodes from bootstrap list. This is
-    /// necessary to check whether node is alive before adding it to close
-    /// nodes lists.
-    fn ping_nodes_to_bootstrap(&self, request_queue: &mut RequestQueue, nodes_to_bootstrap: &mut NodesQueue, pk: PublicKey) -> impl Future<Item = (), Error = Error> + Send {
-        let capacity = nodes_to_bootstrap.capacity() as u8;
-        let nodes_to_bootstrap = mem::replace(nodes_to_bootstrap, NodesQueue::new(capacity));
+    /// Restrict communication to the given list of networks. Packets from
+    /// addresses outside of these networks will be dropped and packets to such
+    /// addresses won't be sent.
+    pub fn set_allowed_networks(&self, nets: Vec<IpNet>) {
+        *self.allowed_networks.write() = Some(nets);
+    }
 
-        let futures = nodes_to_bootstrap.iter()
-            .map(|node| self.send_nodes_req(&node, request_queue, pk))
-            .collect::<Vec<_>>();
+    /// Remove restriction of networks `Server` is allowed to communicate with.
+    pub fn reset_allowed_networks(&self) {
+        *self.allowed_networks.write() = None;
+    }
 
-        future::join_all(futures).map(|_| ())
+    /// Check if `Server` is allowed to communicate with the given address.
+    fn is_addr_allowed(&self, addr: &SocketAddr) -> bool {
+        is_addr_allowed(&self.allowed_networks.read(), addr)
     }
 
-    /// Iterate over nodes from close nodes list and send `NodesRequest` packets
-    /// to them if necessary.
-    fn ping_close_nodes<'a, T>(&self, request_queue: &mut RequestQueue, nodes: T, pk: PublicKey) -> Box<dyn Future<Item = (), Error = Error> + Send>
-        where T: Iterator<Item = &'a mut DhtNode> // if change to impl Future the result will be dependent on nodes lifetime
-    {
-        let futures = nodes
-            .flat_map(|node| {
-                let ping_addr_v4 = node.assoc4
-                    .ping_addr()
-                    .map(|addr| PackedNode::new(addr.into(), &node.pk));
-                let ping_addr_v6 = node.assoc6
-                    .ping_addr()
-                    .map(|addr| PackedNode::new(addr.into(), &node.pk));
-                ping_addr_v4.into_iter().chain(ping_addr_v6.into_iter())
-            })
-            .map(|node| self.send_nodes_req(&node, request_queue, pk))
-            .collect::<Vec<_>>();
+    /// Get filter that drops packets to addresses outside of allowed
+    /// networks, packets rejected by outbound filter and all packets while
+    /// `Server` is draining. It should be set to `LanDiscoverySender` so that
+    /// it's restricted the same way as `Server`.
+    pub fn send_filter(&self) -> Arc<PacketFilter> {
+        let is_draining = self.is_draining.clone();
+        let allowed_networks = self.allowed_networks.clone();
+        let outbound_filter = self.outbound_filter.clone();
+        Arc::new(move |packet: &Packet, addr: SocketAddr|
+            !is_draining.load(Ordering::SeqCst) &&
+            is_addr_allowed(&allowed_networks.read(), &addr) &&
+                outbound_filter.read().clone().map_or(true, |filter| filter(packet, addr))
+        )
+    }
 
-        Box::new(future::join_all(futures).map(|_| ()))
+    /// Set maximum number of nodes from the same /24 IPv4 or /64 IPv6 subnet
+    /// in the whole close nodes list. It makes it harder to eclipse us by an
+    /// attacker controlling many nodes in one subnet. `None` means that the
+    /// number is not limited.
+    pub fn set_max_nodes_per_subnet(&mut self, max_nodes_per_subnet: Option<u8>) {
+        self.close_nodes.write().set_max_nodes_per_subnet(max_nodes_per_subnet);
     }
 
-    /// Send `NodesRequest` packet to a random good node every 20 seconds or if
-    /// it was sent less than `NODES_REQ_INTERVAL`. This function should be
-    /// called every second.
-    fn send_nodes_req_random<'a, T>(&self, request_queue: &mut RequestQueue, nodes: T, pk: PublicKey) -> Box<dyn Future<Item = (), Error = Error> + Send>
-        where T: Iterator<Item = &'a DhtNode> // if change to impl Future the result will be dependent on nodes lifetime
-    {
-        let good_nodes = nodes
-            .filter(|&node| !node.is_bad())
-            .flat_map(|node| node.to_all_packed_nodes())
-            .collect::<Vec<_>>();
+    /// Set address family preferred when nodes with both IPv4 and IPv6
+    /// addresses are sent to other nodes. By default the address the node
+    /// responded from most recently is used.
+    pub fn set_addr_preference(&self, addr_preference: AddrPreference) {
+        self.close_nodes.write().set_addr_preference(addr_preference);
+    }
 
-        if good_nodes.is_empty() {
-            // Random request should be sent only to good nodes
-            return Box::new(future::ok(()))
-        }
+    /// Set maximum time since the last response of a node to send it in
+    /// `NodesResponse`. It prevents propagating nodes that stopped answering
+    /// but are not considered bad yet. `None` means that all good nodes are
+    /// sent which is the default.
+    pub fn set_nodes_resp_max_age(&mut self, nodes_resp_max_age: Option<Duration>) {
+        self.nodes_resp_max_age = nodes_resp_max_age;
+    }
 
-        let mut random_node_idx = random_usize() % good_nodes.len();
-        // Increase probability of sending packet to a close node (has lower index)
-        if random_node_idx != 0 {
-            random_node_idx -= random_usize() % (random_node_idx + 1);
+    /// Enable/disable including our own node in `NodesResponse` when we are
+    /// among the nodes closest to the searched key. It helps the requester
+    /// to learn about us directly. Our node is included only if we learned an
+    /// external address other nodes see us from. Enabled by default.
+    pub fn enable_self_in_nodes_resp(&mut self, enable: bool) {
+        self.self_in_nodes_resp_enabled = enable;
+        self.invalidate_nodes_resp_cache();
+    }
+
+    /// Set strategy of filling close nodes list while bootstrapping. It's
+    /// `FillStrategy::Passive` by default.
+    pub fn set_fill_strategy(&mut self, fill_strategy: FillStrategy) {
+        self.fill_strategy = fill_strategy;
+    }
+
+    /// Set number of ports following the highest port a friend was seen from
+    /// that are tried during hole punching. Symmetric NAT usually allocates
+    /// ports sequentially so predicted ports improve chances of punching a
+    /// hole through it. Zero, the default, disables port prediction.
+    pub fn set_port_prediction_range(&mut self, port_prediction_range: u16) {
+        self.port_prediction_range = port_prediction_range;
+        for friend in self.friends.write().iter_mut() {
+            friend.hole_punch.port_prediction_range = port_prediction_range;
         }
+    }
 
-        let random_node = &good_nodes[random_node_idx];
+    /// Set time during which a node removed from close nodes lists for being
+    /// bad can't be added back. It helps to avoid wasting slots of close nodes
+    /// lists by nodes that go online and offline frequently.
+    pub fn set_quarantine_timeout(&mut self, timeout: Duration) {
+        self.quarantine_timeout = timeout;
+        self.close_nodes.write().set_quarantine_timeout(timeout);
+        for friend in self.friends.write().iter_mut() {
+            friend.close_nodes.set_quarantine_timeout(timeout);
+        }
+    }
 
-        Box::new(self.send_nodes_req(&random_node, request_queue, pk))
+    /// Set time after which announced onion node is removed from the list of
+    /// announced nodes if it didn't re-announce itself.
+    pub fn set_onion_announce_timeout(&mut self, timeout: Duration) {
+        self.onion_announce.write().set_entry_timeout(timeout);
     }
 
-    /// Send `PingRequest` packet to the node.
-    pub fn send_ping_req(&self, node: &PackedNode, request_queue: &mut RequestQueue) -> impl Future<Item = (), Error = Error> + Send {
-        let payload = PingRequestPayload {
-            id: request_queue.new_ping_id(node.pk),
-        };
-        let ping_req = Packet::PingRequest(PingRequest::new(
-            &self.precomputed_keys.get(node.pk),
-            &self.pk,
-            &payload
-        ));
-        self.send_to_direct(node.saddr, ping_req)
+    /// Enable/disable responding with empty `OnionDataResponse` packet to
+    /// `OnionDataRequest` packets for unknown destinations.
+    pub fn enable_onion_data_failure_response(&mut self, enable: bool) {
+        self.onion_data_failure_response_enabled = enable;
     }
 
-    /// Send `NodesRequest` packet to the node.
-    pub fn send_nodes_req(&self, node: &PackedNode, request_queue: &mut RequestQueue, search_pk: PublicKey) -> impl Future<Item = (), Error = Error> + Send {
-        // Check if packet is going to be sent to ourselves.
-        if self.pk == node.pk {
-            trace!("Attempt to send NodesRequest to ourselves.");
-            return Either::A(future::ok(()))
-        }
+    /// Enable/disable responding to `NodesRequest` packets with `NodesResponse`
+    /// that doesn't contain nodes. Disabling it avoids revealing that we have
+    /// just joined the network. Enabled by default.
+    pub fn enable_empty_nodes_response(&mut self, enable: bool) {
+        self.empty_nodes_response_enabled = enable;
+    }
 
-        let payload = NodesRequestPayload {
-            pk: search_pk,
-            id: request_queue.new_ping_id(node.pk),
-        };
-        let nodes_req = Packet::NodesRequest(NodesRequest::new(
-            &self.precomputed_keys.get(node.pk),
-            &self.pk,
-            &payload
-        ));
-        Either::B(self.send_to_direct(node.saddr, nodes_req))
+    /// Enable/disable respond only mode. In this mode `PingRequest` packets
+    /// are answered but their senders are not pinged to be added to close
+    /// nodes list. It's useful for pure bootstrap nodes that don't want to
+    /// maintain close nodes list towards arbitrary pingers. Disabled by
+    /// default.
+    pub fn enable_respond_only_mode(&mut self, enable: bool) {
+        self.respond_only_mode = enable;
     }
 
-    /// Send `NatPingRequest` packet to all friends and try to punch holes.
-    fn send_nat_ping_req(&self, request_queue: &mut RequestQueue, friends: &mut Vec<DhtFriend>) -> impl Future<Item = (), Error = Error> + Send {
-        let futures = friends.iter_mut()
-            // we don't want to punch holes to fake friends under any circumstances
-            .skip(FAKE_FRIENDS_NUMBER)
-            .filter(|friend| !friend.is_addr_known())
-            .map(|friend| {
-                let addrs = friend.get_returned_addrs();
-                (friend, addrs)
-            })
-            // Send NatPingRequest and try to punch holes only if we have enough
-            // close nodes connected to a friend
-            .filter(|(_, addrs)| addrs.len() >= FRIEND_CLOSE_NODES_COUNT as usize / 2)
-            .map(|(friend, addrs)| {
-                let punch_future = self.punch_holes(request_queue, friend, &addrs);
+    /// Enable/disable responding to `BootstrapInfo` requests sent with a
+    /// version different from ours. Enabled by default.
+    pub fn enable_incompatible_version_response(&mut self, enable: bool) {
+        self.incompatible_version_response_enabled = enable;
+    }
 
-                if friend.hole_punch.last_send_ping_time.map_or(true, |time| clock_elapsed(time) >= Duration::from_secs(PUNCH_INTERVAL)) {
-                    friend.hole_punch.last_send_ping_time = Some(clock_now());
-                    let payload = DhtRequestPayload::NatPingRequest(NatPingRequest {
-                        id: friend.hole_punch.ping_id,
-                    });
-                    let nat_ping_req_packet = DhtRequest::new(
-                        &self.precomputed_keys.get(friend.pk),
-                        &friend.pk,
-                        &self.pk,
-                        &payload
-                    );
-                    let nat_ping_future = self.send_nat_ping_req_inner(friend, nat_ping_req_packet);
+    /// Pause sending of periodical requests. Incoming packets are still
+    /// handled while `Server` is paused.
+    pub fn pause(&self) {
+        self.is_paused.store(true, Ordering::Relaxed);
+    }
 
-                    Either::A(punch_future.join(nat_ping_future).map(|_| ()))
-                } else {
-                    Either::B(punch_future)
-                }
-            })
-            .collect::<Vec<_>>();
+    /// Resume sending of periodical requests.
+    pub fn resume(&self) {
+        self.is_paused.store(false, Ordering::Relaxed);
+    }
 
-        join_all(futures).map(|_| ())
+    /// Check if sending of periodical requests is paused.
+    pub fn is_paused(&self) -> bool {
+        self.is_paused.load(Ordering::Relaxed)
     }
 
-    /// Try to punch holes to specified friend.
-    fn punch_holes(&self, request_queue: &mut RequestQueue, friend: &mut DhtFriend, returned_addrs: &[SocketAddr]) -> impl Future<Item = (), Error = Error> + Send {
-        let punch_addrs = friend.hole_punch.next_punch_addrs(returned_addrs);
+    /// Pause onion relay. Onion packets are dropped until it's resumed.
+    pub fn pause_onion_relay(&self) {
+        self.is_onion_relay_paused.store(true, Ordering::Relaxed);
+    }
 
-        let packets = punch_addrs.into_iter().map(|addr| {
-            let payload = PingRequestPayload {
-                id: request_queue.new_ping_id(friend.pk),
-            };
-            let packet = Packet::PingRequest(PingRequest::new(
-                &self.precomputed_keys.get(friend.pk),
-                &self.pk,
-                &payload
-            ));
+    /// Resume onion relay.
+    pub fn resume_onion_relay(&self) {
+        self.is_onion_relay_paused.store(false, Ordering::Relaxed);
+    }
 
-            (packet, addr)
-        }).collect::<Vec<_>>();
+    /// Check if onion relay is paused.
+    pub fn is_onion_relay_paused(&self) -> bool {
+        self.is_onion_relay_paused.load(Ordering::Relaxed)
+    }
 
-        Box::new(send_all_to_bounded(&self.tx, stream::iter_ok(packets), Duration::from_secs(DHT_SEND_TIMEOUT))
-                     .map_err(|e| Error::new(ErrorKind::Other, format!("{:?}", e))))
+    /// Pause NAT traversal. `NatPingRequest` packets are not sent to friends
+    /// and received NAT ping packets are ignored until it's resumed.
+    pub fn pause_nat(&self) {
+        self.is_nat_paused.store(true, Ordering::Relaxed);
     }
 
-    /// Send `NatPingRequest` packet to all close nodes of friend in the hope
-    /// that they will redirect it to this friend.
-    fn send_nat_ping_req_inner(&self, friend: &DhtFriend, nat_ping_req_packet: DhtRequest) -> impl Future<Item = (), Error = Error> + Send {
-        let packet = Packet::DhtRequest(nat_ping_req_packet);
-        let futures = friend.close_nodes.nodes.iter().map(|node| {
-            self.send_to_node(node, &packet)
-        }).collect::<Vec<_>>();
+    /// Resume NAT traversal.
+    pub fn resume_nat(&self) {
+        self.is_nat_paused.store(false, Ordering::Relaxed);
+    }
 
-        join_all(futures).map(|_| ())
+    /// Check if NAT traversal is paused.
+    pub fn is_nat_paused(&self) -> bool {
+        self.is_nat_paused.load(Ordering::Relaxed)
     }
 
-    /// Function to handle incoming packets and send responses if necessary.
-    pub fn handle_packet(&self, packet: Packet, addr: SocketAddr) -> impl Future<Item = (), Error = Error> + Send {
-        match packet {
-            Packet::PingRequest(packet) => Box::new(self.handle_ping_req(&packet, addr)) as Box<dyn Future<Item = _, Error = _> + Send>,
-            Packet::PingResponse(packet) => Box::new(self.handle_ping_resp(&packet, addr)),
-            Packet::NodesRequest(packet) => Box::new(self.handle_nodes_req(&packet, addr)),
-            Packet::NodesResponse(packet) => Box::new(self.handle_nodes_resp(&packet, addr)),
-            Packet::CookieRequest(packet) => Box::new(self.handle_cookie_request(&packet, addr)),
-            Packet::CookieResponse(packet) => Box::new(self.handle_cookie_response(&packet, addr)),
-            Packet::CryptoHandshake(packet) => Box::new(self.handle_crypto_handshake(&packet, addr)),
-            Packet::DhtRequest(packet) => Box::new(self.handle_dht_req(packet, addr)),
-            Packet::LanDiscovery(packet) => Box::new(self.handle_lan_discovery(&packet, addr)),
-            Packet::OnionRequest0(packet) => Box::new(self.handle_onion_request_0(&packet, addr)),
-            Packet::OnionRequest1(packet) => Box::new(self.handle_onion_request_1(&packet, addr)),
-            Packet::OnionRequest2(packet) => Box::new(self.handle_onion_request_2(&packet, addr)),
-            Packet::OnionAnnounceRequest(packet) => Box::new(self.handle_onion_announce_request(packet, addr)),
-            Packet::OnionDataRequest(packet) => Box::new(self.handle_onion_data_request(packet)),
-            Packet::OnionResponse3(packet) => Box::new(self.handle_onion_response_3(packet)),
-            Packet::OnionResponse2(packet) => Box::new(self.handle_onion_response_2(packet)),
-            Packet::OnionResponse1(packet) => Box::new(self.handle_onion_response_1(packet)),
-            Packet::BootstrapInfo(packet) => Box::new(self.handle_bootstrap_info(&packet, addr)),
-            // This packet should be handled in client only
-            Packet::CryptoData(packet) => Box::new(future::err(
-                Error::new(ErrorKind::Other,
-                           format!("Packet is not handled {:?}", packet)
-                ))),
-            // This packet should be handled in client only
-            Packet::OnionDataResponse(packet) => Box::new(future::err(
-                Error::new(ErrorKind::Other,
-                           format!("Packet is not handled {:?}", packet)
-                ))),
-            // This packet should be handled in client only
-            Packet::OnionAnnounceResponse(packet) => Box::new(future::err(
-                Error::new(ErrorKind::Other,
-                           format!("Packet is not handled {:?}", packet)
-                ))),
+    /// Replace the sink `Server` sends packets to, e.g. when UDP socket is
+    /// rebound after network change. Since our NAT mapping likely changed
+    /// too, `Server` starts bootstrapping again by sending random
+    /// `NodesRequest` packets as if it has just started, and forgets our
+    /// external addresses returned by close nodes.
+    ///
+    /// Only clones of `Server` see the new sink. Components that were given
+    /// their own clone of the old sink keep sending to it and must be
+    /// rewired by the caller: `LanDiscoverySender`, `NetCrypto` passed to
+    /// `set_net_crypto` and any other holder of the old `Tx`.
+    pub fn replace_tx(&self, tx: Tx) {
+        *self.tx.write() = tx;
+        for node in self.close_nodes.write().iter_mut() {
+            node.assoc4.ret_saddr = None;
+            node.assoc4.ret_last_resp_time = None;
+            node.assoc6.ret_saddr = None;
+            node.assoc6.ret_last_resp_time = None;
         }
+        self.udp_works.store(false, Ordering::SeqCst);
+        self.consecutive_send_failures.store(0, Ordering::SeqCst);
+        *self.random_requests_count.write() = 0;
+        *self.random_requests_backoff.write() = 0;
+        *self.active_fill.write() = Some(Vec::new());
+    }
+
+    /// Check if the receiver of outgoing packets is considered dropped, i.e.
+    /// the last `MAX_CONSECUTIVE_SEND_FAILURES` sends failed because of it.
+    /// Packets can't be sent anymore until the sink is replaced with
+    /// `replace_tx`.
+    pub fn is_tx_closed(&self) -> bool {
+        self.consecutive_send_failures.load(Ordering::SeqCst) >= MAX_CONSECUTIVE_SEND_FAILURES
+    }
+
+    /// Stop sending new packets and wait until all pending sends are
+    /// completed. A send is completed when the packet is accepted by the
+    /// outgoing channel, so packets still might be buffered in the channel
+    /// when the returned future resolves. To make sure they are written to
+    /// the socket the receiving half of the channel should be run until it
+    /// ends, i.e. until `Server` and other holders of the channel are dropped.
+    ///
+    /// Sends of `LanDiscoverySender` that uses `send_filter` are stopped as
+    /// well. Modules that have their own copy of the channel like `NetCrypto`
+    /// are not affected. Sending can be allowed again with `undrain`.
+    pub fn drain(&self) -> impl Future<Item = (), Error = Error> + Send {
+        self.is_draining.store(true, Ordering::SeqCst);
+
+        let pending_sends = self.pending_sends.clone();
+        let drain_tasks = self.drain_tasks.clone();
+        future::poll_fn(move || {
+            if pending_sends.load(Ordering::SeqCst) == 0 {
+                return Ok(Async::Ready(()));
+            }
+
+            let mut tasks = drain_tasks.write();
+            if !tasks.iter().any(|task| task.will_notify_current()) {
+                tasks.push(task::current());
+            }
+            // check again after registering the task so that the last
+            // completed send can't be missed
+            if pending_sends.load(Ordering::SeqCst) == 0 {
+                tasks.retain(|task| !task.will_notify_current());
+                Ok(Async::Ready(()))
+            } else {
+                Ok(Async::NotReady)
+            }
+        })
     }
 
-    /// Send UDP packet node. If the node has both IPv4 and IPv6 addresses,
-    /// then it sends packet to both addresses.
-    fn send_to_node(&self, node: &DhtNode, packet: &Packet) -> impl Future<Item = (), Error = Error> + Send {
-        let addrs = node.get_all_addrs();
+    /// Allow sending packets again after `drain`.
+    pub fn undrain(&self) {
+        self.is_draining.store(false, Ordering::SeqCst);
+    }
 
-        let futures = addrs.into_iter()
-            .map(|addr| self.send_to_direct(addr, packet.clone()))
-            .collect::<Vec<_>>();
+    /// Count a new send as pending until the returned guard is dropped.
+    /// Returns `None` if `Server` is draining and new packets must not be
+    /// sent.
+    fn start_send(&self) -> Option<PendingSend> {
+        if self.is_draining.load(Ordering::SeqCst) {
+            return None;
+        }
 
-        join_all(futures).map(|_| ())
+        self.pending_sends.fetch_add(1, Ordering::SeqCst);
+        Some(PendingSend {
+            pending_sends: self.pending_sends.clone(),
+            drain_tasks: self.drain_tasks.clone(),
+        })
     }
 
-    /// Send UDP packet to specified address.
-    fn send_to_direct(&self, addr: SocketAddr, packet: Packet) -> impl Future<Item = (), Error = Error> + Send {
-        send_to_bounded(&self.tx, (packet, addr), Duration::from_secs(DHT_SEND_TIMEOUT)).map_err(|e|
-            Error::new(ErrorKind::Other,
-                format!("Failed to send packet: {:?}", e)
-        ))
+    /// Subscribe to `HealthSnapshot`s that are sent every health interval from
+    /// DHT main loop.
+    pub fn health_subscribe(&self) -> mpsc::UnboundedReceiver<HealthSnapshot> {
+        let (tx, rx) = mpsc::unbounded();
+        self.health_subscribers.write().push(tx);
+        rx
     }
 
-    /// Handle received `PingRequest` packet and response with `PingResponse`
-    /// packet. If node that sent this packet is not present in close nodes list
-    /// and can be added there then it will be added to ping list.
-    fn handle_ping_req(&self, packet: &PingRequest, addr: SocketAddr) -> impl Future<Item = (), Error = Error> + Send {
-        let precomputed_key = self.precomputed_keys.get(packet.pk);
-        let payload = match packet.get_payload(&precomputed_key) {
-            Err(e) => return Either::A(future::err(Error::from(e))),
-            Ok(payload) => payload,
-        };
+    /// Set interval for sending `HealthSnapshot` to subscribers. By default
+    /// it's `HEALTH_SNAPSHOT_INTERVAL` seconds.
+    pub fn set_health_interval(&mut self, interval: Duration) {
+        self.health_interval = interval;
+    }
 
-        let resp_payload = PingResponsePayload {
-            id: payload.id,
-        };
-        let ping_resp = Packet::PingResponse(PingResponse::new(
-            &precomputed_key,
-            &self.pk,
-            &resp_payload
-        ));
+    /// Get time passed since `Server` was created.
+    pub fn uptime(&self) -> Duration {
+        clock_elapsed(self.start_time)
+    }
 
-        Either::B(self.ping_add(&PackedNode::new(addr, &packet.pk))
-            .join(self.send_to_direct(addr, ping_resp))
-            .map(|_| ())
-        )
+    /// Get time when the last packet was received. `None` if no packets were
+    /// received yet. It helps to detect a node that silently stopped getting
+    /// packets. The time is precise to milliseconds.
+    pub fn last_packet_at(&self) -> Option<Instant> {
+        match self.last_packet_offset.load(Ordering::Relaxed) {
+            0 => None,
+            offset => Some(self.start_time + Duration::from_millis(offset as u64 - 1)),
+        }
     }
 
-    /// Handle received `PingResponse` packet and if it's correct add the node
-    /// that sent this packet to close nodes lists.
-    fn handle_ping_resp(&self, packet: &PingResponse, addr: SocketAddr) -> impl Future<Item = (), Error = Error> + Send {
-        let precomputed_key = self.precomputed_keys.get(packet.pk);
-        let payload = match packet.get_payload(&precomputed_key) {
-            Err(e) => return future::err(Error::from(e)),
-            Ok(payload) => payload,
-        };
+    /// Remember that a packet was received just now.
+    fn update_last_packet_time(&self) {
+        let elapsed = clock_elapsed(self.start_time);
+        let offset = elapsed.as_secs() as usize * 1000 + elapsed.subsec_millis() as usize + 1;
+        self.last_packet_offset.store(offset, Ordering::Relaxed);
+    }
 
-        if payload.id == 0u64 {
-            return future::err(
-                Error::new(ErrorKind::Other,
-                    "PingResponse.ping_id == 0"
-            ))
+    /// Send `HealthSnapshot` to subscribers if health interval is passed.
+    /// Subscribers that dropped their receivers are removed.
+    fn send_health_snapshot(&self, close_nodes: &Ktree, friends: &[DhtFriend], nodes_to_bootstrap: &NodesQueue, request_queue: &RequestQueue) {
+        let mut health_subscribers = self.health_subscribers.write();
+        if health_subscribers.is_empty() {
+            return;
         }
 
-        let mut request_queue = self.request_queue.write();
+        let mut last_health_time = self.last_health_time.write();
+        let interval_passed = match *last_health_time {
+            Some(time) => clock_elapsed(time) >= self.health_interval,
+            None => true,
+        };
+        if !interval_passed {
+            return;
+        }
+        *last_health_time = Some(clock_now());
+
+        let snapshot = HealthSnapshot {
+            close_nodes: close_nodes.iter().count(),
+            friends: friends.len().saturating_sub(FAKE_FRIENDS_NUMBER),
+            nodes_to_bootstrap: nodes_to_bootstrap.len(),
+            nodes_to_ping: self.nodes_to_ping.read().len(),
+            pending_requests: request_queue.len(),
+            announced_nodes: self.onion_announce.read().entries_count(),
+            uptime: self.uptime(),
+            last_packet_at: self.last_packet_at(),
+        };
+        health_subscribers.retain(|tx| tx.unbounded_send(snapshot.clone()).is_ok());
+    }
 
-        if request_queue.check_ping_id(packet.pk, payload.id) {
-            let mut close_nodes = self.close_nodes.write();
-            let mut friends = self.friends.write();
+    /// Get `PublicKey`s of nodes that are currently announced at this relay.
+    /// Onion returns of announced nodes are not exposed since they contain
+    /// routing information.
+    pub fn onion_announced_keys(&self) -> Vec<PublicKey> {
+        self.onion_announce.read().announced_keys()
+    }
 
-            let pn = PackedNode::new(addr, &packet.pk);
-            close_nodes.try_add(&pn);
-            for friend in friends.iter_mut() {
-                friend.try_add_to_close(&pn);
-            }
+    /// Get the report about our connectivity inferred from responses of other
+    /// nodes: our external addresses, the type of NAT we are behind and
+    /// whether UDP works at all.
+    pub fn connectivity_report(&self) -> ConnectivityReport {
+        ConnectivityReport::new(&self.external_addrs(), self.udp_works.load(Ordering::SeqCst))
+    }
 
-            future::ok(())
-        } else {
-            future::err(
-                Error::new(ErrorKind::Other, "PingResponse.ping_id does not match")
-            )
+    /// Collect our external addresses returned by close nodes in
+    /// `NodesResponse` packets. Addresses returned by bad nodes are ignored.
+    fn external_addrs(&self) -> ExternalAddrs {
+        let mut external_addrs = ExternalAddrs::new();
+        for node in self.close_nodes.read().iter() {
+            if let (false, Some(saddr), Some(ret_saddr)) = (node.assoc4.is_bad(), node.assoc4.saddr, node.assoc4.ret_saddr) {
+                external_addrs.add(SocketAddr::V4(saddr), SocketAddr::V4(ret_saddr));
+            }
+            if let (false, Some(saddr), Some(ret_saddr)) = (node.assoc6.is_bad(), node.assoc6.saddr, node.assoc6.ret_saddr) {
+                external_addrs.add(SocketAddr::V6(saddr), SocketAddr::V6(ret_saddr));
+            }
         }
+        external_addrs
     }
 
-    /// Handle received `NodesRequest` packet and respond with `NodesResponse`
-    /// packet. If node that sent this packet is not present in close nodes list
-    /// and can be added there then it will be added to ping list.
-    fn handle_nodes_req(&self, packet: &NodesRequest, addr: SocketAddr) -> impl Future<Item = (), Error = Error> + Send {
-        let precomputed_key = self.precomputed_keys.get(packet.pk);
-        let payload = match packet.get_payload(&precomputed_key) {
-            Err(e) => return Either::A(future::err(Error::from(e))),
-            Ok(payload) => payload,
-        };
-
-        let close_nodes = self.get_closest(&payload.pk, IsGlobal::is_global(&addr.ip()));
+    /// Get the node that got us onto the network, i.e. the first node that
+    /// responded to our request when close nodes list was empty. It's useful to
+    /// find out which of configured bootstrap nodes actually works.
+    pub fn first_bootstrap(&self) -> Option<PackedNode> {
+        *self.first_bootstrap.read()
+    }
 
-        let resp_payload = NodesResponsePayload {
-            nodes: close_nodes.into(),
-            id: payload.id,
-        };
-        let nodes_resp = Packet::NodesResponse(NodesResponse::new(
-            &precomputed_key,
-            &self.pk,
-            &resp_payload
-        ));
+    /// Get the number of nodes waiting to be sent `PingRequest` packet.
+    pub fn nodes_to_ping_len(&self) -> usize {
+        self.nodes_to_ping.read().len()
+    }
 
-        Either::B(self.ping_add(&PackedNode::new(addr, &packet.pk))
-            .join(self.send_to_direct(addr, nodes_resp))
-            .map(|_| ())
-        )
+    /// Remove all nodes waiting to be sent `PingRequest` packet. It's useful
+    /// after a network change when these nodes are likely unreachable.
+    pub fn clear_nodes_to_ping(&self) {
+        self.nodes_to_ping.write().clear();
     }
 
-    /// Handle received `NodesResponse` packet and if it's correct add the node
-    /// that sent this packet to close nodes lists. Nodes from response will be
-    /// added to bootstrap nodes list to send `NodesRequest` packet to them
-    /// later.
-    fn handle_nodes_resp(&self, packet: &NodesResponse, addr: SocketAddr) -> impl Future<Item = (), Error = Error> + Send {
-        let precomputed_key = self.precomputed_keys.get(packet.pk);
-        let payload = match packet.get_payload(&precomputed_key) {
-            Err(e) => return future::err(Error::from(e)),
-            Ok(payload) => payload,
+    /// Enable/disable detection of replayed onion requests. When enabled
+    /// onion requests that come with the same onion return within
+    /// `ONION_REPLAY_CACHE_TIMEOUT` seconds are dropped.
+    pub fn enable_onion_replay_detection(&mut self, enable: bool) {
+        self.onion_replay_cache = if enable {
+            Some(Arc::new(RwLock::new(OnionReplayCache::new(Duration::from_secs(ONION_REPLAY_CACHE_TIMEOUT)))))
+        } else {
+            None
         };
+    }
 
-        let mut request_queue = self.request_queue.write();
+    /// Check if onion request with given onion return wasn't forwarded by us
+    /// recently. If it was then the request is replayed.
+    fn check_onion_replay(&self, onion_return: &OnionReturn) -> bool {
+        match self.onion_replay_cache {
+            Some(ref cache) => if cache.write().check(onion_return) {
+                true
+            } else {
+                trace!("Dropping onion request with repeated onion return, suspected replay");
+                false
+            },
+            None => true,
+        }
+    }
 
-        if request_queue.check_ping_id(packet.pk, payload.id) {
-            trace!("Received nodes with NodesResponse from {}: {:?}", addr, payload.nodes);
+    /// Enable/disable random requests tapering. When enabled the interval
+    /// between random `NodesRequest` packets is doubled every time the packet
+    /// is sent while close nodes list is full and doesn't have bad nodes, up
+    /// to `2^MAX_RANDOM_REQUESTS_BACKOFF_EXP` times of `NODES_REQ_INTERVAL`.
+    /// The interval is reset as soon as close nodes list becomes unstable.
+    pub fn enable_random_requests_tapering(&mut self, enable: bool) {
+        self.random_requests_tapering_enabled = enable;
+    }
 
-            let mut close_nodes = self.close_nodes.write();
-            let mut friends = self.friends.write();
-            let mut nodes_to_bootstrap = self.nodes_to_bootstrap.write();
+    /// Check if close nodes list is stable i.e. it has at least
+    /// `KBUCKET_DEFAULT_SIZE` nodes and none of them is bad.
+    fn is_close_list_stable(close_nodes: &Ktree) -> bool {
+        close_nodes.iter().count() >= KBUCKET_DEFAULT_SIZE as usize &&
+            close_nodes.iter().all(|node| !node.is_bad())
+    }
 
-            // Add node that sent NodesResponse to close nodes lists
-            let pn = PackedNode::new(addr, &packet.pk);
-            close_nodes.try_add(&pn);
-            for friend in friends.iter_mut() {
-                friend.try_add_to_close(&pn);
-            }
+    /// Enable/disable `LanDiscovery` packets handling.
+    pub fn enable_lan_discovery(&mut self, enable: bool) {
+        self.lan_discovery_enabled = enable;
+    }
 
-            // Process nodes from NodesResponse
-            for node in &payload.nodes {
-                if !self.is_ipv6_enabled && node.saddr.is_ipv6() {
-                    continue;
-                }
+    /// Suppress LAN discovery for the given duration, e.g. when we are on a
+    /// metered connection. `LanDiscovery` packets are neither handled nor sent
+    /// by `LanDiscoverySender` sharing the quiet period. LAN discovery resumes
+    /// automatically when the duration is passed.
+    pub fn suppress_lan_discovery(&self, duration: Duration) {
+        self.lan_discovery_quiet_period.suppress(duration);
+    }
 
-                if close_nodes.can_add(node) {
-                    nodes_to_bootstrap.try_add(&self.pk, node);
-                }
+    /// Immediately broadcast `LanDiscovery` packets without waiting for the
+    /// next iteration of `LanDiscoverySender`. It's useful when a network
+    /// interface comes up so that peers in the local network are discovered
+    /// faster. Nothing is sent if LAN discovery is disabled, suppressed or
+    /// `Server` is paused. Packets are sent like any other packet of `Server`
+    /// so broadcast addresses outside of allowed networks are skipped.
+    pub fn trigger_lan_discovery(&self) -> IoFuture<()> {
+        if !self.lan_discovery_enabled || self.is_paused() || self.lan_discovery_quiet_period.is_active() {
+            return Box::new(future::ok(()));
+        }
 
-                for friend in friends.iter_mut() {
-                    if friend.can_add_to_close(node) {
-                        friend.nodes_to_bootstrap.try_add(&friend.pk, node);
-                    }
-                }
+        let packet = Packet::LanDiscovery(LanDiscovery { pk: self.pk });
+        let futures = broadcast_socket_addrs(self.is_ipv6_enabled, &self.lan_discovery_ports).into_iter()
+            .filter(|&addr| self.can_reach(addr))
+            .map(|addr| self.send_to_direct(addr, packet.clone()))
+            .collect::<Vec<_>>();
 
-                self.update_returned_addr(node, &packet.pk, &mut close_nodes, &mut friends);
-            }
-            future::ok(())
-        } else {
-            // Some old version toxcore responds with wrong ping_id.
-            // So we do not treat this as our own error.
-            trace!("NodesResponse.ping_id does not match");
-            future::ok(())
-        }
+        Box::new(join_all(futures).map(|_| ()))
     }
 
-    /// Update returned socket address and time of receiving packet
-    fn update_returned_addr(&self, node: &PackedNode, packet_pk: &PublicKey, close_nodes: &mut Ktree, friends: &mut Vec<DhtFriend>) {
-        if self.pk == node.pk {
-            if let Some(node_to_update) = close_nodes.get_node_mut(packet_pk) {
-                node_to_update.update_returned_addr(node.saddr);
-            }
-        }
+    /// Get ports window of LAN discovery to share it with
+    /// `LanDiscoverySender`.
+    pub fn lan_discovery_ports(&self) -> LanPorts {
+        self.lan_discovery_ports.clone()
+    }
 
-        if let Some(friend) = friends.iter_mut().find(|friend| friend.pk == node.pk) {
-            if let Some(node_to_update) = friend.close_nodes.get_node_mut(&friend.pk, packet_pk) {
-                node_to_update.update_returned_addr(node.saddr);
-            }
-        }
+    /// Get quiet period of LAN discovery to share it with
+    /// `LanDiscoverySender`.
+    pub fn lan_discovery_quiet_period(&self) -> QuietPeriod {
+        self.lan_discovery_quiet_period.clone()
     }
 
-    /// Handle received `CookieRequest` packet and pass it to `net_crypto`
-    /// module.
-    fn handle_cookie_request(&self, packet: &CookieRequest, addr: SocketAddr) -> impl Future<Item = (), Error = Error> + Send {
-        if let Some(ref net_crypto) = self.net_crypto {
-            Either::A(net_crypto.handle_udp_cookie_request(packet, addr))
-        } else {
-            Either::B( future::err(
-                Error::new(ErrorKind::Other, "Net crypto is not initialised")
-            ))
-        }
+    /// Set maximum number of friends processed during one DHT main loop
+    /// iteration. Friends are processed in round-robin order so every friend
+    /// will be processed eventually. `None` means that all friends are
+    /// processed every iteration. `Some(0)` is treated as `None` since no
+    /// friend would ever be processed otherwise.
+    pub fn set_max_friends_per_tick(&mut self, max_friends_per_tick: Option<usize>) {
+        self.max_friends_per_tick = max_friends_per_tick.filter(|&max_friends_per_tick| max_friends_per_tick > 0);
     }
 
-    /// Handle received `CookieResponse` packet and pass it to `net_crypto`
-    /// module.
-    fn handle_cookie_response(&self, packet: &CookieResponse, addr: SocketAddr) -> impl Future<Item = (), Error = Error> + Send {
-        if let Some(ref net_crypto) = self.net_crypto {
-            Either::A(net_crypto.handle_udp_cookie_response(packet, addr))
-        } else {
-            Either::B( future::err(
-                Error::new(ErrorKind::Other, "Net crypto is not initialised")
-            ))
-        }
+    /// Set maximum number of new nodes added to close nodes list from
+    /// `NodesResponse` packets during one DHT main loop iteration. Nodes above
+    /// the limit are added during the next iterations. `None` means that all
+    /// responding nodes are added immediately.
+    pub fn set_max_close_admissions_per_tick(&mut self, max_close_admissions_per_tick: Option<usize>) {
+        self.max_close_admissions_per_tick = max_close_admissions_per_tick;
     }
 
-    /// Handle received `CryptoHandshake` packet and pass it to `net_crypto`
-    /// module.
-    fn handle_crypto_handshake(&self, packet: &CryptoHandshake, addr: SocketAddr) -> impl Future<Item = (), Error = Error> + Send {
-        if let Some(ref net_crypto) = self.net_crypto {
-            Either::A(net_crypto.handle_udp_crypto_handshake(packet, addr))
-        } else {
-            Either::B( future::err(
-                Error::new(ErrorKind::Other, "Net crypto is not initialised")
-            ))
-        }
+    /// Set maximum number of friends for which nodes from `NodesResponse`
+    /// packets are checked as candidates to their close nodes. Processing
+    /// every node for every friend is expensive with many friends, so above
+    /// the limit nodes from every response are checked only for the given
+    /// number of friends taken in turn. Friends still find their close nodes
+    /// with their own periodic `NodesRequest` packets. `None` means that it's
+    /// not limited.
+    pub fn set_max_friends_fan_out(&mut self, max_friends_fan_out: Option<usize>) {
+        self.max_friends_fan_out = max_friends_fan_out;
     }
 
-    /// Handle received `DhtRequest` packet, redirect it if it's sent for
-    /// someone else or parse it and handle the payload if it's sent for us.
-    fn handle_dht_req(&self, packet: DhtRequest, addr: SocketAddr) -> impl Future<Item = (), Error = Error> + Send { // TODO: split to functions
-        if packet.rpk == self.pk { // the target peer is me
-            Either::A(self.handle_dht_req_for_us(&packet, addr))
-        } else {
-            Either::B(self.handle_dht_req_for_others(packet))
+    /// Get the index of the first friend and the number of friends that should
+    /// be processed during the current DHT main loop iteration and move
+    /// `friends_offset` to the next friends.
+    fn friends_window(&self, friends_count: usize) -> (usize, usize) {
+        match self.max_friends_per_tick {
+            Some(max_friends) if max_friends < friends_count => {
+                let mut friends_offset = self.friends_offset.write();
+                let start = *friends_offset % friends_count;
+                *friends_offset = (start + max_friends) % friends_count;
+                (start, max_friends)
+            },
+            _ => (0, friends_count),
         }
     }
 
-    /// Parse received `DhtRequest` packet and handle the payload.
-    fn handle_dht_req_for_us(&self, packet: &DhtRequest, addr: SocketAddr) -> impl Future<Item = (), Error = Error> + Send {
-        let precomputed_key = self.precomputed_keys.get(packet.spk);
-        let payload = packet.get_payload(&precomputed_key);
-        let payload = match payload {
-            Err(e) => return Box::new(future::err(Error::from(e))) as Box<dyn Future<Item = _, Error = _> + Send>,
-            Ok(payload) => payload,
-        };
-
-        match payload {
-            DhtRequestPayload::NatPingRequest(nat_payload) => {
-                debug!("Received nat ping request");
-                Box::new(self.handle_nat_ping_req(nat_payload, &packet.spk, addr)) as Box<dyn Future<Item = _, Error = _> + Send>
-            },
-            DhtRequestPayload::NatPingResponse(nat_payload) => {
-                debug!("Received nat ping response");
-                Box::new(self.handle_nat_ping_resp(nat_payload, &packet.spk))
-            },
-            DhtRequestPayload::DhtPkAnnounce(_dht_pk_payload) => {
-                debug!("Received DHT PublicKey Announce");
-                // TODO: handle this packet in onion client
-                Box::new( future::ok(()) )
-            },
-            DhtRequestPayload::HardeningRequest(_dht_pk_payload) => {
-                debug!("Received Hardening request");
-                // TODO: implement handler
-                Box::new( future::ok(()) )
-            },
-            DhtRequestPayload::HardeningResponse(_dht_pk_payload) => {
-                debug!("Received Hardening response");
-                // TODO: implement handler
-                Box::new( future::ok(()) )
+    /// Get the index of the first friend and the number of friends for which
+    /// nodes from the current `NodesResponse` should be checked and move
+    /// `friends_fan_out_offset` to the next friends.
+    fn friends_fan_out_window(&self, friends_count: usize) -> (usize, usize) {
+        let real_friends = friends_count.saturating_sub(FAKE_FRIENDS_NUMBER);
+        match self.max_friends_fan_out {
+            Some(max_friends) if max_friends < real_friends => {
+                warn!("Too many friends to check nodes from NodesResponse for all of them: {}", real_friends);
+                let mut friends_offset = self.friends_fan_out_offset.write();
+                let start = *friends_offset % friends_count;
+                *friends_offset = (start + max_friends) % friends_count;
+                (start, max_friends)
             },
+            _ => (0, friends_count),
         }
     }
 
-    /// Redirect received `DhtRequest` packet.
-    fn handle_dht_req_for_others(&self, packet: DhtRequest) -> impl Future<Item = (), Error = Error> + Send {
-        let close_nodes = self.close_nodes.read();
-        if let Some(node) = close_nodes.get_node(&packet.rpk) { // search close_nodes to find target peer
-            let packet = Packet::DhtRequest(packet);
-            Either::A(self.send_to_node(node, &packet))
-        } else {
-            Either::B(future::ok(()))
-        }
+    /// Get closest nodes from both close_nodes and friend's close_nodes. If
+    /// `only_ipv4` is `true` then only IPv4 addresses of nodes are returned.
+    fn get_closest(&self, base_pk: &PublicKey, only_global: bool, only_ipv4: bool) -> NodesQueue {
+        self.get_closest_fresh(base_pk, only_global, only_ipv4, None)
     }
 
-    /// Handle received `NatPingRequest` packet and respond with
-    /// `NatPingResponse` packet.
-    fn handle_nat_ping_req(&self, payload: NatPingRequest, spk: &PublicKey, addr: SocketAddr) -> impl Future<Item = (), Error = Error> + Send {
-        let mut friends = self.friends.write();
+    /// Get closest nodes like `get_closest` but only from nodes that answered
+    /// within `max_age` if it's set.
+    fn get_closest_fresh(&self, base_pk: &PublicKey, only_global: bool, only_ipv4: bool, max_age: Option<Duration>) -> NodesQueue {
+        let close_nodes = self.close_nodes.read();
+        let friends = self.friends.read();
 
-        let friend = friends.iter_mut()
-            .find(|friend| friend.pk == *spk);
-        let friend = match friend {
-            None => return Either::A( future::err(
-                Error::new(ErrorKind::Other,
-                           "Can't find friend"
-                ))),
-            Some(friend) => friend,
-        };
+        let mut queue = close_nodes.get_closest_fresh(base_pk, only_global, only_ipv4, max_age);
 
-        friend.hole_punch.last_recv_ping_time = clock_now();
+        let is_fresh = |node: &DhtNode| max_age.map_or(true, |max_age| node.is_fresh(max_age));
+        for node in friends.iter().flat_map(|friend| friend.close_nodes.iter()).filter(|node| is_fresh(node)) {
+            let pn = if only_ipv4 {
+                node.to_packed_node_ipv4()
+            } else {
+                node.to_preferred_packed_node(close_nodes.addr_preference())
+            };
+            if let Some(pn) = pn {
+                if !only_global || IsGlobal::is_global(&pn.saddr.ip()) {
+                    queue.try_add(base_pk, &pn);
+                }
+            }
+        }
 
-        let resp_payload = DhtRequestPayload::NatPingResponse(NatPingResponse {
-            id: payload.id,
-        });
-        let nat_ping_resp = Packet::DhtRequest(DhtRequest::new(
-            &self.precomputed_keys.get(*spk),
-            spk,
-            &self.pk,
-            &resp_payload
-        ));
-        Either::B(self.send_to_direct(addr, nat_ping_resp))
+        queue
     }
 
-    /// Handle received `NatPingResponse` packet and enable hole punching if
-    /// it's correct.
-    fn handle_nat_ping_resp(&self, payload: NatPingResponse, spk: &PublicKey) -> impl Future<Item = (), Error = Error> + Send {
-        if payload.id == 0 {
-            return future::err(
-                Error::new(ErrorKind::Other,
-                    "NodesResponse.ping_id == 0"
-            ))
+    /// Get `PackedNode` of our own node to include it in `NodesResponse`.
+    /// Only addresses other nodes see us from are used since the address our
+    /// socket is bound to might be unreachable for them. An address has to be
+    /// confirmed by several nodes from distinct subnets so that a single node
+    /// can't make us advertise a wrong one. Returns `None` if we don't know a
+    /// suitable address.
+    fn own_packed_node(&self, only_global: bool, only_ipv4: bool) -> Option<PackedNode> {
+        let external_addrs = self.external_addrs().confirmed_addrs();
+        external_addrs.into_iter()
+            .find(|addr|
+                !addr.ip().is_unspecified() &&
+                (!only_global || IsGlobal::is_global(&addr.ip())) &&
+                (!only_ipv4 || addr.is_ipv4())
+            )
+            .map(|addr| PackedNode::new(addr, &self.pk))
+    }
+
+    /// Get nodes closest to `search_pk` to send them in `NodesResponse` to the
+    /// node with `requester_pk`. Our own node is included if it's among the
+    /// closest ones. Nodes are reused if the same request was handled less
+    /// than `NODES_RESP_CACHE_TIMEOUT` seconds ago, except nodes that became
+    /// bad or were removed from close nodes lists since then.
+    fn get_closest_cached(&self, requester_pk: PublicKey, search_pk: &PublicKey, only_global: bool, only_ipv4: bool) -> Vec<PackedNode> {
+        let cached_nodes = self.nodes_resp_cache.read().peek(&requester_pk)
+            .filter(|cached|
+                cached.search_pk == *search_pk && cached.only_global == only_global && cached.only_ipv4 == only_ipv4 &&
+                    clock_elapsed(cached.time) < Duration::from_secs(NODES_RESP_CACHE_TIMEOUT)
+            )
+            .map(|cached| cached.nodes.clone());
+        if let Some(cached_nodes) = cached_nodes {
+            let close_nodes = self.close_nodes.read();
+            let friends = self.friends.read();
+            let is_alive = |node: &DhtNode| !node.is_bad();
+            return cached_nodes.into_iter()
+                .filter(|pn|
+                    pn.pk == self.pk ||
+                        close_nodes.get_node(&pn.pk).map_or(false, is_alive) ||
+                        friends.iter().any(|friend| friend.close_nodes.get_node(&friend.pk, &pn.pk).map_or(false, is_alive))
+                )
+                .collect();
         }
 
-        let mut friends = self.friends.write();
+        // cache lock is not held here since close nodes lists are locked
+        // before the cache when they change
+        let mut queue = self.get_closest_fresh(search_pk, only_global, only_ipv4, self.nodes_resp_max_age);
+        if self.self_in_nodes_resp_enabled {
+            if let Some(own_node) = self.own_packed_node(only_global, only_ipv4) {
+                queue.try_add(search_pk, &own_node);
+            }
+        }
+        let nodes: Vec<PackedNode> = queue.into();
+        self.nodes_resp_cache.write().put(requester_pk, CachedNodesResp {
+            search_pk: *search_pk,
+            only_global,
+            only_ipv4,
+            nodes: nodes.clone(),
+            time: clock_now(),
+        });
+        nodes
+    }
 
-        let friend = friends.iter_mut()
-            .find(|friend| friend.pk == *spk);
-        let friend = match friend {
-            None => return future::err(
-                Error::new(ErrorKind::Other,
-                           "Can't find friend"
-                )),
-            Some(friend) => friend,
-        };
+    /// Drop cached `NodesResponse` nodes since they might be outdated after
+    /// close nodes lists have changed.
+    fn invalidate_nodes_resp_cache(&self) {
+        self.nodes_resp_cache.write().clear();
+    }
 
-        if friend.hole_punch.ping_id == payload.id {
-            // Refresh ping id for the next NatPingRequest
-            friend.hole_punch.ping_id = gen_ping_id();
-            // We send NatPingRequest packet only if we are not directly
-            // connected to a friend but we have several nodes that connected
-            // to him. If we received NatPingResponse that means that this
-            // friend is likely behind NAT so we should try to punch holes.
-            friend.hole_punch.is_punching_done = false;
-            future::ok(())
-        } else {
-            future::err(
-                Error::new(ErrorKind::Other, "NatPingResponse.ping_id does not match or timed out")
-            )
+    /// Add a friend.
+    /// `node_to_bootstrap` of new friend is filled with close nodes for fast bootstrapping.
+    pub fn add_friend(&self, friend_pk: PublicKey) {
+        if !self.friends_enabled {
+            warn!("Friends are disabled, friend {:?} is not added", friend_pk);
+            return;
         }
-    }
 
-    /// Handle received `LanDiscovery` packet and response with `NodesRequest`
-    /// packet.
-    fn handle_lan_discovery(&self, packet: &LanDiscovery, addr: SocketAddr) -> impl Future<Item = (), Error = Error> + Send {
-        // LanDiscovery is optional
-        if !self.lan_discovery_enabled {
-            return Either::A(future::ok(()));
+        if friend_pk == self.pk {
+            warn!("Attempt to add ourselves as a friend, friend is not added");
+            return;
         }
 
-        // if Lan Discovery packet has my PK, then it is sent by myself.
-        if packet.pk == self.pk {
-            return Either::A(future::ok(()));
+        let mut friend = DhtFriend::new(friend_pk);
+        friend.close_nodes.set_quarantine_timeout(self.quarantine_timeout);
+        friend.hole_punch.port_prediction_range = self.port_prediction_range;
+        let close_nodes = self.get_closest(&friend.pk, true, false);
+
+        for node in close_nodes.iter() {
+            friend.nodes_to_bootstrap.try_add(&friend.pk, &node);
         }
 
-        Either::B(self.send_nodes_req(&PackedNode::new(addr, &packet.pk), &mut self.request_queue.write(), self.pk))
+        self.friends.write().push(friend);
+        self.invalidate_nodes_resp_cache();
     }
 
-    /// Handle received `OnionRequest0` packet and send `OnionRequest1` packet
-    /// to the next peer.
-    fn handle_onion_request_0(&self, packet: &OnionRequest0, addr: SocketAddr) -> impl Future<Item = (), Error = Error> + Send {
-        let onion_symmetric_key = self.onion_symmetric_key.read();
-        let shared_secret = self.precomputed_keys.get(packet.temporary_pk);
-        let payload = packet.get_payload(&shared_secret);
-        let payload = match payload {
-            Err(e) => return Either::A(future::err(e)),
-            Ok(payload) => payload,
+    /// Set interval between `NodesRequest` packets sent to search for the
+    /// friend with given `PublicKey`. By default it's `NODES_REQ_INTERVAL`
+    /// seconds. Returns `false` if there is no friend with given `PublicKey`.
+    pub fn set_friend_search_interval(&self, friend_pk: PublicKey, interval: Duration) -> bool {
+        match self.friends.write().iter_mut().find(|friend| friend.pk == friend_pk) {
+            Some(friend) => {
+                friend.search_interval = interval;
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// The main loop of DHT server which should be called every second. This
+    /// method iterates over all nodes from close nodes list, close nodes of
+    /// friends and bootstrap nodes and sends `NodesRequest` packets if
+    /// necessary.
+    fn dht_main_loop(&self) -> impl Future<Item = (), Error = Error> + Send {
+        // Check if we should send `NodesRequest` packet to a random node. This
+        // request is sent every second `max_bootstrap_times` times and then
+        // every `interval`.
+        fn send_random_request(last_nodes_req_time: &mut Instant, random_requests_count: &mut u32, max_bootstrap_times: u32, interval: Duration) -> bool {
+            if clock_elapsed(*last_nodes_req_time) > interval || *random_requests_count < max_bootstrap_times {
+                *random_requests_count = random_requests_count.saturating_add(1);
+                *last_nodes_req_time = clock_now();
+                true
+            } else {
+                false
+            }
+        }
+
+        if self.is_paused() {
+            return Either::A(future::ok(()));
+        }
+
+        let loop_start_time = clock_now();
+
+        // Requests are sent synchronously while futures are created so
+        // identical requests can be detected until the end of this function
+        *self.tick_nodes_reqs.write() = Some(HashSet::new());
+
+        let mut request_queue = self.request_queue.write();
+        let mut nodes_to_bootstrap = self.nodes_to_bootstrap.write();
+        let mut close_nodes = self.close_nodes.write();
+        let mut friends = self.friends.write();
+
+        self.count_unanswered_pings(request_queue.take_timed_out(), &close_nodes, &friends);
+        if let Some(ref requests_limiter) = self.requests_limiter {
+            requests_limiter.write().clear_expired();
+        }
+
+        // Start new iteration of counting new close nodes and add nodes that
+        // were deferred during the previous iterations
+        self.close_admissions.store(0, Ordering::SeqCst);
+        let deferred_close_nodes = mem::replace(&mut *self.deferred_close_nodes.write(), NodesQueue::new(MAX_DEFERRED_CLOSE_NODES));
+        let deferred_close_nodes: Vec<PackedNode> = deferred_close_nodes.into();
+        for node in &deferred_close_nodes {
+            self.admit_to_close(&mut close_nodes, node);
+        }
+        self.announce_responses_limiter.write().clear_expired();
+        if let Some(ref handshakes_limiter) = self.handshakes_limiter {
+            handshakes_limiter.write().clear_expired();
+        }
+        if let Some(ref handshakes_per_addr_limiter) = self.handshakes_per_addr_limiter {
+            handshakes_per_addr_limiter.write().clear_expired();
+        }
+        self.onion_announce.write().clear_timed_out();
+        if let Some(ref cache) = self.onion_replay_cache {
+            cache.write().clear_expired();
+        }
+        close_nodes.clear_quarantine();
+        for friend in friends.iter_mut() {
+            friend.close_nodes.clear_quarantine();
+        }
+        if self.fill_strategy == FillStrategy::Active {
+            self.update_active_fill(&close_nodes);
+        }
+
+        self.send_health_snapshot(&close_nodes, &friends, &nodes_to_bootstrap, &request_queue);
+
+        // Send NodesRequest packets to nodes from the Server
+        let ping_nodes_to_bootstrap = self.ping_nodes_to_bootstrap(&mut request_queue, &mut nodes_to_bootstrap, self.pk);
+        let ping_close_nodes = self.ping_close_nodes(&mut request_queue, close_nodes.iter_mut(), self.pk);
+        let taper_random_requests = self.random_requests_tapering_enabled && Server::is_close_list_stable(&close_nodes);
+        let mut random_requests_backoff = self.random_requests_backoff.write();
+        if !taper_random_requests {
+            *random_requests_backoff = 0;
+        }
+        let random_requests_interval = Duration::from_secs(NODES_REQ_INTERVAL << *random_requests_backoff);
+        let send_nodes_req_random = if send_random_request(&mut self.last_nodes_req_time.write(), &mut self.random_requests_count.write(), self.max_bootstrap_times, random_requests_interval) {
+            if taper_random_requests {
+                *random_requests_backoff = (*random_requests_backoff + 1).min(MAX_RANDOM_REQUESTS_BACKOFF_EXP);
+            }
+            Either::A(self.send_nodes_req_random(&mut request_queue, close_nodes.iter(), self.pk))
+        } else {
+            Either::B(future::ok(()))
         };
 
-        let onion_return = OnionReturn::new(
-            &onion_symmetric_key,
-            &IpPort::from_udp_saddr(addr),
-            None // no previous onion return
-        );
-        let next_packet = Packet::OnionRequest1(OnionRequest1 {
-            nonce: packet.nonce,
-            temporary_pk: payload.temporary_pk,
-            payload: payload.inner,
-            onion_return
-        });
-        Either::B(self.send_to_direct(payload.ip_port.to_saddr(), next_packet))
-    }
+        // Process only friends that fit into the work budget of this iteration
+        let friends_count = friends.len();
+        let (friends_start, friends_to_process) = self.friends_window(friends_count);
+        let in_window = |index: usize| (index + friends_count - friends_start) % friends_count < friends_to_process;
 
-    /// Handle received `OnionRequest1` packet and send `OnionRequest2` packet
-    /// to the next peer.
-    fn handle_onion_request_1(&self, packet: &OnionRequest1, addr: SocketAddr) -> impl Future<Item = (), Error = Error> + Send {
-        let onion_symmetric_key = self.onion_symmetric_key.read();
-        let shared_secret = self.precomputed_keys.get(packet.temporary_pk);
-        let payload = packet.get_payload(&shared_secret);
-        let payload = match payload {
-            Err(e) => return Either::A(future::err(e)),
-            Ok(payload) => payload,
+        // Send NodesRequest packets to nodes from every DhtFriend
+        let send_nodes_req_to_friends = friends.iter_mut()
+            .enumerate()
+            .filter(|&(index, _)| in_window(index))
+            .map(|(_, friend)| {
+                let ping_nodes_to_bootstrap = self.ping_nodes_to_bootstrap(&mut request_queue, &mut friend.nodes_to_bootstrap, friend.pk);
+                let ping_close_nodes = self.ping_close_nodes(&mut request_queue, friend.close_nodes.nodes.iter_mut(), friend.pk);
+                let send_nodes_req_random = if send_random_request(&mut friend.last_nodes_req_time, &mut friend.random_requests_count, self.max_bootstrap_times, friend.search_interval) {
+                    Either::A(self.send_nodes_req_random(&mut request_queue, friend.close_nodes.nodes.iter(), friend.pk))
+                } else {
+                    Either::B(future::ok(()))
+                };
+                ping_nodes_to_bootstrap.join3(ping_close_nodes, send_nodes_req_random)
+            }).collect::<Vec<_>>();
+
+        let friends_to_ping = friends.iter_mut()
+            .enumerate()
+            // we don't want to punch holes to fake friends under any circumstances
+            .skip(FAKE_FRIENDS_NUMBER)
+            .filter(|&(index, _)| in_window(index))
+            .map(|(_, friend)| friend);
+        let send_nat_ping_req = if self.is_nat_paused() {
+            Either::A(future::ok(()))
+        } else {
+            Either::B(self.send_nat_ping_req(&mut request_queue, friends_to_ping))
         };
 
-        let onion_return = OnionReturn::new(
-            &onion_symmetric_key,
-            &IpPort::from_udp_saddr(addr),
-            Some(&packet.onion_return)
-        );
-        let next_packet = Packet::OnionRequest2(OnionRequest2 {
-            nonce: packet.nonce,
-            temporary_pk: payload.temporary_pk,
-            payload: payload.inner,
-            onion_return
-        });
-        Either::B(self.send_to_direct(payload.ip_port.to_saddr(), next_packet))
+        *self.tick_nodes_reqs.write() = None;
+
+        let stats = self.stats.clone();
+
+        Either::B(ping_nodes_to_bootstrap.join5(
+            ping_close_nodes,
+            send_nodes_req_random,
+            future::join_all(send_nodes_req_to_friends),
+            send_nat_ping_req
+        ).then(move |res| {
+            let loop_duration = clock_elapsed(loop_start_time);
+            if loop_duration > Duration::from_secs(MAIN_LOOP_INTERVAL) {
+                warn!("DHT main loop took {:?} which is longer than its interval", loop_duration);
+            }
+            stats.write().update_loop_duration(loop_duration);
+            res.map(|_| ())
+        }))
     }
 
-    /// Handle received `OnionRequest2` packet and send `OnionAnnounceRequest`
-    /// or `OnionDataRequest` packet to the next peer.
-    fn handle_onion_request_2(&self, packet: &OnionRequest2, addr: SocketAddr) -> impl Future<Item = (), Error = Error> + Send {
-        let onion_symmetric_key = self.onion_symmetric_key.read();
-        let shared_secret = self.precomputed_keys.get(packet.temporary_pk);
-        let payload = packet.get_payload(&shared_secret);
-        let payload = match payload {
-            Err(e) => return Either::A(future::err(e)),
-            Ok(payload) => payload,
-        };
+    /// Run DHT periodical tasks. Result future will never be completed
+    /// successfully.
+    pub fn run(self) -> impl Future<Item = (), Error = Error> + Send {
+        self.clone().run_pings_sending().join4(
+            self.clone().run_onion_key_refresing(),
+            self.clone().run_main_loop(),
+            self.run_bootstrap_requests_sending()
+        ).map(|_| ())
+    }
 
-        let onion_return = OnionReturn::new(
-            &onion_symmetric_key,
-            &IpPort::from_udp_saddr(addr),
-            Some(&packet.onion_return)
-        );
-        let next_packet = match payload.inner {
-            InnerOnionRequest::InnerOnionAnnounceRequest(inner) => Packet::OnionAnnounceRequest(OnionAnnounceRequest {
-                inner,
-                onion_return
-            }),
-            InnerOnionRequest::InnerOnionDataRequest(inner) => Packet::OnionDataRequest(OnionDataRequest {
-                inner,
-                onion_return
-            }),
-        };
-        Either::B(self.send_to_direct(payload.ip_port.to_saddr(), next_packet))
+    /// Spawn a task that handles packets sent to the returned channel. It
+    /// decouples receiving of packets from DHT periodical tasks. Every call
+    /// spawns a separate task so packets can be handled concurrently on a
+    /// thread pool. Locks of the shared state are held only for the duration
+    /// of handling a single packet so handlers don't block each other for
+    /// long. The task stops when all senders are dropped. This function uses
+    /// `tokio::spawn` inside so it should be executed via tokio to be able to
+    /// get tokio default executor.
+    pub fn spawn_handler(&self) -> mpsc::Sender<(Packet, SocketAddr)> {
+        let (tx, rx) = mpsc::channel(HANDLER_CHANNEL_SIZE);
+        let self_c = self.clone();
+        tokio::spawn(rx.for_each(move |(packet, addr)| {
+            self_c.handle_packet(packet, addr).or_else(|err| {
+                error!("Failed to handle packet: {:?}", err);
+                future::ok(())
+            })
+        }));
+        tx
     }
 
-    /// Handle received `OnionAnnounceRequest` packet and response with
-    /// `OnionAnnounceResponse` packet if the request succeed.
-    ///
-    /// The response packet will contain up to 4 closest to `search_pk` nodes
-    /// from ktree. They are used to search closest to long term `PublicKey`
-    /// nodes to announce.
-    fn handle_onion_announce_request(&self, packet: OnionAnnounceRequest, addr: SocketAddr) -> impl Future<Item = (), Error = Error> + Send {
-        let mut onion_announce = self.onion_announce.write();
+    /// Store bootstap nodes
+    pub fn add_initial_bootstrap(&mut self, pn: PackedNode) {
+        self.initial_bootstrap.write().push(pn);
+    }
 
-        let shared_secret = self.precomputed_keys.get(packet.inner.pk);
-        let payload = match packet.inner.get_payload(&shared_secret) {
-            Err(e) => return Either::A(future::err(e)),
-            Ok(payload) => payload,
+    /// Replace initial bootstrap nodes with the given ones. The list is
+    /// replaced at once so it's never seen empty in between which is useful
+    /// when bootstrap nodes are reloaded from a config file.
+    pub fn replace_bootstrap_nodes(&self, nodes: Vec<PackedNode>) {
+        *self.initial_bootstrap.write() = nodes;
+    }
+
+    /// Set the node that will be queried as a last resort when there are
+    /// neither close nodes nor initial bootstrap nodes.
+    pub fn set_fallback_bootstrap_node(&mut self, fallback_bootstrap_node: Option<PackedNode>) {
+        self.fallback_bootstrap_node = fallback_bootstrap_node;
+    }
+
+    /// Send `NodesRequest` packet to the fallback bootstrap node if there are
+    /// neither close nodes nor initial bootstrap nodes to bootstrap from.
+    pub fn fallback_bootstrap(&self) -> IoFuture<()> {
+        let node = match self.fallback_bootstrap_node {
+            Some(node) if self.needs_bootstrap() => node,
+            _ => return Box::new(future::ok(())),
         };
 
-        let (announce_status, ping_id_or_pk) = onion_announce.handle_onion_announce_request(
-            &payload,
-            packet.inner.pk,
-            packet.onion_return.clone(),
-            addr
-        );
+        info!("Querying fallback bootstrap node {}", node.saddr);
 
-        let close_nodes = self.get_closest(&payload.search_pk, IsGlobal::is_global(&addr.ip()));
+        let mut request_queue = self.request_queue.write();
+        Box::new(self.send_nodes_req_skip_self(&node, &mut request_queue, self.pk))
+    }
 
-        let response_payload = OnionAnnounceResponsePayload {
-            announce_status,
-            ping_id_or_pk,
-            nodes: close_nodes.into()
-        };
-        let response = OnionAnnounceResponse::new(&shared_secret, payload.sendback_data, &response_payload);
+    /// Immediately send `NodesRequest` packets to initial bootstrap nodes and
+    /// to nodes waiting in the bootstrap list instead of waiting for DHT main
+    /// loop. It speeds up startup after bootstrap nodes are added.
+    pub fn kick_bootstrap(&self) -> IoFuture<()> {
+        let mut request_queue = self.request_queue.write();
+        let ping_nodes_to_bootstrap = self.ping_nodes_to_bootstrap(&mut request_queue, &mut self.nodes_to_bootstrap.write(), self.pk);
+        let futures = self.initial_bootstrap.read().iter()
+            .map(|node| self.send_nodes_req_skip_self(node, &mut request_queue, self.pk))
+            .collect::<Vec<_>>();
 
-        Either::B(self.send_to_direct(addr, Packet::OnionResponse3(OnionResponse3 {
-            onion_return: packet.onion_return,
-            payload: InnerOnionResponse::OnionAnnounceResponse(response)
-        })))
+        Box::new(ping_nodes_to_bootstrap.join(join_all(futures)).map(|_| ()))
     }
 
-    /// Handle received `OnionDataRequest` packet and send `OnionResponse3`
-    /// packet with inner `OnionDataResponse` to destination node through its
-    /// onion path.
-    fn handle_onion_data_request(&self, packet: OnionDataRequest) -> impl Future<Item = (), Error = Error> + Send {
-        let onion_announce = self.onion_announce.read();
-        match onion_announce.handle_data_request(packet) {
-            Ok((response, addr)) => Either::A(self.send_to_direct(addr, Packet::OnionResponse3(response))),
-            Err(e) => Either::B(future::err(e))
-        }
+    /// Check if close nodes list has at least one good node.
+    pub fn is_connected(&self) -> bool {
+        self.is_well_connected(1)
     }
 
-    /// Handle received `OnionResponse3` packet and send `OnionResponse2` packet
-    /// to the next peer which address is stored in encrypted onion return.
-    fn handle_onion_response_3(&self, packet: OnionResponse3) -> impl Future<Item = (), Error = Error> + Send {
-        let onion_symmetric_key = self.onion_symmetric_key.read();
-        let payload = packet.onion_return.get_payload(&onion_symmetric_key);
-        let payload = match payload {
-            Err(e) => {
-                // Onion symmetric key is changed every 2 hours to enforce onion
-                // paths expiration. It means that we can get packets with old
-                // onion key. So we do not consider this as error.
-                trace!("Failed to decrypt onion_return from OnionResponse3: {}", e);
-                return Either::A(future::ok(()));
-            },
-            Ok(payload) => payload,
-        };
+    /// Check if close nodes list has at least `min` good nodes. Some apps
+    /// want to wait for several nodes before considering discovery complete.
+    pub fn is_well_connected(&self, min: usize) -> bool {
+        self.close_nodes.read().iter()
+            .filter(|node| !node.is_bad())
+            .take(min)
+            .count() == min
+    }
 
-        if let (ip_port, Some(next_onion_return)) = payload {
-            let next_packet = Packet::OnionResponse2(OnionResponse2 {
-                onion_return: next_onion_return,
-                payload: packet.payload
-            });
-            Either::B(self.send_to_direct(ip_port.to_saddr(), next_packet))
+    /// Get number of distinct /24 IPv4 and /48 IPv6 subnets nodes from close
+    /// nodes list belong to. A low number relative to the number of nodes
+    /// means that the list might be eclipsed by few hosts.
+    pub fn close_list_subnet_diversity(&self) -> usize {
+        self.close_nodes.read().subnets_count()
+    }
+
+    /// Wait until close nodes list has at least `min` good nodes. The check
+    /// is done every `MAIN_LOOP_INTERVAL` seconds.
+    pub fn wait_connected(&self, min: usize) -> impl Future<Item = (), Error = Error> + Send {
+        let server = self.clone();
+        let wakeups = Interval::new(Instant::now(), Duration::from_secs(MAIN_LOOP_INTERVAL));
+        wakeups
+            .map_err(|e| Error::new(ErrorKind::Other, format!("DHT server timer error: {:?}", e)))
+            .skip_while(move |_instant| future::ok(!server.is_well_connected(min)))
+            .into_future()
+            .map(|_| ())
+            .map_err(|(e, _)| e)
+    }
+
+    /// Add node that responded to our request to close nodes list. Nodes from
+    /// our initial bootstrap list are considered trusted and can replace
+    /// untrusted nodes when kbucket is full. The first node that populates
+    /// empty close nodes list is remembered as the one that got us onto the
+    /// network.
+    fn try_add_to_close(&self, close_nodes: &mut Ktree, pn: &PackedNode) -> bool {
+        let is_new = !close_nodes.contains(&pn.pk);
+        let was_empty = close_nodes.is_empty();
+        let added = if self.initial_bootstrap.read().iter().any(|node| node.pk == pn.pk) {
+            close_nodes.try_add_trusted(pn)
         } else {
-            Either::A( future::err(
-                Error::new(ErrorKind::Other,
-                    "OnionResponse3 next_onion_return is none".to_string()
-            )))
+            close_nodes.try_add(pn)
+        };
+        // updates of nodes we already have don't change the closest nodes
+        if added && is_new {
+            self.invalidate_nodes_resp_cache();
+        }
+        if added && was_empty {
+            let mut first_bootstrap = self.first_bootstrap.write();
+            if first_bootstrap.is_none() {
+                *first_bootstrap = Some(*pn);
+            }
+        }
+        if self.eviction_tracking {
+            self.report_evicted(close_nodes);
+        }
+        added
+    }
+
+    /// Count and report nodes evicted from close nodes list since the
+    /// previous call. It also includes evictions caused by adding nodes to
+    /// close nodes list directly.
+    fn report_evicted(&self, close_nodes: &mut Ktree) {
+        for (evicted_pk, new_pk) in close_nodes.take_evicted() {
+            debug!("Node {:?} is evicted from close nodes list by {:?}", evicted_pk, new_pk);
+            self.evicted_close_nodes.fetch_add(1, Ordering::Relaxed);
+            if let Some(ref event_sink) = self.event_sink {
+                if let Err(e) = event_sink.unbounded_send(DhtEvent::NodeEvicted(evicted_pk)) {
+                    warn!("Failed to send NodeEvicted event: {}", e);
+                }
+            }
         }
     }
 
-    /// Handle received `OnionResponse2` packet and send `OnionResponse1` packet
-    /// to the next peer which address is stored in encrypted onion return.
-    fn handle_onion_response_2(&self, packet: OnionResponse2) -> impl Future<Item = (), Error = Error> + Send {
-        let onion_symmetric_key = self.onion_symmetric_key.read();
-        let payload = packet.onion_return.get_payload(&onion_symmetric_key);
-        let payload = match payload {
-            Err(e) => {
-                // Onion symmetric key is changed every 2 hours to enforce onion
-                // paths expiration. It means that we can get packets with old
-                // onion key. So we do not consider this as error.
-                trace!("Failed to decrypt onion_return from OnionResponse2: {}", e);
-                return Either::A(future::ok(()));
+    /// Add node that sent `NodesResponse` to close nodes list unless too many
+    /// new nodes were added during the current DHT main loop iteration. In
+    /// that case the node is deferred to the next iteration.
+    fn admit_to_close(&self, close_nodes: &mut Ktree, pn: &PackedNode) {
+        let max_close_admissions = match self.max_close_admissions_per_tick {
+            Some(max_close_admissions) if !close_nodes.contains(&pn.pk) => max_close_admissions,
+            _ => {
+                self.try_add_to_close(close_nodes, pn);
+                return;
             },
-            Ok(payload) => payload,
         };
 
-        if let (ip_port, Some(next_onion_return)) = payload {
-            let next_packet = Packet::OnionResponse1(OnionResponse1 {
-                onion_return: next_onion_return,
-                payload: packet.payload
-            });
-            Either::B(self.send_to_direct(ip_port.to_saddr(), next_packet))
-        } else {
-            Either::A( future::err(
-                Error::new(ErrorKind::Other,
-                    "OnionResponse2 next_onion_return is none".to_string()
-            )))
+        if self.close_admissions.load(Ordering::SeqCst) >= max_close_admissions {
+            trace!("Too many new close nodes during this iteration, deferring {:?}", pn);
+            self.deferred_close_nodes.write().try_add(&self.pk, pn);
+        } else if self.try_add_to_close(close_nodes, pn) {
+            self.close_admissions.fetch_add(1, Ordering::SeqCst);
         }
     }
 
-    /// Handle received `OnionResponse1` packet and send `OnionAnnounceResponse`
-    /// or `OnionDataResponse` packet to the next peer which address is stored
-    /// in encrypted onion return.
-    fn handle_onion_response_1(&self, packet: OnionResponse1) -> impl Future<Item = (), Error = Error> + Send {
-        let onion_symmetric_key = self.onion_symmetric_key.read();
-        let payload = packet.onion_return.get_payload(&onion_symmetric_key);
-        let payload = match payload {
-            Err(e) => {
-                // Onion symmetric key is changed every 2 hours to enforce onion
-                // paths expiration. It means that we can get packets with old
-                // onion key. So we do not consider this as error.
-                trace!("Failed to decrypt onion_return from OnionResponse1: {}", e);
-                return Box::new(future::ok(())) as Box<dyn Future<Item = _, Error = _> + Send>;
-            },
-            Ok(payload) => payload,
-        };
+    /// Add node that responded to our request to close nodes lists of
+    /// friends. Our own node is never added there since it's pointless.
+    fn try_add_to_friends_close(&self, friends: &mut [DhtFriend], pn: &PackedNode) {
+        if pn.pk == self.pk {
+            return;
+        }
 
-        if let (ip_port, None) = payload {
-            match ip_port.protocol {
-                ProtocolType::UDP => {
-                    let next_packet = match packet.payload {
-                        InnerOnionResponse::OnionAnnounceResponse(inner) => Packet::OnionAnnounceResponse(inner),
-                        InnerOnionResponse::OnionDataResponse(inner) => Packet::OnionDataResponse(inner),
-                    };
-                    Box::new(self.send_to_direct(ip_port.to_saddr(), next_packet)) as Box<dyn Future<Item = _, Error = _> + Send>
-                },
-                ProtocolType::TCP => {
-                    if let Some(ref tcp_onion_sink) = self.tcp_onion_sink {
-                        Box::new(tcp_onion_sink.clone() // clone sink for 1 send only
-                            .send((packet.payload, ip_port.to_saddr()))
-                            .map(|_sink| ()) // ignore sink because it was cloned
-                            .map_err(|_| {
-                                // This may only happen if sink is gone
-                                // So cast SendError<T> to a corresponding std::io::Error
-                                Error::from(ErrorKind::UnexpectedEof)
-                            })
-                        )
-                    } else {
-                        Box::new( future::err(
-                            Error::new(ErrorKind::Other,
-                                "OnionResponse1 can't be redirected to TCP relay".to_string()
-                        )))
-                    }
-                },
-            }
-        } else {
-            Box::new( future::err(
-                Error::new(ErrorKind::Other,
-                    "OnionResponse1 next_onion_return is some".to_string()
-            )))
+        let mut added = false;
+        for friend in friends.iter_mut() {
+            let is_new = !friend.close_nodes.contains(&friend.pk, &pn.pk);
+            added |= friend.try_add_to_close(pn) && is_new;
+        }
+        if added {
+            self.invalidate_nodes_resp_cache();
         }
     }
 
-    /// Refresh onion symmetric key to enforce onion paths expiration.
-    fn refresh_onion_key(&self) {
-        *self.onion_symmetric_key.write() = secretbox::gen_key();
+    /// Check if `Server` can't join the network because both close nodes list
+    /// and initial bootstrap nodes list are empty.
+    pub fn needs_bootstrap(&self) -> bool {
+        self.close_nodes.read().is_empty() && self.initial_bootstrap.read().is_empty()
     }
 
-    /// Add `PackedNode` to close nodes list.
-    #[cfg(test)]
-    fn try_add_to_close_nodes(&self, pn: &PackedNode) -> bool {
-        let mut close_nodes = self.close_nodes.write();
-        close_nodes.try_add(pn)
+    /// Log a warning that `Server` doesn't have any nodes to bootstrap from.
+    /// The warning is logged at most once per `NEEDS_BOOTSTRAP_WARN_INTERVAL`
+    /// seconds.
+    fn warn_needs_bootstrap(&self) {
+        let mut last_warn_time = self.last_needs_bootstrap_warn_time.write();
+        let interval_passed = match *last_warn_time {
+            Some(time) => clock_elapsed(time) >= Duration::from_secs(NEEDS_BOOTSTRAP_WARN_INTERVAL),
+            None => true,
+        };
+        if interval_passed {
+            warn!("DHT server has neither close nodes nor bootstrap nodes, add bootstrap nodes to join the network");
+            *last_warn_time = Some(clock_now());
+        }
     }
 
-    /// Handle `OnionRequest` from TCP relay and send `OnionRequest1` packet
-    /// to the next node in the onion path.
-    pub fn handle_tcp_onion_request(&self, packet: OnionRequest, addr: SocketAddr) -> impl Future<Item = (), Error = Error> + Send {
-        let onion_symmetric_key = self.onion_symmetric_key.read();
+    /// Run initial bootstrapping. It sends `NodesRequest` packet to bootstrap
+    /// nodes periodically if all nodes in Ktree are discarded (including the
+    /// case when it's empty). It has to be an endless loop because we might
+    /// loose the network connection and thereby loose all nodes in Ktree.
+    fn run_bootstrap_requests_sending(self) -> impl Future<Item = (), Error = Error> + Send {
+        let interval = Duration::from_secs(BOOTSTRAP_INTERVAL);
+        let wakeups = Interval::new(Instant::now(), interval);
 
-        let onion_return = OnionReturn::new(
-            &onion_symmetric_key,
-            &IpPort::from_tcp_saddr(addr),
-            None // no previous onion return
-        );
-        let next_packet = Packet::OnionRequest1(OnionRequest1 {
-            nonce: packet.nonce,
-            temporary_pk: packet.temporary_pk,
-            payload: packet.payload,
-            onion_return
-        });
-        self.send_to_direct(packet.ip_port.to_saddr(), next_packet)
+        wakeups
+            .map_err(|e| Error::new(ErrorKind::Other, format!("Bootstrap timer error: {:?}", e)))
+            .for_each(move |_instant| {
+                trace!("Bootstrap wake up");
+                self.send_bootstrap_requests()
+            })
     }
 
-    /// Handle `BootstrapInfo` packet and response with `BootstrapInfo` packet.
-    fn handle_bootstrap_info(&self, packet: &BootstrapInfo, addr: SocketAddr) -> impl Future<Item = (), Error = Error> + Send {
-        if packet.motd.len() != BOOSTRAP_CLIENT_MAX_MOTD_LENGTH {
-            return Either::A( future::err(
-                Error::new(ErrorKind::Other,
-                    "Wrong BootstrapInfo packet length".to_string()
-            )))
+    /// Check if all nodes in Ktree are discarded (including the case when
+    /// it's empty) and if so then send `NodesRequest` packet to nodes from
+    /// initial bootstrap list and from Ktree.
+    fn send_bootstrap_requests(&self) -> impl Future<Item = (), Error = Error> + Send {
+        if self.is_paused() {
+            return Either::A(future::ok(()));
         }
 
-        if let Some(ref bootstrap_info) = self.bootstrap_info {
-            let mut motd = (bootstrap_info.motd_cb)(&self);
-            if motd.len() > BOOSTRAP_SERVER_MAX_MOTD_LENGTH {
-                warn!(
-                    "Too long MOTD: {} bytes. Truncating to {} bytes",
-                    motd.len(),
-                    BOOSTRAP_SERVER_MAX_MOTD_LENGTH
-                );
-                motd.truncate(BOOSTRAP_SERVER_MAX_MOTD_LENGTH);
-            }
-            let packet = Packet::BootstrapInfo(BootstrapInfo {
-                version: bootstrap_info.version,
-                motd,
-            });
-            Either::B(self.send_to_direct(addr, packet))
-        } else {
-            // Do not respond to BootstrapInfo packets if bootstrap_info not defined
-            Either::A(future::ok(()))
+        let mut request_queue = self.request_queue.write();
+        let close_nodes = self.close_nodes.read();
+
+        if !close_nodes.is_all_discarded() {
+            return Either::A(future::ok(()));
         }
+
+        let initial_bootstrap = self.initial_bootstrap.read();
+        let fallback_bootstrap_node = if close_nodes.is_empty() && initial_bootstrap.is_empty() {
+            self.warn_needs_bootstrap();
+            self.fallback_bootstrap_node
+        } else {
+            None
+        };
+
+        let futures = close_nodes
+            .iter()
+            .flat_map(|node| node.to_all_packed_nodes())
+            .chain(initial_bootstrap.iter().cloned())
+            .chain(fallback_bootstrap_node)
+            .map(|node| self.send_nodes_req_skip_self(&node, &mut request_queue, self.pk))
+            .collect::<Vec<_>>();
+
+        Either::B(join_all(futures).map(|_| ()))
     }
 
-    /// Set toxcore version and message of the day callback.
-    pub fn set_bootstrap_info(&mut self, version: u32, motd_cb: Box<Fn(&Server) -> Vec<u8> + Send + Sync>) {
-        self.bootstrap_info = Some(ServerBootstrapInfo {
-            version,
-            motd_cb: motd_cb.into(),
-        });
+    /// Run DHT main loop periodically. Result future will never be completed
+    /// successfully.
+    fn run_main_loop(self) -> impl Future<Item = (), Error = Error> + Send {
+        let interval = Duration::from_secs(MAIN_LOOP_INTERVAL);
+        let wakeups = Interval::new(Instant::now(), interval);
+        wakeups
+            .map_err(|e| Error::new(ErrorKind::Other, format!("DHT server timer error: {:?}", e)))
+            .for_each(move |_instant| {
+                trace!("DHT server wake up");
+                if self.is_tx_closed() {
+                    return Either::A(future::err(Error::new(ErrorKind::BrokenPipe,
+                        "Receiver of outgoing packets is dropped"
+                    )));
+                }
+                Either::B(self.dht_main_loop().then(|res| {
+                    if let Err(e) = res {
+                        warn!("Failed to send DHT periodical packets: {}", e);
+                    }
+                    future::ok(())
+                }))
+            })
     }
 
-    /// Set TCP sink for onion packets.
-    pub fn set_tcp_onion_sink(&mut self, tcp_onion_sink: TcpOnionTx) {
-        self.tcp_onion_sink = Some(tcp_onion_sink)
+    /// Refresh onion symmetric key periodically. Result future will never be
+    /// completed successfully.
+    fn run_onion_key_refresing(self) -> impl Future<Item = (), Error = Error> + Send {
+        let interval = Duration::from_secs(ONION_REFRESH_KEY_INTERVAL);
+        let wakeups = Interval::new(Instant::now() + interval, interval);
+        wakeups
+            .map_err(|e| Error::new(ErrorKind::Other, format!("DHT server timer error: {:?}", e)))
+            .for_each(move |_instant| {
+                trace!("Refreshing onion key");
+                self.refresh_onion_key();
+                future::ok(())
+            })
     }
 
-    /// Set `net_crypto` module.
-    pub fn set_net_crypto(&mut self, net_crypto: NetCrypto) {
-        self.net_crypto = Some(net_crypto);
+    /// Run ping sending periodically. Result future will never be completed
+    /// successfully.
+    fn run_pings_sending(self) -> impl Future<Item = (), Error = Error> + Send {
+        let interval = Duration::from_secs(TIME_TO_PING);
+        let wakeups = Interval::new(Instant::now() + interval, interval);
+        wakeups
+            .map_err(|e| Error::new(ErrorKind::Other, format!("Ping timer error: {:?}", e)))
+            .for_each(move |_instant| {
+                trace!("Pings sending wake up");
+                self.send_pings()
+            })
+    }
+
+    /// Send `PingRequest` packets to nodes from `nodes_to_ping` list.
+    fn send_pings(&self) -> impl Future<Item = (), Error = Error> + Send {
+        if self.is_paused() {
+            return Either::A(future::ok(()))
+        }
+
+        let nodes_to_ping = mem::replace(
+            &mut *self.nodes_to_ping.write(),
+            NodesQueue::new(MAX_TO_PING)
+        );
+
+        if nodes_to_ping.is_empty() {
+            return Either::A(future::ok(()))
+        }
+
+        let mut request_queue = self.request_queue.write();
+
+        let futures = nodes_to_ping.iter().map(|node|
+            self.send_ping_req(node, &mut request_queue)
+        ).collect::<Vec<_>>();
+
+        Either::B(future::join_all(futures).map(|_| ()))
+    }
+
+    /// Add node to a `nodes_to_ping` list to send ping later. If node is
+    /// a friend and we don't know it's address then this method will send
+    /// `PingRequest` immediately instead of adding to a `nodes_to_ping`
+    /// list.
+    ///
+    /// A known node that sent us a packet from a new address is added as
+    /// well. Since the packet could be replayed from a spoofed address its
+    /// address is switched only when it answers the `PingRequest` sent to the
+    /// new address.
+    fn ping_add(&self, node: &PackedNode) -> impl Future<Item = (), Error = Error> + Send {
+        let close_nodes = self.close_nodes.read();
+
+        if !close_nodes.can_add(node) {
+            return Either::A(future::ok(()))
+        }
+
+        let friends = self.friends.read();
+
+        // If node is friend and we don't know friend's IP address yet then send
+        // PingRequest immediately and unconditionally
+        if friends.iter().any(|friend| friend.pk == node.pk && !friend.is_addr_known()) {
+            return Either::B(self.send_ping_req(node, &mut self.request_queue.write()))
+        }
+
+        self.nodes_to_ping.write().try_add(&self.pk, node);
+
+        Either::A(future::ok(()))
+    }
+
+    /// Send `NodesRequest` packets to nodes from bootstrap list. This is
+    /// necessary to check whether node is alive before adding it to close
+    /// nodes lists.
+    ///
+    /// `NodesQueue` keeps nodes sorted by distance to its base `PublicKey` so
+    /// requests are sent to the closest nodes first.
+    fn ping_nodes_to_bootstrap(&self, request_queue: &mut RequestQueue, nodes_to_bootstrap: &mut NodesQueue, pk: PublicKey) -> impl Future<Item = (), Error = Error> + Send {
+        let capacity = nodes_to_bootstrap.capacity() as u8;
+        let nodes_to_bootstrap = mem::replace(nodes_to_bootstrap, NodesQueue::new(capacity));
+
+        let futures = nodes_to_bootstrap.iter()
+            .map(|node| self.send_nodes_req_skip_self(&node, request_queue, pk))
+            .collect::<Vec<_>>();
+
+        future::join_all(futures).map(|_| ())
     }
 
-    /// Get `PrecomputedKey`s cache.
-    pub fn get_precomputed_keys(&self) -> PrecomputedCache {
-        self.precomputed_keys.clone()
-    }
-}
+    /// Iterate over nodes from close nodes list and send `NodesRequest` packets
+    /// to them if necessary.
+    fn ping_close_nodes<'a, T>(&self, request_queue: &mut RequestQueue, nodes: T, pk: PublicKey) -> Box<dyn Future<Item = (), Error = Error> + Send>
+        where T: Iterator<Item = &'a mut DhtNode> // if change to impl Future the result will be dependent on nodes lifetime
+    {
+        let unanswered_pings = self.unanswered_pings.read();
+        let futures = nodes
+            .flat_map(|node| {
+                let interval = Server::ping_interval(unanswered_pings.get(&node.pk).cloned().unwrap_or(0));
+                let is_interval_passed = |time: Option<Instant>| time.map_or(true, |time| clock_elapsed(time) >= interval);
+                let ping_addr_v4 = if is_interval_passed(node.assoc4.last_ping_req_time) {
+                    node.assoc4.ping_addr().map(|addr| PackedNode::new(addr.into(), &node.pk))
+                } else {
+                    None
+                };
+                let ping_addr_v6 = if is_interval_passed(node.assoc6.last_ping_req_time) {
+                    node.assoc6.ping_addr().map(|addr| PackedNode::new(addr.into(), &node.pk))
+                } else {
+                    None
+                };
+                ping_addr_v4.into_iter().chain(ping_addr_v6.into_iter())
+            })
+            .map(|node| self.send_nodes_req_skip_self(&node, request_queue, pk))
+            .collect::<Vec<_>>();
+
+        Box::new(future::join_all(futures).map(|_| ()))
+    }
+
+    /// Get interval between ping requests to a node that didn't answer our
+    /// requests during the given number of DHT main loop iterations. It's
+    /// doubled for every such iteration up to `2^MAX_PING_BACKOFF_EXP` times
+    /// of `PING_INTERVAL`.
+    fn ping_interval(unanswered_pings: u32) -> Duration {
+        Duration::from_secs(PING_INTERVAL << unanswered_pings.min(MAX_PING_BACKOFF_EXP))
+    }
+
+    /// Count nodes that didn't answer requests that timed out. Every node is
+    /// counted once per DHT main loop iteration however many requests were
+    /// sent to it. Nodes that are not in close nodes lists anymore are
+    /// forgotten.
+    fn count_unanswered_pings(&self, timed_out: Vec<PublicKey>, close_nodes: &Ktree, friends: &[DhtFriend]) {
+        let mut unanswered_pings = self.unanswered_pings.write();
+        for pk in timed_out.into_iter().collect::<HashSet<_>>() {
+            let count = unanswered_pings.entry(pk).or_insert(0);
+            *count = count.saturating_add(1);
+        }
+        unanswered_pings.retain(|pk, _|
+            close_nodes.contains(pk) || friends.iter().any(|friend| friend.close_nodes.contains(&friend.pk, pk))
+        );
+    }
+
+    /// Send `NodesRequest` packet to a random good node every 20 seconds or if
+    /// it was sent less than `NODES_REQ_INTERVAL`. This function should be
+    /// called every second.
+    fn send_nodes_req_random<'a, T>(&self, request_queue: &mut RequestQueue, nodes: T, pk: PublicKey) -> Box<dyn Future<Item = (), Error = Error> + Send>
+        where T: Iterator<Item = &'a DhtNode> // if change to impl Future the result will be dependent on nodes lifetime
+    {
+        let good_nodes = nodes
+            .filter(|&node| !node.is_bad())
+            .flat_map(|node| node.to_all_packed_nodes())
+            .collect::<Vec<_>>();
+
+        if good_nodes.is_empty() {
+            // Random request should be sent only to good nodes
+            return Box::new(future::ok(()))
+        }
+
+        let mut random_node_idx = random_usize() % good_nodes.len();
+        // Increase probability of sending packet to a close node (has lower index)
+        if random_node_idx != 0 {
+            random_node_idx -= random_usize() % (random_node_idx + 1);
+        }
+
+        let random_node = &good_nodes[random_node_idx];
+
+        Box::new(self.send_nodes_req_skip_self(&random_node, request_queue, pk))
+    }
+
+    /// Send `PingRequest` packet to the node.
+    pub fn send_ping_req(&self, node: &PackedNode, request_queue: &mut RequestQueue) -> impl Future<Item = (), Error = Error> + Send {
+        let payload = PingRequestPayload {
+            id: request_queue.new_ping_id(node.pk),
+        };
+        let ping_req = Packet::PingRequest(PingRequest::new(
+            &self.precomputed_keys.get(node.pk),
+            &self.pk,
+            &payload
+        ));
+        self.send_to_direct(node.saddr, ping_req)
+    }
+
+    /// Send `NodesRequest` packet to the node. Returns error with
+    /// `SendErrorKind::TargetIsSelf` kind if the node has our `PublicKey`.
+    pub fn send_nodes_req(&self, node: &PackedNode, request_queue: &mut RequestQueue, search_pk: PublicKey) -> impl Future<Item = (), Error = SendError> + Send {
+        // Check if packet is going to be sent to ourselves.
+        if self.pk == node.pk {
+            trace!("Attempt to send NodesRequest to ourselves.");
+            return Either::A(future::err(SendError::target_is_self()))
+        }
+
+        if let Some(ref mut tick_nodes_reqs) = *self.tick_nodes_reqs.write() {
+            if !tick_nodes_reqs.insert((node.saddr, node.pk, search_pk)) {
+                trace!("NodesRequest to {:?} is already sent during this iteration", node);
+                return Either::A(future::ok(()))
+            }
+        }
+
+        let payload = NodesRequestPayload {
+            pk: search_pk,
+            id: request_queue.new_ping_id(node.pk),
+        };
+        let nodes_req = Packet::NodesRequest(NodesRequest::new(
+            &self.precomputed_keys.get(node.pk),
+            &self.pk,
+            &payload
+        ));
+        Either::B(self.send_to_direct(node.saddr, nodes_req).map_err(SendError::send_to))
+    }
+
+    /// Send `NodesRequest` packet to the node. Unlike `send_nodes_req` an
+    /// attempt to send the packet to ourselves is not considered as error.
+    fn send_nodes_req_skip_self(&self, node: &PackedNode, request_queue: &mut RequestQueue, search_pk: PublicKey) -> impl Future<Item = (), Error = Error> + Send {
+        self.send_nodes_req(node, request_queue, search_pk).or_else(|e|
+            if *e.kind() == SendErrorKind::TargetIsSelf {
+                Ok(())
+            } else {
+                Err(Error::from(e))
+            }
+        )
+    }
+
+    /// Send `NatPingRequest` packet to friends and try to punch holes. Fake
+    /// friends should not be passed to this function. Friends that already
+    /// have an established `net_crypto` connection are skipped.
+    fn send_nat_ping_req<'a, T>(&self, request_queue: &mut RequestQueue, friends: T) -> Box<dyn Future<Item = (), Error = Error> + Send>
+        where T: Iterator<Item = &'a mut DhtFriend> // if change to impl Future the result will be dependent on friends lifetime
+    {
+        let futures = friends
+            .filter(|friend| !friend.is_addr_known())
+            .filter(|friend| match self.net_crypto {
+                Some(ref net_crypto) => !net_crypto.is_established_by_dht_pk(&friend.pk),
+                None => true,
+            })
+            .map(|friend| {
+                let addrs = friend.get_returned_addrs();
+                (friend, addrs)
+            })
+            // Send NatPingRequest and try to punch holes only if we have enough
+            // close nodes connected to a friend
+            .filter(|(_, addrs)| addrs.len() >= FRIEND_CLOSE_NODES_COUNT as usize / 2)
+            .map(|(friend, addrs)| {
+                let punch_future = self.punch_holes(request_queue, friend, &addrs);
+
+                if friend.hole_punch.last_send_ping_time.map_or(true, |time| clock_elapsed(time) >= Duration::from_secs(PUNCH_INTERVAL)) {
+                    friend.hole_punch.last_send_ping_time = Some(clock_now());
+                    let payload = DhtRequestPayload::NatPingRequest(NatPingRequest {
+                        id: friend.hole_punch.ping_id,
+                    });
+                    let nat_ping_req_packet = DhtRequest::new(
+                        &self.precomputed_keys.get(friend.pk),
+                        &friend.pk,
+                        &self.pk,
+                        &payload
+                    );
+                    let nat_ping_future = self.send_nat_ping_req_inner(friend, nat_ping_req_packet);
+
+                    Either::A(punch_future.join(nat_ping_future).map(|_| ()))
+                } else {
+                    Either::B(punch_future)
+                }
+            })
+            .collect::<Vec<_>>();
+
+        Box::new(join_all(futures).map(|_| ()))
+    }
+
+    /// Try to punch holes to specified friend.
+    fn punch_holes(&self, request_queue: &mut RequestQueue, friend: &mut DhtFriend, returned_addrs: &[SocketAddr]) -> impl Future<Item = (), Error = Error> + Send {
+        let punch_addrs = friend.hole_punch.next_punch_addrs(returned_addrs);
+
+        let futures = punch_addrs.into_iter().map(|addr| {
+            let payload = PingRequestPayload {
+                id: request_queue.new_ping_id(friend.pk),
+            };
+            let packet = Packet::PingRequest(PingRequest::new(
+                &self.precomputed_keys.get(friend.pk),
+                &self.pk,
+                &payload
+            ));
+
+            self.send_to_direct(addr, packet)
+        }).collect::<Vec<_>>();
+
+        join_all(futures).map(|_| ())
+    }
+
+    /// Send `NatPingRequest` packet to all close nodes of friend in the hope
+    /// that they will redirect it to this friend.
+    fn send_nat_ping_req_inner(&self, friend: &DhtFriend, nat_ping_req_packet: DhtRequest) -> impl Future<Item = (), Error = Error> + Send {
+        let packet = Packet::DhtRequest(nat_ping_req_packet);
+        let futures = friend.close_nodes.nodes.iter().map(|node| {
+            self.send_to_node(node, &packet)
+        }).collect::<Vec<_>>();
+
+        join_all(futures).map(|_| ())
+    }
+
+    /// Function to handle incoming packets and send responses if necessary.
+    ///
+    /// Packets that contain `PublicKey` of the sender are decrypted with the
+    /// key precomputed from this `PublicKey` so successful decryption proves
+    /// that the sender owns the corresponding `SecretKey`. A packet with the
+    /// sender key that doesn't match its encryption is rejected with an error
+    /// before it can change the state of the node. `DhtRequest` packets that
+    /// are not addressed to us are redirected without decryption.
+    pub fn handle_packet(&self, packet: Packet, addr: SocketAddr) -> impl Future<Item = (), Error = Error> + Send {
+        self.received_packets[Server::packet_type_index(&packet)].fetch_add(1, Ordering::Relaxed);
+        self.update_last_packet_time();
+
+        if let Some(reason) = self.drop_reason(&packet, addr) {
+            trace!("Dropping packet from {}: {}", addr, reason);
+            self.dropped_packets.fetch_add(1, Ordering::Relaxed);
+            return Box::new(future::ok(())) as Box<dyn Future<Item = _, Error = _> + Send>;
+        }
+
+        match packet {
+            Packet::PingRequest(packet) => Box::new(self.handle_ping_req(&packet, addr)) as Box<dyn Future<Item = _, Error = _> + Send>,
+            Packet::PingResponse(packet) => Box::new(self.handle_ping_resp(&packet, addr)),
+            Packet::NodesRequest(packet) => Box::new(self.handle_nodes_req(&packet, addr)),
+            Packet::NodesResponse(packet) => Box::new(self.handle_nodes_resp(&packet, addr)),
+            Packet::CookieRequest(packet) => Box::new(self.handle_cookie_request(&packet, addr)),
+            Packet::CookieResponse(packet) => Box::new(self.handle_cookie_response(&packet, addr)),
+            Packet::CryptoHandshake(packet) => Box::new(self.handle_crypto_handshake(&packet, addr)),
+            Packet::DhtRequest(packet) => Box::new(self.handle_dht_req(packet, addr)),
+            Packet::LanDiscovery(packet) => Box::new(self.handle_lan_discovery(&packet, addr)),
+            Packet::OnionRequest0(packet) => Box::new(self.handle_onion_request_0(&packet, addr)),
+            Packet::OnionRequest1(packet) => Box::new(self.handle_onion_request_1(&packet, addr)),
+            Packet::OnionRequest2(packet) => Box::new(self.handle_onion_request_2(&packet, addr)),
+            Packet::OnionAnnounceRequest(packet) => Box::new(self.handle_onion_announce_request(packet, addr)),
+            Packet::OnionDataRequest(packet) => Box::new(self.handle_onion_data_request(packet, addr)),
+            Packet::OnionResponse3(packet) => Box::new(self.handle_onion_response_3(packet)),
+            Packet::OnionResponse2(packet) => Box::new(self.handle_onion_response_2(packet)),
+            Packet::OnionResponse1(packet) => Box::new(self.handle_onion_response_1(packet)),
+            Packet::BootstrapInfo(packet) => Box::new(self.handle_bootstrap_info(&packet, addr)),
+            // This packet should be handled in client only
+            Packet::CryptoData(packet) => Box::new(future::err(
+                Error::new(ErrorKind::Other,
+                           format!("Packet is not handled {:?}", packet)
+                ))),
+            // This packet should be handled in client only
+            Packet::OnionDataResponse(packet) => Box::new(self.handle_onion_response_for_us(InnerOnionResponse::OnionDataResponse(packet))),
+            // This packet should be handled in client only
+            Packet::OnionAnnounceResponse(packet) => Box::new(self.handle_onion_response_for_us(InnerOnionResponse::OnionAnnounceResponse(packet))),
+        }
+    }
+
+    /// Deliver onion response addressed to us to the local onion client. It
+    /// can't be handled if there is no local onion client.
+    fn handle_onion_response_for_us(&self, response: InnerOnionResponse) -> impl Future<Item = (), Error = Error> + Send {
+        match self.onion_response_sink {
+            Some(ref onion_response_sink) => Either::A(self.deliver_onion_response(onion_response_sink, response)),
+            None => Either::B(future::err(
+                Error::new(ErrorKind::Other,
+                           format!("Packet is not handled {:?}", response)
+                ))),
+        }
+    }
+
+    /// Send UDP packet node. If the node has both IPv4 and IPv6 addresses,
+    /// then it sends packet to both addresses.
+    fn send_to_node(&self, node: &DhtNode, packet: &Packet) -> impl Future<Item = (), Error = Error> + Send {
+        let addrs = node.get_all_addrs();
+
+        let futures = addrs.into_iter()
+            .map(|addr| self.send_to_direct(addr, packet.clone()))
+            .collect::<Vec<_>>();
+
+        join_all(futures).map(|_| ())
+    }
+
+    /// Get the reason why the packet should be dropped before it's handled or
+    /// `None` if it should be handled.
+    fn drop_reason(&self, packet: &Packet, addr: SocketAddr) -> Option<&'static str> {
+        if !self.is_addr_allowed(&addr) {
+            return Some("address is not allowed");
+        }
+
+        let inbound_filter = self.inbound_filter.read().clone();
+        if let Some(filter) = inbound_filter {
+            if !filter(packet, addr) {
+                return Some("packet is dropped by inbound filter");
+            }
+        }
+
+        if !self.is_packet_enabled(packet) {
+            return Some("packet handler is disabled");
+        }
+
+        None
+    }
+
+    /// Record the address a packet is sent to if the packet being handled is
+    /// traced.
+    fn trace_send(&self, addr: SocketAddr) {
+        if let Some(ref send_trace) = self.send_trace {
+            send_trace.write().push(addr);
+        }
+    }
+
+    /// Handle packet like `handle_packet` does and describe what was done with
+    /// it. Packets are sent through the usual send path of `Server` which
+    /// records their addresses. Since the record belongs to a single call the
+    /// packet is handled by a copy of `Server` that shares its state.
+    pub fn handle_packet_traced(&self, packet: Packet, addr: SocketAddr) -> impl Future<Item = RoutingDecision, Error = Error> + Send {
+        if let Some(reason) = self.drop_reason(&packet, addr) {
+            return Either::A(future::ok(RoutingDecision::Dropped(reason.to_owned())));
+        }
+
+        let send_trace = Arc::new(RwLock::new(Vec::new()));
+        let mut server = self.clone();
+        server.send_trace = Some(send_trace.clone());
+
+        let future = server.handle_packet(packet, addr).then(move |res| {
+            let sent_addrs = mem::replace(&mut *send_trace.write(), Vec::new());
+            let decision = match res {
+                Err(e) => RoutingDecision::Dropped(e.to_string()),
+                Ok(()) if sent_addrs.contains(&addr) => RoutingDecision::RepliedTo(addr),
+                Ok(()) if !sent_addrs.is_empty() => RoutingDecision::ForwardedTo(sent_addrs),
+                Ok(()) => RoutingDecision::DeliveredLocally,
+            };
+            future::ok(decision)
+        });
+
+        Either::B(future)
+    }
+
+    /// Send UDP packet to specified address.
+    fn send_to_direct(&self, addr: SocketAddr, packet: Packet) -> impl Future<Item = (), Error = Error> + Send {
+        if !self.can_reach(addr) {
+            return Either::A(future::err(Error::new(ErrorKind::Other,
+                format!("Sending packets to {} is not allowed", addr)
+            )))
+        }
+
+        let outbound_filter = self.outbound_filter.read().clone();
+        if let Some(filter) = outbound_filter {
+            if !filter(&packet, addr) {
+                trace!("Packet to {} is dropped by outbound filter", addr);
+                return Either::A(future::ok(()))
+            }
+        }
+
+        let pending_send = match self.start_send() {
+            Some(pending_send) => pending_send,
+            None => return Either::A(future::err(Error::new(ErrorKind::Other,
+                format!("Server is draining, packet to {} is not sent", addr)
+            ))),
+        };
+
+        self.trace_send(addr);
+
+        let queue_error_sink = self.queue_error_sink.clone();
+        let consecutive_send_failures = self.consecutive_send_failures.clone();
+        let event_sink = self.event_sink.clone();
+        Either::B(send_to_bounded(&*self.tx.read(), (packet, addr), Duration::from_secs(DHT_SEND_TIMEOUT)).then(move |res| {
+            drop(pending_send);
+            // inner error means that the receiver is dropped while timeouts
+            // are possible when the receiver is just slow
+            let is_closed = match res {
+                Err(ref e) => e.is_inner(),
+                Ok(()) => false,
+            };
+            count_send_result(&consecutive_send_failures, event_sink.as_ref(), is_closed);
+            res.map_err(|e| {
+                let error = Error::new(ErrorKind::Other,
+                    format!("Failed to send packet: {:?}", e)
+                );
+                if let Some(queue_error_sink) = queue_error_sink {
+                    if let Err(e) = queue_error_sink.unbounded_send((addr, Error::new(error.kind(), error.to_string()))) {
+                        warn!("Failed to report packet that was not queued: {}", e);
+                    }
+                }
+                error
+            })
+        }))
+    }
+
+    /// Handle received `PingRequest` packet and response with `PingResponse`
+    /// packet. If node that sent this packet is not present in close nodes list
+    /// and can be added there then it will be added to ping list.
+    fn handle_ping_req(&self, packet: &PingRequest, addr: SocketAddr) -> impl Future<Item = (), Error = Error> + Send {
+        let precomputed_key = self.precomputed_keys.get(packet.pk);
+        let payload = match packet.get_payload(&precomputed_key) {
+            Err(e) => return Either::A(future::err(Error::from(e))),
+            Ok(payload) => payload,
+        };
+
+        if !self.check_not_self(&packet.pk) {
+            trace!("Dropping PingRequest from {} sent from our own PublicKey", addr);
+            return Either::A(future::ok(()));
+        }
+
+        if !self.check_requests_limit(packet.pk) {
+            trace!("Too many requests from {:?}, dropping PingRequest", packet.pk);
+            return Either::A(future::ok(()));
+        }
+
+        let resp_payload = PingResponsePayload {
+            id: payload.id,
+        };
+        let ping_resp = Packet::PingResponse(PingResponse::new(
+            &precomputed_key,
+            &self.pk,
+            &resp_payload
+        ));
+
+        if self.respond_only_mode {
+            return Either::B(Either::A(self.send_to_direct(addr, ping_resp)));
+        }
+
+        Either::B(Either::B(self.ping_add(&PackedNode::new(addr, &packet.pk))
+            .join(self.send_to_direct(addr, ping_resp))
+            .map(|_| ())
+        ))
+    }
+
+    /// Handle received `PingResponse` packet and if it's correct add the node
+    /// that sent this packet to close nodes lists.
+    fn handle_ping_resp(&self, packet: &PingResponse, addr: SocketAddr) -> impl Future<Item = (), Error = Error> + Send {
+        let precomputed_key = self.precomputed_keys.get(packet.pk);
+        let payload = match packet.get_payload(&precomputed_key) {
+            Err(e) => return future::err(Error::from(e)),
+            Ok(payload) => payload,
+        };
+
+        if payload.id == 0u64 {
+            return future::err(
+                Error::new(ErrorKind::Other,
+                    "PingResponse.ping_id == 0"
+            ))
+        }
+
+        let mut request_queue = self.request_queue.write();
+
+        if request_queue.check_ping_id(packet.pk, payload.id) {
+            self.udp_works.store(true, Ordering::SeqCst);
+            self.unanswered_pings.write().remove(&packet.pk);
+
+            let mut close_nodes = self.close_nodes.write();
+            let mut friends = self.friends.write();
+
+            let pn = PackedNode::new(addr, &packet.pk);
+            self.try_add_to_close(&mut close_nodes, &pn);
+            self.try_add_to_friends_close(&mut friends, &pn);
+
+            future::ok(())
+        } else {
+            future::err(
+                Error::new(ErrorKind::Other, "PingResponse.ping_id does not match")
+            )
+        }
+    }
+
+    /// Handle received `NodesRequest` packet and respond with `NodesResponse`
+    /// packet. If node that sent this packet is not present in close nodes list
+    /// and can be added there then it will be added to ping list.
+    fn handle_nodes_req(&self, packet: &NodesRequest, addr: SocketAddr) -> impl Future<Item = (), Error = Error> + Send {
+        let precomputed_key = self.precomputed_keys.get(packet.pk);
+        let payload = match packet.get_payload(&precomputed_key) {
+            Err(e) => return Either::A(future::err(Error::from(e))),
+            Ok(payload) => payload,
+        };
+
+        if !self.check_not_self(&packet.pk) {
+            trace!("Dropping NodesRequest from {} sent from our own PublicKey", addr);
+            return Either::A(future::ok(()));
+        }
+
+        if !self.check_requests_limit(packet.pk) {
+            trace!("Too many requests from {:?}, dropping NodesRequest", packet.pk);
+            return Either::A(future::ok(()));
+        }
+
+        // IPv6 nodes are useless for the requester that is connected via IPv4
+        let is_ipv4 = PackedNode::new(addr, &packet.pk).saddr.is_ipv4();
+        let close_nodes = self.get_closest_cached(packet.pk, &payload.pk, IsGlobal::is_global(&addr.ip()), is_ipv4);
+
+        if close_nodes.is_empty() && !self.empty_nodes_response_enabled {
+            trace!("No nodes to respond with, only adding {} to the list of nodes to ping", addr);
+            return Either::B(Either::A(self.ping_add(&PackedNode::new(addr, &packet.pk))));
+        }
+
+        let resp_payload = NodesResponsePayload {
+            nodes: close_nodes,
+            id: payload.id,
+        };
+        let nodes_resp = Packet::NodesResponse(NodesResponse::new(
+            &precomputed_key,
+            &self.pk,
+            &resp_payload
+        ));
+
+        Either::B(Either::B(self.ping_add(&PackedNode::new(addr, &packet.pk))
+            .join(self.send_to_direct(addr, nodes_resp))
+            .map(|_| ())
+        ))
+    }
+
+    /// Handle received `NodesResponse` packet and if it's correct add the node
+    /// that sent this packet to close nodes lists. Nodes from response will be
+    /// added to bootstrap nodes list to send `NodesRequest` packet to them
+    /// later.
+    fn handle_nodes_resp(&self, packet: &NodesResponse, addr: SocketAddr) -> impl Future<Item = (), Error = Error> + Send {
+        let precomputed_key = self.precomputed_keys.get(packet.pk);
+        let payload = match packet.get_payload(&precomputed_key) {
+            Err(e) => return Either::B(future::err(Error::from(e))),
+            Ok(payload) => payload,
+        };
+
+        let mut request_queue = self.request_queue.write();
+
+        if request_queue.check_ping_id(packet.pk, payload.id) {
+            trace!("Received nodes with NodesResponse from {}: {:?}", addr, payload.nodes);
+
+            self.udp_works.store(true, Ordering::SeqCst);
+            self.unanswered_pings.write().remove(&packet.pk);
+
+            let mut close_nodes = self.close_nodes.write();
+            let mut friends = self.friends.write();
+            let mut nodes_to_bootstrap = self.nodes_to_bootstrap.write();
+
+            // Add node that sent NodesResponse to close nodes lists
+            let pn = PackedNode::new(addr, &packet.pk);
+            self.admit_to_close(&mut close_nodes, &pn);
+            self.try_add_to_friends_close(&mut friends, &pn);
+
+            // Node that sent NodesResponse is verified and can be used in
+            // onion paths if it's reachable from the internet
+            if let Some(ref path_node_sink) = self.path_node_sink {
+                if IsGlobal::is_global(&addr.ip()) {
+                    if let Err(e) = path_node_sink.unbounded_send(pn) {
+                        warn!("Failed to send path node: {}", e);
+                    }
+                }
+            }
+
+            let friends_count = friends.len();
+            let (fan_out_start, fan_out_count) = self.friends_fan_out_window(friends_count);
+
+            // Process nodes from NodesResponse
+            for node in &payload.nodes {
+                if !self.is_ipv6_enabled && node.saddr.is_ipv6() {
+                    continue;
+                }
+
+                if close_nodes.can_add(node) {
+                    nodes_to_bootstrap.try_add(&self.pk, node);
+                }
+
+                for i in 0 .. fan_out_count {
+                    let friend = &mut friends[(fan_out_start + i) % friends_count];
+                    if node.pk != self.pk && friend.can_add_to_close(node) {
+                        friend.nodes_to_bootstrap.try_add(&friend.pk, node);
+                    }
+                }
+
+                self.update_returned_addr(node, &packet.pk, &mut close_nodes, &mut friends);
+            }
+
+            if self.fill_strategy == FillStrategy::Active && self.active_fill.read().is_some() {
+                debug!("Querying nodes from NodesResponse to fill close nodes list");
+                Either::A(self.ping_nodes_to_bootstrap(&mut request_queue, &mut nodes_to_bootstrap, self.pk))
+            } else {
+                Either::B(future::ok(()))
+            }
+        } else {
+            // Some old version toxcore responds with wrong ping_id.
+            // So we do not treat this as our own error.
+            trace!("NodesResponse.ping_id does not match");
+            Either::B(future::ok(()))
+        }
+    }
+
+    /// Update returned socket address and time of receiving packet
+    fn update_returned_addr(&self, node: &PackedNode, packet_pk: &PublicKey, close_nodes: &mut Ktree, friends: &mut Vec<DhtFriend>) {
+        if self.pk == node.pk {
+            if let Some(node_to_update) = close_nodes.get_node_mut(packet_pk) {
+                node_to_update.update_returned_addr(node.saddr);
+            }
+        }
+
+        if let Some(friend) = friends.iter_mut().find(|friend| friend.pk == node.pk) {
+            if let Some(node_to_update) = friend.close_nodes.get_node_mut(&friend.pk, packet_pk) {
+                node_to_update.update_returned_addr(node.saddr);
+            }
+        }
+    }
+
+    /// Handle received `CookieRequest` packet and pass it to `net_crypto`
+    /// module.
+    fn handle_cookie_request(&self, packet: &CookieRequest, addr: SocketAddr) -> impl Future<Item = (), Error = Error> + Send {
+        let net_crypto = match self.net_crypto {
+            Some(ref net_crypto) => net_crypto,
+            None => return Either::B(future::err(
+                Error::new(ErrorKind::Other, "Net crypto is not initialised")
+            )),
+        };
+
+        if !self.check_handshakes_limit(net_crypto, addr) {
+            return Either::B(future::ok(()));
+        }
+
+        Either::A(net_crypto.handle_udp_cookie_request(packet, addr))
+    }
+
+    /// Handle received `CookieResponse` packet and pass it to `net_crypto`
+    /// module.
+    fn handle_cookie_response(&self, packet: &CookieResponse, addr: SocketAddr) -> impl Future<Item = (), Error = Error> + Send {
+        let net_crypto = match self.net_crypto {
+            Some(ref net_crypto) => net_crypto,
+            None => return Either::B(future::err(
+                Error::new(ErrorKind::Other, "Net crypto is not initialised")
+            )),
+        };
+
+        if !self.check_handshakes_limit(net_crypto, addr) {
+            return Either::B(future::ok(()));
+        }
+
+        Either::A(net_crypto.handle_udp_cookie_response(packet, addr))
+    }
+
+    /// Handle received `CryptoHandshake` packet and pass it to `net_crypto`
+    /// module.
+    fn handle_crypto_handshake(&self, packet: &CryptoHandshake, addr: SocketAddr) -> impl Future<Item = (), Error = Error> + Send {
+        let net_crypto = match self.net_crypto {
+            Some(ref net_crypto) => net_crypto,
+            None => return Either::B(future::err(
+                Error::new(ErrorKind::Other, "Net crypto is not initialised")
+            )),
+        };
+
+        if !self.check_handshakes_limit(net_crypto, addr) {
+            return Either::B(future::ok(()));
+        }
+
+        Either::A(net_crypto.handle_udp_crypto_handshake(packet, addr))
+    }
+
+    /// Handle received `DhtRequest` packet, redirect it if it's sent for
+    /// someone else or parse it and handle the payload if it's sent for us.
+    fn handle_dht_req(&self, packet: DhtRequest, addr: SocketAddr) -> impl Future<Item = (), Error = Error> + Send { // TODO: split to functions
+        if packet.rpk == self.pk { // the target peer is me
+            Either::A(self.handle_dht_req_for_us(&packet, addr))
+        } else {
+            Either::B(self.handle_dht_req_for_others(packet))
+        }
+    }
+
+    /// Parse received `DhtRequest` packet and handle the payload.
+    fn handle_dht_req_for_us(&self, packet: &DhtRequest, addr: SocketAddr) -> impl Future<Item = (), Error = Error> + Send {
+        let precomputed_key = self.precomputed_keys.get(packet.spk);
+        let payload = packet.get_payload(&precomputed_key);
+        let payload = match payload {
+            Err(e) => return Box::new(future::err(Error::from(e))) as Box<dyn Future<Item = _, Error = _> + Send>,
+            Ok(payload) => payload,
+        };
+
+        match payload {
+            DhtRequestPayload::NatPingRequest(_) | DhtRequestPayload::NatPingResponse(_) if self.is_nat_paused() => {
+                trace!("Dropping nat ping packet since NAT traversal is paused");
+                Box::new(future::ok(()))
+            },
+            DhtRequestPayload::NatPingRequest(nat_payload) => {
+                debug!("Received nat ping request");
+                Box::new(self.handle_nat_ping_req(nat_payload, &packet.spk, addr)) as Box<dyn Future<Item = _, Error = _> + Send>
+            },
+            DhtRequestPayload::NatPingResponse(nat_payload) => {
+                debug!("Received nat ping response");
+                Box::new(self.handle_nat_ping_resp(nat_payload, &packet.spk))
+            },
+            DhtRequestPayload::DhtPkAnnounce(_dht_pk_payload) => {
+                debug!("Received DHT PublicKey Announce");
+                // TODO: handle this packet in onion client
+                Box::new( future::ok(()) )
+            },
+            DhtRequestPayload::HardeningRequest(_dht_pk_payload) => {
+                debug!("Received Hardening request");
+                // TODO: implement handler
+                Box::new( future::ok(()) )
+            },
+            DhtRequestPayload::HardeningResponse(_dht_pk_payload) => {
+                debug!("Received Hardening response");
+                // TODO: implement handler
+                Box::new( future::ok(()) )
+            },
+        }
+    }
+
+    /// Redirect received `DhtRequest` packet.
+    fn handle_dht_req_for_others(&self, packet: DhtRequest) -> impl Future<Item = (), Error = Error> + Send {
+        let close_nodes = self.close_nodes.read();
+        if let Some(node) = close_nodes.get_node(&packet.rpk) { // search close_nodes to find target peer
+            let packet = Packet::DhtRequest(packet);
+            Either::A(self.send_to_node(node, &packet))
+        } else {
+            Either::B(future::ok(()))
+        }
+    }
+
+    /// Handle received `NatPingRequest` packet and respond with
+    /// `NatPingResponse` packet.
+    fn handle_nat_ping_req(&self, payload: NatPingRequest, spk: &PublicKey, addr: SocketAddr) -> impl Future<Item = (), Error = Error> + Send {
+        let mut friends = self.friends.write();
+
+        let friend = friends.iter_mut()
+            .find(|friend| friend.pk == *spk);
+        let friend = match friend {
+            None => return Either::A( future::err(
+                Error::new(ErrorKind::Other,
+                           "Can't find friend"
+                ))),
+            Some(friend) => friend,
+        };
+
+        friend.hole_punch.last_recv_ping_time = clock_now();
+
+        let resp_payload = DhtRequestPayload::NatPingResponse(NatPingResponse {
+            id: payload.id,
+        });
+        let nat_ping_resp = Packet::DhtRequest(DhtRequest::new(
+            &self.precomputed_keys.get(*spk),
+            spk,
+            &self.pk,
+            &resp_payload
+        ));
+        Either::B(self.send_to_direct(addr, nat_ping_resp))
+    }
+
+    /// Handle received `NatPingResponse` packet and enable hole punching if
+    /// it's correct.
+    fn handle_nat_ping_resp(&self, payload: NatPingResponse, spk: &PublicKey) -> impl Future<Item = (), Error = Error> + Send {
+        if payload.id == 0 {
+            return future::err(
+                Error::new(ErrorKind::Other,
+                    "NodesResponse.ping_id == 0"
+            ))
+        }
+
+        let mut friends = self.friends.write();
+
+        let friend = friends.iter_mut()
+            .find(|friend| friend.pk == *spk);
+        let friend = match friend {
+            None => {
+                // Such response might be sent by a friend that was removed
+                // recently so it's not an error
+                debug!("Dropping NatPingResponse from unknown node {:?}", spk);
+                self.unknown_nat_ping_responses.fetch_add(1, Ordering::Relaxed);
+                return future::ok(())
+            },
+            Some(friend) => friend,
+        };
+
+        if friend.hole_punch.ping_id == payload.id {
+            // Refresh ping id for the next NatPingRequest
+            friend.hole_punch.ping_id = gen_ping_id();
+            // We send NatPingRequest packet only if we are not directly
+            // connected to a friend but we have several nodes that connected
+            // to him. If we received NatPingResponse that means that this
+            // friend is likely behind NAT so we should try to punch holes.
+            friend.hole_punch.is_punching_done = false;
+            future::ok(())
+        } else {
+            future::err(
+                Error::new(ErrorKind::Other, "NatPingResponse.ping_id does not match or timed out")
+            )
+        }
+    }
+
+    /// Handle received `LanDiscovery` packet and response with `NodesRequest`
+    /// packet.
+    fn handle_lan_discovery(&self, packet: &LanDiscovery, addr: SocketAddr) -> impl Future<Item = (), Error = Error> + Send {
+        // LanDiscovery is optional
+        if !self.lan_discovery_enabled {
+            return Either::A(future::ok(()));
+        }
+
+        if self.lan_discovery_quiet_period.is_active() {
+            trace!("LAN discovery is suppressed, dropping LanDiscovery from {}", addr);
+            return Either::A(future::ok(()));
+        }
+
+        // if Lan Discovery packet has my PK, then it is sent by myself.
+        if packet.pk == self.pk {
+            return Either::A(future::ok(()));
+        }
+
+        Either::B(self.send_nodes_req_skip_self(&PackedNode::new(addr, &packet.pk), &mut self.request_queue.write(), self.pk))
+    }
+
+    /// Handle received `OnionRequest0` packet and send `OnionRequest1` packet
+    /// to the next peer.
+    fn handle_onion_request_0(&self, packet: &OnionRequest0, addr: SocketAddr) -> impl Future<Item = (), Error = Error> + Send {
+        if !self.check_onion_payload_size(packet.payload.len(), ONION_REQUEST_0_MAX_PAYLOAD_SIZE) {
+            return Either::A(future::ok(()));
+        }
+
+        if !self.check_onion_forwards_limit() {
+            return Either::A(future::ok(()));
+        }
+
+        let onion_symmetric_key = self.onion_symmetric_key.read();
+        let shared_secret = self.precomputed_keys.get(packet.temporary_pk);
+        let payload = packet.get_payload(&shared_secret);
+        let payload = match payload {
+            Err(e) => return Either::A(future::err(e)),
+            Ok(payload) => payload,
+        };
+
+        if let Err(e) = self.check_onion_temporary_pk(&payload.temporary_pk) {
+            return Either::A(future::err(e));
+        }
+
+        if !self.check_onion_next_hop_port(payload.ip_port.port) {
+            return Either::A(future::ok(()));
+        }
+
+        let onion_return = OnionReturn::new(
+            &onion_symmetric_key,
+            &IpPort::from_udp_saddr(addr),
+            None // no previous onion return
+        );
+        let next_packet = Packet::OnionRequest1(OnionRequest1 {
+            nonce: packet.nonce,
+            temporary_pk: payload.temporary_pk,
+            payload: payload.inner,
+            onion_return
+        });
+        Either::B(self.forward_onion_packet(payload.ip_port.to_saddr(), next_packet))
+    }
+
+    /// Handle received `OnionRequest1` packet and send `OnionRequest2` packet
+    /// to the next peer.
+    fn handle_onion_request_1(&self, packet: &OnionRequest1, addr: SocketAddr) -> impl Future<Item = (), Error = Error> + Send {
+        if !self.check_onion_payload_size(packet.payload.len(), ONION_REQUEST_1_MAX_PAYLOAD_SIZE) {
+            return Either::A(future::ok(()));
+        }
+
+        if !self.check_onion_forwards_limit() {
+            return Either::A(future::ok(()));
+        }
+
+        let onion_symmetric_key = self.onion_symmetric_key.read();
+        let shared_secret = self.precomputed_keys.get(packet.temporary_pk);
+        let payload = packet.get_payload(&shared_secret);
+        let payload = match payload {
+            Err(e) => return Either::A(future::err(e)),
+            Ok(payload) => payload,
+        };
+
+        if let Err(e) = self.check_onion_temporary_pk(&payload.temporary_pk) {
+            return Either::A(future::err(e));
+        }
+
+        if !self.check_onion_replay(&packet.onion_return) {
+            return Either::A(future::ok(()));
+        }
+
+        if !self.check_onion_next_hop_port(payload.ip_port.port) {
+            return Either::A(future::ok(()));
+        }
+
+        let onion_return = OnionReturn::new(
+            &onion_symmetric_key,
+            &IpPort::from_udp_saddr(addr),
+            Some(&packet.onion_return)
+        );
+        let next_packet = Packet::OnionRequest2(OnionRequest2 {
+            nonce: packet.nonce,
+            temporary_pk: payload.temporary_pk,
+            payload: payload.inner,
+            onion_return
+        });
+        Either::B(self.forward_onion_packet(payload.ip_port.to_saddr(), next_packet))
+    }
+
+    /// Handle received `OnionRequest2` packet and send `OnionAnnounceRequest`
+    /// or `OnionDataRequest` packet to the next peer.
+    fn handle_onion_request_2(&self, packet: &OnionRequest2, addr: SocketAddr) -> impl Future<Item = (), Error = Error> + Send {
+        if !self.check_onion_payload_size(packet.payload.len(), ONION_REQUEST_2_MAX_PAYLOAD_SIZE) {
+            return Either::A(future::ok(()));
+        }
+
+        if !self.check_onion_forwards_limit() {
+            return Either::A(future::ok(()));
+        }
+
+        let onion_symmetric_key = self.onion_symmetric_key.read();
+        let shared_secret = self.precomputed_keys.get(packet.temporary_pk);
+        let payload = packet.get_payload(&shared_secret);
+        let payload = match payload {
+            Err(e) => {
+                // inner payload can be only an announce or data request so
+                // anything else means that the onion path is malformed
+                self.malformed_onion_packets.fetch_add(1, Ordering::Relaxed);
+                return Either::A(future::err(e))
+            },
+            Ok(payload) => payload,
+        };
+
+        if !self.check_onion_replay(&packet.onion_return) {
+            return Either::A(future::ok(()));
+        }
+
+        if !self.check_onion_next_hop_port(payload.ip_port.port) {
+            return Either::A(future::ok(()));
+        }
+
+        let onion_return = OnionReturn::new(
+            &onion_symmetric_key,
+            &IpPort::from_udp_saddr(addr),
+            Some(&packet.onion_return)
+        );
+        let next_packet = match payload.inner {
+            InnerOnionRequest::InnerOnionAnnounceRequest(inner) => Packet::OnionAnnounceRequest(OnionAnnounceRequest {
+                inner,
+                onion_return
+            }),
+            InnerOnionRequest::InnerOnionDataRequest(inner) => Packet::OnionDataRequest(OnionDataRequest {
+                inner,
+                onion_return
+            }),
+        };
+        Either::B(self.forward_onion_packet(payload.ip_port.to_saddr(), next_packet))
+    }
+
+    /// Handle received `OnionAnnounceRequest` packet and response with
+    /// `OnionAnnounceResponse` packet if the request succeed.
+    ///
+    /// The response packet will contain up to 4 closest to `search_pk` nodes
+    /// from ktree. They are used to search closest to long term `PublicKey`
+    /// nodes to announce.
+    fn handle_onion_announce_request(&self, packet: OnionAnnounceRequest, addr: SocketAddr) -> impl Future<Item = (), Error = Error> + Send {
+        // checked before decrypting so that flood doesn't cost us anything
+        if !self.announce_responses_limiter.write().check(addr.ip()) {
+            trace!("Too many announce responses to {}, dropping OnionAnnounceRequest", addr);
+            return Either::A(future::ok(()));
+        }
+
+        let mut onion_announce = self.onion_announce.write();
+
+        let shared_secret = self.precomputed_keys.get(packet.inner.pk);
+        let payload = match packet.inner.get_payload(&shared_secret) {
+            Err(e) => return Either::A(future::err(e)),
+            Ok(payload) => payload,
+        };
+
+        let (announce_status, ping_id_or_pk) = onion_announce.handle_onion_announce_request(
+            &payload,
+            packet.inner.pk,
+            packet.onion_return.clone(),
+            addr
+        );
+
+        // nodes are sent regardless of the status so that the searcher can
+        // continue the search when the key is not found
+        let mut close_nodes: Vec<PackedNode> = self.get_closest(&payload.search_pk, IsGlobal::is_global(&addr.ip()), false).into();
+        close_nodes.truncate(self.onion_announce_nodes_count);
+
+        let response_payload = OnionAnnounceResponsePayload {
+            announce_status,
+            ping_id_or_pk,
+            nodes: close_nodes
+        };
+        let response = OnionAnnounceResponse::new(&shared_secret, payload.sendback_data, &response_payload);
+
+        Either::B(self.send_to_direct(addr, Packet::OnionResponse3(OnionResponse3 {
+            onion_return: packet.onion_return,
+            payload: InnerOnionResponse::OnionAnnounceResponse(response)
+        })))
+    }
+
+    /// Handle received `OnionDataRequest` packet and send `OnionResponse3`
+    /// packet with inner `OnionDataResponse` to destination node through its
+    /// onion path.
+    fn handle_onion_data_request(&self, packet: OnionDataRequest, addr: SocketAddr) -> impl Future<Item = (), Error = Error> + Send {
+        let onion_announce = self.onion_announce.read();
+        if self.onion_data_failure_response_enabled && !onion_announce.is_announced(packet.inner.destination_pk) {
+            trace!("Failed to deliver OnionDataRequest: no announced node with public key {:?}", packet.inner.destination_pk);
+            // Empty payload can't be a valid encrypted data so the sender
+            // will be able to distinguish this response
+            let response = OnionResponse3 {
+                onion_return: packet.onion_return,
+                payload: InnerOnionResponse::OnionDataResponse(OnionDataResponse {
+                    nonce: packet.inner.nonce,
+                    temporary_pk: packet.inner.temporary_pk,
+                    payload: Vec::new()
+                })
+            };
+            return Either::A(self.send_to_direct(addr, Packet::OnionResponse3(response)));
+        }
+        match onion_announce.handle_data_request(packet) {
+            Ok((response, addr)) => Either::A(self.send_to_direct(addr, Packet::OnionResponse3(response))),
+            Err(e) => Either::B(future::err(e)),
+        }
+    }
+
+    /// Handle received `OnionResponse3` packet and send `OnionResponse2` packet
+    /// to the next peer which address is stored in encrypted onion return.
+    fn handle_onion_response_3(&self, packet: OnionResponse3) -> impl Future<Item = (), Error = Error> + Send {
+        let onion_symmetric_key = self.onion_symmetric_key.read();
+        let payload = packet.onion_return.get_payload(&onion_symmetric_key);
+        let payload = match payload {
+            Err(e) => {
+                // Onion symmetric key is changed every 2 hours to enforce onion
+                // paths expiration. It means that we can get packets with old
+                // onion key. So we do not consider this as error.
+                trace!("Failed to decrypt onion_return from OnionResponse3: {}", e);
+                return Either::A(future::ok(()));
+            },
+            Ok(payload) => payload,
+        };
+
+        if let (ip_port, Some(next_onion_return)) = payload {
+            let next_packet = Packet::OnionResponse2(OnionResponse2 {
+                onion_return: next_onion_return,
+                payload: packet.payload
+            });
+            Either::B(self.send_to_direct(ip_port.to_saddr(), next_packet))
+        } else {
+            Either::A( future::err(
+                Error::new(ErrorKind::Other,
+                    "OnionResponse3 next_onion_return is none".to_string()
+            )))
+        }
+    }
+
+    /// Handle received `OnionResponse2` packet and send `OnionResponse1` packet
+    /// to the next peer which address is stored in encrypted onion return.
+    fn handle_onion_response_2(&self, packet: OnionResponse2) -> impl Future<Item = (), Error = Error> + Send {
+        let onion_symmetric_key = self.onion_symmetric_key.read();
+        let payload = packet.onion_return.get_payload(&onion_symmetric_key);
+        let payload = match payload {
+            Err(e) => {
+                // Onion symmetric key is changed every 2 hours to enforce onion
+                // paths expiration. It means that we can get packets with old
+                // onion key. So we do not consider this as error.
+                trace!("Failed to decrypt onion_return from OnionResponse2: {}", e);
+                return Either::A(future::ok(()));
+            },
+            Ok(payload) => payload,
+        };
+
+        if let (ip_port, Some(next_onion_return)) = payload {
+            let next_packet = Packet::OnionResponse1(OnionResponse1 {
+                onion_return: next_onion_return,
+                payload: packet.payload
+            });
+            Either::B(self.send_to_direct(ip_port.to_saddr(), next_packet))
+        } else {
+            Either::A( future::err(
+                Error::new(ErrorKind::Other,
+                    "OnionResponse2 next_onion_return is none".to_string()
+            )))
+        }
+    }
+
+    /// Handle received `OnionResponse1` packet and send `OnionAnnounceResponse`
+    /// or `OnionDataResponse` packet to the next peer which address is stored
+    /// in encrypted onion return.
+    fn handle_onion_response_1(&self, packet: OnionResponse1) -> impl Future<Item = (), Error = Error> + Send {
+        let onion_symmetric_key = self.onion_symmetric_key.read();
+        let payload = packet.onion_return.get_payload(&onion_symmetric_key);
+        let payload = match payload {
+            Err(e) => {
+                // Onion symmetric key is changed every 2 hours to enforce onion
+                // paths expiration. It means that we can get packets with old
+                // onion key. So we do not consider this as error.
+                trace!("Failed to decrypt onion_return from OnionResponse1: {}", e);
+                return Box::new(future::ok(())) as Box<dyn Future<Item = _, Error = _> + Send>;
+            },
+            Ok(payload) => payload,
+        };
+
+        if let (ip_port, None) = payload {
+            match ip_port.protocol {
+                ProtocolType::UDP => {
+                    if let Some(ref onion_response_sink) = self.onion_response_sink {
+                        if self.is_own_addr(ip_port.to_saddr()) {
+                            return Box::new(self.deliver_onion_response(onion_response_sink, packet.payload));
+                        }
+                    }
+
+                    let next_packet = match packet.payload {
+                        InnerOnionResponse::OnionAnnounceResponse(inner) => Packet::OnionAnnounceResponse(inner),
+                        InnerOnionResponse::OnionDataResponse(inner) => Packet::OnionDataResponse(inner),
+                    };
+                    Box::new(self.send_to_direct(ip_port.to_saddr(), next_packet)) as Box<dyn Future<Item = _, Error = _> + Send>
+                },
+                ProtocolType::TCP => {
+                    if let Some(ref tcp_onion_sink) = self.tcp_onion_sink {
+                        self.trace_send(ip_port.to_saddr());
+                        Box::new(tcp_onion_sink.clone() // clone sink for 1 send only
+                            .send((packet.payload, ip_port.to_saddr()))
+                            .map(|_sink| ()) // ignore sink because it was cloned
+                            .map_err(|_| {
+                                // This may only happen if sink is gone
+                                // So cast SendError<T> to a corresponding std::io::Error
+                                Error::from(ErrorKind::UnexpectedEof)
+                            })
+                        )
+                    } else {
+                        Box::new( future::err(
+                            Error::new(ErrorKind::Other,
+                                "OnionResponse1 can't be redirected to TCP relay".to_string()
+                        )))
+                    }
+                },
+            }
+        } else {
+            Box::new( future::err(
+                Error::new(ErrorKind::Other,
+                    "OnionResponse1 next_onion_return is some".to_string()
+            )))
+        }
+    }
+
+    /// Get time passed since onion symmetric key was generated. The key is
+    /// refreshed every `ONION_REFRESH_KEY_INTERVAL` seconds and onion paths
+    /// going through our node expire after that.
+    pub fn onion_key_age(&self) -> Duration {
+        clock_elapsed(*self.onion_symmetric_key_time.read())
+    }
+
+    /// Refresh onion symmetric key to enforce onion paths expiration and
+    /// notify events sink about it.
+    fn refresh_onion_key(&self) {
+        *self.onion_symmetric_key.write() = secretbox::gen_key();
+        *self.onion_symmetric_key_time.write() = clock_now();
+
+        if let Some(ref event_sink) = self.event_sink {
+            if let Err(e) = event_sink.unbounded_send(DhtEvent::OnionKeyRotated) {
+                warn!("Failed to send OnionKeyRotated event: {}", e);
+            }
+        }
+    }
+
+    /// Add `PackedNode` to close nodes list.
+    #[cfg(test)]
+    fn try_add_to_close_nodes(&self, pn: &PackedNode) -> bool {
+        let mut close_nodes = self.close_nodes.write();
+        close_nodes.try_add(pn)
+    }
+
+    /// Handle `OnionRequest` from TCP relay and send `OnionRequest1` packet
+    /// to the next node in the onion path.
+    pub fn handle_tcp_onion_request(&self, packet: OnionRequest, addr: SocketAddr) -> impl Future<Item = (), Error = Error> + Send {
+        if !self.onion_relay_enabled || self.is_onion_relay_paused() {
+            trace!("Dropping TCP onion request since onion relay is disabled");
+            return Either::A(future::ok(()));
+        }
+
+        // payload is sent as is inside OnionRequest1 packet
+        if !self.check_onion_payload_size(packet.payload.len(), ONION_REQUEST_1_MAX_PAYLOAD_SIZE) {
+            return Either::A(future::ok(()));
+        }
+
+        if let Err(e) = self.check_onion_temporary_pk(&packet.temporary_pk) {
+            return Either::A(future::err(e));
+        }
+
+        if !self.check_onion_forwards_limit() {
+            return Either::A(future::ok(()));
+        }
+
+        let onion_symmetric_key = self.onion_symmetric_key.read();
+
+        let onion_return = OnionReturn::new(
+            &onion_symmetric_key,
+            &IpPort::from_tcp_saddr(addr),
+            None // no previous onion return
+        );
+        let next_packet = Packet::OnionRequest1(OnionRequest1 {
+            nonce: packet.nonce,
+            temporary_pk: packet.temporary_pk,
+            payload: packet.payload,
+            onion_return
+        });
+        Either::B(self.forward_onion_packet(packet.ip_port.to_saddr(), next_packet))
+    }
+
+    /// Handle `BootstrapInfo` packet and response with `BootstrapInfo` packet.
+    fn handle_bootstrap_info(&self, packet: &BootstrapInfo, addr: SocketAddr) -> impl Future<Item = (), Error = Error> + Send {
+        if packet.motd.len() != BOOSTRAP_CLIENT_MAX_MOTD_LENGTH {
+            return Either::A( future::err(
+                Error::new(ErrorKind::Other,
+                    "Wrong BootstrapInfo packet length".to_string()
+            )))
+        }
+
+        if let Some(ref bootstrap_info) = self.bootstrap_info {
+            // Version 0 means that requester didn't specify its version
+            if packet.version != 0 && packet.version != bootstrap_info.version {
+                debug!("BootstrapInfo request from {} has incompatible version {}", addr, packet.version);
+                self.incompatible_version_packets.fetch_add(1, Ordering::Relaxed);
+                if !self.incompatible_version_response_enabled {
+                    return Either::A(future::ok(()))
+                }
+            }
+
+            let mut motd = (bootstrap_info.motd_cb)(&self);
+            if motd.len() > BOOSTRAP_SERVER_MAX_MOTD_LENGTH {
+                warn!(
+                    "Too long MOTD: {} bytes. Truncating to {} bytes",
+                    motd.len(),
+                    BOOSTRAP_SERVER_MAX_MOTD_LENGTH
+                );
+                motd.truncate(BOOSTRAP_SERVER_MAX_MOTD_LENGTH);
+            }
+            let packet = Packet::BootstrapInfo(BootstrapInfo {
+                version: bootstrap_info.version,
+                motd,
+            });
+            Either::B(self.send_to_direct(addr, packet))
+        } else {
+            // Do not respond to BootstrapInfo packets if bootstrap_info not defined
+            Either::A(future::ok(()))
+        }
+    }
+
+    /// Set toxcore version and message of the day callback.
+    pub fn set_bootstrap_info(&mut self, version: u32, motd_cb: Box<Fn(&Server) -> Vec<u8> + Send + Sync>) {
+        self.bootstrap_info = Some(ServerBootstrapInfo {
+            version,
+            motd_cb: motd_cb.into(),
+        });
+    }
+
+    /// Set TCP sink for onion packets.
+    pub fn set_tcp_onion_sink(&mut self, tcp_onion_sink: TcpOnionTx) {
+        self.tcp_onion_sink = Some(tcp_onion_sink)
+    }
+
+    /// Set sink for onion responses addressed to our own node. Both responses
+    /// redirected by the last onion hop and `OnionAnnounceResponse` or
+    /// `OnionDataResponse` packets received directly are delivered to it.
+    pub fn set_onion_response_sink(&mut self, onion_response_sink: OnionResponseTx) {
+        self.onion_response_sink = Some(onion_response_sink)
+    }
+
+    /// Set address our UDP socket is bound to. When the socket is bound to
+    /// an unspecified address like `0.0.0.0` or `[::]` addresses of all
+    /// network interfaces with the same port are considered local. IPv6
+    /// socket is assumed to be dual-stack.
+    pub fn set_local_addr(&mut self, local_addr: SocketAddr) {
+        if !local_addr.ip().is_unspecified() {
+            self.local_addrs = vec![local_addr];
+            return;
+        }
+
+        let ips = match get_if_addrs::get_if_addrs() {
+            Ok(interfaces) => interfaces.into_iter().map(|interface| interface.ip()).collect(),
+            Err(e) => {
+                warn!("Failed to get addresses of network interfaces: {}", e);
+                Vec::new()
+            },
+        };
+        self.local_addrs = ips.into_iter()
+            .filter(|ip| local_addr.is_ipv6() || ip.is_ipv4())
+            .map(|ip| SocketAddr::new(ip, local_addr.port()))
+            .collect();
+    }
+
+    /// Check if the address belongs to our own node, i.e. it's the address
+    /// our socket is reachable on locally or the external address confirmed
+    /// by several nodes. Unconfirmed reports are not trusted since otherwise
+    /// a single node could make us swallow responses we have to relay.
+    fn is_own_addr(&self, addr: SocketAddr) -> bool {
+        // dual-stack socket sees IPv4 addresses as IPv4-mapped IPv6 ones
+        let ipv4_addr = match addr.ip() {
+            IpAddr::V6(ip) => ip.to_ipv4().map(|ip| SocketAddr::new(IpAddr::V4(ip), addr.port())),
+            IpAddr::V4(_) => None,
+        };
+        let is_own = |addrs: &[SocketAddr]| addrs.contains(&addr) || ipv4_addr.map_or(false, |ipv4_addr| addrs.contains(&ipv4_addr));
+        is_own(&self.local_addrs) || is_own(&self.external_addrs().confirmed_addrs())
+    }
+
+    /// Send onion response to the local onion client.
+    fn deliver_onion_response(&self, onion_response_sink: &OnionResponseTx, response: InnerOnionResponse) -> impl Future<Item = (), Error = Error> + Send {
+        future::result(onion_response_sink.unbounded_send(response)
+            .map_err(|e| Error::new(ErrorKind::Other, format!("Failed to deliver onion response: {}", e))))
+    }
+
+    /// Set sink for DHT events.
+    pub fn set_event_sink(&mut self, event_sink: EventTx) {
+        self.event_sink = Some(event_sink)
+    }
+
+    /// Set sink for packets that were not queued for sending. Destination of
+    /// every packet that couldn't be passed to the outgoing channel is
+    /// reported to it with the error. It happens when the receiver of the
+    /// channel is dropped or doesn't take packets for `DHT_SEND_TIMEOUT`
+    /// seconds, so it shows backpressure of the socket writer. Errors of the
+    /// socket itself and ICMP errors happen after the packet left the channel
+    /// and are not reported here.
+    pub fn set_queue_error_sink(&mut self, queue_error_sink: QueueErrorTx) {
+        self.queue_error_sink = Some(queue_error_sink)
+    }
+
+    /// Set sink for onion path nodes. Nodes with global addresses that
+    /// responded to our `NodesRequest` are sent to it so that onion client can
+    /// build paths through them.
+    pub fn set_path_node_sink(&mut self, path_node_sink: PathNodeTx) {
+        self.path_node_sink = Some(path_node_sink)
+    }
+
+    /// Remove nodes with given address from close nodes lists. Returns the
+    /// number of removed nodes.
+    pub fn remove_node_by_addr(&self, addr: SocketAddr) -> usize {
+        let mut removed = 0;
+
+        let mut close_nodes = self.close_nodes.write();
+        let pks = close_nodes.iter()
+            .filter(|node| node.get_all_addrs().contains(&addr))
+            .map(|node| node.pk)
+            .collect::<Vec<_>>();
+        for pk in pks {
+            if close_nodes.remove(&pk).is_some() {
+                removed += 1;
+            }
+        }
+
+        for friend in self.friends.write().iter_mut() {
+            let pks = friend.close_nodes.nodes.iter()
+                .filter(|node| node.get_all_addrs().contains(&addr))
+                .map(|node| node.pk)
+                .collect::<Vec<_>>();
+            for pk in pks {
+                if friend.close_nodes.remove(&friend.pk, &pk).is_some() {
+                    removed += 1;
+                }
+            }
+        }
+
+        if removed > 0 {
+            self.invalidate_nodes_resp_cache();
+        }
+
+        removed
+    }
+
+    /// Set `net_crypto` module.
+    pub fn set_net_crypto(&mut self, net_crypto: NetCrypto) {
+        self.net_crypto = Some(net_crypto);
+    }
+
+    /// Get `PrecomputedKey`s cache.
+    pub fn get_precomputed_keys(&self) -> PrecomputedCache {
+        self.precomputed_keys.clone()
+    }
+
+    /// Get the number of nodes in close nodes list for every non-empty
+    /// kbucket. Each pair contains kbucket index, i.e. the number of leading
+    /// bits that node's `PublicKey` shares with our own `PublicKey`, and the
+    /// number of nodes in it. Can be used to check whether close nodes are
+    /// spread over a wide range of distances.
+    pub fn routing_coverage(&self) -> Vec<(u32, usize)> {
+        self.bucket_fill().into_iter()
+            .filter(|&(_, count)| count > 0)
+            .map(|(index, count)| (index as u32, count))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::Future;
+    use std::net::SocketAddr;
+
+    use tokio::util::FutureExt;
+    use tokio_executor;
+    use tokio_timer::clock::*;
+
+    use crate::toxcore::binary_io::*;
+    use crate::toxcore::dht::lan_discovery::{DEFAULT_PORT, PORTS_PER_DISCOVERY, START_PORT};
+    use crate::toxcore::time::{ConstNow, MutNow};
+
+    const ONION_RETURN_1_PAYLOAD_SIZE: usize = ONION_RETURN_1_SIZE - secretbox::NONCEBYTES;
+    const ONION_RETURN_2_PAYLOAD_SIZE: usize = ONION_RETURN_2_SIZE - secretbox::NONCEBYTES;
+    const ONION_RETURN_3_PAYLOAD_SIZE: usize = ONION_RETURN_3_SIZE - secretbox::NONCEBYTES;
+
+    fn create_node() -> (Server, PrecomputedKey, PublicKey, SecretKey,
+            mpsc::Receiver<(Packet, SocketAddr)>, SocketAddr) {
+        crypto_init().unwrap();
+
+        let (pk, sk) = gen_keypair();
+        let (tx, rx) = mpsc::channel(32);
+        let alice = Server::new(tx, pk, sk);
+        let (bob_pk, bob_sk) = gen_keypair();
+        let precomp = precompute(&alice.pk, &bob_sk);
+
+        let addr: SocketAddr = "127.0.0.1:12346".parse().unwrap();
+        (alice, precomp, bob_pk, bob_sk, rx, addr)
+    }
+
+    /// Onion path with fixed nodes and temporary keys. It wraps requests the
+    /// same way onion client does so that tests can check exact packets
+    /// relayed by every hop.
+    struct TestOnionPath {
+        /// `PublicKey` and address of every node of the path.
+        nodes: [(PublicKey, SocketAddr); 3],
+        /// Temporary key pairs used to encrypt payload for every node.
+        temporary_keys: [(PublicKey, SecretKey); 3],
+    }
+
+    impl TestOnionPath {
+        fn seal<P: ToBytes>(payload: &P, nonce: &Nonce, shared_secret: &PrecomputedKey) -> Vec<u8> {
+            let mut buf = [0; ONION_MAX_PACKET_SIZE];
+            let (_, size) = payload.to_bytes((&mut buf, 0)).unwrap();
+            seal_precomputed(&buf[..size], nonce, shared_secret)
+        }
+
+        /// Wrap inner request to `OnionRequest0` for the first node of the
+        /// path. All layers are encrypted with the same nonce since relays
+        /// pass it on unchanged.
+        fn wrap(&self, nonce: Nonce, destination: SocketAddr, inner: InnerOnionRequest) -> OnionRequest0 {
+            let precomputed_key = |i: usize| precompute(&self.nodes[i].0, &self.temporary_keys[i].1);
+
+            let payload_2 = TestOnionPath::seal(&OnionRequest2Payload {
+                ip_port: IpPort::from_udp_saddr(destination),
+                inner,
+            }, &nonce, &precomputed_key(2));
+            let payload_1 = TestOnionPath::seal(&OnionRequest1Payload {
+                ip_port: IpPort::from_udp_saddr(self.nodes[2].1),
+                temporary_pk: self.temporary_keys[2].0,
+                inner: payload_2,
+            }, &nonce, &precomputed_key(1));
+            let payload_0 = TestOnionPath::seal(&OnionRequest0Payload {
+                ip_port: IpPort::from_udp_saddr(self.nodes[1].1),
+                temporary_pk: self.temporary_keys[1].0,
+                inner: payload_1,
+            }, &nonce, &precomputed_key(0));
+
+            OnionRequest0 {
+                nonce,
+                temporary_pk: self.temporary_keys[0].0,
+                payload: payload_0,
+            }
+        }
+    }
+
+    #[test]
+    fn onion_request_relayed_by_three_servers() {
+        crypto_init().unwrap();
+
+        let mut servers = Vec::new();
+        let mut nodes = Vec::new();
+        for i in 0 .. 3 {
+            let (pk, sk) = gen_keypair();
+            let (tx, rx) = mpsc::channel(32);
+            let addr = SocketAddr::new("127.0.0.1".parse().unwrap(), 33445 + i);
+            nodes.push((pk, addr));
+            servers.push((Server::new(tx, pk, sk), rx));
+        }
+        let path = TestOnionPath {
+            nodes: [nodes[0], nodes[1], nodes[2]],
+            temporary_keys: [gen_keypair(), gen_keypair(), gen_keypair()],
+        };
+
+        let client_addr = "127.0.0.1:12345".parse().unwrap();
+        let destination = "127.0.0.1:54321".parse().unwrap();
+        let nonce = gen_nonce();
+        let inner = InnerOnionAnnounceRequest {
+            nonce: gen_nonce(),
+            pk: gen_keypair().0,
+            payload: vec![42; 123]
+        };
+        let mut packet = Packet::OnionRequest0(path.wrap(nonce, destination, InnerOnionRequest::InnerOnionAnnounceRequest(inner.clone())));
+        let mut from = client_addr;
+
+        // every server peels one layer and passes the request to the next hop
+        for (i, (server, rx)) in servers.into_iter().enumerate() {
+            server.handle_packet(packet, from).wait().unwrap();
+            drop(server);
+            let mut sent = rx.collect().wait().unwrap();
+            assert_eq!(sent.len(), 1);
+            let (next_packet, addr_to_send) = sent.remove(0);
+            match next_packet {
+                Packet::OnionRequest1(ref request) => {
+                    assert_eq!(i, 0);
+                    assert_eq!(request.nonce, nonce);
+                    assert_eq!(request.temporary_pk, path.temporary_keys[1].0);
+                    assert_eq!(addr_to_send, path.nodes[1].1);
+                },
+                Packet::OnionRequest2(ref request) => {
+                    assert_eq!(i, 1);
+                    assert_eq!(request.nonce, nonce);
+                    assert_eq!(request.temporary_pk, path.temporary_keys[2].0);
+                    assert_eq!(addr_to_send, path.nodes[2].1);
+                },
+                Packet::OnionAnnounceRequest(ref request) => {
+                    assert_eq!(i, 2);
+                    assert_eq!(request.inner, inner);
+                    assert_eq!(addr_to_send, destination);
+                },
+                ref packet => panic!("Unexpected packet {:?}", packet),
+            }
+            packet = next_packet;
+            from = path.nodes[i].1;
+        }
+    }
+
+    #[test]
+    fn server_is_clonable() {
+        crypto_init().unwrap();
+        let (pk, sk) = gen_keypair();
+        let (tx, _rx) = mpsc::channel(1);
+        let server = Server::new(tx, pk, sk);
+
+        let _ = server.clone();
+    }
+
+    #[test]
+    fn add_friend_test() {
+        let (alice, _precomp, bob_pk, _bob_sk, _rx, _addr) = create_node();
+
+        let packed_node = PackedNode::new("211.192.153.67:33445".parse().unwrap(), &bob_pk);
+        assert!(alice.try_add_to_close_nodes(&packed_node));
+
+        let friend_pk = gen_keypair().0;
+        alice.add_friend(friend_pk);
+
+        let inserted_friend = &alice.friends.read()[FAKE_FRIENDS_NUMBER];
+        assert!(inserted_friend.nodes_to_bootstrap.contains(&friend_pk, &bob_pk));
+    }
+
+    #[test]
+    fn add_friend_self() {
+        let (alice, _precomp, _bob_pk, _bob_sk, _rx, _addr) = create_node();
+
+        alice.add_friend(alice.pk);
+
+        assert_eq!(alice.friends.read().len(), FAKE_FRIENDS_NUMBER);
+    }
+
+    // handle_packet_traced
+    #[test]
+    fn handle_packet_traced_ping_req() {
+        let (alice, precomp, bob_pk, _bob_sk, rx, addr) = create_node();
+
+        let ping_req = Packet::PingRequest(PingRequest::new(&precomp, &bob_pk, &PingRequestPayload { id: 42 }));
+
+        let decision = alice.handle_packet_traced(ping_req, addr).wait().unwrap();
+        assert_eq!(decision, RoutingDecision::RepliedTo(addr));
+
+        // intercepted response is still sent
+        let (received, _rx) = rx.into_future().wait().unwrap();
+        let (packet, addr_to_send) = received.unwrap();
+
+        assert_eq!(addr_to_send, addr);
+        unpack!(packet, Packet::PingResponse);
+    }
+
+    #[test]
+    fn handle_packet_traced_onion_request_0() {
+        let (alice, precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
+
+        let ip_port = IpPort {
+            protocol: ProtocolType::UDP,
+            ip_addr: "5.6.7.8".parse().unwrap(),
+            port: 12345
+        };
+        let payload = OnionRequest0Payload {
+            ip_port: ip_port.clone(),
+            temporary_pk: gen_keypair().0,
+            inner: vec![42; 123]
+        };
+        let packet = Packet::OnionRequest0(OnionRequest0::new(&precomp, &bob_pk, &payload));
+
+        let decision = alice.handle_packet_traced(packet, addr).wait().unwrap();
+        assert_eq!(decision, RoutingDecision::ForwardedTo(vec![ip_port.to_saddr()]));
+    }
+
+    #[test]
+    fn handle_packet_traced_onion_response_1_redirect_to_tcp() {
+        let (mut alice, _precomp, _bob_pk, _bob_sk, _rx, addr) = create_node();
+        let (tcp_onion_tx, _tcp_onion_rx) = mpsc::channel(1);
+        alice.set_tcp_onion_sink(tcp_onion_tx);
+
+        let ip_port = IpPort {
+            protocol: ProtocolType::TCP,
+            ip_addr: "5.6.7.8".parse().unwrap(),
+            port: 12345
+        };
+        let onion_return = OnionReturn::new(&alice.onion_symmetric_key.read(), &ip_port, None);
+        let packet = Packet::OnionResponse1(OnionResponse1 {
+            onion_return,
+            payload: InnerOnionResponse::OnionAnnounceResponse(OnionAnnounceResponse {
+                sendback_data: 12345,
+                nonce: gen_nonce(),
+                payload: vec![42; 123]
+            })
+        });
+
+        let decision = alice.handle_packet_traced(packet, addr).wait().unwrap();
+        assert_eq!(decision, RoutingDecision::ForwardedTo(vec![ip_port.to_saddr()]));
+    }
+
+    #[test]
+    fn handle_packet_traced_ping_resp() {
+        let (alice, precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
+
+        let ping_id = alice.request_queue.write().new_ping_id(bob_pk);
+        let ping_resp = Packet::PingResponse(PingResponse::new(&precomp, &bob_pk, &PingResponsePayload { id: ping_id }));
+
+        let decision = alice.handle_packet_traced(ping_resp, addr).wait().unwrap();
+        assert_eq!(decision, RoutingDecision::DeliveredLocally);
+    }
+
+    #[test]
+    fn handle_packet_traced_dropped() {
+        let (mut alice, precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
+
+        let ping_req = Packet::PingRequest(PingRequest {
+            pk: bob_pk,
+            nonce: gen_nonce(),
+            payload: vec![42; 123]
+        });
+
+        let decision = alice.handle_packet_traced(ping_req, addr).wait().unwrap();
+        unpack!(decision, RoutingDecision::Dropped);
+
+        alice.set_role(NodeRole::BootstrapOnly);
+
+        let cookie_req = Packet::CookieRequest(CookieRequest::new(&precomp, &bob_pk, &CookieRequestPayload {
+            pk: gen_keypair().0,
+            id: 42
+        }));
+
+        let decision = alice.handle_packet_traced(cookie_req, addr).wait().unwrap();
+        assert_eq!(decision, RoutingDecision::Dropped("packet handler is disabled".to_owned()));
+    }
+
+    // handle_bootstrap_info
+    #[test]
+    fn handle_bootstrap_info() {
+        let (mut alice, _precomp, _bob_pk, _bob_sk, rx, addr) = create_node();
+
+        let version = 42;
+        let motd = b"motd".to_vec();
+        let motd_c = motd.clone();
+
+        alice.set_bootstrap_info(version, Box::new(move |_| motd_c.clone()));
+
+        let packet = Packet::BootstrapInfo(BootstrapInfo {
+            version: 00,
+            motd: vec![0; BOOSTRAP_CLIENT_MAX_MOTD_LENGTH],
+        });
+
+        alice.handle_packet(packet, addr).wait().unwrap();
+
+        let (received, _rx) = rx.into_future().wait().unwrap();
+        let (packet, addr_to_send) = received.unwrap();
+
+        assert_eq!(addr_to_send, addr);
+
+        let bootstrap_info = unpack!(packet, Packet::BootstrapInfo);
+
+        assert_eq!(bootstrap_info.version, version);
+        assert_eq!(bootstrap_info.motd, motd);
+    }
+
+    #[test]
+    fn handle_bootstrap_info_incompatible_version() {
+        let (mut alice, _precomp, _bob_pk, _bob_sk, rx, addr) = create_node();
+
+        alice.set_bootstrap_info(42, Box::new(|_| b"motd".to_vec()));
+
+        let packet = Packet::BootstrapInfo(BootstrapInfo {
+            version: 43,
+            motd: vec![0; BOOSTRAP_CLIENT_MAX_MOTD_LENGTH],
+        });
+
+        alice.handle_packet(packet.clone(), addr).wait().unwrap();
+        assert_eq!(alice.incompatible_version_packets(), 1);
+
+        alice.enable_incompatible_version_response(false);
+        alice.handle_packet(packet, addr).wait().unwrap();
+        assert_eq!(alice.incompatible_version_packets(), 2);
+
+        // Necessary to drop tx so that rx.collect() can be finished
+        drop(alice);
+
+        // only the first request is answered
+        let packets = rx.collect().wait().unwrap();
+        assert_eq!(packets.len(), 1);
+        unpack!(packets[0].0.clone(), Packet::BootstrapInfo);
+    }
+
+    #[test]
+    fn bootstrap_only_role_answers_dht_requests() {
+        let (mut alice, precomp, bob_pk, _bob_sk, rx, addr) = create_node();
+
+        alice.set_role(NodeRole::BootstrapOnly);
+        alice.set_bootstrap_info(42, Box::new(|_| b"motd".to_vec()));
+
+        let ping_req = Packet::PingRequest(PingRequest::new(&precomp, &bob_pk, &PingRequestPayload { id: 42 }));
+        alice.handle_packet(ping_req, addr).wait().unwrap();
+
+        let nodes_req = Packet::NodesRequest(NodesRequest::new(&precomp, &bob_pk, &NodesRequestPayload { pk: bob_pk, id: 42 }));
+        alice.handle_packet(nodes_req, addr).wait().unwrap();
+
+        let bootstrap_info = Packet::BootstrapInfo(BootstrapInfo {
+            version: 00,
+            motd: vec![0; BOOSTRAP_CLIENT_MAX_MOTD_LENGTH],
+        });
+        alice.handle_packet(bootstrap_info, addr).wait().unwrap();
+
+        // Necessary to drop tx so that rx.collect() can be finished
+        drop(alice);
+
+        let packets = rx.collect().wait().unwrap();
+        assert_eq!(packets.len(), 3);
+        unpack!(packets[0].0.clone(), Packet::PingResponse);
+        unpack!(packets[1].0.clone(), Packet::NodesResponse);
+        unpack!(packets[2].0.clone(), Packet::BootstrapInfo);
+    }
+
+    #[test]
+    fn bootstrap_only_role_drops_cookie_and_onion_requests() {
+        let (udp_tx, rx) = mpsc::channel(32);
+        let (dht_pk, dht_sk) = gen_keypair();
+        let mut alice = Server::with_role(udp_tx, dht_pk, dht_sk, NodeRole::BootstrapOnly);
+        let (bob_pk, bob_sk) = gen_keypair();
+        let precomp = precompute(&alice.pk, &bob_sk);
+        let addr = "127.0.0.1:12346".parse().unwrap();
+
+        let cookie_request_payload = CookieRequestPayload {
+            pk: gen_keypair().0,
+            id: 12345,
+        };
+        let cookie_request = Packet::CookieRequest(CookieRequest::new(&precomp, &bob_pk, &cookie_request_payload));
+        alice.handle_packet(cookie_request, addr).wait().unwrap();
+
+        let payload = OnionRequest0Payload {
+            ip_port: IpPort {
+                protocol: ProtocolType::UDP,
+                ip_addr: "5.6.7.8".parse().unwrap(),
+                port: 12345
+            },
+            temporary_pk: gen_keypair().0,
+            inner: vec![42; 123]
+        };
+        let onion_request = Packet::OnionRequest0(OnionRequest0::new(&precomp, &bob_pk, &payload));
+        alice.handle_packet(onion_request, addr).wait().unwrap();
+
+        let friend_pk = gen_keypair().0;
+        alice.add_friend(friend_pk);
+        assert!(alice.friends.read().iter().all(|friend| friend.pk != friend_pk));
+
+        // onion relay is enabled back explicitly
+        alice.enable_onion_relay(true);
+        let onion_request = Packet::OnionRequest0(OnionRequest0::new(&precomp, &bob_pk, &payload));
+        alice.handle_packet(onion_request, addr).wait().unwrap();
+
+        // Necessary to drop tx so that rx.collect() can be finished
+        drop(alice);
+
+        let packets = rx.collect().wait().unwrap();
+        assert_eq!(packets.len(), 1);
+        unpack!(packets[0].0.clone(), Packet::OnionRequest1);
+    }
+
+    #[test]
+    fn handle_bootstrap_info_wrong_length() {
+        let (mut alice, _precomp, _bob_pk, _bob_sk, rx, addr) = create_node();
+
+        let version = 42;
+        let motd = b"motd".to_vec();
+
+        alice.set_bootstrap_info(version, Box::new(move |_| motd.clone()));
+
+        let packet = Packet::BootstrapInfo(BootstrapInfo {
+            version: 00,
+            motd: Vec::new(),
+        });
+
+        assert!(alice.handle_packet(packet, addr).wait().is_err());
+
+        // Necessary to drop tx so that rx.collect() can be finished
+        drop(alice);
+
+        assert!(rx.collect().wait().unwrap().is_empty());
+    }
+
+    // handle_ping_req
+    #[test]
+    fn handle_ping_req() {
+        let (alice, precomp, bob_pk, bob_sk, rx, addr) = create_node();
+
+        let req_payload = PingRequestPayload { id: 42 };
+        let ping_req = Packet::PingRequest(PingRequest::new(&precomp, &bob_pk, &req_payload));
+
+        alice.handle_packet(ping_req, addr).wait().unwrap();
+
+        let (received, _rx) = rx.into_future().wait().unwrap();
+        let (packet, addr_to_send) = received.unwrap();
+
+        assert_eq!(addr_to_send, addr);
+
+        let ping_resp = unpack!(packet, Packet::PingResponse);
+        let precomputed_key = precompute(&ping_resp.pk, &bob_sk);
+        let ping_resp_payload = ping_resp.get_payload(&precomputed_key).unwrap();
+
+        assert_eq!(ping_resp_payload.id, req_payload.id);
+
+        assert!(alice.nodes_to_ping.read().contains(&alice.pk, &bob_pk));
+    }
+
+    #[test]
+    fn handle_ping_req_respond_only_mode() {
+        let (mut alice, precomp, bob_pk, _bob_sk, rx, addr) = create_node();
+
+        alice.enable_respond_only_mode(true);
+
+        let req_payload = PingRequestPayload { id: 42 };
+        let ping_req = Packet::PingRequest(PingRequest::new(&precomp, &bob_pk, &req_payload));
+
+        alice.handle_packet(ping_req, addr).wait().unwrap();
+
+        let (received, _rx) = rx.into_future().wait().unwrap();
+        let (packet, addr_to_send) = received.unwrap();
+
+        assert_eq!(addr_to_send, addr);
+        unpack!(packet, Packet::PingResponse);
+
+        assert!(!alice.nodes_to_ping.read().contains(&alice.pk, &bob_pk));
+    }
+
+    #[test]
+    fn handle_ping_req_uses_precomputed_cache() {
+        let (alice, precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
+
+        // put a wrong key to the cache so that the request can be decrypted
+        // only if the key is precomputed from scratch
+        let (_, eve_sk) = gen_keypair();
+        alice.precomputed_keys.insert(bob_pk, precompute(&alice.pk, &eve_sk));
+
+        let req_payload = PingRequestPayload { id: 42 };
+        let ping_req = Packet::PingRequest(PingRequest::new(&precomp, &bob_pk, &req_payload));
+
+        assert!(alice.handle_packet(ping_req, addr).wait().is_err());
+    }
+
+    #[test]
+    fn handle_ping_req_from_allowed_network() {
+        let (alice, precomp, bob_pk, bob_sk, rx, addr) = create_node();
+
+        alice.set_allowed_networks(vec!["127.0.0.0/8".parse().unwrap()]);
+
+        let req_payload = PingRequestPayload { id: 42 };
+        let ping_req = Packet::PingRequest(PingRequest::new(&precomp, &bob_pk, &req_payload));
+
+        alice.handle_packet(ping_req, addr).wait().unwrap();
+
+        let (received, _rx) = rx.into_future().wait().unwrap();
+        let (packet, addr_to_send) = received.unwrap();
+
+        assert_eq!(addr_to_send, addr);
+
+        let ping_resp = unpack!(packet, Packet::PingResponse);
+        let precomputed_key = precompute(&ping_resp.pk, &bob_sk);
+        let ping_resp_payload = ping_resp.get_payload(&precomputed_key).unwrap();
+
+        assert_eq!(ping_resp_payload.id, req_payload.id);
+    }
+
+    #[test]
+    fn handle_ping_req_from_not_allowed_network() {
+        let (alice, precomp, bob_pk, _bob_sk, rx, addr) = create_node();
+
+        alice.set_allowed_networks(vec!["10.0.0.0/8".parse().unwrap()]);
+
+        let req_payload = PingRequestPayload { id: 42 };
+        let ping_req = Packet::PingRequest(PingRequest::new(&precomp, &bob_pk, &req_payload));
+
+        alice.handle_packet(ping_req, addr).wait().unwrap();
+
+        assert!(!alice.nodes_to_ping.read().contains(&alice.pk, &bob_pk));
+
+        drop(alice);
+        assert!(rx.collect().wait().unwrap().is_empty());
+    }
+
+    #[test]
+    fn inbound_filter() {
+        let (alice, precomp, bob_pk, _bob_sk, rx, addr) = create_node();
+
+        alice.set_inbound_filter(Box::new(|packet, _addr| match *packet {
+            Packet::PingRequest(_) => false,
+            _ => true,
+        }));
+
+        let req_payload = PingRequestPayload { id: 42 };
+        let ping_req = Packet::PingRequest(PingRequest::new(&precomp, &bob_pk, &req_payload));
+        alice.handle_packet(ping_req, addr).wait().unwrap();
+
+        let req_payload = NodesRequestPayload { pk: bob_pk, id: 42 };
+        let nodes_req = Packet::NodesRequest(NodesRequest::new(&precomp, &bob_pk, &req_payload));
+        alice.handle_packet(nodes_req, addr).wait().unwrap();
+
+        drop(alice);
+        let packets = rx.collect().wait().unwrap();
+
+        // only NodesRequest is handled
+        assert_eq!(packets.len(), 1);
+        let (packet, _addr) = packets[0].clone();
+        unpack!(packet, Packet::NodesResponse);
+    }
+
+    #[test]
+    fn outbound_filter() {
+        let (alice, _precomp, bob_pk, _bob_sk, rx, addr) = create_node();
+
+        alice.set_outbound_filter(Box::new(|packet, _addr| match *packet {
+            Packet::LanDiscovery(_) => false,
+            _ => true,
+        }));
+
+        let lan_discovery = Packet::LanDiscovery(LanDiscovery { pk: bob_pk });
+        alice.send_to_direct(addr, lan_discovery).wait().unwrap();
+
+        let bootstrap_info = Packet::BootstrapInfo(BootstrapInfo { version: 42, motd: vec![1, 2, 3] });
+        alice.send_to_direct(addr, bootstrap_info.clone()).wait().unwrap();
+
+        drop(alice);
+        let packets = rx.collect().wait().unwrap();
+
+        assert_eq!(packets, vec![(bootstrap_info, addr)]);
+    }
+
+    #[test]
+    fn send_filter() {
+        let (alice, _precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
+
+        let send_filter = alice.send_filter();
+        let lan_discovery = Packet::LanDiscovery(LanDiscovery { pk: bob_pk });
+        assert!(send_filter(&lan_discovery, addr));
+
+        // filters set after the send filter was taken are applied as well
+        alice.set_outbound_filter(Box::new(|packet, _addr| match *packet {
+            Packet::LanDiscovery(_) => false,
+            _ => true,
+        }));
+        assert!(!send_filter(&lan_discovery, addr));
+
+        alice.set_allowed_networks(vec!["10.0.0.0/8".parse().unwrap()]);
+        let bootstrap_info = Packet::BootstrapInfo(BootstrapInfo { version: 42, motd: vec![1, 2, 3] });
+        assert!(!send_filter(&bootstrap_info, addr));
+        assert!(send_filter(&bootstrap_info, "10.1.2.3:33445".parse().unwrap()));
+    }
+
+    #[test]
+    fn send_to_not_allowed_network() {
+        let (alice, _precomp, bob_pk, _bob_sk, rx, _addr) = create_node();
+
+        alice.set_allowed_networks(vec!["10.0.0.0/8".parse().unwrap()]);
+
+        let packet = Packet::LanDiscovery(LanDiscovery { pk: bob_pk });
+        assert!(alice.send_to_direct("127.0.0.1:33445".parse().unwrap(), packet.clone()).wait().is_err());
+        alice.send_to_direct("10.1.2.3:33445".parse().unwrap(), packet.clone()).wait().unwrap();
+
+        alice.reset_allowed_networks();
+        alice.send_to_direct("127.0.0.1:33445".parse().unwrap(), packet).wait().unwrap();
+
+        drop(alice);
+        let addrs = rx.collect().wait().unwrap().into_iter()
+            .map(|(_packet, addr)| addr)
+            .collect::<Vec<_>>();
+        assert_eq!(addrs, vec!["10.1.2.3:33445".parse().unwrap(), "127.0.0.1:33445".parse().unwrap()]);
+    }
+
+    #[test]
+    fn can_reach() {
+        let (mut alice, _precomp, _bob_pk, _bob_sk, _rx, _addr) = create_node();
+
+        let ipv4 = "1.2.3.4:33445".parse().unwrap();
+        let ipv6 = "[2001:db8::1]:33445".parse().unwrap();
+        let ipv4_mapped = "[::ffff:1.2.3.4]:33445".parse().unwrap();
+
+        // IPv4 mode
+        assert!(alice.can_reach(ipv4));
+        assert!(!alice.can_reach(ipv6));
+        assert!(alice.can_reach(ipv4_mapped));
+
+        // IPv6 mode
+        alice.enable_ipv6_mode(true);
+        assert!(alice.can_reach(ipv4));
+        assert!(alice.can_reach(ipv6));
+        assert!(alice.can_reach(ipv4_mapped));
+
+        // IPv6 mode with allowed networks
+        alice.set_allowed_networks(vec!["1.0.0.0/8".parse().unwrap()]);
+        assert!(alice.can_reach(ipv4));
+        assert!(!alice.can_reach(ipv6));
+        assert!(alice.can_reach(ipv4_mapped));
+        assert!(!alice.can_reach("2.3.4.5:33445".parse().unwrap()));
+        assert!(!alice.can_reach("[::ffff:2.3.4.5]:33445".parse().unwrap()));
+    }
+
+    #[test]
+    fn send_to_reports_queue_error() {
+        let (mut alice, _precomp, bob_pk, _bob_sk, rx, _addr) = create_node();
+
+        let (queue_error_tx, queue_error_rx) = mpsc::unbounded();
+        alice.set_queue_error_sink(queue_error_tx);
+
+        // closed channel makes every send fail
+        drop(rx);
+
+        let addr = "127.1.1.1:12345".parse().unwrap();
+        let packet = Packet::LanDiscovery(LanDiscovery { pk: bob_pk });
+        assert!(alice.send_to_direct(addr, packet).wait().is_err());
+
+        drop(alice);
+        let failed_addrs = queue_error_rx.collect().wait().unwrap().into_iter()
+            .map(|(addr, _error)| addr)
+            .collect::<Vec<_>>();
+        assert_eq!(failed_addrs, vec![addr]);
+    }
+
+    #[test]
+    fn remove_node_by_addr() {
+        let (alice, _precomp, bob_pk, _bob_sk, _rx, _addr) = create_node();
+
+        let addr = "127.1.1.1:12345".parse().unwrap();
+        let pn = PackedNode::new(addr, &bob_pk);
+        let other_pn = PackedNode::new("127.1.1.2:12345".parse().unwrap(), &gen_keypair().0);
+        assert!(alice.close_nodes.write().try_add(&pn));
+        assert!(alice.close_nodes.write().try_add(&other_pn));
+
+        let friend_pk = gen_keypair().0;
+        alice.add_friend(friend_pk);
+        assert!(alice.friends.write()[FAKE_FRIENDS_NUMBER].try_add_to_close(&pn));
+
+        assert_eq!(alice.remove_node_by_addr(addr), 2);
+
+        assert!(!alice.close_nodes.read().contains(&bob_pk));
+        assert!(alice.close_nodes.read().contains(&other_pn.pk));
+        assert!(!alice.friends.read()[FAKE_FRIENDS_NUMBER].close_nodes.contains(&friend_pk, &bob_pk));
+    }
+
+    #[test]
+    fn send_to_ipv6_in_ipv4_mode() {
+        let (alice, _precomp, bob_pk, _bob_sk, rx, _addr) = create_node();
+
+        let packet = Packet::LanDiscovery(LanDiscovery { pk: bob_pk });
+        assert!(alice.send_to_direct("[2001:db8::1]:33445".parse().unwrap(), packet).wait().is_err());
+
+        drop(alice);
+        assert!(rx.collect().wait().unwrap().is_empty());
+    }
+
+    #[test]
+    fn handle_ping_req_from_self() {
+        let (alice, _precomp, _bob_pk, _bob_sk, rx, addr) = create_node();
+
+        let precomp = precompute(&alice.pk, &alice.sk);
+        let req_payload = PingRequestPayload { id: 42 };
+        let ping_req = Packet::PingRequest(PingRequest::new(&precomp, &alice.pk, &req_payload));
+
+        alice.handle_packet(ping_req, addr).wait().unwrap();
+
+        assert_eq!(alice.self_requests(), 1);
+        assert!(alice.nodes_to_ping.read().is_empty());
+
+        drop(alice);
+        assert!(rx.collect().wait().unwrap().is_empty());
+    }
+
+    #[test]
+    fn handle_ping_req_from_friend_with_unknown_addr() {
+        let (alice, precomp, bob_pk, bob_sk, rx, addr) = create_node();
+
+        alice.add_friend(bob_pk);
+
+        let req_payload = PingRequestPayload { id: 42 };
+        let ping_req = Packet::PingRequest(PingRequest::new(&precomp, &bob_pk, &req_payload));
+
+        alice.handle_packet(ping_req, addr).wait().unwrap();
+
+        let mut request_queue = alice.request_queue.write();
+
+        rx.take(2).map(|(packet, addr_to_send)| {
+            assert_eq!(addr_to_send, addr);
+
+            if let Packet::PingResponse(ping_resp) = packet {
+                let precomputed_key = precompute(&ping_resp.pk, &bob_sk);
+                let ping_resp_payload = ping_resp.get_payload(&precomputed_key).unwrap();
+                assert_eq!(ping_resp_payload.id, req_payload.id);
+            } else {
+                let ping_req = unpack!(packet, Packet::PingRequest);
+                let precomputed_key = precompute(&ping_req.pk, &bob_sk);
+                let ping_req_payload = ping_req.get_payload(&precomputed_key).unwrap();
+                assert!(request_queue.check_ping_id(bob_pk, ping_req_payload.id));
+            }
+        }).collect().wait().unwrap();
+
+        // In case of friend with yet unknown address we should send ping
+        // request immediately instead of adding node to nodes_to_ping list
+        assert!(!alice.nodes_to_ping.read().contains(&alice.pk, &bob_pk));
+    }
+
+    #[test]
+    fn handle_ping_req_invalid_payload() {
+        let (alice, precomp, _bob_pk, _bob_sk, _rx, addr) = create_node();
+
+        // can't be decrypted payload since packet contains wrong key
+        let req_payload = PingRequestPayload { id: 42 };
+        let ping_req = Packet::PingRequest(PingRequest::new(&precomp, &alice.pk, &req_payload));
+
+        assert!(alice.handle_packet(ping_req, addr).wait().is_err());
+    }
+
+    #[test]
+    fn spawn_handler() {
+        let (alice, precomp, bob_pk, bob_sk, rx, addr) = create_node();
+
+        let req_payload = PingRequestPayload { id: 42 };
+        let ping_req = Packet::PingRequest(PingRequest::new(&precomp, &bob_pk, &req_payload));
+
+        let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+        let (received, _rx) = runtime.block_on(future::lazy(move || {
+            let handler_tx = alice.spawn_handler();
+            handler_tx.send((ping_req, addr))
+                .map_err(|e| Error::new(ErrorKind::Other, e))
+                .and_then(|_handler_tx| rx.into_future().map_err(|_| Error::new(ErrorKind::Other, "rx error")))
+        })).unwrap();
+        let (packet, addr_to_send) = received.unwrap();
+
+        assert_eq!(addr_to_send, addr);
+        let ping_resp = unpack!(packet, Packet::PingResponse);
+        let precomputed_key = precompute(&ping_resp.pk, &bob_sk);
+        let ping_resp_payload = ping_resp.get_payload(&precomputed_key).unwrap();
+        assert_eq!(ping_resp_payload.id, req_payload.id);
+    }
+
+    #[test]
+    fn handle_ping_req_mismatched_sender_pk() {
+        let (alice, precomp, _bob_pk, _bob_sk, rx, addr) = create_node();
+        let eve_pk = gen_keypair().0;
+
+        let pn = PackedNode::new(addr, &eve_pk);
+        assert!(alice.close_nodes.write().try_add(&pn));
+        let last_resp_time = alice.close_nodes.read().get_node(&eve_pk).unwrap().assoc4.last_resp_time;
+
+        // packet is encrypted by bob but claims to be sent by eve
+        let req_payload = PingRequestPayload { id: 42 };
+        let ping_req = Packet::PingRequest(PingRequest::new(&precomp, &eve_pk, &req_payload));
+
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(Instant::now() + Duration::from_secs(1)));
+
+        with_default(&clock, &mut enter, |_| {
+            assert!(alice.handle_packet(ping_req, addr).wait().is_err());
+        });
+
+        assert_eq!(alice.close_nodes.read().get_node(&eve_pk).unwrap().assoc4.last_resp_time, last_resp_time);
+
+        drop(alice);
+        assert!(rx.collect().wait().unwrap().is_empty());
+    }
+
+    #[test]
+    fn handle_ping_req_flood_from_one_pk() {
+        let (alice, precomp, bob_pk, _bob_sk, rx, _addr) = create_node();
+
+        let req_payload = PingRequestPayload { id: 42 };
+        let ping_req = Packet::PingRequest(PingRequest::new(&precomp, &bob_pk, &req_payload));
+
+        // the same PublicKey sends requests from different addresses
+        for i in 0 .. MAX_REQUESTS_PER_PK + 2 {
+            let addr = SocketAddr::new("127.0.0.1".parse().unwrap(), 12346 + i as u16);
+            alice.handle_packet(ping_req.clone(), addr).wait().unwrap();
+        }
+
+        // Necessary to drop tx so that rx.collect() can be finished
+        drop(alice);
+
+        let responses = rx.collect().wait().unwrap().into_iter()
+            .filter(|(packet, _)| match packet {
+                Packet::PingResponse(_) => true,
+                _ => false,
+            })
+            .count();
+
+        assert_eq!(responses, MAX_REQUESTS_PER_PK as usize);
+    }
+
+    #[test]
+    fn handle_ping_req_flood_from_one_pk_not_limited() {
+        let (mut alice, precomp, bob_pk, _bob_sk, rx, _addr) = create_node();
+
+        alice.set_max_requests_per_pk(None);
+
+        let req_payload = PingRequestPayload { id: 42 };
+        let ping_req = Packet::PingRequest(PingRequest::new(&precomp, &bob_pk, &req_payload));
+
+        for i in 0 .. MAX_REQUESTS_PER_PK + 2 {
+            let addr = SocketAddr::new("127.0.0.1".parse().unwrap(), 12346 + i as u16);
+            alice.handle_packet(ping_req.clone(), addr).wait().unwrap();
+        }
+
+        // Necessary to drop tx so that rx.collect() can be finished
+        drop(alice);
+
+        let responses = rx.collect().wait().unwrap().into_iter()
+            .filter(|(packet, _)| match packet {
+                Packet::PingResponse(_) => true,
+                _ => false,
+            })
+            .count();
+
+        assert_eq!(responses, MAX_REQUESTS_PER_PK as usize + 2);
+    }
+
+    // handle_ping_resp
+    #[test]
+    fn handle_ping_resp() {
+        let (alice, precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
+
+        alice.add_friend(bob_pk);
+
+        let packed_node = PackedNode::new(addr, &bob_pk);
+        assert!(alice.try_add_to_close_nodes(&packed_node));
+
+        let ping_id = alice.request_queue.write().new_ping_id(bob_pk);
+
+        let resp_payload = PingResponsePayload { id: ping_id };
+        let ping_resp = Packet::PingResponse(PingResponse::new(&precomp, &bob_pk, &resp_payload));
+
+        let time = Instant::now() + Duration::from_secs(1);
+
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(time));
+
+        with_default(&clock, &mut enter, |_| {
+            alice.handle_packet(ping_resp, addr).wait().unwrap();
+        });
+
+        let friends = alice.friends.read();
+        let friend = friends.first().unwrap();
+
+        // All nodes from PingResponse should be added to bootstrap nodes list
+        // of each friend
+        assert!(friend.close_nodes.contains(&bob_pk, &bob_pk));
+
+        let close_nodes = alice.close_nodes.read();
+        let node = close_nodes.get_node(&bob_pk).unwrap();
+
+        // Node that sent PingResponse should be added to close nodes list and
+        // have updated last_resp_time
+        assert_eq!(node.assoc4.last_resp_time.unwrap(), time);
+    }
+
+    #[test]
+    fn handle_ping_resp_not_in_close_nodes() {
+        let (alice, precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
+
+        // node is pinged but it's not present in close nodes list yet
+        let ping_id = alice.request_queue.write().new_ping_id(bob_pk);
+
+        let resp_payload = PingResponsePayload { id: ping_id };
+        let ping_resp = Packet::PingResponse(PingResponse::new(&precomp, &bob_pk, &resp_payload));
+
+        alice.handle_packet(ping_resp, addr).wait().unwrap();
+
+        assert!(alice.close_nodes.read().contains(&bob_pk));
+    }
+
+    #[test]
+    fn close_nodes_keep_both_families_in_ipv6_mode() {
+        let (mut alice, _precomp, _bob_pk, _bob_sk, _rx, _addr) = create_node();
+
+        alice.enable_ipv6_mode(true);
+
+        for i in 0 .. KBUCKET_DEFAULT_SIZE {
+            let saddr = SocketAddr::new("1.2.3.4".parse().unwrap(), 12345 + u16::from(i));
+            assert!(alice.try_add_to_close_nodes(&PackedNode::new(saddr, &pk_in_kbucket(&alice.pk, 0, i))));
+        }
+
+        let node_v6 = PackedNode::new("[2001:db8::1]:12345".parse().unwrap(), &pk_in_kbucket(&alice.pk, 0, KBUCKET_DEFAULT_SIZE));
+        assert!(alice.try_add_to_close_nodes(&node_v6));
+
+        let close_nodes = alice.close_nodes.read();
+        assert!(close_nodes.contains(&node_v6.pk));
+        assert_eq!(close_nodes.iter().filter(|node| node.assoc4.saddr.is_some()).count(), KBUCKET_DEFAULT_SIZE as usize - 1);
+    }
+
+    #[test]
+    fn handle_ping_resp_invalid_payload() {
+        let (alice, precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
+
+        let packed_node = PackedNode::new(addr, &bob_pk);
+        assert!(alice.try_add_to_close_nodes(&packed_node));
+
+        let ping_id = alice.request_queue.write().new_ping_id(bob_pk);
+
+        // can't be decrypted payload since packet contains wrong key
+        let payload = PingResponsePayload { id: ping_id };
+        let ping_resp = Packet::PingResponse(PingResponse::new(&precomp, &alice.pk, &payload));
+
+        assert!(alice.handle_packet(ping_resp, addr).wait().is_err());
+    }
+
+    #[test]
+    fn handle_ping_resp_ping_id_is_0() {
+        let (alice, precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
+
+        let packed_node = PackedNode::new(addr, &bob_pk);
+        assert!(alice.try_add_to_close_nodes(&packed_node));
+
+        let payload = PingResponsePayload { id: 0 };
+        let ping_resp = Packet::PingResponse(PingResponse::new(&precomp, &bob_pk, &payload));
+
+        assert!(alice.handle_packet(ping_resp, addr).wait().is_err());
+    }
+
+    #[test]
+    fn handle_ping_resp_invalid_ping_id() {
+        let (alice, precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
+
+        let packed_node = PackedNode::new(addr, &bob_pk);
+        assert!(alice.try_add_to_close_nodes(&packed_node));
+
+        let ping_id = alice.request_queue.write().new_ping_id(bob_pk);
+
+        let payload = PingResponsePayload { id: ping_id + 1 };
+        let ping_resp = Packet::PingResponse(PingResponse::new(&precomp, &bob_pk, &payload));
+
+        assert!(alice.handle_packet(ping_resp, addr).wait().is_err());
+    }
+
+    // handle_nodes_req
+    #[test]
+    fn handle_nodes_req_from_self() {
+        let (alice, _precomp, _bob_pk, _bob_sk, rx, addr) = create_node();
+
+        let precomp = precompute(&alice.pk, &alice.sk);
+        let req_payload = NodesRequestPayload { pk: gen_keypair().0, id: 42 };
+        let nodes_req = Packet::NodesRequest(NodesRequest::new(&precomp, &alice.pk, &req_payload));
+
+        alice.handle_packet(nodes_req, addr).wait().unwrap();
+
+        assert_eq!(alice.self_requests(), 1);
+
+        drop(alice);
+        assert!(rx.collect().wait().unwrap().is_empty());
+    }
+
+    #[test]
+    fn handle_nodes_req() {
+        let (alice, precomp, bob_pk, bob_sk, rx, addr) = create_node();
+
+        let packed_node = PackedNode::new("127.0.0.1:12345".parse().unwrap(), &bob_pk);
+
+        assert!(alice.try_add_to_close_nodes(&packed_node));
+
+        let req_payload = NodesRequestPayload { pk: bob_pk, id: 42 };
+        let nodes_req = Packet::NodesRequest(NodesRequest::new(&precomp, &bob_pk, &req_payload));
+
+        alice.handle_packet(nodes_req, addr).wait().unwrap();
+
+        let (received, _rx) = rx.into_future().wait().unwrap();
+        let (packet, addr_to_send) = received.unwrap();
+
+        assert_eq!(addr_to_send, addr);
+
+        let nodes_resp = unpack!(packet, Packet::NodesResponse);
+        let precomputed_key = precompute(&nodes_resp.pk, &bob_sk);
+        let nodes_resp_payload = nodes_resp.get_payload(&precomputed_key).unwrap();
+
+        assert_eq!(nodes_resp_payload.id, req_payload.id);
+        assert_eq!(nodes_resp_payload.nodes, vec!(packed_node));
+
+        assert!(alice.nodes_to_ping.read().contains(&alice.pk, &bob_pk));
+    }
+
+    #[test]
+    fn handle_nodes_req_excludes_pending_nodes() {
+        let (alice, precomp, bob_pk, bob_sk, rx, addr) = create_node();
+        let (node_pk, node_sk) = gen_keypair();
+
+        let bob_node = PackedNode::new(addr, &bob_pk);
+        let pending_node = PackedNode::new("127.0.0.1:12345".parse().unwrap(), &node_pk);
+
+        // bob responds with a node that we haven't verified yet
+        let ping_id = alice.request_queue.write().new_ping_id(bob_pk);
+        let resp_payload = NodesResponsePayload { nodes: vec![pending_node], id: ping_id };
+        let nodes_resp = Packet::NodesResponse(NodesResponse::new(&precomp, &bob_pk, &resp_payload));
+        alice.handle_packet(nodes_resp, addr).wait().unwrap();
+
+        assert!(alice.close_nodes.read().contains(&bob_pk));
+        assert!(alice.nodes_to_bootstrap.read().contains(&alice.pk, &node_pk));
+
+        let req_payload = NodesRequestPayload { pk: node_pk, id: 42 };
+        let nodes_req = Packet::NodesRequest(NodesRequest::new(&precomp, &bob_pk, &req_payload));
+        alice.handle_packet(nodes_req, addr).wait().unwrap();
+
+        let (received, rx) = rx.into_future().wait().unwrap();
+        let (packet, _addr_to_send) = received.unwrap();
+        let nodes_resp = unpack!(packet, Packet::NodesResponse);
+        let nodes_resp_payload = nodes_resp.get_payload(&precompute(&nodes_resp.pk, &bob_sk)).unwrap();
+
+        // pending node is not returned
+        assert_eq!(nodes_resp_payload.nodes, vec![bob_node]);
+
+        // pending node responds to our request and becomes verified
+        let ping_id = alice.request_queue.write().new_ping_id(node_pk);
+        let resp_payload = NodesResponsePayload { nodes: Vec::new(), id: ping_id };
+        let node_precomp = precompute(&alice.pk, &node_sk);
+        let nodes_resp = Packet::NodesResponse(NodesResponse::new(&node_precomp, &node_pk, &resp_payload));
+        alice.handle_packet(nodes_resp, pending_node.saddr).wait().unwrap();
+
+        let req_payload = NodesRequestPayload { pk: node_pk, id: 43 };
+        let nodes_req = Packet::NodesRequest(NodesRequest::new(&precomp, &bob_pk, &req_payload));
+        alice.handle_packet(nodes_req, addr).wait().unwrap();
+
+        let (received, _rx) = rx.into_future().wait().unwrap();
+        let (packet, _addr_to_send) = received.unwrap();
+        let nodes_resp = unpack!(packet, Packet::NodesResponse);
+        let nodes_resp_payload = nodes_resp.get_payload(&precompute(&nodes_resp.pk, &bob_sk)).unwrap();
+
+        assert!(nodes_resp_payload.nodes.contains(&pending_node));
+    }
+
+    #[test]
+    fn handle_nodes_req_includes_ourselves() {
+        let (alice, precomp, bob_pk, bob_sk, rx, addr) = create_node();
+
+        let external_addr = "127.0.0.1:33445".parse().unwrap();
+
+        let packed_node = PackedNode::new("127.0.0.1:12345".parse().unwrap(), &bob_pk);
+        assert!(alice.try_add_to_close_nodes(&packed_node));
+        // external address is confirmed by nodes from distinct subnets
+        let charlie_pk = gen_keypair().0;
+        let charlie_node = PackedNode::new("127.1.0.1:12345".parse().unwrap(), &charlie_pk);
+        assert!(alice.try_add_to_close_nodes(&charlie_node));
+        for pk in &[bob_pk, charlie_pk] {
+            alice.close_nodes.write().get_node_mut(pk).unwrap().update_returned_addr(external_addr);
+        }
+
+        // search for a key that is closer to us than to any other node
+        let mut search_pk_bytes = alice.pk.0;
+        search_pk_bytes[PUBLICKEYBYTES - 1] ^= 1;
+        let search_pk = PublicKey(search_pk_bytes);
+        let req_payload = NodesRequestPayload { pk: search_pk, id: 42 };
+        let nodes_req = Packet::NodesRequest(NodesRequest::new(&precomp, &bob_pk, &req_payload));
+
+        alice.handle_packet(nodes_req, addr).wait().unwrap();
+
+        let (received, _rx) = rx.into_future().wait().unwrap();
+        let (packet, _addr_to_send) = received.unwrap();
+
+        let nodes_resp = unpack!(packet, Packet::NodesResponse);
+        let precomputed_key = precompute(&nodes_resp.pk, &bob_sk);
+        let nodes_resp_payload = nodes_resp.get_payload(&precomputed_key).unwrap();
+
+        assert_eq!(nodes_resp_payload.nodes.len(), 3);
+        assert_eq!(nodes_resp_payload.nodes[0], PackedNode::new(external_addr, &alice.pk));
+        assert!(nodes_resp_payload.nodes.contains(&packed_node));
+        assert!(nodes_resp_payload.nodes.contains(&charlie_node));
+    }
+
+    #[test]
+    fn handle_nodes_req_with_unconfirmed_external_addr_excludes_ourselves() {
+        let (alice, precomp, bob_pk, bob_sk, rx, addr) = create_node();
+
+        let packed_node = PackedNode::new("127.0.0.1:12345".parse().unwrap(), &bob_pk);
+        assert!(alice.try_add_to_close_nodes(&packed_node));
+        // the only node that reports our address can lie about it
+        alice.close_nodes.write().get_node_mut(&bob_pk).unwrap().update_returned_addr("127.0.0.1:33445".parse().unwrap());
+
+        // search for a key that is closer to us than to any other node
+        let mut search_pk_bytes = alice.pk.0;
+        search_pk_bytes[PUBLICKEYBYTES - 1] ^= 1;
+        let search_pk = PublicKey(search_pk_bytes);
+        let req_payload = NodesRequestPayload { pk: search_pk, id: 42 };
+        let nodes_req = Packet::NodesRequest(NodesRequest::new(&precomp, &bob_pk, &req_payload));
+
+        alice.handle_packet(nodes_req, addr).wait().unwrap();
+
+        let (received, _rx) = rx.into_future().wait().unwrap();
+        let (packet, _addr_to_send) = received.unwrap();
+
+        let nodes_resp = unpack!(packet, Packet::NodesResponse);
+        let precomputed_key = precompute(&nodes_resp.pk, &bob_sk);
+        let nodes_resp_payload = nodes_resp.get_payload(&precomputed_key).unwrap();
+
+        assert_eq!(nodes_resp_payload.nodes, vec!(packed_node));
+    }
+
+    #[test]
+    fn handle_nodes_req_with_only_local_addr_excludes_ourselves() {
+        let (mut alice, precomp, bob_pk, bob_sk, rx, addr) = create_node();
+
+        alice.set_local_addr("127.0.0.1:33445".parse().unwrap());
+        alice.enable_self_in_nodes_resp(true);
+
+        let packed_node = PackedNode::new("127.0.0.1:12345".parse().unwrap(), &bob_pk);
+        assert!(alice.try_add_to_close_nodes(&packed_node));
+
+        // search for a key that is closer to us than to any other node
+        let mut search_pk_bytes = alice.pk.0;
+        search_pk_bytes[PUBLICKEYBYTES - 1] ^= 1;
+        let search_pk = PublicKey(search_pk_bytes);
+        let req_payload = NodesRequestPayload { pk: search_pk, id: 42 };
+        let nodes_req = Packet::NodesRequest(NodesRequest::new(&precomp, &bob_pk, &req_payload));
+
+        alice.handle_packet(nodes_req, addr).wait().unwrap();
+
+        let (received, _rx) = rx.into_future().wait().unwrap();
+        let (packet, _addr_to_send) = received.unwrap();
+
+        let nodes_resp = unpack!(packet, Packet::NodesResponse);
+        let precomputed_key = precompute(&nodes_resp.pk, &bob_sk);
+        let nodes_resp_payload = nodes_resp.get_payload(&precomputed_key).unwrap();
+
+        assert_eq!(nodes_resp_payload.nodes, vec!(packed_node));
+    }
+
+    #[test]
+    fn handle_nodes_req_self_in_nodes_resp() {
+        let (mut alice, precomp, bob_pk, bob_sk, rx, addr) = create_node();
+
+        let external_addr = "1.2.3.4:33445".parse().unwrap();
+
+        let packed_node = PackedNode::new("127.0.0.1:12345".parse().unwrap(), &bob_pk);
+        assert!(alice.try_add_to_close_nodes(&packed_node));
+        // external address is confirmed by nodes from distinct subnets
+        let charlie_pk = gen_keypair().0;
+        let charlie_node = PackedNode::new("127.1.0.1:12345".parse().unwrap(), &charlie_pk);
+        assert!(alice.try_add_to_close_nodes(&charlie_node));
+        for pk in &[bob_pk, charlie_pk] {
+            alice.close_nodes.write().get_node_mut(pk).unwrap().update_returned_addr(external_addr);
+        }
+
+        // search for a key that is closer to us than to any other node
+        let mut search_pk_bytes = alice.pk.0;
+        search_pk_bytes[PUBLICKEYBYTES - 1] ^= 1;
+        let search_pk = PublicKey(search_pk_bytes);
+
+        // our node is not included when disabled
+        alice.enable_self_in_nodes_resp(false);
+
+        let req_payload = NodesRequestPayload { pk: search_pk, id: 42 };
+        let nodes_req = Packet::NodesRequest(NodesRequest::new(&precomp, &bob_pk, &req_payload));
+        alice.handle_packet(nodes_req, addr).wait().unwrap();
+
+        let (received, rx) = rx.into_future().wait().unwrap();
+        let (packet, _addr_to_send) = received.unwrap();
+        let nodes_resp = unpack!(packet, Packet::NodesResponse);
+        let nodes_resp_payload = nodes_resp.get_payload(&precompute(&nodes_resp.pk, &bob_sk)).unwrap();
+
+        assert!(!nodes_resp_payload.nodes.iter().any(|node| node.pk == alice.pk));
+
+        // our node is included with the external address when enabled
+        alice.enable_self_in_nodes_resp(true);
+
+        let req_payload = NodesRequestPayload { pk: search_pk, id: 43 };
+        let nodes_req = Packet::NodesRequest(NodesRequest::new(&precomp, &bob_pk, &req_payload));
+        alice.handle_packet(nodes_req, addr).wait().unwrap();
+
+        let (received, _rx) = rx.into_future().wait().unwrap();
+        let (packet, _addr_to_send) = received.unwrap();
+        let nodes_resp = unpack!(packet, Packet::NodesResponse);
+        let nodes_resp_payload = nodes_resp.get_payload(&precompute(&nodes_resp.pk, &bob_sk)).unwrap();
+
+        assert_eq!(nodes_resp_payload.nodes[0], PackedNode::new(external_addr, &alice.pk));
+    }
+
+    #[test]
+    fn handle_nodes_req_without_own_addr_excludes_ourselves() {
+        let (alice, precomp, bob_pk, bob_sk, rx, addr) = create_node();
+
+        let req_payload = NodesRequestPayload { pk: alice.pk, id: 42 };
+        let nodes_req = Packet::NodesRequest(NodesRequest::new(&precomp, &bob_pk, &req_payload));
+
+        alice.handle_packet(nodes_req, addr).wait().unwrap();
+
+        let (received, _rx) = rx.into_future().wait().unwrap();
+        let (packet, _addr_to_send) = received.unwrap();
+
+        let nodes_resp = unpack!(packet, Packet::NodesResponse);
+        let precomputed_key = precompute(&nodes_resp.pk, &bob_sk);
+        let nodes_resp_payload = nodes_resp.get_payload(&precomputed_key).unwrap();
+
+        assert!(nodes_resp_payload.nodes.iter().all(|node| node.pk != alice.pk));
+    }
+
+    #[test]
+    fn handle_nodes_req_only_fresh_nodes() {
+        let (mut alice, precomp, bob_pk, bob_sk, rx, addr) = create_node();
+
+        alice.set_nodes_resp_max_age(Some(Duration::from_secs(30)));
+
+        let stale_node = PackedNode::new("127.1.1.1:12345".parse().unwrap(), &gen_keypair().0);
+        let fresh_node = PackedNode::new("127.1.1.2:12345".parse().unwrap(), &gen_keypair().0);
+
+        let now = Instant::now();
+        let mut enter = tokio_executor::enter().unwrap();
+        for &(secs, node) in &[(0, stale_node), (50, fresh_node)] {
+            let clock = Clock::new_with_now(ConstNow(now + Duration::from_secs(secs)));
+            with_default(&clock, &mut enter, |_| {
+                assert!(alice.try_add_to_close_nodes(&node));
+            });
+        }
+
+        let clock = Clock::new_with_now(ConstNow(now + Duration::from_secs(60)));
+        with_default(&clock, &mut enter, |_| {
+            let req_payload = NodesRequestPayload { pk: bob_pk, id: 42 };
+            let nodes_req = Packet::NodesRequest(NodesRequest::new(&precomp, &bob_pk, &req_payload));
+            alice.handle_packet(nodes_req, addr).wait().unwrap();
+        });
+
+        let (received, _rx) = rx.into_future().wait().unwrap();
+        let (packet, _addr_to_send) = received.unwrap();
+
+        let nodes_resp = unpack!(packet, Packet::NodesResponse);
+        let precomputed_key = precompute(&nodes_resp.pk, &bob_sk);
+        let nodes_resp_payload = nodes_resp.get_payload(&precomputed_key).unwrap();
+
+        assert_eq!(nodes_resp_payload.nodes, vec!(fresh_node));
+    }
+
+    #[test]
+    fn handle_nodes_req_reuses_cached_nodes() {
+        let (alice, precomp, bob_pk, bob_sk, mut rx, addr) = create_node();
+
+        let pn_1 = PackedNode::new("127.0.0.1:12345".parse().unwrap(), &gen_keypair().0);
+        let pn_2 = PackedNode::new("127.0.0.1:12346".parse().unwrap(), &gen_keypair().0);
+        assert!(alice.close_nodes.write().try_add(&pn_1));
+
+        let mut request_nodes = |id| {
+            let req_payload = NodesRequestPayload { pk: bob_pk, id };
+            let nodes_req = Packet::NodesRequest(NodesRequest::new(&precomp, &bob_pk, &req_payload));
+            alice.handle_packet(nodes_req, addr).wait().unwrap();
+
+            let (received, _rx) = rx.by_ref().into_future().wait().unwrap();
+            let (packet, _addr_to_send) = received.unwrap();
+            let nodes_resp = unpack!(packet, Packet::NodesResponse);
+            let precomputed_key = precompute(&nodes_resp.pk, &bob_sk);
+            let nodes_resp_payload = nodes_resp.get_payload(&precomputed_key).unwrap();
+            assert_eq!(nodes_resp_payload.id, id);
+            nodes_resp_payload.nodes
+        };
+
+        assert_eq!(request_nodes(1), vec![pn_1]);
+
+        // update of a known node doesn't invalidate the cache so closest
+        // nodes are not selected again and pn_2 is not returned yet
+        assert!(alice.try_add_to_close(&mut alice.close_nodes.write(), &pn_1));
+        assert!(alice.close_nodes.write().try_add(&pn_2));
+        assert_eq!(request_nodes(2), vec![pn_1]);
+
+        // cached nodes expire
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(Instant::now() + Duration::from_secs(NODES_RESP_CACHE_TIMEOUT)));
+
+        with_default(&clock, &mut enter, |_| {
+            assert_eq!(request_nodes(3).len(), 2);
+        });
+    }
+
+    #[test]
+    fn handle_nodes_req_cached_nodes_skip_bad_and_removed() {
+        let (alice, precomp, bob_pk, bob_sk, mut rx, addr) = create_node();
+
+        let pn_1 = PackedNode::new("127.0.0.1:12345".parse().unwrap(), &gen_keypair().0);
+        let pn_2 = PackedNode::new("127.0.0.1:12346".parse().unwrap(), &gen_keypair().0);
+        assert!(alice.close_nodes.write().try_add(&pn_1));
+        assert!(alice.close_nodes.write().try_add(&pn_2));
+
+        let mut request_nodes = |id| {
+            let req_payload = NodesRequestPayload { pk: bob_pk, id };
+            let nodes_req = Packet::NodesRequest(NodesRequest::new(&precomp, &bob_pk, &req_payload));
+            alice.handle_packet(nodes_req, addr).wait().unwrap();
+
+            let (received, _rx) = rx.by_ref().into_future().wait().unwrap();
+            let (packet, _addr_to_send) = received.unwrap();
+            let nodes_resp = unpack!(packet, Packet::NodesResponse);
+            let precomputed_key = precompute(&nodes_resp.pk, &bob_sk);
+            let nodes_resp_payload = nodes_resp.get_payload(&precomputed_key).unwrap();
+            assert_eq!(nodes_resp_payload.id, id);
+            nodes_resp_payload.nodes
+        };
+
+        assert_eq!(request_nodes(1).len(), 2);
+
+        // changes made bypassing the server are taken into account when
+        // cached nodes are reused
+        alice.close_nodes.write().get_node_mut(&pn_1.pk).unwrap().assoc4.last_resp_time = None;
+        assert_eq!(request_nodes(2), vec![pn_2]);
+
+        assert!(alice.close_nodes.write().remove(&pn_2.pk).is_some());
+        assert!(request_nodes(3).is_empty());
+    }
+
+    #[test]
+    fn handle_nodes_req_cached_nodes_invalidated() {
+        let (alice, precomp, bob_pk, bob_sk, rx, addr) = create_node();
+
+        let pn_1 = PackedNode::new("127.0.0.1:12345".parse().unwrap(), &gen_keypair().0);
+        let pn_2 = PackedNode::new("127.0.0.1:12346".parse().unwrap(), &gen_keypair().0);
+        assert!(alice.close_nodes.write().try_add(&pn_1));
+
+        let req_payload = NodesRequestPayload { pk: bob_pk, id: 42 };
+        let nodes_req = Packet::NodesRequest(NodesRequest::new(&precomp, &bob_pk, &req_payload));
+        alice.handle_packet(nodes_req.clone(), addr).wait().unwrap();
+
+        assert!(alice.try_add_to_close(&mut alice.close_nodes.write(), &pn_2));
+
+        alice.handle_packet(nodes_req, addr).wait().unwrap();
+
+        drop(alice);
+        let nodes_lens = rx.collect().wait().unwrap().into_iter().map(|(packet, _addr)| {
+            let nodes_resp = unpack!(packet, Packet::NodesResponse);
+            let precomputed_key = precompute(&nodes_resp.pk, &bob_sk);
+            nodes_resp.get_payload(&precomputed_key).unwrap().nodes.len()
+        }).collect::<Vec<_>>();
+        assert_eq!(nodes_lens, vec![1, 2]);
+    }
+
+    #[test]
+    fn handle_nodes_req_does_not_refresh_close_node() {
+        let (alice, precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
+
+        let packed_node = PackedNode::new(addr, &bob_pk);
+        assert!(alice.try_add_to_close_nodes(&packed_node));
+
+        let req_payload = NodesRequestPayload { pk: bob_pk, id: 42 };
+        let nodes_req = Packet::NodesRequest(NodesRequest::new(&precomp, &bob_pk, &req_payload));
+
+        let time = Instant::now() + Duration::from_secs(1);
+
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(time));
+
+        with_default(&clock, &mut enter, |_| {
+            alice.handle_packet(nodes_req, addr).wait().unwrap();
+        });
+
+        // requests are not authenticated responses so they don't prove
+        // that the node is alive
+        let close_nodes = alice.close_nodes.read();
+        let node = close_nodes.get_node(&bob_pk).unwrap();
+
+        assert_ne!(node.assoc4.last_resp_time.unwrap(), time);
+    }
+
+    #[test]
+    fn handle_nodes_req_verifies_new_close_node_addr() {
+        let (alice, precomp, bob_pk, bob_sk, rx, addr) = create_node();
+
+        alice.add_friend(gen_keypair().0);
+
+        let old_addr = "127.0.0.1:12345".parse().unwrap();
+        let packed_node = PackedNode::new(old_addr, &bob_pk);
+        assert!(alice.try_add_to_close_nodes(&packed_node));
+        assert!(alice.friends.write()[FAKE_FRIENDS_NUMBER].try_add_to_close(&packed_node));
+
+        let req_payload = NodesRequestPayload { pk: bob_pk, id: 42 };
+        let nodes_req = Packet::NodesRequest(NodesRequest::new(&precomp, &bob_pk, &req_payload));
+
+        // the old address doesn't answer anymore
+        let time = Instant::now() + Duration::from_secs(BAD_NODE_TIMEOUT + 1);
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(time));
+
+        with_default(&clock, &mut enter, |_| {
+            alice.handle_packet(nodes_req, addr).wait().unwrap();
+        });
+
+        // the request could be replayed from a spoofed address so neither the
+        // address nor the liveness of the node is changed
+        {
+            let close_nodes = alice.close_nodes.read();
+            let node = close_nodes.get_node(&bob_pk).unwrap();
+            assert_eq!(node.assoc4.saddr.map(SocketAddr::V4), Some(old_addr));
+            assert!(node.assoc4.last_resp_time.unwrap() < time);
+        }
+
+        // instead the new address is pinged
+        assert!(alice.nodes_to_ping.read().contains(&alice.pk, &bob_pk));
+        with_default(&clock, &mut enter, |_| {
+            alice.send_pings().wait().unwrap();
+        });
+
+        let ping_req = rx.map(|(packet, _)| packet).filter_map(|packet| match packet {
+            Packet::PingRequest(ping_req) => Some(ping_req),
+            _ => None,
+        }).into_future().wait().ok().unwrap().0.unwrap();
+        let ping_req_payload = ping_req.get_payload(&precompute(&ping_req.pk, &bob_sk)).unwrap();
+
+        // and the address is switched when the node answers from it
+        let resp_payload = PingResponsePayload { id: ping_req_payload.id };
+        let ping_resp = Packet::PingResponse(PingResponse::new(&precomp, &bob_pk, &resp_payload));
+
+        with_default(&clock, &mut enter, |_| {
+            alice.handle_packet(ping_resp, addr).wait().unwrap();
+        });
+
+        let close_nodes = alice.close_nodes.read();
+        let node = close_nodes.get_node(&bob_pk).unwrap();
+        assert_eq!(node.assoc4.saddr.map(SocketAddr::V4), Some(addr));
+
+        let friends = alice.friends.read();
+        let friend = &friends[FAKE_FRIENDS_NUMBER];
+        let node = friend.close_nodes.get_node(&friend.pk, &bob_pk).unwrap();
+        assert_eq!(node.assoc4.saddr.map(SocketAddr::V4), Some(addr));
+    }
+
+    #[test]
+    fn handle_nodes_req_without_empty_nodes_response() {
+        let (mut alice, precomp, bob_pk, _bob_sk, rx, addr) = create_node();
+
+        alice.enable_empty_nodes_response(false);
+
+        let req_payload = NodesRequestPayload { pk: bob_pk, id: 42 };
+        let nodes_req = Packet::NodesRequest(NodesRequest::new(&precomp, &bob_pk, &req_payload));
+
+        alice.handle_packet(nodes_req, addr).wait().unwrap();
+
+        assert!(alice.nodes_to_ping.read().contains(&alice.pk, &bob_pk));
+
+        // Necessary to drop tx so that rx.collect() can be finished
+        drop(alice);
+
+        assert!(rx.collect().wait().unwrap().is_empty());
+    }
+
+    #[test]
+    fn handle_nodes_req_should_return_nodes_from_friends() {
+        let (alice, precomp, bob_pk, bob_sk, rx, addr) = create_node();
+
+        alice.add_friend(bob_pk);
+
+        let packed_node = PackedNode::new("127.0.0.1:12345".parse().unwrap(), &bob_pk);
+        assert!(alice.friends.write()[FAKE_FRIENDS_NUMBER].try_add_to_close(&packed_node));
+
+        let req_payload = NodesRequestPayload { pk: bob_pk, id: 42 };
+        let nodes_req = Packet::NodesRequest(NodesRequest::new(&precomp, &bob_pk, &req_payload));
+
+        alice.handle_packet(nodes_req, addr).wait().unwrap();
+
+        let (received, _rx) = rx.into_future().wait().unwrap();
+        let (packet, addr_to_send) = received.unwrap();
+
+        assert_eq!(addr_to_send, addr);
+
+        let nodes_resp = unpack!(packet, Packet::NodesResponse);
+        let precomputed_key = precompute(&nodes_resp.pk, &bob_sk);
+        let nodes_resp_payload = nodes_resp.get_payload(&precomputed_key).unwrap();
+
+        assert_eq!(nodes_resp_payload.id, req_payload.id);
+        assert_eq!(nodes_resp_payload.nodes, vec!(packed_node));
+
+        assert!(alice.nodes_to_ping.read().contains(&alice.pk, &bob_pk));
+    }
+
+    #[test]
+    fn handle_nodes_req_should_not_return_bad_nodes() {
+        let (alice, precomp, bob_pk, bob_sk, rx, addr) = create_node();
+
+        let packed_node = PackedNode::new("127.0.0.1:12345".parse().unwrap(), &bob_pk);
+
+        assert!(alice.try_add_to_close_nodes(&packed_node));
+
+        let req_payload = NodesRequestPayload { pk: bob_pk, id: 42 };
+        let nodes_req = Packet::NodesRequest(NodesRequest::new(&precomp, &bob_pk, &req_payload));
+
+        let time = Instant::now() + Duration::from_secs(BAD_NODE_TIMEOUT + 1);
+
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(time));
+
+        with_default(&clock, &mut enter, |_| {
+            alice.handle_packet(nodes_req, addr).wait().unwrap();
+        });
+
+        let (received, _rx) = rx.into_future().wait().unwrap();
+        let (packet, addr_to_send) = received.unwrap();
+
+        assert_eq!(addr_to_send, addr);
+
+        let nodes_resp = unpack!(packet, Packet::NodesResponse);
+        let precomputed_key = precompute(&nodes_resp.pk, &bob_sk);
+        let nodes_resp_payload = nodes_resp.get_payload(&precomputed_key).unwrap();
+
+        assert_eq!(nodes_resp_payload.id, req_payload.id);
+        assert!(nodes_resp_payload.nodes.is_empty());
+
+        assert!(alice.nodes_to_ping.read().contains(&alice.pk, &bob_pk));
+    }
+
+    #[test]
+    fn handle_nodes_req_should_not_return_lan_nodes_when_address_is_global() {
+        let (alice, precomp, bob_pk, bob_sk, rx, _addr) = create_node();
+        let addr = "8.10.8.10:12345".parse().unwrap();
+
+        let packed_node = PackedNode::new("192.168.42.42:12345".parse().unwrap(), &bob_pk);
+
+        assert!(alice.try_add_to_close_nodes(&packed_node));
+
+        let req_payload = NodesRequestPayload { pk: bob_pk, id: 42 };
+        let nodes_req = Packet::NodesRequest(NodesRequest::new(&precomp, &bob_pk, &req_payload));
+
+        alice.handle_packet(nodes_req, addr).wait().unwrap();
+
+        let (received, _rx) = rx.into_future().wait().unwrap();
+        let (packet, addr_to_send) = received.unwrap();
+
+        assert_eq!(addr_to_send, addr);
+
+        let nodes_resp = unpack!(packet, Packet::NodesResponse);
+        let precomputed_key = precompute(&nodes_resp.pk, &bob_sk);
+        let nodes_resp_payload = nodes_resp.get_payload(&precomputed_key).unwrap();
+
+        assert_eq!(nodes_resp_payload.id, req_payload.id);
+        assert!(nodes_resp_payload.nodes.is_empty());
+
+        assert!(alice.nodes_to_ping.read().contains(&alice.pk, &bob_pk));
+    }
+
+    #[test]
+    fn handle_nodes_req_should_prefer_global_nodes_when_address_is_global() {
+        let (alice, precomp, bob_pk, bob_sk, rx, _addr) = create_node();
+        let addr = "8.10.8.10:12345".parse().unwrap();
+
+        // LAN node is the closest one to the requested key
+        let lan_node = PackedNode::new("192.168.42.42:12345".parse().unwrap(), &bob_pk);
+        let global_node = PackedNode::new("8.8.8.8:12345".parse().unwrap(), &gen_keypair().0);
+
+        assert!(alice.try_add_to_close_nodes(&lan_node));
+        assert!(alice.try_add_to_close_nodes(&global_node));
+
+        let req_payload = NodesRequestPayload { pk: bob_pk, id: 42 };
+        let nodes_req = Packet::NodesRequest(NodesRequest::new(&precomp, &bob_pk, &req_payload));
+
+        alice.handle_packet(nodes_req, addr).wait().unwrap();
+
+        let (received, _rx) = rx.into_future().wait().unwrap();
+        let (packet, _addr_to_send) = received.unwrap();
+
+        let nodes_resp = unpack!(packet, Packet::NodesResponse);
+        let precomputed_key = precompute(&nodes_resp.pk, &bob_sk);
+        let nodes_resp_payload = nodes_resp.get_payload(&precomputed_key).unwrap();
+
+        assert_eq!(nodes_resp_payload.nodes, vec![global_node]);
+    }
+
+    #[test]
+    fn handle_nodes_req_should_not_return_ipv6_nodes_when_address_is_ipv4() {
+        let (alice, precomp, bob_pk, bob_sk, rx, addr) = create_node();
+
+        let node_v4 = PackedNode::new("127.0.0.1:12345".parse().unwrap(), &gen_keypair().0);
+        let node_v6 = PackedNode::new("[2001:db8::1]:12345".parse().unwrap(), &gen_keypair().0);
+
+        assert!(alice.try_add_to_close_nodes(&node_v4));
+        assert!(alice.try_add_to_close_nodes(&node_v6));
+
+        let req_payload = NodesRequestPayload { pk: bob_pk, id: 42 };
+        let nodes_req = Packet::NodesRequest(NodesRequest::new(&precomp, &bob_pk, &req_payload));
+
+        alice.handle_packet(nodes_req, addr).wait().unwrap();
+
+        let (received, _rx) = rx.into_future().wait().unwrap();
+        let (packet, addr_to_send) = received.unwrap();
+
+        assert_eq!(addr_to_send, addr);
+
+        let nodes_resp = unpack!(packet, Packet::NodesResponse);
+        let precomputed_key = precompute(&nodes_resp.pk, &bob_sk);
+        let nodes_resp_payload = nodes_resp.get_payload(&precomputed_key).unwrap();
+
+        assert_eq!(nodes_resp_payload.id, req_payload.id);
+        assert_eq!(nodes_resp_payload.nodes, vec!(node_v4));
+    }
+
+    #[test]
+    fn handle_nodes_req_invalid_payload() {
+        let (alice, precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
+
+        // can't be decrypted payload since packet contains wrong key
+        let req_payload = NodesRequestPayload { pk: bob_pk, id: 42 };
+        let nodes_req = Packet::NodesRequest(NodesRequest::new(&precomp, &alice.pk, &req_payload));
+
+        assert!(alice.handle_packet(nodes_req, addr).wait().is_err());
+    }
+
+    // handle_nodes_resp
+    #[test]
+    fn handle_nodes_resp() {
+        let (alice, precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
+
+        alice.add_friend(bob_pk);
+
+        let node = PackedNode::new("127.0.0.1:12345".parse().unwrap(), &gen_keypair().0);
+
+        let ping_id = alice.request_queue.write().new_ping_id(bob_pk);
+
+        let resp_payload = NodesResponsePayload { nodes: vec![node], id: ping_id };
+        let nodes_resp = Packet::NodesResponse(NodesResponse::new(&precomp, &bob_pk, &resp_payload));
+
+        let time = Instant::now() + Duration::from_secs(1);
+
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(time));
+
+        with_default(&clock, &mut enter, |_| {
+            alice.handle_packet(nodes_resp, addr).wait().unwrap();
+        });
+
+        // All nodes from NodesResponse should be added to bootstrap nodes list
+        assert!(alice.nodes_to_bootstrap.read().contains(&alice.pk, &node.pk));
+
+        let friends = alice.friends.read();
+        let friend = friends.first().unwrap();
+
+        // Node that sent NodesResponse should be added to close nodes list of
+        // each friend
+        assert!(friend.nodes_to_bootstrap.contains(&bob_pk, &node.pk));
+        // All nodes from NodesResponse should be added to bootstrap nodes list
+        // of each friend
+        assert!(friend.close_nodes.contains(&bob_pk, &bob_pk));
+
+        let close_nodes = alice.close_nodes.read();
+        let node = close_nodes.get_node(&bob_pk).unwrap();
+
+        // Node that sent NodesResponse should be added to close nodes list and
+        // have updated last_resp_time
+        assert_eq!(node.assoc4.last_resp_time.unwrap(), time);
+    }
+
+    #[test]
+    fn handle_ping_resp_prefers_trusted_node() {
+        let (mut alice, _precomp, _bob_pk, _bob_sk, _rx, addr) = create_node();
+
+        let untrusted_pks = (0 .. KBUCKET_DEFAULT_SIZE).map(|i| {
+            let pk = pk_in_kbucket(&alice.pk, 0, i + 1);
+            let pn = PackedNode::new(format!("127.1.1.{}:12345", i + 1).parse().unwrap(), &pk);
+            assert!(alice.close_nodes.write().try_add(&pn));
+            pk
+        }).collect::<Vec<_>>();
+
+        // kbucket is full so another untrusted node can't be added
+        assert!(!alice.try_add_to_close(&mut alice.close_nodes.write(), &PackedNode::new(addr, &pk_in_kbucket(&alice.pk, 0, KBUCKET_DEFAULT_SIZE + 1))));
+
+        // node from our bootstrap list that gets into the same kbucket
+        let (trusted_pk, precomp) = loop {
+            let (pk, sk) = gen_keypair();
+            if (pk.0[0] ^ alice.pk.0[0]) & 0x80 != 0 {
+                break (pk, precompute(&alice.pk, &sk));
+            }
+        };
+        alice.add_initial_bootstrap(PackedNode::new(addr, &trusted_pk));
+
+        let ping_id = alice.request_queue.write().new_ping_id(trusted_pk);
+        let resp_payload = PingResponsePayload { id: ping_id };
+        let ping_resp = Packet::PingResponse(PingResponse::new(&precomp, &trusted_pk, &resp_payload));
+
+        alice.handle_packet(ping_resp, addr).wait().unwrap();
+
+        let close_nodes = alice.close_nodes.read();
+        assert!(close_nodes.get_node(&trusted_pk).unwrap().is_trusted);
+        assert_eq!(untrusted_pks.iter().filter(|pk| close_nodes.contains(pk)).count(), KBUCKET_DEFAULT_SIZE as usize - 1);
+    }
+
+    #[test]
+    fn handle_nodes_resp_unresponsive_node_is_not_added_to_close_nodes() {
+        let (alice, precomp, bob_pk, _bob_sk, rx, addr) = create_node();
+
+        let node = PackedNode::new("127.1.1.1:12345".parse().unwrap(), &gen_keypair().0);
+
+        let ping_id = alice.request_queue.write().new_ping_id(bob_pk);
+        let resp_payload = NodesResponsePayload { nodes: vec![node], id: ping_id };
+        let nodes_resp = Packet::NodesResponse(NodesResponse::new(&precomp, &bob_pk, &resp_payload));
+
+        alice.handle_packet(nodes_resp, addr).wait().unwrap();
+
+        // returned node goes to the temporary list, only the responder is
+        // added to close nodes list
+        assert!(alice.nodes_to_bootstrap.read().contains(&alice.pk, &node.pk));
+        assert!(!alice.close_nodes.read().contains(&node.pk));
+        assert!(alice.close_nodes.read().contains(&bob_pk));
+
+        let now = Instant::now();
+        let mut enter = tokio_executor::enter().unwrap();
+
+        // NodesRequest is sent to the returned node to check if it's online
+        let clock = Clock::new_with_now(ConstNow(now));
+        with_default(&clock, &mut enter, |_| {
+            alice.dht_main_loop().wait().unwrap();
+        });
+        assert!(alice.nodes_to_bootstrap.read().is_empty());
+
+        // the node doesn't respond within PING_TIMEOUT
+        let clock = Clock::new_with_now(ConstNow(now + Duration::from_secs(PING_TIMEOUT + 1)));
+        with_default(&clock, &mut enter, |_| {
+            alice.dht_main_loop().wait().unwrap();
+        });
+
+        assert!(!alice.close_nodes.read().contains(&node.pk));
+
+        drop(alice);
+        let requested = rx.collect().wait().unwrap().into_iter()
+            .any(|(packet, to)| to == node.saddr && match packet {
+                Packet::NodesRequest(_) => true,
+                _ => false,
+            });
+        assert!(requested);
+    }
+
+    #[test]
+    fn handle_nodes_resp_records_external_addr() {
+        let (alice, precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
+
+        assert_eq!(alice.connectivity_report(), ConnectivityReport {
+            external_addrs: Vec::new(),
+            nat_type: NatType::Unknown,
+            udp_works: false,
+            tcp_relay_recommended: true,
+        });
+
+        let external_addr = "1.2.3.4:33445".parse().unwrap();
+        let node = PackedNode::new(external_addr, &alice.pk);
+
+        let ping_id = alice.request_queue.write().new_ping_id(bob_pk);
+
+        let resp_payload = NodesResponsePayload { nodes: vec![node], id: ping_id };
+        let nodes_resp = Packet::NodesResponse(NodesResponse::new(&precomp, &bob_pk, &resp_payload));
+
+        alice.handle_packet(nodes_resp, addr).wait().unwrap();
+
+        assert_eq!(alice.connectivity_report(), ConnectivityReport {
+            external_addrs: vec![external_addr],
+            nat_type: NatType::Unknown,
+            udp_works: true,
+            tcp_relay_recommended: false,
+        });
+    }
+
+    #[test]
+    fn handle_nodes_resp_max_close_admissions_per_tick() {
+        let (mut alice, _precomp, _bob_pk, _bob_sk, _rx, _addr) = create_node();
+
+        alice.set_max_close_admissions_per_tick(Some(2));
+
+        let nodes = (0 .. 3).map(|i| {
+            let (pk, sk) = gen_keypair();
+            let addr: SocketAddr = format!("127.1.1.{}:12345", i + 1).parse().unwrap();
+            let precomp = precompute(&alice.pk, &sk);
+            let ping_id = alice.request_queue.write().new_ping_id(pk);
+            let resp_payload = NodesResponsePayload { nodes: vec![], id: ping_id };
+            let nodes_resp = Packet::NodesResponse(NodesResponse::new(&precomp, &pk, &resp_payload));
+            alice.handle_packet(nodes_resp, addr).wait().unwrap();
+            pk
+        }).collect::<Vec<_>>();
+
+        let count = |alice: &Server| nodes.iter().filter(|pk| alice.close_nodes.read().contains(pk)).count();
+
+        // only 2 nodes are added during one iteration
+        assert_eq!(count(&alice), 2);
+
+        // the rest node is added during the next iteration
+        alice.dht_main_loop().wait().unwrap();
+        assert_eq!(count(&alice), 3);
+    }
+
+    #[test]
+    fn handle_nodes_resp_records_first_bootstrap() {
+        let (mut alice, precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
+
+        let bootstrap_node = PackedNode::new(addr, &bob_pk);
+        alice.add_initial_bootstrap(bootstrap_node);
+
+        assert_eq!(alice.first_bootstrap(), None);
+
+        let ping_id = alice.request_queue.write().new_ping_id(bob_pk);
+        let resp_payload = NodesResponsePayload { nodes: vec![], id: ping_id };
+        let nodes_resp = Packet::NodesResponse(NodesResponse::new(&precomp, &bob_pk, &resp_payload));
+        alice.handle_packet(nodes_resp, addr).wait().unwrap();
+
+        assert_eq!(alice.first_bootstrap(), Some(bootstrap_node));
+
+        // responses from other nodes don't change it
+        let (node_pk, node_sk) = gen_keypair();
+        let node_precomp = precompute(&alice.pk, &node_sk);
+        let ping_id = alice.request_queue.write().new_ping_id(node_pk);
+        let resp_payload = NodesResponsePayload { nodes: vec![], id: ping_id };
+        let nodes_resp = Packet::NodesResponse(NodesResponse::new(&node_precomp, &node_pk, &resp_payload));
+        alice.handle_packet(nodes_resp, "127.1.1.1:12345".parse().unwrap()).wait().unwrap();
+
+        assert_eq!(alice.first_bootstrap(), Some(bootstrap_node));
+    }
+
+    #[test]
+    fn handle_ping_resp_records_first_bootstrap() {
+        let (alice, precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
+
+        assert_eq!(alice.first_bootstrap(), None);
+
+        let ping_id = alice.request_queue.write().new_ping_id(bob_pk);
+        let resp_payload = PingResponsePayload { id: ping_id };
+        let ping_resp = Packet::PingResponse(PingResponse::new(&precomp, &bob_pk, &resp_payload));
+        alice.handle_packet(ping_resp, addr).wait().unwrap();
+
+        assert_eq!(alice.first_bootstrap(), Some(PackedNode::new(addr, &bob_pk)));
+    }
+
+    #[test]
+    fn first_bootstrap_is_not_overwritten() {
+        let (alice, precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
+
+        // close nodes list can become empty again after the first bootstrap
+        let first_bootstrap = PackedNode::new("127.1.1.1:12345".parse().unwrap(), &gen_keypair().0);
+        *alice.first_bootstrap.write() = Some(first_bootstrap);
+        assert!(alice.close_nodes.read().is_empty());
+
+        let ping_id = alice.request_queue.write().new_ping_id(bob_pk);
+        let resp_payload = PingResponsePayload { id: ping_id };
+        let ping_resp = Packet::PingResponse(PingResponse::new(&precomp, &bob_pk, &resp_payload));
+        alice.handle_packet(ping_resp, addr).wait().unwrap();
+
+        assert!(alice.close_nodes.read().contains(&bob_pk));
+        assert_eq!(alice.first_bootstrap(), Some(first_bootstrap));
+    }
+
+    #[test]
+    fn handle_nodes_resp_max_friends_fan_out() {
+        let (mut alice, precomp, bob_pk, bob_sk, rx, addr) = create_node();
+
+        alice.set_max_friends_fan_out(Some(2));
+
+        // 2 fake friends + 3 real friends
+        let friend_pks = (0 .. 3).map(|_| gen_keypair().0).collect::<Vec<_>>();
+        for &friend_pk in &friend_pks {
+            alice.add_friend(friend_pk);
+        }
+
+        let node = PackedNode::new("127.0.0.1:12345".parse().unwrap(), &gen_keypair().0);
+        let checked_friends = |alice: &Server| alice.friends.read().iter()
+            .filter(|friend| friend.nodes_to_bootstrap.contains(&friend.pk, &node.pk))
+            .count();
+
+        let ping_id = alice.request_queue.write().new_ping_id(bob_pk);
+
+        let resp_payload = NodesResponsePayload { nodes: vec![node], id: ping_id };
+        let nodes_resp = Packet::NodesResponse(NodesResponse::new(&precomp, &bob_pk, &resp_payload));
+
+        alice.handle_packet(nodes_resp, addr).wait().unwrap();
+
+        // nodes from the response are checked only for 2 friends
+        assert_eq!(checked_friends(&alice), 2);
+        // but the responding node is added to close nodes of all of them
+        assert!(alice.friends.read().iter().all(|friend| friend.close_nodes.contains(&friend.pk, &bob_pk)));
+
+        // next responses are checked for next friends
+        for id in 0 .. 2 {
+            let ping_id = alice.request_queue.write().new_ping_id(bob_pk);
+            let resp_payload = NodesResponsePayload { nodes: vec![node], id: ping_id };
+            let nodes_resp = Packet::NodesResponse(NodesResponse::new(&precomp, &bob_pk, &resp_payload));
+            alice.handle_packet(nodes_resp, addr).wait().unwrap();
+            assert_eq!(checked_friends(&alice), 4 + id);
+        }
+
+        // friends still search their close nodes periodically
+        alice.dht_main_loop().wait().unwrap();
+
+        drop(alice);
+
+        let searched_pks = rx.collect().wait().unwrap().into_iter()
+            .filter(|&(_, addr_to_send)| addr_to_send == addr)
+            .filter_map(|(packet, _)| match packet {
+                Packet::NodesRequest(nodes_req) => Some(nodes_req),
+                _ => None,
+            })
+            .map(|nodes_req| {
+                let precomputed_key = precompute(&nodes_req.pk, &bob_sk);
+                nodes_req.get_payload(&precomputed_key).unwrap().pk
+            })
+            .collect::<Vec<_>>();
+        for friend_pk in friend_pks {
+            assert!(searched_pks.contains(&friend_pk));
+        }
+    }
+
+    #[test]
+    fn handle_nodes_resp_max_friends_fan_out_not_reached() {
+        let (mut alice, precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
+
+        alice.set_max_friends_fan_out(Some(2));
+
+        // fake friends are not counted towards the limit
+        for _ in 0 .. 2 {
+            alice.add_friend(gen_keypair().0);
+        }
+
+        let node = PackedNode::new("127.0.0.1:12345".parse().unwrap(), &gen_keypair().0);
+
+        let ping_id = alice.request_queue.write().new_ping_id(bob_pk);
+
+        let resp_payload = NodesResponsePayload { nodes: vec![node], id: ping_id };
+        let nodes_resp = Packet::NodesResponse(NodesResponse::new(&precomp, &bob_pk, &resp_payload));
+
+        alice.handle_packet(nodes_resp, addr).wait().unwrap();
+
+        assert!(alice.friends.read().iter().all(|friend| friend.nodes_to_bootstrap.contains(&friend.pk, &node.pk)));
+    }
+
+    #[test]
+    fn handle_nodes_resp_fill_strategy() {
+        for &fill_strategy in &[FillStrategy::Passive, FillStrategy::Active] {
+            let (mut alice, precomp, bob_pk, _bob_sk, rx, addr) = create_node();
+            let (node_pk, node_sk) = gen_keypair();
+
+            alice.set_fill_strategy(fill_strategy);
+
+            let node = PackedNode::new("127.1.1.1:12345".parse().unwrap(), &node_pk);
+
+            let ping_id = alice.request_queue.write().new_ping_id(bob_pk);
+
+            let resp_payload = NodesResponsePayload { nodes: vec![node], id: ping_id };
+            let nodes_resp = Packet::NodesResponse(NodesResponse::new(&precomp, &bob_pk, &resp_payload));
+
+            alice.handle_packet(nodes_resp, addr).wait().unwrap();
+
+            let alice_pk = alice.pk;
+            let nodes_to_bootstrap_is_empty = alice.nodes_to_bootstrap.read().is_empty();
+            drop(alice);
+
+            let packets = rx.collect().wait().unwrap();
+
+            if fill_strategy == FillStrategy::Active {
+                // learned node is queried for nodes close to us immediately
+                assert!(nodes_to_bootstrap_is_empty);
+                assert_eq!(packets.len(), 1);
+                let (packet, addr_to_send) = packets[0].clone();
+                assert_eq!(addr_to_send, node.saddr);
+                let nodes_req = unpack!(packet, Packet::NodesRequest);
+                let nodes_req_payload = nodes_req.get_payload(&precompute(&nodes_req.pk, &node_sk)).unwrap();
+                assert_eq!(nodes_req_payload.pk, alice_pk);
+            } else {
+                // learned node waits for the next DHT main loop iteration
+                assert!(!nodes_to_bootstrap_is_empty);
+                assert!(packets.is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn handle_nodes_resp_active_fill_until_stable() {
+        let (mut alice, precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
+
+        alice.set_fill_strategy(FillStrategy::Active);
+
+        let (node_1_pk, node_1_sk) = gen_keypair();
+        let node_1 = PackedNode::new("127.1.1.1:12345".parse().unwrap(), &node_1_pk);
+        let node_2 = PackedNode::new("127.1.1.2:12345".parse().unwrap(), &gen_keypair().0);
+        let node_3 = PackedNode::new("127.1.1.3:12345".parse().unwrap(), &gen_keypair().0);
+
+        // returns whether learned node was queried immediately
+        let respond = |precomp: &PrecomputedKey, pk: PublicKey, addr: SocketAddr, node: PackedNode| {
+            let ping_id = alice.request_queue.write().new_ping_id(pk);
+            let resp_payload = NodesResponsePayload { nodes: vec![node], id: ping_id };
+            let nodes_resp = Packet::NodesResponse(NodesResponse::new(precomp, &pk, &resp_payload));
+            alice.handle_packet(nodes_resp, addr).wait().unwrap();
+            let queried = !alice.nodes_to_bootstrap.read().contains(&alice.pk, &node.pk);
+            alice.nodes_to_bootstrap.write().clear();
+            queried
+        };
+
+        // nodes learned from responses to our requests are queried as well
+        assert!(respond(&precomp, bob_pk, addr, node_1));
+        assert!(respond(&precompute(&alice.pk, &node_1_sk), node_1_pk, node_1.saddr, node_2));
+
+        // the first iteration remembers bucket fill, the second one sees that
+        // it didn't change
+        alice.dht_main_loop().wait().unwrap();
+        assert!(alice.active_fill.read().is_some());
+        alice.dht_main_loop().wait().unwrap();
+        assert!(alice.active_fill.read().is_none());
+
+        assert!(!respond(&precomp, bob_pk, addr, node_3));
+
+        // active filling starts again after the sink is replaced
+        let (tx, _rx) = mpsc::channel(32);
+        alice.replace_tx(tx);
+        assert!(respond(&precomp, bob_pk, addr, node_3));
+    }
+
+    #[test]
+    fn handle_nodes_resp_max_nodes_per_subnet() {
+        crypto_init().unwrap();
+        let (udp_tx, _udp_rx) = mpsc::channel(32);
+        let (alice_pk, alice_sk) = gen_keypair();
+        let mut alice = Server::new(udp_tx, alice_pk, alice_sk);
+
+        alice.set_max_nodes_per_subnet(Some(2));
+
+        // responding nodes beyond the limit are not added to close nodes list
+        for i in 0 .. 4 {
+            let (node_pk, node_sk) = gen_keypair();
+            let precomp = precompute(&alice_pk, &node_sk);
+            let addr = format!("1.2.3.{}:33445", i + 1).parse().unwrap();
+
+            let ping_id = alice.request_queue.write().new_ping_id(node_pk);
+            let resp_payload = NodesResponsePayload { nodes: Vec::new(), id: ping_id };
+            let nodes_resp = Packet::NodesResponse(NodesResponse::new(&precomp, &node_pk, &resp_payload));
+            alice.handle_packet(nodes_resp, addr).wait().unwrap();
+
+            assert_eq!(alice.close_nodes.read().contains(&node_pk), i < 2);
+        }
+
+        // nodes from the same subnet are not queried either
+        let (bob_pk, bob_sk) = gen_keypair();
+        let precomp = precompute(&alice_pk, &bob_sk);
+        let same_subnet_node = PackedNode::new("1.2.3.100:33445".parse().unwrap(), &pk_in_kbucket(&alice_pk, 5, 0));
+        let other_subnet_node = PackedNode::new("1.2.4.100:33445".parse().unwrap(), &pk_in_kbucket(&alice_pk, 6, 0));
+
+        let ping_id = alice.request_queue.write().new_ping_id(bob_pk);
+        let resp_payload = NodesResponsePayload { nodes: vec![same_subnet_node, other_subnet_node], id: ping_id };
+        let nodes_resp = Packet::NodesResponse(NodesResponse::new(&precomp, &bob_pk, &resp_payload));
+        alice.handle_packet(nodes_resp, "1.2.5.1:33445".parse().unwrap()).wait().unwrap();
+
+        let nodes_to_bootstrap = alice.nodes_to_bootstrap.read();
+        assert!(!nodes_to_bootstrap.contains(&alice_pk, &same_subnet_node.pk));
+        assert!(nodes_to_bootstrap.contains(&alice_pk, &other_subnet_node.pk));
+    }
+
+    #[test]
+    fn handle_nodes_resp_ipv6_node_in_ipv4_mode() {
+        let (alice, precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
+
+        let node_v4 = PackedNode::new("127.0.0.1:12345".parse().unwrap(), &gen_keypair().0);
+        let node_v6 = PackedNode::new("[2001:db8::1]:12345".parse().unwrap(), &gen_keypair().0);
+
+        let ping_id = alice.request_queue.write().new_ping_id(bob_pk);
+
+        let resp_payload = NodesResponsePayload { nodes: vec![node_v4, node_v6], id: ping_id };
+        let nodes_resp = Packet::NodesResponse(NodesResponse::new(&precomp, &bob_pk, &resp_payload));
+
+        alice.handle_packet(nodes_resp, addr).wait().unwrap();
+
+        // IPv6 node is unreachable in IPv4 mode so it shouldn't be pinged and
+        // therefore it won't be added to close nodes list
+        assert!(alice.nodes_to_bootstrap.read().contains(&alice.pk, &node_v4.pk));
+        assert!(!alice.nodes_to_bootstrap.read().contains(&alice.pk, &node_v6.pk));
+        assert!(!alice.close_nodes.read().contains(&node_v6.pk));
+    }
+
+    #[test]
+    fn handle_nodes_resp_ipv6_node_in_ipv6_mode() {
+        let (mut alice, precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
+
+        alice.enable_ipv6_mode(true);
+
+        let node_v4 = PackedNode::new("127.0.0.1:12345".parse().unwrap(), &gen_keypair().0);
+        let node_v6 = PackedNode::new("[2001:db8::1]:12345".parse().unwrap(), &gen_keypair().0);
+
+        let ping_id = alice.request_queue.write().new_ping_id(bob_pk);
+
+        let resp_payload = NodesResponsePayload { nodes: vec![node_v4, node_v6], id: ping_id };
+        let nodes_resp = Packet::NodesResponse(NodesResponse::new(&precomp, &bob_pk, &resp_payload));
+
+        alice.handle_packet(nodes_resp, addr).wait().unwrap();
+
+        assert!(alice.nodes_to_bootstrap.read().contains(&alice.pk, &node_v4.pk));
+        assert!(alice.nodes_to_bootstrap.read().contains(&alice.pk, &node_v6.pk));
+    }
+
+    #[test]
+    fn handle_nodes_resp_invalid_payload() {
+        let (alice, precomp, _bob_pk, _bob_sk, _rx, addr) = create_node();
+
+        // can't be decrypted payload since packet contains wrong key
+        let resp_payload = NodesResponsePayload { nodes: vec![
+            PackedNode::new("127.0.0.1:12345".parse().unwrap(), &gen_keypair().0)
+        ], id: 38 };
+        let nodes_resp = Packet::NodesResponse(NodesResponse::new(&precomp, &alice.pk, &resp_payload));
+
+        assert!(alice.handle_packet(nodes_resp, addr).wait().is_err());
+    }
+
+    #[test]
+    fn handle_nodes_resp_our_node_is_not_added_to_friends() {
+        let (alice, precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
+
+        alice.add_friend(gen_keypair().0);
+
+        let node = PackedNode::new("127.0.0.1:12345".parse().unwrap(), &alice.pk);
+
+        let ping_id = alice.request_queue.write().new_ping_id(bob_pk);
+        let resp_payload = NodesResponsePayload { nodes: vec![node], id: ping_id };
+        let nodes_resp = Packet::NodesResponse(NodesResponse::new(&precomp, &bob_pk, &resp_payload));
+
+        alice.handle_packet(nodes_resp, addr).wait().unwrap();
+
+        for friend in alice.friends.read().iter() {
+            assert!(!friend.close_nodes.contains(&friend.pk, &alice.pk));
+            assert!(!friend.nodes_to_bootstrap.contains(&friend.pk, &alice.pk));
+        }
+    }
+
+    #[test]
+    fn handle_nodes_resp_sends_path_node() {
+        let (mut alice, precomp, bob_pk, _bob_sk, _rx, _addr) = create_node();
+
+        let (path_node_tx, path_node_rx) = mpsc::unbounded();
+        alice.set_path_node_sink(path_node_tx);
+
+        let global_addr = "8.8.8.8:33445".parse().unwrap();
+        let local_addr = "192.168.1.1:33445".parse().unwrap();
+
+        for &addr in &[global_addr, local_addr] {
+            let ping_id = alice.request_queue.write().new_ping_id(bob_pk);
+            let resp_payload = NodesResponsePayload { nodes: vec![], id: ping_id };
+            let nodes_resp = Packet::NodesResponse(NodesResponse::new(&precomp, &bob_pk, &resp_payload));
+            alice.handle_packet(nodes_resp, addr).wait().unwrap();
+        }
+
+        // response with unknown ping_id is not verified
+        let resp_payload = NodesResponsePayload { nodes: vec![], id: 42 };
+        let nodes_resp = Packet::NodesResponse(NodesResponse::new(&precomp, &bob_pk, &resp_payload));
+        alice.handle_packet(nodes_resp, global_addr).wait().unwrap();
+
+        drop(alice);
+        let path_nodes = path_node_rx.collect().wait().unwrap();
+        assert_eq!(path_nodes, vec![PackedNode::new(global_addr, &bob_pk)]);
+    }
+
+    #[test]
+    fn handle_nodes_resp_ping_id_is_0() {
+        let (alice, precomp, bob_pk, _bob_sk, rx, addr) = create_node();
+
+        let resp_payload = NodesResponsePayload { nodes: vec![
+            PackedNode::new("127.0.0.1:12345".parse().unwrap(), &gen_keypair().0)
+        ], id: 0 };
+        let nodes_resp = Packet::NodesResponse(NodesResponse::new(&precomp, &bob_pk, &resp_payload));
+
+        alice.handle_packet(nodes_resp, addr).wait().unwrap();
+
+        // Necessary to drop tx so that rx.collect() can be finished
+        drop(alice);
+
+        assert!(rx.collect().wait().unwrap().is_empty());
+    }
+
+    #[test]
+    fn handle_nodes_resp_invalid_ping_id() {
+        let (alice, precomp, bob_pk, _bob_sk, rx, addr) = create_node();
+
+        let ping_id = alice.request_queue.write().new_ping_id(bob_pk);
+
+        let resp_payload = NodesResponsePayload {
+            nodes: vec![
+                PackedNode::new("127.0.0.1:12345".parse().unwrap(), &gen_keypair().0)
+            ],
+            id: ping_id + 1
+        };
+        let nodes_resp = Packet::NodesResponse(NodesResponse::new(&precomp, &bob_pk, &resp_payload));
+
+        alice.handle_packet(nodes_resp, addr).wait().unwrap();
+
+        // Necessary to drop tx so that rx.collect() can be finished
+        drop(alice);
+
+        assert!(rx.collect().wait().unwrap().is_empty());
+    }
+
+    #[test]
+    fn handle_nodes_resp_ping_id_of_other_node() {
+        let (alice, precomp, bob_pk, _bob_sk, rx, addr) = create_node();
+        let (node_pk, _node_sk) = gen_keypair();
+
+        // request was sent to another node so bob's response is not expected
+        let ping_id = alice.request_queue.write().new_ping_id(node_pk);
+
+        let resp_payload = NodesResponsePayload {
+            nodes: vec![
+                PackedNode::new("127.0.0.1:12345".parse().unwrap(), &gen_keypair().0)
+            ],
+            id: ping_id
+        };
+        let nodes_resp = Packet::NodesResponse(NodesResponse::new(&precomp, &bob_pk, &resp_payload));
+
+        alice.handle_packet(nodes_resp, addr).wait().unwrap();
+
+        assert!(!alice.close_nodes.read().contains(&bob_pk));
+        assert!(alice.nodes_to_bootstrap.read().is_empty());
+        // ping id of the queried node is still valid
+        assert!(alice.request_queue.write().check_ping_id(node_pk, ping_id));
+
+        // Necessary to drop tx so that rx.collect() can be finished
+        drop(alice);
+
+        assert!(rx.collect().wait().unwrap().is_empty());
+    }
+
+    // handle_cookie_request
+    #[test]
+    fn handle_cookie_request() {
+        crypto_init().unwrap();
+        let (udp_tx, udp_rx) = mpsc::channel(1);
+        let (dht_pk, dht_sk) = gen_keypair();
+        let mut alice = Server::new(udp_tx.clone(), dht_pk, dht_sk.clone());
+
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (real_pk, _real_sk) = gen_keypair();
+        let (bob_pk, bob_sk) = gen_keypair();
+        let (bob_real_pk, _bob_real_sk) = gen_keypair();
+        let precomp = precompute(&alice.pk, &bob_sk);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk,
+            real_pk,
+            precomputed_keys: alice.get_precomputed_keys(),
+        });
+
+        alice.set_net_crypto(net_crypto);
+
+        let addr = "127.0.0.1:12346".parse().unwrap();
+
+        let cookie_request_id = 12345;
+        let cookie_request_payload = CookieRequestPayload {
+            pk: bob_real_pk,
+            id: cookie_request_id,
+        };
+        let cookie_request = Packet::CookieRequest(CookieRequest::new(&precomp, &bob_pk, &cookie_request_payload));
+
+        alice.handle_packet(cookie_request, addr).wait().unwrap();
+
+        let (received, _udp_rx) = udp_rx.into_future().wait().unwrap();
+        let (packet, addr_to_send) = received.unwrap();
+
+        assert_eq!(addr_to_send, addr);
+
+        let packet = unpack!(packet, Packet::CookieResponse);
+        let payload = packet.get_payload(&precomp).unwrap();
+
+        assert_eq!(payload.id, cookie_request_id);
+    }
+
+    #[test]
+    fn handle_cookie_request_max_handshakes() {
+        crypto_init().unwrap();
+        let (udp_tx, udp_rx) = mpsc::channel(8);
+        let (dht_pk, dht_sk) = gen_keypair();
+        let mut alice = Server::new(udp_tx.clone(), dht_pk, dht_sk.clone());
+
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (real_pk, _real_sk) = gen_keypair();
+        let (bob_pk, bob_sk) = gen_keypair();
+        let (bob_real_pk, _bob_real_sk) = gen_keypair();
+        let precomp = precompute(&alice.pk, &bob_sk);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk,
+            real_pk,
+            precomputed_keys: alice.get_precomputed_keys(),
+        });
+
+        alice.set_net_crypto(net_crypto);
+        alice.set_max_handshakes(Some(2));
+
+        let addr = "127.0.0.1:12346".parse().unwrap();
+
+        let cookie_request_payload = CookieRequestPayload {
+            pk: bob_real_pk,
+            id: 12345,
+        };
+        let cookie_request = Packet::CookieRequest(CookieRequest::new(&precomp, &bob_pk, &cookie_request_payload));
+
+        for _ in 0 .. 4 {
+            alice.handle_packet(cookie_request.clone(), addr).wait().unwrap();
+        }
+
+        assert_eq!(alice.dropped_handshake_packets(), 2);
+
+        // Necessary to drop tx so that udp_rx.collect() can be finished
+        drop(alice);
+
+        let packets = udp_rx.collect().wait().unwrap();
+        assert_eq!(packets.len(), 2);
+        for (packet, _addr) in packets {
+            unpack!(packet, Packet::CookieResponse);
+        }
+    }
+
+    #[test]
+    fn handle_cookie_request_max_handshakes_per_addr() {
+        crypto_init().unwrap();
+        let (udp_tx, udp_rx) = mpsc::channel(8);
+        let (dht_pk, dht_sk) = gen_keypair();
+        let mut alice = Server::new(udp_tx.clone(), dht_pk, dht_sk.clone());
+
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (real_pk, _real_sk) = gen_keypair();
+        let (bob_pk, bob_sk) = gen_keypair();
+        let (bob_real_pk, _bob_real_sk) = gen_keypair();
+        let precomp = precompute(&alice.pk, &bob_sk);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk,
+            real_pk,
+            precomputed_keys: alice.get_precomputed_keys(),
+        });
+
+        alice.set_net_crypto(net_crypto);
+        alice.set_max_handshakes_per_addr(Some(1));
+        alice.set_max_handshakes(Some(2));
+
+        let cookie_request_payload = CookieRequestPayload {
+            pk: bob_real_pk,
+            id: 12345,
+        };
+        let cookie_request = Packet::CookieRequest(CookieRequest::new(&precomp, &bob_pk, &cookie_request_payload));
+
+        // the first address exceeds its own limit but doesn't use up the
+        // total one
+        for _ in 0 .. 3 {
+            alice.handle_packet(cookie_request.clone(), "127.0.0.1:12346".parse().unwrap()).wait().unwrap();
+        }
+        assert_eq!(alice.dropped_handshake_packets(), 2);
+
+        alice.handle_packet(cookie_request.clone(), "127.0.0.2:12346".parse().unwrap()).wait().unwrap();
+        assert_eq!(alice.dropped_handshake_packets(), 2);
+
+        // the total limit is reached
+        alice.handle_packet(cookie_request, "127.0.0.3:12346".parse().unwrap()).wait().unwrap();
+        assert_eq!(alice.dropped_handshake_packets(), 3);
+
+        // Necessary to drop tx so that udp_rx.collect() can be finished
+        drop(alice);
+
+        assert_eq!(udp_rx.collect().wait().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn handle_cookie_request_uninitialized() {
+        let (alice, precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
+
+        let (bob_real_pk, _bob_real_sk) = gen_keypair();
+
+        let cookie_request_payload = CookieRequestPayload {
+            pk: bob_real_pk,
+            id: 12345,
+        };
+        let cookie_request = Packet::CookieRequest(CookieRequest::new(&precomp, &bob_pk, &cookie_request_payload));
+
+        assert!(alice.handle_packet(cookie_request, addr).wait().is_err());
+    }
+
+    // handle_cookie_response
+    #[test]
+    fn handle_cookie_response_uninitialized() {
+        let (alice, precomp, _bob_pk, _bob_sk, _rx, addr) = create_node();
+
+        let cookie = EncryptedCookie {
+            nonce: secretbox::gen_nonce(),
+            payload: vec![43; 88]
+        };
+        let cookie_response_payload = CookieResponsePayload {
+            cookie: cookie.clone(),
+            id: 12345
+        };
+        let cookie_response = Packet::CookieResponse(CookieResponse::new(&precomp, &cookie_response_payload));
+
+        assert!(alice.handle_packet(cookie_response, addr).wait().is_err());
+    }
+
+    // handle_crypto_handshake
+    #[test]
+    fn handle_crypto_handshake_uninitialized() {
+        let (alice, precomp, _bob_pk, _bob_sk, _rx, addr) = create_node();
+
+        let cookie = EncryptedCookie {
+            nonce: secretbox::gen_nonce(),
+            payload: vec![43; 88]
+        };
+        let crypto_handshake_payload = CryptoHandshakePayload {
+            base_nonce: gen_nonce(),
+            session_pk: gen_keypair().0,
+            cookie_hash: cookie.hash(),
+            cookie: cookie.clone()
+        };
+        let crypto_handshake = Packet::CryptoHandshake(CryptoHandshake::new(&precomp, &crypto_handshake_payload, cookie));
+
+        assert!(alice.handle_packet(crypto_handshake, addr).wait().is_err());
+    }
+
+    // handle_dht_req
+    #[test]
+    fn handle_dht_req_for_unknown_node() {
+        let (alice, _precomp, bob_pk, bob_sk, rx, addr) = create_node();
+
+        let (charlie_pk, _charlie_sk) = gen_keypair();
+        let precomp = precompute(&charlie_pk, &bob_sk);
+
+        // if receiver' pk != node's pk just returns ok()
+        let nat_req = NatPingRequest { id: 42 };
+        let nat_payload = DhtRequestPayload::NatPingRequest(nat_req);
+        let dht_req = Packet::DhtRequest(DhtRequest::new(&precomp, &charlie_pk, &bob_pk, &nat_payload));
+
+        alice.handle_packet(dht_req, addr).wait().unwrap();
+
+        // Necessary to drop tx so that rx.collect() can be finished
+        drop(alice);
+
+        assert!(rx.collect().wait().unwrap().is_empty());
+    }
+
+    #[test]
+    fn handle_dht_req_for_known_node() {
+        let (alice, _precomp, bob_pk, bob_sk, rx, addr) = create_node();
+
+        let charlie_addr = "1.2.3.4:12345".parse().unwrap();
+        let (charlie_pk, _charlie_sk) = gen_keypair();
+        let precomp = precompute(&charlie_pk, &bob_sk);
+
+        // if receiver' pk != node's pk and receiver's pk exists in close_nodes, returns ok()
+        let pn = PackedNode::new(charlie_addr, &charlie_pk);
+        alice.try_add_to_close_nodes(&pn);
+
+        let nat_req = NatPingRequest { id: 42 };
+        let nat_payload = DhtRequestPayload::NatPingRequest(nat_req);
+        let dht_req = Packet::DhtRequest(DhtRequest::new(&precomp, &charlie_pk, &bob_pk, &nat_payload));
+
+        alice.handle_packet(dht_req.clone(), addr).wait().unwrap();
+
+        let (received, _rx) = rx.into_future().wait().unwrap();
+        let (packet, addr_to_send) = received.unwrap();
+
+        assert_eq!(addr_to_send, charlie_addr);
+        assert_eq!(packet, dht_req);
+    }
+
+    #[test]
+    fn handle_dht_req_invalid_payload() {
+        let (alice, _precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
+
+        let dht_req = Packet::DhtRequest(DhtRequest {
+            rpk: alice.pk,
+            spk: bob_pk,
+            nonce: gen_nonce(),
+            payload: vec![42; 123]
+        });
+
+        assert!(alice.handle_packet(dht_req, addr).wait().is_err());
+    }
+
+    #[test]
+    fn handle_dht_req_mismatched_spk() {
+        let (alice, precomp, _bob_pk, _bob_sk, rx, addr) = create_node();
+        let eve_pk = gen_keypair().0;
+
+        alice.add_friend(eve_pk);
+
+        // packet is encrypted by bob but claims to be sent by eve
+        let nat_req = NatPingRequest { id: 42 };
+        let nat_payload = DhtRequestPayload::NatPingRequest(nat_req);
+        let dht_req = Packet::DhtRequest(DhtRequest::new(&precomp, &alice.pk, &eve_pk, &nat_payload));
+
+        assert!(alice.handle_packet(dht_req, addr).wait().is_err());
+
+        drop(alice);
+        assert!(rx.collect().wait().unwrap().is_empty());
+    }
+
+    // handle_nat_ping_request
+    #[test]
+    fn handle_nat_ping_req() {
+        let (alice, precomp, bob_pk, bob_sk, rx, addr) = create_node();
+
+        alice.add_friend(bob_pk);
+
+        let nat_req = NatPingRequest { id: 42 };
+        let nat_payload = DhtRequestPayload::NatPingRequest(nat_req);
+        let dht_req = Packet::DhtRequest(DhtRequest::new(&precomp, &alice.pk, &bob_pk, &nat_payload));
+
+        let mut enter = tokio_executor::enter().unwrap();
+        let time = Instant::now() + Duration::from_secs(1);
+        let clock = Clock::new_with_now(ConstNow(time));
+
+        with_default(&clock, &mut enter, |_| {
+            alice.handle_packet(dht_req, addr).wait().unwrap();
+        });
+
+        let (received, _rx) = rx.into_future().wait().unwrap();
+        let (packet, addr_to_send) = received.unwrap();
+
+        assert_eq!(addr_to_send, addr);
+
+        let dht_req = unpack!(packet, Packet::DhtRequest);
+        let precomputed_key = precompute(&dht_req.spk, &bob_sk);
+        let dht_payload = dht_req.get_payload(&precomputed_key).unwrap();
+        let nat_ping_resp_payload = unpack!(dht_payload, DhtRequestPayload::NatPingResponse);
+
+        assert_eq!(nat_ping_resp_payload.id, nat_req.id);
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        let friends = alice.friends.read();
 
-    use futures::Future;
-    use std::net::SocketAddr;
+        assert_eq!(friends[FAKE_FRIENDS_NUMBER].hole_punch.last_recv_ping_time, time);
+    }
 
-    use tokio_executor;
-    use tokio_timer::clock::*;
+    // handle_nat_ping_response
+    #[test]
+    fn handle_nat_ping_resp() {
+        let (alice, precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
 
-    use crate::toxcore::time::ConstNow;
+        alice.add_friend(bob_pk);
+        let ping_id = alice.friends.read()[FAKE_FRIENDS_NUMBER].hole_punch.ping_id;
 
-    const ONION_RETURN_1_PAYLOAD_SIZE: usize = ONION_RETURN_1_SIZE - secretbox::NONCEBYTES;
-    const ONION_RETURN_2_PAYLOAD_SIZE: usize = ONION_RETURN_2_SIZE - secretbox::NONCEBYTES;
-    const ONION_RETURN_3_PAYLOAD_SIZE: usize = ONION_RETURN_3_SIZE - secretbox::NONCEBYTES;
+        let nat_res = NatPingResponse { id: ping_id };
+        let nat_payload = DhtRequestPayload::NatPingResponse(nat_res);
+        let dht_req = Packet::DhtRequest(DhtRequest::new(&precomp, &alice.pk, &bob_pk, &nat_payload));
 
-    fn create_node() -> (Server, PrecomputedKey, PublicKey, SecretKey,
-            mpsc::Receiver<(Packet, SocketAddr)>, SocketAddr) {
-        crypto_init().unwrap();
+        alice.handle_packet(dht_req, addr).wait().unwrap();
 
-        let (pk, sk) = gen_keypair();
-        let (tx, rx) = mpsc::channel(32);
-        let alice = Server::new(tx, pk, sk);
-        let (bob_pk, bob_sk) = gen_keypair();
-        let precomp = precompute(&alice.pk, &bob_sk);
+        let friends = alice.friends.read();
 
-        let addr: SocketAddr = "127.0.0.1:12346".parse().unwrap();
-        (alice, precomp, bob_pk, bob_sk, rx, addr)
+        assert!(!friends[FAKE_FRIENDS_NUMBER].hole_punch.is_punching_done);
     }
 
     #[test]
-    fn server_is_clonable() {
-        crypto_init().unwrap();
-        let (pk, sk) = gen_keypair();
-        let (tx, _rx) = mpsc::channel(1);
-        let server = Server::new(tx, pk, sk);
+    fn handle_nat_ping_resp_ping_id_is_0() {
+        let (alice, precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
 
-        let _ = server.clone();
+        // error case, ping_id = 0
+        let nat_res = NatPingResponse { id: 0 };
+        let nat_payload = DhtRequestPayload::NatPingResponse(nat_res);
+        let dht_req = Packet::DhtRequest(DhtRequest::new(&precomp, &alice.pk, &bob_pk, &nat_payload));
+
+        assert!(alice.handle_packet(dht_req, addr).wait().is_err());
     }
 
     #[test]
-    fn add_friend_test() {
-        let (alice, _precomp, bob_pk, _bob_sk, _rx, _addr) = create_node();
+    fn handle_nat_ping_resp_from_unknown_node() {
+        let (alice, precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
 
-        let packed_node = PackedNode::new("211.192.153.67:33445".parse().unwrap(), &bob_pk);
-        assert!(alice.try_add_to_close_nodes(&packed_node));
+        let nat_res = NatPingResponse { id: 42 };
+        let nat_payload = DhtRequestPayload::NatPingResponse(nat_res);
+        let dht_req = Packet::DhtRequest(DhtRequest::new(&precomp, &alice.pk, &bob_pk, &nat_payload));
 
-        let friend_pk = gen_keypair().0;
-        alice.add_friend(friend_pk);
+        alice.handle_packet(dht_req, addr).wait().unwrap();
 
-        let inserted_friend = &alice.friends.read()[FAKE_FRIENDS_NUMBER];
-        assert!(inserted_friend.nodes_to_bootstrap.contains(&friend_pk, &bob_pk));
+        assert_eq!(alice.unknown_nat_ping_responses(), 1);
     }
 
-    // handle_bootstrap_info
     #[test]
-    fn handle_bootstrap_info() {
-        let (mut alice, _precomp, _bob_pk, _bob_sk, rx, addr) = create_node();
+    fn handle_nat_ping_resp_invalid_ping_id() {
+        let (alice, precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
 
-        let version = 42;
-        let motd = b"motd".to_vec();
-        let motd_c = motd.clone();
+        alice.add_friend(bob_pk);
 
-        alice.set_bootstrap_info(version, Box::new(move |_| motd_c.clone()));
+        // error case, incorrect ping_id
+        let ping_id = alice.friends.read()[FAKE_FRIENDS_NUMBER].hole_punch.ping_id;
 
-        let packet = Packet::BootstrapInfo(BootstrapInfo {
-            version: 00,
-            motd: vec![0; BOOSTRAP_CLIENT_MAX_MOTD_LENGTH],
-        });
+        let nat_res = NatPingResponse { id: ping_id + 1 };
+        let nat_payload = DhtRequestPayload::NatPingResponse(nat_res);
+        let dht_req = Packet::DhtRequest(DhtRequest::new(&precomp, &alice.pk, &bob_pk, &nat_payload));
+
+        assert!(alice.handle_packet(dht_req, addr).wait().is_err());
+    }
+
+    // handle_onion_request_0
+    #[test]
+    fn handle_onion_request_0() {
+        let (alice, precomp, bob_pk, _bob_sk, rx, addr) = create_node();
+
+        let temporary_pk = gen_keypair().0;
+        let inner = vec![42; 123];
+        let ip_port = IpPort {
+            protocol: ProtocolType::UDP,
+            ip_addr: "5.6.7.8".parse().unwrap(),
+            port: 12345
+        };
+        let payload = OnionRequest0Payload {
+            ip_port: ip_port.clone(),
+            temporary_pk,
+            inner: inner.clone()
+        };
+        let packet = Packet::OnionRequest0(OnionRequest0::new(&precomp, &bob_pk, &payload));
 
         alice.handle_packet(packet, addr).wait().unwrap();
 
         let (received, _rx) = rx.into_future().wait().unwrap();
         let (packet, addr_to_send) = received.unwrap();
 
-        assert_eq!(addr_to_send, addr);
+        assert_eq!(addr_to_send, ip_port.to_saddr());
 
-        let bootstrap_info = unpack!(packet, Packet::BootstrapInfo);
+        let next_packet = unpack!(packet, Packet::OnionRequest1);
 
-        assert_eq!(bootstrap_info.version, version);
-        assert_eq!(bootstrap_info.motd, motd);
+        assert_eq!(next_packet.temporary_pk, temporary_pk);
+        assert_eq!(next_packet.payload, inner);
+
+        let onion_symmetric_key = alice.onion_symmetric_key.read();
+        let onion_return_payload = next_packet.onion_return.get_payload(&onion_symmetric_key).unwrap();
+
+        assert_eq!(onion_return_payload.0, IpPort::from_udp_saddr(addr));
     }
 
     #[test]
-    fn handle_bootstrap_info_wrong_length() {
-        let (mut alice, _precomp, _bob_pk, _bob_sk, rx, addr) = create_node();
-
-        let version = 42;
-        let motd = b"motd".to_vec();
+    fn pause_onion_relay() {
+        let (alice, precomp, bob_pk, _bob_sk, rx, addr) = create_node();
 
-        alice.set_bootstrap_info(version, Box::new(move |_| motd.clone()));
+        let payload = OnionRequest0Payload {
+            ip_port: IpPort {
+                protocol: ProtocolType::UDP,
+                ip_addr: "5.6.7.8".parse().unwrap(),
+                port: 12345
+            },
+            temporary_pk: gen_keypair().0,
+            inner: vec![42; 123]
+        };
+        let packet = Packet::OnionRequest0(OnionRequest0::new(&precomp, &bob_pk, &payload));
 
-        let packet = Packet::BootstrapInfo(BootstrapInfo {
-            version: 00,
-            motd: Vec::new(),
-        });
+        // toggle on a clone to make sure the flag is shared
+        alice.clone().pause_onion_relay();
+        assert!(alice.is_onion_relay_paused());
+        alice.handle_packet(packet.clone(), addr).wait().unwrap();
 
-        assert!(alice.handle_packet(packet, addr).wait().is_err());
+        alice.clone().resume_onion_relay();
+        assert!(!alice.is_onion_relay_paused());
+        alice.handle_packet(packet, addr).wait().unwrap();
 
-        // Necessary to drop tx so that rx.collect() can be finished
         drop(alice);
+        let packets = rx.collect().wait().unwrap();
 
-        assert!(rx.collect().wait().unwrap().is_empty());
+        // only the packet received after resuming is relayed
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].1, "5.6.7.8:12345".parse().unwrap());
     }
 
-    // handle_ping_req
     #[test]
-    fn handle_ping_req() {
-        let (alice, precomp, bob_pk, bob_sk, rx, addr) = create_node();
+    fn handle_onion_request_0_max_onion_forwards() {
+        let (mut alice, precomp, bob_pk, _bob_sk, rx, addr) = create_node();
 
-        let req_payload = PingRequestPayload { id: 42 };
-        let ping_req = Packet::PingRequest(PingRequest::new(&precomp, &bob_pk, &req_payload));
+        alice.set_max_onion_forwards(Some(1));
 
-        alice.handle_packet(ping_req, addr).wait().unwrap();
+        let payload = OnionRequest0Payload {
+            ip_port: IpPort {
+                protocol: ProtocolType::UDP,
+                ip_addr: "5.6.7.8".parse().unwrap(),
+                port: 12345
+            },
+            temporary_pk: gen_keypair().0,
+            inner: vec![42; 123]
+        };
+        let packet = Packet::OnionRequest0(OnionRequest0::new(&precomp, &bob_pk, &payload));
 
-        let (received, _rx) = rx.into_future().wait().unwrap();
-        let (packet, addr_to_send) = received.unwrap();
+        // forward is in flight until its future is completed
+        let forward = alice.handle_packet(packet.clone(), addr);
+        assert_eq!(alice.onion_forwards_in_flight.load(Ordering::Relaxed), 1);
 
-        assert_eq!(addr_to_send, addr);
+        // so the next request is dropped
+        alice.handle_packet(packet.clone(), addr).wait().unwrap();
+        assert_eq!(alice.shed_onion_packets(), 1);
 
-        let ping_resp = unpack!(packet, Packet::PingResponse);
-        let precomputed_key = precompute(&ping_resp.pk, &bob_sk);
-        let ping_resp_payload = ping_resp.get_payload(&precomputed_key).unwrap();
+        forward.wait().unwrap();
+        assert_eq!(alice.onion_forwards_in_flight.load(Ordering::Relaxed), 0);
 
-        assert_eq!(ping_resp_payload.id, req_payload.id);
+        // dropped forward doesn't count as in flight either
+        drop(alice.handle_packet(packet, addr));
+        assert_eq!(alice.onion_forwards_in_flight.load(Ordering::Relaxed), 0);
 
-        assert!(alice.nodes_to_ping.read().contains(&alice.pk, &bob_pk));
+        // Necessary to drop tx so that rx.collect() can be finished
+        drop(alice);
+
+        assert_eq!(rx.collect().wait().unwrap().len(), 1);
     }
 
     #[test]
-    fn handle_ping_req_from_friend_with_unknown_addr() {
-        let (alice, precomp, bob_pk, bob_sk, rx, addr) = create_node();
+    fn handle_onion_request_0_well_known_port() {
+        let (mut alice, precomp, bob_pk, _bob_sk, rx, addr) = create_node();
 
-        alice.add_friend(bob_pk);
-
-        let req_payload = PingRequestPayload { id: 42 };
-        let ping_req = Packet::PingRequest(PingRequest::new(&precomp, &bob_pk, &req_payload));
-
-        alice.handle_packet(ping_req, addr).wait().unwrap();
-
-        let mut request_queue = alice.request_queue.write();
+        alice.deny_well_known_onion_ports();
 
-        rx.take(2).map(|(packet, addr_to_send)| {
-            assert_eq!(addr_to_send, addr);
+        let temporary_pk = gen_keypair().0;
+        for &port in &[22, WELL_KNOWN_PORTS_END - 1, WELL_KNOWN_PORTS_END, 12345] {
+            let payload = OnionRequest0Payload {
+                ip_port: IpPort {
+                    protocol: ProtocolType::UDP,
+                    ip_addr: "5.6.7.8".parse().unwrap(),
+                    port
+                },
+                temporary_pk,
+                inner: vec![42; 123]
+            };
+            let packet = Packet::OnionRequest0(OnionRequest0::new(&precomp, &bob_pk, &payload));
+            alice.handle_packet(packet, addr).wait().unwrap();
+        }
 
-            if let Packet::PingResponse(ping_resp) = packet {
-                let precomputed_key = precompute(&ping_resp.pk, &bob_sk);
-                let ping_resp_payload = ping_resp.get_payload(&precomputed_key).unwrap();
-                assert_eq!(ping_resp_payload.id, req_payload.id);
-            } else {
-                let ping_req = unpack!(packet, Packet::PingRequest);
-                let precomputed_key = precompute(&ping_req.pk, &bob_sk);
-                let ping_req_payload = ping_req.get_payload(&precomputed_key).unwrap();
-                assert!(request_queue.check_ping_id(bob_pk, ping_req_payload.id));
-            }
-        }).collect().wait().unwrap();
+        // Necessary to drop tx so that rx.collect() can be finished
+        drop(alice);
 
-        // In case of friend with yet unknown address we should send ping
-        // request immediately instead of adding node to nodes_to_ping list
-        assert!(!alice.nodes_to_ping.read().contains(&alice.pk, &bob_pk));
+        let addrs = rx.collect().wait().unwrap().into_iter()
+            .map(|(_packet, addr)| addr)
+            .collect::<Vec<_>>();
+        assert_eq!(addrs, vec![
+            SocketAddr::new("5.6.7.8".parse().unwrap(), WELL_KNOWN_PORTS_END),
+            "5.6.7.8:12345".parse().unwrap(),
+        ]);
     }
 
     #[test]
-    fn handle_ping_req_invalid_payload() {
-        let (alice, precomp, _bob_pk, _bob_sk, _rx, addr) = create_node();
+    fn handle_onion_request_0_invalid_payload() {
+        let (alice, _precomp, _bob_pk, _bob_sk, _rx, addr) = create_node();
 
-        // can't be decrypted payload since packet contains wrong key
-        let req_payload = PingRequestPayload { id: 42 };
-        let ping_req = Packet::PingRequest(PingRequest::new(&precomp, &alice.pk, &req_payload));
+        let packet = Packet::OnionRequest0(OnionRequest0 {
+            nonce: gen_nonce(),
+            temporary_pk: gen_keypair().0,
+            payload: vec![42; 123] // not encrypted with dht pk
+        });
 
-        assert!(alice.handle_packet(ping_req, addr).wait().is_err());
+        assert!(alice.handle_packet(packet, addr).wait().is_err());
     }
 
-    // handle_ping_resp
     #[test]
-    fn handle_ping_resp() {
-        let (alice, precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
+    fn handle_onion_request_0_zero_temporary_pk() {
+        let (alice, precomp, bob_pk, _bob_sk, rx, addr) = create_node();
 
-        alice.add_friend(bob_pk);
+        let payload = OnionRequest0Payload {
+            ip_port: IpPort {
+                protocol: ProtocolType::UDP,
+                ip_addr: "5.6.7.8".parse().unwrap(),
+                port: 12345
+            },
+            temporary_pk: PublicKey([0; PUBLICKEYBYTES]),
+            inner: vec![42; 123]
+        };
+        let packet = Packet::OnionRequest0(OnionRequest0::new(&precomp, &bob_pk, &payload));
 
-        let packed_node = PackedNode::new(addr, &bob_pk);
-        assert!(alice.try_add_to_close_nodes(&packed_node));
+        assert!(alice.handle_packet(packet, addr).wait().is_err());
 
-        let ping_id = alice.request_queue.write().new_ping_id(bob_pk);
+        // Necessary to drop tx so that rx.collect() can be finished
+        drop(alice);
 
-        let resp_payload = PingResponsePayload { id: ping_id };
-        let ping_resp = Packet::PingResponse(PingResponse::new(&precomp, &bob_pk, &resp_payload));
+        assert!(rx.collect().wait().unwrap().is_empty());
+    }
 
-        let time = Instant::now() + Duration::from_secs(1);
+    // handle_onion_request_1
+    #[test]
+    fn handle_onion_request_1() {
+        let (alice, precomp, bob_pk, _bob_sk, rx, addr) = create_node();
+
+        let temporary_pk = gen_keypair().0;
+        let inner = vec![42; 123];
+        let ip_port = IpPort {
+            protocol: ProtocolType::UDP,
+            ip_addr: "5.6.7.8".parse().unwrap(),
+            port: 12345
+        };
+        let payload = OnionRequest1Payload {
+            ip_port: ip_port.clone(),
+            temporary_pk,
+            inner: inner.clone()
+        };
+        let onion_return = OnionReturn {
+            nonce: secretbox::gen_nonce(),
+            payload: vec![42; ONION_RETURN_1_PAYLOAD_SIZE]
+        };
+        let packet = Packet::OnionRequest1(OnionRequest1::new(&precomp, &bob_pk, &payload, onion_return));
+
+        alice.handle_packet(packet, addr).wait().unwrap();
 
-        let mut enter = tokio_executor::enter().unwrap();
-        let clock = Clock::new_with_now(ConstNow(time));
+        let (received, _rx) = rx.into_future().wait().unwrap();
+        let (packet, addr_to_send) = received.unwrap();
 
-        with_default(&clock, &mut enter, |_| {
-            alice.handle_packet(ping_resp, addr).wait().unwrap();
-        });
+        assert_eq!(addr_to_send, ip_port.to_saddr());
 
-        let friends = alice.friends.read();
-        let friend = friends.first().unwrap();
+        let next_packet = unpack!(packet, Packet::OnionRequest2);
 
-        // All nodes from PingResponse should be added to bootstrap nodes list
-        // of each friend
-        assert!(friend.close_nodes.contains(&bob_pk, &bob_pk));
+        assert_eq!(next_packet.temporary_pk, temporary_pk);
+        assert_eq!(next_packet.payload, inner);
 
-        let close_nodes = alice.close_nodes.read();
-        let node = close_nodes.get_node(&bob_pk).unwrap();
+        let onion_symmetric_key = alice.onion_symmetric_key.read();
+        let onion_return_payload = next_packet.onion_return.get_payload(&onion_symmetric_key).unwrap();
 
-        // Node that sent PingResponse should be added to close nodes list and
-        // have updated last_resp_time
-        assert_eq!(node.assoc4.last_resp_time.unwrap(), time);
+        assert_eq!(onion_return_payload.0, IpPort::from_udp_saddr(addr));
     }
 
     #[test]
-    fn handle_ping_resp_invalid_payload() {
-        let (alice, precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
+    fn handle_onion_request_1_replay() {
+        let (mut alice, precomp, bob_pk, _bob_sk, rx, addr) = create_node();
 
-        let packed_node = PackedNode::new(addr, &bob_pk);
-        assert!(alice.try_add_to_close_nodes(&packed_node));
+        alice.enable_onion_replay_detection(true);
 
-        let ping_id = alice.request_queue.write().new_ping_id(bob_pk);
+        let payload = OnionRequest1Payload {
+            ip_port: IpPort {
+                protocol: ProtocolType::UDP,
+                ip_addr: "5.6.7.8".parse().unwrap(),
+                port: 12345
+            },
+            temporary_pk: gen_keypair().0,
+            inner: vec![42; 123]
+        };
+        let onion_return = OnionReturn {
+            nonce: secretbox::gen_nonce(),
+            payload: vec![42; ONION_RETURN_1_PAYLOAD_SIZE]
+        };
+        let packet = Packet::OnionRequest1(OnionRequest1::new(&precomp, &bob_pk, &payload, onion_return));
 
-        // can't be decrypted payload since packet contains wrong key
-        let payload = PingResponsePayload { id: ping_id };
-        let ping_resp = Packet::PingResponse(PingResponse::new(&precomp, &alice.pk, &payload));
+        alice.handle_packet(packet.clone(), addr).wait().unwrap();
+        // the same onion return came again so the second request is dropped
+        alice.handle_packet(packet, addr).wait().unwrap();
 
-        assert!(alice.handle_packet(ping_resp, addr).wait().is_err());
+        // Necessary to drop tx so that rx.collect() can be finished
+        drop(alice);
+
+        let packets = rx.collect().wait().unwrap();
+        assert_eq!(packets.len(), 1);
+        unpack!(packets[0].0.clone(), Packet::OnionRequest2);
     }
 
     #[test]
-    fn handle_ping_resp_ping_id_is_0() {
-        let (alice, precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
+    fn handle_onion_request_1_too_big_payload() {
+        let (alice, precomp, bob_pk, _bob_sk, rx, addr) = create_node();
 
-        let packed_node = PackedNode::new(addr, &bob_pk);
-        assert!(alice.try_add_to_close_nodes(&packed_node));
+        let ip_port = IpPort {
+            protocol: ProtocolType::UDP,
+            ip_addr: "5.6.7.8".parse().unwrap(),
+            port: 12345
+        };
+        let payload = OnionRequest1Payload {
+            ip_port,
+            temporary_pk: gen_keypair().0,
+            inner: vec![42; ONION_REQUEST_1_MAX_PAYLOAD_SIZE]
+        };
+        let onion_return = OnionReturn {
+            nonce: secretbox::gen_nonce(),
+            payload: vec![42; ONION_RETURN_1_PAYLOAD_SIZE]
+        };
+        let packet = Packet::OnionRequest1(OnionRequest1::new(&precomp, &bob_pk, &payload, onion_return));
 
-        let payload = PingResponsePayload { id: 0 };
-        let ping_resp = Packet::PingResponse(PingResponse::new(&precomp, &bob_pk, &payload));
+        alice.handle_packet(packet, addr).wait().unwrap();
 
-        assert!(alice.handle_packet(ping_resp, addr).wait().is_err());
+        assert_eq!(alice.oversized_onion_packets(), 1);
+
+        drop(alice);
+        assert!(rx.collect().wait().unwrap().is_empty());
     }
 
     #[test]
-    fn handle_ping_resp_invalid_ping_id() {
-        let (alice, precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
-
-        let packed_node = PackedNode::new(addr, &bob_pk);
-        assert!(alice.try_add_to_close_nodes(&packed_node));
-
-        let ping_id = alice.request_queue.write().new_ping_id(bob_pk);
+    fn handle_onion_request_1_invalid_payload() {
+        let (alice, _precomp, _bob_pk, _bob_sk, _rx, addr) = create_node();
 
-        let payload = PingResponsePayload { id: ping_id + 1 };
-        let ping_resp = Packet::PingResponse(PingResponse::new(&precomp, &bob_pk, &payload));
+        let packet = Packet::OnionRequest1(OnionRequest1 {
+            nonce: gen_nonce(),
+            temporary_pk: gen_keypair().0,
+            payload: vec![42; 123], // not encrypted with dht pk
+            onion_return: OnionReturn {
+                nonce: secretbox::gen_nonce(),
+                payload: vec![42; ONION_RETURN_1_PAYLOAD_SIZE]
+            }
+        });
 
-        assert!(alice.handle_packet(ping_resp, addr).wait().is_err());
+        assert!(alice.handle_packet(packet, addr).wait().is_err());
     }
 
-    // handle_nodes_req
+    // handle_onion_request_2
     #[test]
-    fn handle_nodes_req() {
-        let (alice, precomp, bob_pk, bob_sk, rx, addr) = create_node();
-
-        let packed_node = PackedNode::new("127.0.0.1:12345".parse().unwrap(), &bob_pk);
-
-        assert!(alice.try_add_to_close_nodes(&packed_node));
+    fn handle_onion_request_2_with_onion_announce_request() {
+        let (alice, precomp, bob_pk, _bob_sk, rx, addr) = create_node();
 
-        let req_payload = NodesRequestPayload { pk: bob_pk, id: 42 };
-        let nodes_req = Packet::NodesRequest(NodesRequest::new(&precomp, &bob_pk, &req_payload));
+        let inner = InnerOnionAnnounceRequest {
+            nonce: gen_nonce(),
+            pk: gen_keypair().0,
+            payload: vec![42; 123]
+        };
+        let ip_port = IpPort {
+            protocol: ProtocolType::UDP,
+            ip_addr: "5.6.7.8".parse().unwrap(),
+            port: 12345
+        };
+        let payload = OnionRequest2Payload {
+            ip_port: ip_port.clone(),
+            inner: InnerOnionRequest::InnerOnionAnnounceRequest(inner.clone())
+        };
+        let onion_return = OnionReturn {
+            nonce: secretbox::gen_nonce(),
+            payload: vec![42; ONION_RETURN_2_PAYLOAD_SIZE]
+        };
+        let packet = Packet::OnionRequest2(OnionRequest2::new(&precomp, &bob_pk, &payload, onion_return));
 
-        alice.handle_packet(nodes_req, addr).wait().unwrap();
+        alice.handle_packet(packet, addr).wait().unwrap();
 
         let (received, _rx) = rx.into_future().wait().unwrap();
         let (packet, addr_to_send) = received.unwrap();
 
-        assert_eq!(addr_to_send, addr);
+        assert_eq!(addr_to_send, ip_port.to_saddr());
 
-        let nodes_resp = unpack!(packet, Packet::NodesResponse);
-        let precomputed_key = precompute(&nodes_resp.pk, &bob_sk);
-        let nodes_resp_payload = nodes_resp.get_payload(&precomputed_key).unwrap();
+        let next_packet = unpack!(packet, Packet::OnionAnnounceRequest);
 
-        assert_eq!(nodes_resp_payload.id, req_payload.id);
-        assert_eq!(nodes_resp_payload.nodes, vec!(packed_node));
+        assert_eq!(next_packet.inner, inner);
 
-        assert!(alice.nodes_to_ping.read().contains(&alice.pk, &bob_pk));
+        let onion_symmetric_key = alice.onion_symmetric_key.read();
+        let onion_return_payload = next_packet.onion_return.get_payload(&onion_symmetric_key).unwrap();
+
+        assert_eq!(onion_return_payload.0, IpPort::from_udp_saddr(addr));
     }
 
     #[test]
-    fn handle_nodes_req_should_return_nodes_from_friends() {
-        let (alice, precomp, bob_pk, bob_sk, rx, addr) = create_node();
+    fn handle_onion_request_2_denied_port() {
+        let (mut alice, precomp, bob_pk, _bob_sk, rx, addr) = create_node();
 
-        alice.add_friend(bob_pk);
+        alice.set_denied_onion_ports(Some(vec![12345]));
 
-        let packed_node = PackedNode::new("127.0.0.1:12345".parse().unwrap(), &bob_pk);
-        assert!(alice.friends.write()[FAKE_FRIENDS_NUMBER].try_add_to_close(&packed_node));
+        let inner = InnerOnionAnnounceRequest {
+            nonce: gen_nonce(),
+            pk: gen_keypair().0,
+            payload: vec![42; 123]
+        };
+        let payload = OnionRequest2Payload {
+            ip_port: IpPort {
+                protocol: ProtocolType::UDP,
+                ip_addr: "5.6.7.8".parse().unwrap(),
+                port: 12345
+            },
+            inner: InnerOnionRequest::InnerOnionAnnounceRequest(inner)
+        };
+        let onion_return = OnionReturn {
+            nonce: secretbox::gen_nonce(),
+            payload: vec![42; ONION_RETURN_2_PAYLOAD_SIZE]
+        };
+        let packet = Packet::OnionRequest2(OnionRequest2::new(&precomp, &bob_pk, &payload, onion_return));
 
-        let req_payload = NodesRequestPayload { pk: bob_pk, id: 42 };
-        let nodes_req = Packet::NodesRequest(NodesRequest::new(&precomp, &bob_pk, &req_payload));
+        alice.handle_packet(packet, addr).wait().unwrap();
 
-        alice.handle_packet(nodes_req, addr).wait().unwrap();
+        // Necessary to drop tx so that rx.collect() can be finished
+        drop(alice);
 
-        let (received, _rx) = rx.into_future().wait().unwrap();
-        let (packet, addr_to_send) = received.unwrap();
+        assert!(rx.collect().wait().unwrap().is_empty());
+    }
 
-        assert_eq!(addr_to_send, addr);
+    #[test]
+    fn handle_onion_request_2_with_onion_data_request() {
+        let (alice, precomp, bob_pk, _bob_sk, rx, addr) = create_node();
 
-        let nodes_resp = unpack!(packet, Packet::NodesResponse);
-        let precomputed_key = precompute(&nodes_resp.pk, &bob_sk);
-        let nodes_resp_payload = nodes_resp.get_payload(&precomputed_key).unwrap();
+        let inner = InnerOnionDataRequest {
+            destination_pk: gen_keypair().0,
+            nonce: gen_nonce(),
+            temporary_pk: gen_keypair().0,
+            payload: vec![42; 123]
+        };
+        let ip_port = IpPort {
+            protocol: ProtocolType::UDP,
+            ip_addr: "5.6.7.8".parse().unwrap(),
+            port: 12345
+        };
+        let payload = OnionRequest2Payload {
+            ip_port: ip_port.clone(),
+            inner: InnerOnionRequest::InnerOnionDataRequest(inner.clone())
+        };
+        let onion_return = OnionReturn {
+            nonce: secretbox::gen_nonce(),
+            payload: vec![42; ONION_RETURN_2_PAYLOAD_SIZE]
+        };
+        let packet = Packet::OnionRequest2(OnionRequest2::new(&precomp, &bob_pk, &payload, onion_return));
 
-        assert_eq!(nodes_resp_payload.id, req_payload.id);
-        assert_eq!(nodes_resp_payload.nodes, vec!(packed_node));
+        alice.handle_packet(packet, addr).wait().unwrap();
 
-        assert!(alice.nodes_to_ping.read().contains(&alice.pk, &bob_pk));
-    }
+        let (received, _rx) = rx.into_future().wait().unwrap();
+        let (packet, addr_to_send) = received.unwrap();
 
-    #[test]
-    fn handle_nodes_req_should_not_return_bad_nodes() {
-        let (alice, precomp, bob_pk, bob_sk, rx, addr) = create_node();
+        assert_eq!(addr_to_send, ip_port.to_saddr());
 
-        let packed_node = PackedNode::new("127.0.0.1:12345".parse().unwrap(), &bob_pk);
+        let next_packet = unpack!(packet, Packet::OnionDataRequest);
 
-        assert!(alice.try_add_to_close_nodes(&packed_node));
+        assert_eq!(next_packet.inner, inner);
 
-        let req_payload = NodesRequestPayload { pk: bob_pk, id: 42 };
-        let nodes_req = Packet::NodesRequest(NodesRequest::new(&precomp, &bob_pk, &req_payload));
+        let onion_symmetric_key = alice.onion_symmetric_key.read();
+        let onion_return_payload = next_packet.onion_return.get_payload(&onion_symmetric_key).unwrap();
 
-        let time = Instant::now() + Duration::from_secs(BAD_NODE_TIMEOUT + 1);
+        assert_eq!(onion_return_payload.0, IpPort::from_udp_saddr(addr));
+    }
 
-        let mut enter = tokio_executor::enter().unwrap();
-        let clock = Clock::new_with_now(ConstNow(time));
+    #[test]
+    fn handle_onion_request_2_invalid_payload() {
+        let (alice, _precomp, _bob_pk, _bob_sk, _rx, addr) = create_node();
 
-        with_default(&clock, &mut enter, |_| {
-            alice.handle_packet(nodes_req, addr).wait().unwrap();
+        let packet = Packet::OnionRequest2(OnionRequest2 {
+            nonce: gen_nonce(),
+            temporary_pk: gen_keypair().0,
+            payload: vec![42; 123], // not encrypted with dht pk
+            onion_return: OnionReturn {
+                nonce: secretbox::gen_nonce(),
+                payload: vec![42; ONION_RETURN_2_PAYLOAD_SIZE]
+            }
         });
 
-        let (received, _rx) = rx.into_future().wait().unwrap();
-        let (packet, addr_to_send) = received.unwrap();
+        assert!(alice.handle_packet(packet, addr).wait().is_err());
+    }
 
-        assert_eq!(addr_to_send, addr);
+    #[test]
+    fn handle_onion_request_2_malformed_inner() {
+        let (alice, precomp, bob_pk, _bob_sk, rx, addr) = create_node();
 
-        let nodes_resp = unpack!(packet, Packet::NodesResponse);
-        let precomputed_key = precompute(&nodes_resp.pk, &bob_sk);
-        let nodes_resp_payload = nodes_resp.get_payload(&precomputed_key).unwrap();
+        // payload is encrypted properly but it's not a valid
+        // OnionRequest2Payload
+        let nonce = gen_nonce();
+        let packet = Packet::OnionRequest2(OnionRequest2 {
+            nonce,
+            temporary_pk: bob_pk,
+            payload: seal_precomputed(&[42; 123], &nonce, &precomp),
+            onion_return: OnionReturn {
+                nonce: secretbox::gen_nonce(),
+                payload: vec![42; ONION_RETURN_2_PAYLOAD_SIZE]
+            }
+        });
 
-        assert_eq!(nodes_resp_payload.id, req_payload.id);
-        assert!(nodes_resp_payload.nodes.is_empty());
+        assert!(alice.handle_packet(packet, addr).wait().is_err());
+        assert_eq!(alice.malformed_onion_packets(), 1);
+
+        // Necessary to drop tx so that rx.collect() can be finished
+        drop(alice);
 
-        assert!(alice.nodes_to_ping.read().contains(&alice.pk, &bob_pk));
+        assert!(rx.collect().wait().unwrap().is_empty());
     }
 
+    // handle_onion_announce_request
     #[test]
-    fn handle_nodes_req_should_not_return_lan_nodes_when_address_is_global() {
-        let (alice, precomp, bob_pk, bob_sk, rx, _addr) = create_node();
-        let addr = "8.10.8.10:12345".parse().unwrap();
-
-        let packed_node = PackedNode::new("192.168.42.42:12345".parse().unwrap(), &bob_pk);
-
-        assert!(alice.try_add_to_close_nodes(&packed_node));
+    fn handle_onion_announce_request() {
+        let (alice, precomp, bob_pk, _bob_sk, rx, addr) = create_node();
 
-        let req_payload = NodesRequestPayload { pk: bob_pk, id: 42 };
-        let nodes_req = Packet::NodesRequest(NodesRequest::new(&precomp, &bob_pk, &req_payload));
+        let sendback_data = 42;
+        let payload = OnionAnnounceRequestPayload {
+            ping_id: initial_ping_id(),
+            search_pk: gen_keypair().0,
+            data_pk: gen_keypair().0,
+            sendback_data
+        };
+        let inner = InnerOnionAnnounceRequest::new(&precomp, &bob_pk, &payload);
+        let onion_return = OnionReturn {
+            nonce: secretbox::gen_nonce(),
+            payload: vec![42; ONION_RETURN_3_PAYLOAD_SIZE]
+        };
+        let packet = Packet::OnionAnnounceRequest(OnionAnnounceRequest {
+            inner,
+            onion_return: onion_return.clone()
+        });
 
-        alice.handle_packet(nodes_req, addr).wait().unwrap();
+        alice.handle_packet(packet, addr).wait().unwrap();
 
         let (received, _rx) = rx.into_future().wait().unwrap();
         let (packet, addr_to_send) = received.unwrap();
 
         assert_eq!(addr_to_send, addr);
 
-        let nodes_resp = unpack!(packet, Packet::NodesResponse);
-        let precomputed_key = precompute(&nodes_resp.pk, &bob_sk);
-        let nodes_resp_payload = nodes_resp.get_payload(&precomputed_key).unwrap();
+        let response = unpack!(packet, Packet::OnionResponse3);
 
-        assert_eq!(nodes_resp_payload.id, req_payload.id);
-        assert!(nodes_resp_payload.nodes.is_empty());
+        assert_eq!(response.onion_return, onion_return);
 
-        assert!(alice.nodes_to_ping.read().contains(&alice.pk, &bob_pk));
-    }
+        let response = unpack!(response.payload, InnerOnionResponse::OnionAnnounceResponse);
 
-    #[test]
-    fn handle_nodes_req_invalid_payload() {
-        let (alice, precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
+        assert_eq!(response.sendback_data, sendback_data);
 
-        // can't be decrypted payload since packet contains wrong key
-        let req_payload = NodesRequestPayload { pk: bob_pk, id: 42 };
-        let nodes_req = Packet::NodesRequest(NodesRequest::new(&precomp, &alice.pk, &req_payload));
+        let payload = response.get_payload(&precomp).unwrap();
 
-        assert!(alice.handle_packet(nodes_req, addr).wait().is_err());
+        assert_eq!(payload.announce_status, AnnounceStatus::Failed);
     }
 
-    // handle_nodes_resp
     #[test]
-    fn handle_nodes_resp() {
-        let (alice, precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
-
-        alice.add_friend(bob_pk);
-
-        let node = PackedNode::new("127.0.0.1:12345".parse().unwrap(), &gen_keypair().0);
-
-        let ping_id = alice.request_queue.write().new_ping_id(bob_pk);
-
-        let resp_payload = NodesResponsePayload { nodes: vec![node], id: ping_id };
-        let nodes_resp = Packet::NodesResponse(NodesResponse::new(&precomp, &bob_pk, &resp_payload));
+    fn handle_onion_announce_request_not_found_includes_nodes() {
+        let (mut alice, precomp, bob_pk, _bob_sk, rx, addr) = create_node();
 
-        let time = Instant::now() + Duration::from_secs(1);
+        alice.set_onion_announce_nodes_count(2);
 
-        let mut enter = tokio_executor::enter().unwrap();
-        let clock = Clock::new_with_now(ConstNow(time));
+        let search_pk = gen_keypair().0;
+        let mut nodes = Vec::new();
+        for i in 0 .. 4 {
+            let node = PackedNode::new(SocketAddr::new("127.0.0.1".parse().unwrap(), 33445 + i), &gen_keypair().0);
+            assert!(alice.try_add_to_close_nodes(&node));
+            nodes.push(node);
+        }
+        nodes.sort_by(|a, b| search_pk.distance(&a.pk, &b.pk));
 
-        with_default(&clock, &mut enter, |_| {
-            alice.handle_packet(nodes_resp, addr).wait().unwrap();
+        let payload = OnionAnnounceRequestPayload {
+            ping_id: initial_ping_id(),
+            search_pk,
+            data_pk: gen_keypair().0,
+            sendback_data: 42
+        };
+        let inner = InnerOnionAnnounceRequest::new(&precomp, &bob_pk, &payload);
+        let onion_return = OnionReturn {
+            nonce: secretbox::gen_nonce(),
+            payload: vec![42; ONION_RETURN_3_PAYLOAD_SIZE]
+        };
+        let packet = Packet::OnionAnnounceRequest(OnionAnnounceRequest {
+            inner,
+            onion_return
         });
 
-        // All nodes from NodesResponse should be added to bootstrap nodes list
-        assert!(alice.nodes_to_bootstrap.read().contains(&alice.pk, &node.pk));
-
-        let friends = alice.friends.read();
-        let friend = friends.first().unwrap();
-
-        // Node that sent NodesResponse should be added to close nodes list of
-        // each friend
-        assert!(friend.nodes_to_bootstrap.contains(&bob_pk, &node.pk));
-        // All nodes from NodesResponse should be added to bootstrap nodes list
-        // of each friend
-        assert!(friend.close_nodes.contains(&bob_pk, &bob_pk));
+        alice.handle_packet(packet, addr).wait().unwrap();
 
-        let close_nodes = alice.close_nodes.read();
-        let node = close_nodes.get_node(&bob_pk).unwrap();
+        let (received, _rx) = rx.into_future().wait().unwrap();
+        let (packet, _addr_to_send) = received.unwrap();
+        let response = unpack!(packet, Packet::OnionResponse3);
+        let response = unpack!(response.payload, InnerOnionResponse::OnionAnnounceResponse);
+        let payload = response.get_payload(&precomp).unwrap();
 
-        // Node that sent NodesResponse should be added to close nodes list and
-        // have updated last_resp_time
-        assert_eq!(node.assoc4.last_resp_time.unwrap(), time);
+        assert_eq!(payload.announce_status, AnnounceStatus::Failed);
+        assert_eq!(payload.nodes, nodes[.. 2].to_vec());
     }
 
     #[test]
-    fn handle_nodes_resp_invalid_payload() {
-        let (alice, precomp, _bob_pk, _bob_sk, _rx, addr) = create_node();
+    fn handle_onion_announce_request_flood_from_one_addr() {
+        let (alice, precomp, bob_pk, _bob_sk, rx, addr) = create_node();
 
-        // can't be decrypted payload since packet contains wrong key
-        let resp_payload = NodesResponsePayload { nodes: vec![
-            PackedNode::new("127.0.0.1:12345".parse().unwrap(), &gen_keypair().0)
-        ], id: 38 };
-        let nodes_resp = Packet::NodesResponse(NodesResponse::new(&precomp, &alice.pk, &resp_payload));
+        // limit set on a clone is shared with the original
+        alice.clone().set_max_announce_responses_per_addr(4);
 
-        assert!(alice.handle_packet(nodes_resp, addr).wait().is_err());
-    }
+        let payload = OnionAnnounceRequestPayload {
+            ping_id: initial_ping_id(),
+            search_pk: gen_keypair().0,
+            data_pk: gen_keypair().0,
+            sendback_data: 42
+        };
+        let inner = InnerOnionAnnounceRequest::new(&precomp, &bob_pk, &payload);
+        let onion_return = OnionReturn {
+            nonce: secretbox::gen_nonce(),
+            payload: vec![42; ONION_RETURN_3_PAYLOAD_SIZE]
+        };
+        let packet = Packet::OnionAnnounceRequest(OnionAnnounceRequest {
+            inner,
+            onion_return
+        });
 
-    #[test]
-    fn handle_nodes_resp_ping_id_is_0() {
-        let (alice, precomp, bob_pk, _bob_sk, rx, addr) = create_node();
+        for _ in 0 .. 3 {
+            alice.handle_packet(packet.clone(), addr).wait().unwrap();
+        }
 
-        let resp_payload = NodesResponsePayload { nodes: vec![
-            PackedNode::new("127.0.0.1:12345".parse().unwrap(), &gen_keypair().0)
-        ], id: 0 };
-        let nodes_resp = Packet::NodesResponse(NodesResponse::new(&precomp, &bob_pk, &resp_payload));
+        // other ports of the same host share the limit
+        let other_port_addr = SocketAddr::new(addr.ip(), addr.port() + 1);
+        for _ in 0 .. 3 {
+            alice.handle_packet(packet.clone(), other_port_addr).wait().unwrap();
+        }
 
-        alice.handle_packet(nodes_resp, addr).wait().unwrap();
+        // requests from other hosts are not affected
+        let other_addr = "127.0.0.2:12347".parse().unwrap();
+        alice.handle_packet(packet, other_addr).wait().unwrap();
 
         // Necessary to drop tx so that rx.collect() can be finished
         drop(alice);
 
-        assert!(rx.collect().wait().unwrap().is_empty());
+        let packets = rx.collect().wait().unwrap();
+        assert_eq!(packets.iter().filter(|&&(_, to)| to.ip() == addr.ip()).count(), 4);
+        assert_eq!(packets.iter().filter(|&&(_, to)| to == other_addr).count(), 1);
     }
 
     #[test]
-    fn handle_nodes_resp_invalid_ping_id() {
+    fn handle_onion_announce_request_invalid_payload() {
+        let (alice, _precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
+
+        let inner = InnerOnionAnnounceRequest {
+            nonce: gen_nonce(),
+            pk: bob_pk,
+            payload: vec![42; 123]
+        };
+        let onion_return = OnionReturn {
+            nonce: secretbox::gen_nonce(),
+            payload: vec![42; ONION_RETURN_3_PAYLOAD_SIZE]
+        };
+        let packet = Packet::OnionAnnounceRequest(OnionAnnounceRequest {
+            inner,
+            onion_return: onion_return.clone()
+        });
+
+        assert!(alice.handle_packet(packet, addr).wait().is_err());
+    }
+
+    // handle_onion_data_request
+    #[test]
+    fn handle_onion_data_request() {
         let (alice, precomp, bob_pk, _bob_sk, rx, addr) = create_node();
 
-        let ping_id = alice.request_queue.write().new_ping_id(bob_pk);
+        // get ping id
 
-        let resp_payload = NodesResponsePayload {
-            nodes: vec![
-                PackedNode::new("127.0.0.1:12345".parse().unwrap(), &gen_keypair().0)
-            ],
-            id: ping_id + 1
+        let payload = OnionAnnounceRequestPayload {
+            ping_id: initial_ping_id(),
+            search_pk: gen_keypair().0,
+            data_pk: gen_keypair().0,
+            sendback_data: 42
         };
-        let nodes_resp = Packet::NodesResponse(NodesResponse::new(&precomp, &bob_pk, &resp_payload));
-
-        alice.handle_packet(nodes_resp, addr).wait().unwrap();
+        let inner = InnerOnionAnnounceRequest::new(&precomp, &bob_pk, &payload);
+        let onion_return = OnionReturn {
+            nonce: secretbox::gen_nonce(),
+            payload: vec![42; ONION_RETURN_3_PAYLOAD_SIZE]
+        };
+        let packet = Packet::OnionAnnounceRequest(OnionAnnounceRequest {
+            inner,
+            onion_return: onion_return.clone()
+        });
 
-        // Necessary to drop tx so that rx.collect() can be finished
-        drop(alice);
+        alice.handle_packet(packet, addr).wait().unwrap();
 
-        assert!(rx.collect().wait().unwrap().is_empty());
-    }
+        let (received, rx) = rx.into_future().wait().unwrap();
+        let (packet, _addr_to_send) = received.unwrap();
+        let response = unpack!(packet, Packet::OnionResponse3);
+        let response = unpack!(response.payload, InnerOnionResponse::OnionAnnounceResponse);
+        let payload = response.get_payload(&precomp).unwrap();
+        let ping_id = payload.ping_id_or_pk;
 
-    // handle_cookie_request
-    #[test]
-    fn handle_cookie_request() {
-        crypto_init().unwrap();
-        let (udp_tx, udp_rx) = mpsc::channel(1);
-        let (dht_pk, dht_sk) = gen_keypair();
-        let mut alice = Server::new(udp_tx.clone(), dht_pk, dht_sk.clone());
+        // announce node
 
-        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
-        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
-        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
-        let (real_pk, _real_sk) = gen_keypair();
-        let (bob_pk, bob_sk) = gen_keypair();
-        let (bob_real_pk, _bob_real_sk) = gen_keypair();
-        let precomp = precompute(&alice.pk, &bob_sk);
-        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
-            udp_tx,
-            dht_pk_tx,
-            lossless_tx,
-            lossy_tx,
-            dht_pk,
-            dht_sk,
-            real_pk,
-            precomputed_keys: alice.get_precomputed_keys(),
+        let payload = OnionAnnounceRequestPayload {
+            ping_id,
+            search_pk: gen_keypair().0,
+            data_pk: gen_keypair().0,
+            sendback_data: 42
+        };
+        let inner = InnerOnionAnnounceRequest::new(&precomp, &bob_pk, &payload);
+        let packet = Packet::OnionAnnounceRequest(OnionAnnounceRequest {
+            inner,
+            onion_return: onion_return.clone()
         });
 
-        alice.set_net_crypto(net_crypto);
+        alice.handle_packet(packet, addr).wait().unwrap();
 
-        let addr = "127.0.0.1:12346".parse().unwrap();
+        // send onion data request
 
-        let cookie_request_id = 12345;
-        let cookie_request_payload = CookieRequestPayload {
-            pk: bob_real_pk,
-            id: cookie_request_id,
+        let nonce = gen_nonce();
+        let temporary_pk = gen_keypair().0;
+        let payload = vec![42; 123];
+        let inner = InnerOnionDataRequest {
+            destination_pk: bob_pk,
+            nonce,
+            temporary_pk,
+            payload: payload.clone()
         };
-        let cookie_request = Packet::CookieRequest(CookieRequest::new(&precomp, &bob_pk, &cookie_request_payload));
+        let packet = Packet::OnionDataRequest(OnionDataRequest {
+            inner,
+            onion_return: onion_return.clone()
+        });
 
-        alice.handle_packet(cookie_request, addr).wait().unwrap();
+        alice.handle_packet(packet, addr).wait().unwrap();
 
-        let (received, _udp_rx) = udp_rx.into_future().wait().unwrap();
+        let (received, _rx) = rx.skip(1).into_future().wait().unwrap();
         let (packet, addr_to_send) = received.unwrap();
 
         assert_eq!(addr_to_send, addr);
 
-        let packet = unpack!(packet, Packet::CookieResponse);
-        let payload = packet.get_payload(&precomp).unwrap();
-
-        assert_eq!(payload.id, cookie_request_id);
-    }
-
-    #[test]
-    fn handle_cookie_request_uninitialized() {
-        let (alice, precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
+        let response = unpack!(packet, Packet::OnionResponse3);
 
-        let (bob_real_pk, _bob_real_sk) = gen_keypair();
+        assert_eq!(response.onion_return, onion_return);
 
-        let cookie_request_payload = CookieRequestPayload {
-            pk: bob_real_pk,
-            id: 12345,
-        };
-        let cookie_request = Packet::CookieRequest(CookieRequest::new(&precomp, &bob_pk, &cookie_request_payload));
+        let response = unpack!(response.payload, InnerOnionResponse::OnionDataResponse);
 
-        assert!(alice.handle_packet(cookie_request, addr).wait().is_err());
+        assert_eq!(response.nonce, nonce);
+        assert_eq!(response.temporary_pk, temporary_pk);
+        assert_eq!(response.payload, payload);
     }
 
-    // handle_cookie_response
     #[test]
-    fn handle_cookie_response_uninitialized() {
-        let (alice, precomp, _bob_pk, _bob_sk, _rx, addr) = create_node();
+    fn handle_onion_data_request_unknown_destination() {
+        let (alice, _precomp, _bob_pk, _bob_sk, _rx, addr) = create_node();
 
-        let cookie = EncryptedCookie {
-            nonce: secretbox::gen_nonce(),
-            payload: vec![43; 88]
-        };
-        let cookie_response_payload = CookieResponsePayload {
-            cookie: cookie.clone(),
-            id: 12345
+        let inner = InnerOnionDataRequest {
+            destination_pk: gen_keypair().0,
+            nonce: gen_nonce(),
+            temporary_pk: gen_keypair().0,
+            payload: vec![42; 123]
         };
-        let cookie_response = Packet::CookieResponse(CookieResponse::new(&precomp, &cookie_response_payload));
+        let packet = Packet::OnionDataRequest(OnionDataRequest {
+            inner,
+            onion_return: OnionReturn {
+                nonce: secretbox::gen_nonce(),
+                payload: vec![42; ONION_RETURN_3_PAYLOAD_SIZE]
+            }
+        });
 
-        assert!(alice.handle_packet(cookie_response, addr).wait().is_err());
+        assert!(alice.handle_packet(packet, addr).wait().is_err());
     }
 
-    // handle_crypto_handshake
     #[test]
-    fn handle_crypto_handshake_uninitialized() {
-        let (alice, precomp, _bob_pk, _bob_sk, _rx, addr) = create_node();
+    fn handle_onion_data_request_unknown_destination_failure_response() {
+        let (mut alice, _precomp, _bob_pk, _bob_sk, rx, addr) = create_node();
 
-        let cookie = EncryptedCookie {
+        alice.enable_onion_data_failure_response(true);
+
+        let nonce = gen_nonce();
+        let temporary_pk = gen_keypair().0;
+        let onion_return = OnionReturn {
             nonce: secretbox::gen_nonce(),
-            payload: vec![43; 88]
+            payload: vec![42; ONION_RETURN_3_PAYLOAD_SIZE]
         };
-        let crypto_handshake_payload = CryptoHandshakePayload {
-            base_nonce: gen_nonce(),
-            session_pk: gen_keypair().0,
-            cookie_hash: cookie.hash(),
-            cookie: cookie.clone()
+        let inner = InnerOnionDataRequest {
+            destination_pk: gen_keypair().0,
+            nonce,
+            temporary_pk,
+            payload: vec![42; 123]
         };
-        let crypto_handshake = Packet::CryptoHandshake(CryptoHandshake::new(&precomp, &crypto_handshake_payload, cookie));
+        let packet = Packet::OnionDataRequest(OnionDataRequest {
+            inner,
+            onion_return: onion_return.clone()
+        });
 
-        assert!(alice.handle_packet(crypto_handshake, addr).wait().is_err());
-    }
+        alice.handle_packet(packet, addr).wait().unwrap();
 
-    // handle_dht_req
-    #[test]
-    fn handle_dht_req_for_unknown_node() {
-        let (alice, _precomp, bob_pk, bob_sk, rx, addr) = create_node();
+        let (received, _rx) = rx.into_future().wait().unwrap();
+        let (packet, addr_to_send) = received.unwrap();
 
-        let (charlie_pk, _charlie_sk) = gen_keypair();
-        let precomp = precompute(&charlie_pk, &bob_sk);
+        assert_eq!(addr_to_send, addr);
 
-        // if receiver' pk != node's pk just returns ok()
-        let nat_req = NatPingRequest { id: 42 };
-        let nat_payload = DhtRequestPayload::NatPingRequest(nat_req);
-        let dht_req = Packet::DhtRequest(DhtRequest::new(&precomp, &charlie_pk, &bob_pk, &nat_payload));
+        let response = unpack!(packet, Packet::OnionResponse3);
 
-        alice.handle_packet(dht_req, addr).wait().unwrap();
+        assert_eq!(response.onion_return, onion_return);
 
-        // Necessary to drop tx so that rx.collect() can be finished
-        drop(alice);
+        let response = unpack!(response.payload, InnerOnionResponse::OnionDataResponse);
 
-        assert!(rx.collect().wait().unwrap().is_empty());
+        assert_eq!(response.nonce, nonce);
+        assert_eq!(response.temporary_pk, temporary_pk);
+        assert!(response.payload.is_empty());
     }
 
+    // handle_onion_response_3
     #[test]
-    fn handle_dht_req_for_known_node() {
-        let (alice, _precomp, bob_pk, bob_sk, rx, addr) = create_node();
-
-        let charlie_addr = "1.2.3.4:12345".parse().unwrap();
-        let (charlie_pk, _charlie_sk) = gen_keypair();
-        let precomp = precompute(&charlie_pk, &bob_sk);
+    fn handle_onion_response_3() {
+        let (alice, _precomp, _bob_pk, _bob_sk, rx, addr) = create_node();
 
-        // if receiver' pk != node's pk and receiver's pk exists in close_nodes, returns ok()
-        let pn = PackedNode::new(charlie_addr, &charlie_pk);
-        alice.try_add_to_close_nodes(&pn);
+        let onion_symmetric_key = alice.onion_symmetric_key.read();
 
-        let nat_req = NatPingRequest { id: 42 };
-        let nat_payload = DhtRequestPayload::NatPingRequest(nat_req);
-        let dht_req = Packet::DhtRequest(DhtRequest::new(&precomp, &charlie_pk, &bob_pk, &nat_payload));
+        let ip_port = IpPort {
+            protocol: ProtocolType::UDP,
+            ip_addr: "5.6.7.8".parse().unwrap(),
+            port: 12345
+        };
+        let next_onion_return = OnionReturn {
+            nonce: secretbox::gen_nonce(),
+            payload: vec![42; ONION_RETURN_2_PAYLOAD_SIZE]
+        };
+        let onion_return = OnionReturn::new(&onion_symmetric_key, &ip_port, Some(&next_onion_return));
+        let payload = InnerOnionResponse::OnionAnnounceResponse(OnionAnnounceResponse {
+            sendback_data: 12345,
+            nonce: gen_nonce(),
+            payload: vec![42; 123]
+        });
+        let packet = Packet::OnionResponse3(OnionResponse3 {
+            onion_return,
+            payload: payload.clone()
+        });
 
-        alice.handle_packet(dht_req.clone(), addr).wait().unwrap();
+        alice.handle_packet(packet, addr).wait().unwrap();
 
         let (received, _rx) = rx.into_future().wait().unwrap();
         let (packet, addr_to_send) = received.unwrap();
 
-        assert_eq!(addr_to_send, charlie_addr);
-        assert_eq!(packet, dht_req);
+        assert_eq!(addr_to_send, ip_port.to_saddr());
+
+        let next_packet = unpack!(packet, Packet::OnionResponse2);
+
+        assert_eq!(next_packet.payload, payload);
+        assert_eq!(next_packet.onion_return, next_onion_return);
     }
 
     #[test]
-    fn handle_dht_req_invalid_payload() {
-        let (alice, _precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
+    fn handle_onion_response_3_invalid_onion_return() {
+        let (alice, _precomp, _bob_pk, _bob_sk, rx, addr) = create_node();
 
-        let dht_req = Packet::DhtRequest(DhtRequest {
-            rpk: alice.pk,
-            spk: bob_pk,
+        let onion_return = OnionReturn {
+            nonce: secretbox::gen_nonce(),
+            payload: vec![42; ONION_RETURN_3_PAYLOAD_SIZE] // not encrypted with onion_symmetric_key
+        };
+        let payload = InnerOnionResponse::OnionAnnounceResponse(OnionAnnounceResponse {
+            sendback_data: 12345,
             nonce: gen_nonce(),
             payload: vec![42; 123]
         });
+        let packet = Packet::OnionResponse3(OnionResponse3 {
+            onion_return,
+            payload
+        });
 
-        assert!(alice.handle_packet(dht_req, addr).wait().is_err());
-    }
+        alice.handle_packet(packet, addr).wait().unwrap();
 
-    // handle_nat_ping_request
-    #[test]
-    fn handle_nat_ping_req() {
-        let (alice, precomp, bob_pk, bob_sk, rx, addr) = create_node();
+        // Necessary to drop tx so that rx.collect() can be finished
+        drop(alice);
 
-        alice.add_friend(bob_pk);
+        assert!(rx.collect().wait().unwrap().is_empty());
+    }
 
-        let nat_req = NatPingRequest { id: 42 };
-        let nat_payload = DhtRequestPayload::NatPingRequest(nat_req);
-        let dht_req = Packet::DhtRequest(DhtRequest::new(&precomp, &alice.pk, &bob_pk, &nat_payload));
+    #[test]
+    fn handle_onion_response_3_invalid_next_onion_return() {
+        let (alice, _precomp, _bob_pk, _bob_sk, _rx, addr) = create_node();
 
-        let mut enter = tokio_executor::enter().unwrap();
-        let time = Instant::now() + Duration::from_secs(1);
-        let clock = Clock::new_with_now(ConstNow(time));
+        let onion_symmetric_key = alice.onion_symmetric_key.read();
 
-        with_default(&clock, &mut enter, |_| {
-            alice.handle_packet(dht_req, addr).wait().unwrap();
+        let ip_port = IpPort {
+            protocol: ProtocolType::UDP,
+            ip_addr: "5.6.7.8".parse().unwrap(),
+            port: 12345
+        };
+        let onion_return = OnionReturn::new(&onion_symmetric_key, &ip_port, None);
+        let inner = OnionDataResponse {
+            nonce: gen_nonce(),
+            temporary_pk: gen_keypair().0,
+            payload: vec![42; 123]
+        };
+        let packet = Packet::OnionResponse3(OnionResponse3 {
+            onion_return,
+            payload: InnerOnionResponse::OnionDataResponse(inner.clone())
         });
 
-        let (received, _rx) = rx.into_future().wait().unwrap();
-        let (packet, addr_to_send) = received.unwrap();
-
-        assert_eq!(addr_to_send, addr);
-
-        let dht_req = unpack!(packet, Packet::DhtRequest);
-        let precomputed_key = precompute(&dht_req.spk, &bob_sk);
-        let dht_payload = dht_req.get_payload(&precomputed_key).unwrap();
-        let nat_ping_resp_payload = unpack!(dht_payload, DhtRequestPayload::NatPingResponse);
-
-        assert_eq!(nat_ping_resp_payload.id, nat_req.id);
-
-        let friends = alice.friends.read();
-
-        assert_eq!(friends[FAKE_FRIENDS_NUMBER].hole_punch.last_recv_ping_time, time);
+        assert!(alice.handle_packet(packet, addr).wait().is_err());
     }
 
-    // handle_nat_ping_response
+    // handle_onion_response_2
     #[test]
-    fn handle_nat_ping_resp() {
-        let (alice, precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
+    fn handle_onion_response_2() {
+        let (alice, _precomp, _bob_pk, _bob_sk, rx, addr) = create_node();
 
-        alice.add_friend(bob_pk);
-        let ping_id = alice.friends.read()[FAKE_FRIENDS_NUMBER].hole_punch.ping_id;
+        let onion_symmetric_key = alice.onion_symmetric_key.read();
 
-        let nat_res = NatPingResponse { id: ping_id };
-        let nat_payload = DhtRequestPayload::NatPingResponse(nat_res);
-        let dht_req = Packet::DhtRequest(DhtRequest::new(&precomp, &alice.pk, &bob_pk, &nat_payload));
+        let ip_port = IpPort {
+            protocol: ProtocolType::UDP,
+            ip_addr: "5.6.7.8".parse().unwrap(),
+            port: 12345
+        };
+        let next_onion_return = OnionReturn {
+            nonce: secretbox::gen_nonce(),
+            payload: vec![42; ONION_RETURN_1_PAYLOAD_SIZE]
+        };
+        let onion_return = OnionReturn::new(&onion_symmetric_key, &ip_port, Some(&next_onion_return));
+        let payload = InnerOnionResponse::OnionAnnounceResponse(OnionAnnounceResponse {
+            sendback_data: 12345,
+            nonce: gen_nonce(),
+            payload: vec![42; 123]
+        });
+        let packet = Packet::OnionResponse2(OnionResponse2 {
+            onion_return,
+            payload: payload.clone()
+        });
+
+        alice.handle_packet(packet, addr).wait().unwrap();
+
+        let (received, _rx) = rx.into_future().wait().unwrap();
+        let (packet, addr_to_send) = received.unwrap();
 
-        alice.handle_packet(dht_req, addr).wait().unwrap();
+        assert_eq!(addr_to_send, ip_port.to_saddr());
 
-        let friends = alice.friends.read();
+        let next_packet = unpack!(packet, Packet::OnionResponse1);
 
-        assert!(!friends[FAKE_FRIENDS_NUMBER].hole_punch.is_punching_done);
+        assert_eq!(next_packet.payload, payload);
+        assert_eq!(next_packet.onion_return, next_onion_return);
     }
 
     #[test]
-    fn handle_nat_ping_resp_ping_id_is_0() {
-        let (alice, precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
+    fn handle_onion_response_2_invalid_onion_return() {
+        let (alice, _precomp, _bob_pk, _bob_sk, rx, addr) = create_node();
 
-        // error case, ping_id = 0
-        let nat_res = NatPingResponse { id: 0 };
-        let nat_payload = DhtRequestPayload::NatPingResponse(nat_res);
-        let dht_req = Packet::DhtRequest(DhtRequest::new(&precomp, &alice.pk, &bob_pk, &nat_payload));
+        let onion_return = OnionReturn {
+            nonce: secretbox::gen_nonce(),
+            payload: vec![42; ONION_RETURN_2_PAYLOAD_SIZE] // not encrypted with onion_symmetric_key
+        };
+        let payload = InnerOnionResponse::OnionAnnounceResponse(OnionAnnounceResponse {
+            sendback_data: 12345,
+            nonce: gen_nonce(),
+            payload: vec![42; 123]
+        });
+        let packet = Packet::OnionResponse2(OnionResponse2 {
+            onion_return,
+            payload
+        });
 
-        assert!(alice.handle_packet(dht_req, addr).wait().is_err());
+        alice.handle_packet(packet, addr).wait().unwrap();
+
+        // Necessary to drop tx so that rx.collect() can be finished
+        drop(alice);
+
+        assert!(rx.collect().wait().unwrap().is_empty());
     }
 
     #[test]
-    fn handle_nat_ping_resp_invalid_ping_id() {
-        let (alice, precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
+    fn handle_onion_response_2_invalid_next_onion_return() {
+        let (alice, _precomp, _bob_pk, _bob_sk, _rx, addr) = create_node();
 
-        // error case, incorrect ping_id
-        let ping_id = alice.request_queue.write().new_ping_id(bob_pk);
+        let onion_symmetric_key = alice.onion_symmetric_key.read();
 
-        let nat_res = NatPingResponse { id: ping_id + 1 };
-        let nat_payload = DhtRequestPayload::NatPingResponse(nat_res);
-        let dht_req = Packet::DhtRequest(DhtRequest::new(&precomp, &alice.pk, &bob_pk, &nat_payload));
+        let ip_port = IpPort {
+            protocol: ProtocolType::UDP,
+            ip_addr: "5.6.7.8".parse().unwrap(),
+            port: 12345
+        };
+        let onion_return = OnionReturn::new(&onion_symmetric_key, &ip_port, None);
+        let inner = OnionDataResponse {
+            nonce: gen_nonce(),
+            temporary_pk: gen_keypair().0,
+            payload: vec![42; 123]
+        };
+        let packet = Packet::OnionResponse2(OnionResponse2 {
+            onion_return,
+            payload: InnerOnionResponse::OnionDataResponse(inner.clone())
+        });
 
-        assert!(alice.handle_packet(dht_req, addr).wait().is_err());
+        assert!(alice.handle_packet(packet, addr).wait().is_err());
     }
 
-    // handle_onion_request_0
+    // handle_onion_response_1
     #[test]
-    fn handle_onion_request_0() {
-        let (alice, precomp, bob_pk, _bob_sk, rx, addr) = create_node();
+    fn handle_onion_response_1_with_onion_announce_response() {
+        let (alice, _precomp, _bob_pk, _bob_sk, rx, addr) = create_node();
+
+        let onion_symmetric_key = alice.onion_symmetric_key.read();
 
-        let temporary_pk = gen_keypair().0;
-        let inner = vec![42; 123];
         let ip_port = IpPort {
             protocol: ProtocolType::UDP,
             ip_addr: "5.6.7.8".parse().unwrap(),
             port: 12345
         };
-        let payload = OnionRequest0Payload {
-            ip_port: ip_port.clone(),
-            temporary_pk,
-            inner: inner.clone()
+        let onion_return = OnionReturn::new(&onion_symmetric_key, &ip_port, None);
+        let inner = OnionAnnounceResponse {
+            sendback_data: 12345,
+            nonce: gen_nonce(),
+            payload: vec![42; 123]
         };
-        let packet = Packet::OnionRequest0(OnionRequest0::new(&precomp, &bob_pk, &payload));
+        let packet = Packet::OnionResponse1(OnionResponse1 {
+            onion_return,
+            payload: InnerOnionResponse::OnionAnnounceResponse(inner.clone())
+        });
 
         alice.handle_packet(packet, addr).wait().unwrap();
 
@@ -2096,52 +7529,32 @@ mod tests {
 
         assert_eq!(addr_to_send, ip_port.to_saddr());
 
-        let next_packet = unpack!(packet, Packet::OnionRequest1);
-
-        assert_eq!(next_packet.temporary_pk, temporary_pk);
-        assert_eq!(next_packet.payload, inner);
-
-        let onion_symmetric_key = alice.onion_symmetric_key.read();
-        let onion_return_payload = next_packet.onion_return.get_payload(&onion_symmetric_key).unwrap();
+        let next_packet = unpack!(packet, Packet::OnionAnnounceResponse);
 
-        assert_eq!(onion_return_payload.0, IpPort::from_udp_saddr(addr));
+        assert_eq!(next_packet, inner);
     }
 
     #[test]
-    fn handle_onion_request_0_invalid_payload() {
-        let (alice, _precomp, _bob_pk, _bob_sk, _rx, addr) = create_node();
-
-        let packet = Packet::OnionRequest0(OnionRequest0 {
-            nonce: gen_nonce(),
-            temporary_pk: gen_keypair().0,
-            payload: vec![42; 123] // not encrypted with dht pk
-        });
-
-        assert!(alice.handle_packet(packet, addr).wait().is_err());
-    }
+    fn server_handle_onion_response_1_with_onion_data_response_test() {
+        let (alice, _precomp, _bob_pk, _bob_sk, rx, addr) = create_node();
 
-    // handle_onion_request_1
-    #[test]
-    fn handle_onion_request_1() {
-        let (alice, precomp, bob_pk, _bob_sk, rx, addr) = create_node();
+        let onion_symmetric_key = alice.onion_symmetric_key.read();
 
-        let temporary_pk = gen_keypair().0;
-        let inner = vec![42; 123];
         let ip_port = IpPort {
             protocol: ProtocolType::UDP,
             ip_addr: "5.6.7.8".parse().unwrap(),
             port: 12345
         };
-        let payload = OnionRequest1Payload {
-            ip_port: ip_port.clone(),
-            temporary_pk,
-            inner: inner.clone()
-        };
-        let onion_return = OnionReturn {
-            nonce: secretbox::gen_nonce(),
-            payload: vec![42; ONION_RETURN_1_PAYLOAD_SIZE]
+        let onion_return = OnionReturn::new(&onion_symmetric_key, &ip_port, None);
+        let inner = OnionDataResponse {
+            nonce: gen_nonce(),
+            temporary_pk: gen_keypair().0,
+            payload: vec![42; 123]
         };
-        let packet = Packet::OnionRequest1(OnionRequest1::new(&precomp, &bob_pk, &payload, onion_return));
+        let packet = Packet::OnionResponse1(OnionResponse1 {
+            onion_return,
+            payload: InnerOnionResponse::OnionDataResponse(inner.clone())
+        });
 
         alice.handle_packet(packet, addr).wait().unwrap();
 
@@ -2150,685 +7563,819 @@ mod tests {
 
         assert_eq!(addr_to_send, ip_port.to_saddr());
 
-        let next_packet = unpack!(packet, Packet::OnionRequest2);
+        let next_packet = unpack!(packet, Packet::OnionDataResponse);
 
-        assert_eq!(next_packet.temporary_pk, temporary_pk);
-        assert_eq!(next_packet.payload, inner);
+        assert_eq!(next_packet, inner);
+    }
+
+    #[test]
+    fn handle_onion_response_1_redirect_to_tcp() {
+        let (mut alice, _precomp, _bob_pk, _bob_sk, _rx, _addr) = create_node();
+        let (tcp_onion_tx, tcp_onion_rx) = mpsc::channel(1);
+        alice.set_tcp_onion_sink(tcp_onion_tx);
+
+        let addr: SocketAddr = "127.0.0.1:12346".parse().unwrap();
 
         let onion_symmetric_key = alice.onion_symmetric_key.read();
-        let onion_return_payload = next_packet.onion_return.get_payload(&onion_symmetric_key).unwrap();
 
-        assert_eq!(onion_return_payload.0, IpPort::from_udp_saddr(addr));
+        let ip_port = IpPort {
+            protocol: ProtocolType::TCP,
+            ip_addr: "5.6.7.8".parse().unwrap(),
+            port: 12345
+        };
+        let onion_return = OnionReturn::new(&onion_symmetric_key, &ip_port, None);
+        let inner = InnerOnionResponse::OnionAnnounceResponse(OnionAnnounceResponse {
+            sendback_data: 12345,
+            nonce: gen_nonce(),
+            payload: vec![42; 123]
+        });
+        let packet = Packet::OnionResponse1(OnionResponse1 {
+            onion_return,
+            payload: inner.clone()
+        });
+
+        alice.handle_packet(packet, addr).wait().unwrap();
+
+        let (received, _tcp_onion_rx) = tcp_onion_rx.into_future().wait().unwrap();
+        let (packet, addr_to_send) = received.unwrap();
+
+        assert_eq!(addr_to_send, ip_port.to_saddr());
+        assert_eq!(packet, inner);
     }
 
     #[test]
-    fn handle_onion_request_1_invalid_payload() {
-        let (alice, _precomp, _bob_pk, _bob_sk, _rx, addr) = create_node();
+    fn handle_onion_response_1_to_ourselves() {
+        let (mut alice, _precomp, _bob_pk, _bob_sk, rx, addr) = create_node();
+        let (onion_response_tx, onion_response_rx) = mpsc::unbounded();
+        alice.set_onion_response_sink(onion_response_tx);
 
-        let packet = Packet::OnionRequest1(OnionRequest1 {
+        let local_addr = "127.0.0.1:33445".parse().unwrap();
+        alice.set_local_addr(local_addr);
+
+        let onion_symmetric_key = alice.onion_symmetric_key.read().clone();
+
+        let ip_port = IpPort::from_udp_saddr(local_addr);
+        let onion_return = OnionReturn::new(&onion_symmetric_key, &ip_port, None);
+        let inner = InnerOnionResponse::OnionDataResponse(OnionDataResponse {
             nonce: gen_nonce(),
             temporary_pk: gen_keypair().0,
-            payload: vec![42; 123], // not encrypted with dht pk
-            onion_return: OnionReturn {
-                nonce: secretbox::gen_nonce(),
-                payload: vec![42; ONION_RETURN_1_PAYLOAD_SIZE]
-            }
+            payload: vec![42; 123]
+        });
+        let packet = Packet::OnionResponse1(OnionResponse1 {
+            onion_return,
+            payload: inner.clone()
         });
 
-        assert!(alice.handle_packet(packet, addr).wait().is_err());
+        alice.handle_packet(packet, addr).wait().unwrap();
+
+        // Necessary to drop tx so that rx.collect() can be finished
+        drop(alice);
+
+        assert!(rx.collect().wait().unwrap().is_empty());
+        assert_eq!(onion_response_rx.collect().wait().unwrap(), vec![inner]);
     }
 
-    // handle_onion_request_2
     #[test]
-    fn handle_onion_request_2_with_onion_announce_request() {
-        let (alice, precomp, bob_pk, _bob_sk, rx, addr) = create_node();
+    fn handle_onion_response_1_to_ourselves_unspecified_bind() {
+        let (mut alice, _precomp, _bob_pk, _bob_sk, rx, addr) = create_node();
+        let (onion_response_tx, onion_response_rx) = mpsc::unbounded();
+        alice.set_onion_response_sink(onion_response_tx);
 
-        let inner = InnerOnionAnnounceRequest {
+        alice.set_local_addr("0.0.0.0:33445".parse().unwrap());
+
+        let onion_symmetric_key = alice.onion_symmetric_key.read().clone();
+
+        let ip_port = IpPort::from_udp_saddr("127.0.0.1:33445".parse().unwrap());
+        let onion_return = OnionReturn::new(&onion_symmetric_key, &ip_port, None);
+        let inner = InnerOnionResponse::OnionDataResponse(OnionDataResponse {
             nonce: gen_nonce(),
-            pk: gen_keypair().0,
+            temporary_pk: gen_keypair().0,
             payload: vec![42; 123]
-        };
-        let ip_port = IpPort {
-            protocol: ProtocolType::UDP,
-            ip_addr: "5.6.7.8".parse().unwrap(),
-            port: 12345
-        };
-        let payload = OnionRequest2Payload {
-            ip_port: ip_port.clone(),
-            inner: InnerOnionRequest::InnerOnionAnnounceRequest(inner.clone())
-        };
-        let onion_return = OnionReturn {
-            nonce: secretbox::gen_nonce(),
-            payload: vec![42; ONION_RETURN_2_PAYLOAD_SIZE]
-        };
-        let packet = Packet::OnionRequest2(OnionRequest2::new(&precomp, &bob_pk, &payload, onion_return));
+        });
+        let packet = Packet::OnionResponse1(OnionResponse1 {
+            onion_return,
+            payload: inner.clone()
+        });
 
         alice.handle_packet(packet, addr).wait().unwrap();
 
-        let (received, _rx) = rx.into_future().wait().unwrap();
-        let (packet, addr_to_send) = received.unwrap();
+        // Necessary to drop tx so that rx.collect() can be finished
+        drop(alice);
 
-        assert_eq!(addr_to_send, ip_port.to_saddr());
+        assert!(rx.collect().wait().unwrap().is_empty());
+        assert_eq!(onion_response_rx.collect().wait().unwrap(), vec![inner]);
+    }
 
-        let next_packet = unpack!(packet, Packet::OnionAnnounceRequest);
+    #[test]
+    fn handle_onion_response_1_to_unconfirmed_external_addr() {
+        let (mut alice, _precomp, bob_pk, _bob_sk, rx, addr) = create_node();
+        let (onion_response_tx, onion_response_rx) = mpsc::unbounded();
+        alice.set_onion_response_sink(onion_response_tx);
+
+        // a single node claims that we are seen from the address of a node
+        // we have to relay the response to
+        let external_addr = "1.2.3.4:33445".parse().unwrap();
+        let packed_node = PackedNode::new("127.0.0.1:12345".parse().unwrap(), &bob_pk);
+        assert!(alice.try_add_to_close_nodes(&packed_node));
+        alice.close_nodes.write().get_node_mut(&bob_pk).unwrap().update_returned_addr(external_addr);
 
-        assert_eq!(next_packet.inner, inner);
+        let onion_symmetric_key = alice.onion_symmetric_key.read().clone();
 
-        let onion_symmetric_key = alice.onion_symmetric_key.read();
-        let onion_return_payload = next_packet.onion_return.get_payload(&onion_symmetric_key).unwrap();
+        let ip_port = IpPort::from_udp_saddr(external_addr);
+        let onion_return = OnionReturn::new(&onion_symmetric_key, &ip_port, None);
+        let inner = OnionDataResponse {
+            nonce: gen_nonce(),
+            temporary_pk: gen_keypair().0,
+            payload: vec![42; 123]
+        };
+        let packet = Packet::OnionResponse1(OnionResponse1 {
+            onion_return,
+            payload: InnerOnionResponse::OnionDataResponse(inner.clone())
+        });
+
+        alice.handle_packet(packet, addr).wait().unwrap();
+
+        // Necessary to drop tx so that rx.collect() can be finished
+        drop(alice);
 
-        assert_eq!(onion_return_payload.0, IpPort::from_udp_saddr(addr));
+        assert_eq!(rx.collect().wait().unwrap(), vec![(Packet::OnionDataResponse(inner), external_addr)]);
+        assert!(onion_response_rx.collect().wait().unwrap().is_empty());
     }
 
     #[test]
-    fn handle_onion_request_2_with_onion_data_request() {
-        let (alice, precomp, bob_pk, _bob_sk, rx, addr) = create_node();
+    fn handle_onion_response_1_can_not_redirect_to_tcp() {
+        let (alice, _precomp, _bob_pk, _bob_sk, _rx, addr) = create_node();
+
+        let onion_symmetric_key = alice.onion_symmetric_key.read();
 
-        let inner = InnerOnionDataRequest {
-            destination_pk: gen_keypair().0,
-            nonce: gen_nonce(),
-            temporary_pk: gen_keypair().0,
-            payload: vec![42; 123]
-        };
         let ip_port = IpPort {
-            protocol: ProtocolType::UDP,
+            protocol: ProtocolType::TCP,
             ip_addr: "5.6.7.8".parse().unwrap(),
             port: 12345
         };
-        let payload = OnionRequest2Payload {
-            ip_port: ip_port.clone(),
-            inner: InnerOnionRequest::InnerOnionDataRequest(inner.clone())
+        let onion_return = OnionReturn::new(&onion_symmetric_key, &ip_port, None);
+        let inner = OnionAnnounceResponse {
+            sendback_data: 12345,
+            nonce: gen_nonce(),
+            payload: vec![42; 123]
         };
+        let packet = Packet::OnionResponse1(OnionResponse1 {
+            onion_return,
+            payload: InnerOnionResponse::OnionAnnounceResponse(inner.clone())
+        });
+
+        assert!(alice.handle_packet(packet, addr).wait().is_err());
+    }
+
+    #[test]
+    fn handle_onion_response_1_invalid_onion_return() {
+        let (alice, _precomp, _bob_pk, _bob_sk, rx, addr) = create_node();
+
         let onion_return = OnionReturn {
             nonce: secretbox::gen_nonce(),
-            payload: vec![42; ONION_RETURN_2_PAYLOAD_SIZE]
+            payload: vec![42; ONION_RETURN_1_PAYLOAD_SIZE] // not encrypted with onion_symmetric_key
         };
-        let packet = Packet::OnionRequest2(OnionRequest2::new(&precomp, &bob_pk, &payload, onion_return));
+        let payload = InnerOnionResponse::OnionAnnounceResponse(OnionAnnounceResponse {
+            sendback_data: 12345,
+            nonce: gen_nonce(),
+            payload: vec![42; 123]
+        });
+        let packet = Packet::OnionResponse1(OnionResponse1 {
+            onion_return,
+            payload
+        });
 
         alice.handle_packet(packet, addr).wait().unwrap();
 
-        let (received, _rx) = rx.into_future().wait().unwrap();
-        let (packet, addr_to_send) = received.unwrap();
-
-        assert_eq!(addr_to_send, ip_port.to_saddr());
-
-        let next_packet = unpack!(packet, Packet::OnionDataRequest);
-
-        assert_eq!(next_packet.inner, inner);
-
-        let onion_symmetric_key = alice.onion_symmetric_key.read();
-        let onion_return_payload = next_packet.onion_return.get_payload(&onion_symmetric_key).unwrap();
+        // Necessary to drop tx so that rx.collect() can be finished
+        drop(alice);
 
-        assert_eq!(onion_return_payload.0, IpPort::from_udp_saddr(addr));
+        assert!(rx.collect().wait().unwrap().is_empty());
     }
 
     #[test]
-    fn handle_onion_request_2_invalid_payload() {
+    fn handle_onion_response_1_invalid_next_onion_return() {
         let (alice, _precomp, _bob_pk, _bob_sk, _rx, addr) = create_node();
 
-        let packet = Packet::OnionRequest2(OnionRequest2 {
+        let onion_symmetric_key = alice.onion_symmetric_key.read();
+
+        let ip_port = IpPort {
+            protocol: ProtocolType::UDP,
+            ip_addr: "5.6.7.8".parse().unwrap(),
+            port: 12345
+        };
+        let next_onion_return = OnionReturn {
+            nonce: secretbox::gen_nonce(),
+            payload: vec![42; ONION_RETURN_1_PAYLOAD_SIZE]
+        };
+        let onion_return = OnionReturn::new(&onion_symmetric_key, &ip_port, Some(&next_onion_return));
+        let inner = OnionDataResponse {
             nonce: gen_nonce(),
             temporary_pk: gen_keypair().0,
-            payload: vec![42; 123], // not encrypted with dht pk
-            onion_return: OnionReturn {
-                nonce: secretbox::gen_nonce(),
-                payload: vec![42; ONION_RETURN_2_PAYLOAD_SIZE]
-            }
+            payload: vec![42; 123]
+        };
+        let packet = Packet::OnionResponse1(OnionResponse1 {
+            onion_return,
+            payload: InnerOnionResponse::OnionDataResponse(inner.clone())
         });
 
         assert!(alice.handle_packet(packet, addr).wait().is_err());
     }
 
-    // handle_onion_announce_request
+    // send_nat_ping_req()
     #[test]
-    fn handle_onion_announce_request() {
-        let (alice, precomp, bob_pk, _bob_sk, rx, addr) = create_node();
+    fn send_nat_ping_req() {
+        let (alice, _precomp, _bob_pk, _bob_sk, mut rx, _addr) = create_node();
 
-        let sendback_data = 42;
-        let payload = OnionAnnounceRequestPayload {
-            ping_id: initial_ping_id(),
-            search_pk: gen_keypair().0,
-            data_pk: gen_keypair().0,
-            sendback_data
-        };
-        let inner = InnerOnionAnnounceRequest::new(&precomp, &bob_pk, &payload);
-        let onion_return = OnionReturn {
-            nonce: secretbox::gen_nonce(),
-            payload: vec![42; ONION_RETURN_3_PAYLOAD_SIZE]
-        };
-        let packet = Packet::OnionAnnounceRequest(OnionAnnounceRequest {
-            inner,
-            onion_return: onion_return.clone()
-        });
+        let (friend_pk, friend_sk) = gen_keypair();
 
-        alice.handle_packet(packet, addr).wait().unwrap();
+        let nodes = [
+            PackedNode::new("127.1.1.1:12345".parse().unwrap(), &gen_keypair().0),
+            PackedNode::new("127.1.1.2:12345".parse().unwrap(), &gen_keypair().0),
+            PackedNode::new("127.1.1.3:12345".parse().unwrap(), &gen_keypair().0),
+            PackedNode::new("127.1.1.4:12345".parse().unwrap(), &gen_keypair().0),
+        ];
+        alice.add_friend(friend_pk);
+        {
+            let friends = &mut alice.friends.write();
+            for node in &nodes {
+                friends[FAKE_FRIENDS_NUMBER].try_add_to_close(&node);
+                let dht_node = friends[FAKE_FRIENDS_NUMBER].close_nodes.get_node_mut(&friend_pk, &node.pk).unwrap();
+                dht_node.update_returned_addr(node.saddr);
+            }
+        }
 
-        let (received, _rx) = rx.into_future().wait().unwrap();
-        let (packet, addr_to_send) = received.unwrap();
+        alice.dht_main_loop().wait().unwrap();
 
-        assert_eq!(addr_to_send, addr);
+        loop {
+            let (received, rx1) = rx.into_future().wait().unwrap();
+            let (packet, _addr_to_send) = received.unwrap();
 
-        let response = unpack!(packet, Packet::OnionResponse3);
+            if let Packet::DhtRequest(nat_ping_req) = packet {
+                let precomputed_key = precompute(&nat_ping_req.spk, &friend_sk);
+                let nat_ping_req_payload = nat_ping_req.get_payload(&precomputed_key).unwrap();
+                let nat_ping_req_payload = unpack!(nat_ping_req_payload, DhtRequestPayload::NatPingRequest);
 
-        assert_eq!(response.onion_return, onion_return);
+                assert_eq!(alice.friends.read()[FAKE_FRIENDS_NUMBER].hole_punch.ping_id, nat_ping_req_payload.id);
+                break;
+            }
+            rx = rx1;
+        }
+    }
 
-        let response = unpack!(response.payload, InnerOnionResponse::OnionAnnounceResponse);
+    #[test]
+    fn pause_nat() {
+        let (alice, _precomp, _bob_pk, _bob_sk, rx, _addr) = create_node();
 
-        assert_eq!(response.sendback_data, sendback_data);
+        let friend_pk = gen_keypair().0;
 
-        let payload = response.get_payload(&precomp).unwrap();
+        alice.add_friend(friend_pk);
+        {
+            let friends = &mut alice.friends.write();
+            for i in 0 .. 4 {
+                let node = PackedNode::new(format!("127.1.1.{}:12345", i + 1).parse().unwrap(), &gen_keypair().0);
+                friends[FAKE_FRIENDS_NUMBER].try_add_to_close(&node);
+                let dht_node = friends[FAKE_FRIENDS_NUMBER].close_nodes.get_node_mut(&friend_pk, &node.pk).unwrap();
+                dht_node.update_returned_addr(node.saddr);
+            }
+        }
 
-        assert_eq!(payload.announce_status, AnnounceStatus::Failed);
-    }
+        // toggle on a clone to make sure the flag is shared
+        alice.clone().pause_nat();
+        assert!(alice.is_nat_paused());
 
-    #[test]
-    fn handle_onion_announce_request_invalid_payload() {
-        let (alice, _precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
+        let now = Instant::now();
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(now));
+        with_default(&clock, &mut enter, |_| {
+            alice.dht_main_loop().wait().unwrap();
+        });
+        alice.clone().resume_nat();
+        assert!(!alice.is_nat_paused());
 
-        let inner = InnerOnionAnnounceRequest {
-            nonce: gen_nonce(),
-            pk: bob_pk,
-            payload: vec![42; 123]
-        };
-        let onion_return = OnionReturn {
-            nonce: secretbox::gen_nonce(),
-            payload: vec![42; ONION_RETURN_3_PAYLOAD_SIZE]
-        };
-        let packet = Packet::OnionAnnounceRequest(OnionAnnounceRequest {
-            inner,
-            onion_return: onion_return.clone()
+        let clock = Clock::new_with_now(ConstNow(now + Duration::from_secs(PUNCH_INTERVAL + 1)));
+        with_default(&clock, &mut enter, |_| {
+            alice.dht_main_loop().wait().unwrap();
         });
 
-        assert!(alice.handle_packet(packet, addr).wait().is_err());
+        drop(alice);
+        let nat_ping_reqs = rx.collect().wait().unwrap().into_iter()
+            .filter(|&(ref packet, _)| match *packet {
+                Packet::DhtRequest(_) => true,
+                _ => false,
+            })
+            .count();
+
+        // NatPingRequest is sent only after NAT traversal is resumed
+        assert_eq!(nat_ping_reqs, 4);
     }
 
-    // handle_onion_data_request
     #[test]
-    fn handle_onion_data_request() {
+    fn handle_nat_ping_req_when_nat_paused() {
         let (alice, precomp, bob_pk, _bob_sk, rx, addr) = create_node();
 
-        // get ping id
-
-        let payload = OnionAnnounceRequestPayload {
-            ping_id: initial_ping_id(),
-            search_pk: gen_keypair().0,
-            data_pk: gen_keypair().0,
-            sendback_data: 42
-        };
-        let inner = InnerOnionAnnounceRequest::new(&precomp, &bob_pk, &payload);
-        let onion_return = OnionReturn {
-            nonce: secretbox::gen_nonce(),
-            payload: vec![42; ONION_RETURN_3_PAYLOAD_SIZE]
-        };
-        let packet = Packet::OnionAnnounceRequest(OnionAnnounceRequest {
-            inner,
-            onion_return: onion_return.clone()
-        });
-
-        alice.handle_packet(packet, addr).wait().unwrap();
-
-        let (received, rx) = rx.into_future().wait().unwrap();
-        let (packet, _addr_to_send) = received.unwrap();
-        let response = unpack!(packet, Packet::OnionResponse3);
-        let response = unpack!(response.payload, InnerOnionResponse::OnionAnnounceResponse);
-        let payload = response.get_payload(&precomp).unwrap();
-        let ping_id = payload.ping_id_or_pk;
+        alice.add_friend(bob_pk);
+        alice.pause_nat();
 
-        // announce node
+        let nat_req = NatPingRequest { id: 42 };
+        let nat_payload = DhtRequestPayload::NatPingRequest(nat_req);
+        let dht_req = Packet::DhtRequest(DhtRequest::new(&precomp, &alice.pk, &bob_pk, &nat_payload));
 
-        let payload = OnionAnnounceRequestPayload {
-            ping_id,
-            search_pk: gen_keypair().0,
-            data_pk: gen_keypair().0,
-            sendback_data: 42
-        };
-        let inner = InnerOnionAnnounceRequest::new(&precomp, &bob_pk, &payload);
-        let packet = Packet::OnionAnnounceRequest(OnionAnnounceRequest {
-            inner,
-            onion_return: onion_return.clone()
-        });
+        alice.handle_packet(dht_req, addr).wait().unwrap();
 
-        alice.handle_packet(packet, addr).wait().unwrap();
+        drop(alice);
+        assert!(rx.collect().wait().unwrap().is_empty());
+    }
 
-        // send onion data request
+    #[test]
+    fn send_nat_ping_req_friend_connected_via_net_crypto() {
+        let (mut alice, _precomp, _bob_pk, _bob_sk, rx, _addr) = create_node();
 
-        let nonce = gen_nonce();
-        let temporary_pk = gen_keypair().0;
-        let payload = vec![42; 123];
-        let inner = InnerOnionDataRequest {
-            destination_pk: bob_pk,
-            nonce,
-            temporary_pk,
-            payload: payload.clone()
-        };
-        let packet = Packet::OnionDataRequest(OnionDataRequest {
-            inner,
-            onion_return: onion_return.clone()
+        let (udp_tx, _udp_rx) = mpsc::channel(1);
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (real_pk, _real_sk) = gen_keypair();
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk: alice.pk,
+            dht_sk: alice.sk.clone(),
+            real_pk,
+            precomputed_keys: alice.get_precomputed_keys(),
         });
 
-        alice.handle_packet(packet, addr).wait().unwrap();
-
-        let (received, _rx) = rx.skip(1).into_future().wait().unwrap();
-        let (packet, addr_to_send) = received.unwrap();
-
-        assert_eq!(addr_to_send, addr);
+        let (friend_pk, _friend_sk) = gen_keypair();
+        let (friend_real_pk, _friend_real_sk) = gen_keypair();
+
+        // simulate established net_crypto connection with the friend
+        let mut connection = CryptoConnection::new(&alice.sk, alice.pk, real_pk, friend_real_pk, friend_pk);
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        connection.status = ConnectionStatus::Established {
+            sent_nonce: gen_nonce(),
+            received_nonce: gen_nonce(),
+            peer_session_pk,
+            session_precomputed_key: precompute(&peer_session_pk, &session_sk),
+        };
+        net_crypto.add_connection(connection);
+        alice.set_net_crypto(net_crypto);
 
-        let response = unpack!(packet, Packet::OnionResponse3);
+        let nodes = [
+            PackedNode::new("127.1.1.1:12345".parse().unwrap(), &gen_keypair().0),
+            PackedNode::new("127.1.1.2:12345".parse().unwrap(), &gen_keypair().0),
+            PackedNode::new("127.1.1.3:12345".parse().unwrap(), &gen_keypair().0),
+            PackedNode::new("127.1.1.4:12345".parse().unwrap(), &gen_keypair().0),
+        ];
+        alice.add_friend(friend_pk);
+        {
+            let friends = &mut alice.friends.write();
+            for node in &nodes {
+                friends[FAKE_FRIENDS_NUMBER].try_add_to_close(&node);
+                let dht_node = friends[FAKE_FRIENDS_NUMBER].close_nodes.get_node_mut(&friend_pk, &node.pk).unwrap();
+                dht_node.update_returned_addr(node.saddr);
+            }
+        }
 
-        assert_eq!(response.onion_return, onion_return);
+        alice.dht_main_loop().wait().unwrap();
 
-        let response = unpack!(response.payload, InnerOnionResponse::OnionDataResponse);
+        assert!(alice.friends.read()[FAKE_FRIENDS_NUMBER].hole_punch.last_send_ping_time.is_none());
 
-        assert_eq!(response.nonce, nonce);
-        assert_eq!(response.temporary_pk, temporary_pk);
-        assert_eq!(response.payload, payload);
+        drop(alice);
+        rx.collect().wait().unwrap().into_iter().for_each(|(packet, _addr)| {
+            if let Packet::DhtRequest(ref dht_request) = packet {
+                assert_ne!(dht_request.rpk, friend_pk);
+            }
+        });
     }
 
-    // handle_onion_response_3
     #[test]
-    fn handle_onion_response_3() {
-        let (alice, _precomp, _bob_pk, _bob_sk, rx, addr) = create_node();
-
-        let onion_symmetric_key = alice.onion_symmetric_key.read();
-
-        let ip_port = IpPort {
-            protocol: ProtocolType::UDP,
-            ip_addr: "5.6.7.8".parse().unwrap(),
-            port: 12345
-        };
-        let next_onion_return = OnionReturn {
-            nonce: secretbox::gen_nonce(),
-            payload: vec![42; ONION_RETURN_2_PAYLOAD_SIZE]
-        };
-        let onion_return = OnionReturn::new(&onion_symmetric_key, &ip_port, Some(&next_onion_return));
-        let payload = InnerOnionResponse::OnionAnnounceResponse(OnionAnnounceResponse {
-            sendback_data: 12345,
-            nonce: gen_nonce(),
-            payload: vec![42; 123]
-        });
-        let packet = Packet::OnionResponse3(OnionResponse3 {
-            onion_return,
-            payload: payload.clone()
-        });
+    fn dht_main_loop_records_duration() {
+        let (alice, _precomp, _bob_pk, _bob_sk, _rx, _addr) = create_node();
 
-        alice.handle_packet(packet, addr).wait().unwrap();
+        assert_eq!(alice.stats(), ServerStats::default());
 
-        let (received, _rx) = rx.into_future().wait().unwrap();
-        let (packet, addr_to_send) = received.unwrap();
+        let now = Instant::now();
+        let mut enter = tokio_executor::enter().unwrap();
+        let mut_now = MutNow::new(now);
+        let clock = Clock::new_with_now(mut_now.clone());
 
-        assert_eq!(addr_to_send, ip_port.to_saddr());
+        with_default(&clock, &mut enter, |_| {
+            let future = alice.dht_main_loop();
+            mut_now.set(now + Duration::from_millis(800));
+            future.wait().unwrap();
 
-        let next_packet = unpack!(packet, Packet::OnionResponse2);
+            let future = alice.dht_main_loop();
+            mut_now.set(now + Duration::from_millis(1200));
+            future.wait().unwrap();
+        });
 
-        assert_eq!(next_packet.payload, payload);
-        assert_eq!(next_packet.onion_return, next_onion_return);
+        let stats = alice.stats();
+        assert_eq!(alice.last_loop_duration(), Some(Duration::from_millis(400)));
+        assert_eq!(stats.last_loop_duration, Some(Duration::from_millis(400)));
+        assert_eq!(stats.average_loop_duration, Some(Duration::from_millis(750)));
     }
 
     #[test]
-    fn handle_onion_response_3_invalid_onion_return() {
-        let (alice, _precomp, _bob_pk, _bob_sk, rx, addr) = create_node();
+    fn relay_candidates() {
+        let (alice, _precomp, bob_pk, _bob_sk, _rx, _addr) = create_node();
 
-        let onion_return = OnionReturn {
-            nonce: secretbox::gen_nonce(),
-            payload: vec![42; ONION_RETURN_3_PAYLOAD_SIZE] // not encrypted with onion_symmetric_key
-        };
-        let payload = InnerOnionResponse::OnionAnnounceResponse(OnionAnnounceResponse {
-            sendback_data: 12345,
-            nonce: gen_nonce(),
-            payload: vec![42; 123]
-        });
-        let packet = Packet::OnionResponse3(OnionResponse3 {
-            onion_return,
-            payload
-        });
+        let node_pk = gen_keypair().0;
+        let pn = PackedNode::new("127.1.1.1:12345".parse().unwrap(), &bob_pk);
+        assert!(alice.close_nodes.write().try_add(&pn));
+        assert!(alice.close_nodes.write().try_add(&PackedNode::new("127.1.1.2:12345".parse().unwrap(), &node_pk)));
 
-        alice.handle_packet(packet, addr).wait().unwrap();
+        assert!(alice.relay_candidates().is_empty());
 
-        // Necessary to drop tx so that rx.collect() can be finished
-        drop(alice);
+        assert!(alice.set_node_capabilities(&bob_pk, CAPABILITY_TCP_RELAY | CAPABILITY_ONION));
+        assert!(alice.set_node_capabilities(&node_pk, CAPABILITY_ONION));
+        assert!(!alice.set_node_capabilities(&gen_keypair().0, CAPABILITY_TCP_RELAY));
 
-        assert!(rx.collect().wait().unwrap().is_empty());
+        assert_eq!(alice.relay_candidates(), vec![pn]);
     }
 
     #[test]
-    fn handle_onion_response_3_invalid_next_onion_return() {
-        let (alice, _precomp, _bob_pk, _bob_sk, _rx, addr) = create_node();
+    fn metrics_prometheus() {
+        let (mut alice, precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
 
-        let onion_symmetric_key = alice.onion_symmetric_key.read();
+        alice.add_friend(gen_keypair().0);
+        let pn = PackedNode::new("127.1.1.1:12345".parse().unwrap(), &bob_pk);
+        assert!(alice.close_nodes.write().try_add(&pn));
 
-        let ip_port = IpPort {
-            protocol: ProtocolType::UDP,
-            ip_addr: "5.6.7.8".parse().unwrap(),
-            port: 12345
-        };
-        let onion_return = OnionReturn::new(&onion_symmetric_key, &ip_port, None);
-        let inner = OnionDataResponse {
+        let req_payload = PingRequestPayload { id: 42 };
+        let ping_req = Packet::PingRequest(PingRequest::new(&precomp, &bob_pk, &req_payload));
+        alice.handle_packet(ping_req.clone(), addr).wait().unwrap();
+        alice.handle_packet(ping_req, addr).wait().unwrap();
+
+        alice.enable_onion_relay(false);
+        let onion_request = Packet::OnionRequest0(OnionRequest0 {
             nonce: gen_nonce(),
             temporary_pk: gen_keypair().0,
-            payload: vec![42; 123]
-        };
-        let packet = Packet::OnionResponse3(OnionResponse3 {
-            onion_return,
-            payload: InnerOnionResponse::OnionDataResponse(inner.clone())
+            payload: vec![42; 123],
         });
-
-        assert!(alice.handle_packet(packet, addr).wait().is_err());
+        alice.handle_packet(onion_request, addr).wait().unwrap();
+
+        let metrics = alice.metrics_prometheus();
+
+        assert!(metrics.contains("# TYPE tox_dht_packets_received_total counter\n"));
+        assert!(metrics.contains("tox_dht_packets_received_total{type=\"PingRequest\"} 2\n"));
+        assert!(metrics.contains("tox_dht_packets_received_total{type=\"OnionRequest0\"} 1\n"));
+        assert!(metrics.contains("tox_dht_packets_dropped_total 1\n"));
+        assert!(metrics.contains("tox_dht_onion_packets_relayed_total 0\n"));
+        assert!(metrics.contains("tox_dht_onion_packets_shed_total 0\n"));
+        assert!(metrics.contains("tox_dht_close_nodes 1\n"));
+        assert!(metrics.contains("tox_dht_friends 1\n"));
+        assert!(metrics.contains("tox_dht_packets_received_total{type=\"NodesRequest\"} 0\n"));
+        assert!(metrics.contains("tox_dht_onion_packets_oversized_total 0\n"));
+        assert!(metrics.contains("tox_dht_onion_packets_malformed_total 0\n"));
+        assert!(metrics.contains("tox_dht_incompatible_version_packets_total 0\n"));
+        assert!(metrics.contains("tox_dht_self_requests_total 0\n"));
+        assert!(metrics.contains("tox_dht_unknown_nat_ping_responses_total 0\n"));
+        assert!(metrics.contains("tox_dht_handshake_packets_dropped_total 0\n"));
+        // DHT main loop wasn't executed yet
+        assert!(!metrics.contains("tox_dht_last_loop_duration_milliseconds"));
+        assert!(!metrics.contains("tox_dht_average_loop_duration_milliseconds"));
     }
 
-    // handle_onion_response_2
     #[test]
-    fn handle_onion_response_2() {
-        let (alice, _precomp, _bob_pk, _bob_sk, rx, addr) = create_node();
+    fn uptime_and_last_packet_at() {
+        let (alice, precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
 
-        let onion_symmetric_key = alice.onion_symmetric_key.read();
+        assert_eq!(alice.last_packet_at(), None);
 
-        let ip_port = IpPort {
-            protocol: ProtocolType::UDP,
-            ip_addr: "5.6.7.8".parse().unwrap(),
-            port: 12345
-        };
-        let next_onion_return = OnionReturn {
-            nonce: secretbox::gen_nonce(),
-            payload: vec![42; ONION_RETURN_1_PAYLOAD_SIZE]
-        };
-        let onion_return = OnionReturn::new(&onion_symmetric_key, &ip_port, Some(&next_onion_return));
-        let payload = InnerOnionResponse::OnionAnnounceResponse(OnionAnnounceResponse {
-            sendback_data: 12345,
-            nonce: gen_nonce(),
-            payload: vec![42; 123]
-        });
-        let packet = Packet::OnionResponse2(OnionResponse2 {
-            onion_return,
-            payload: payload.clone()
+        let now = alice.start_time + Duration::from_secs(10);
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(now));
+        with_default(&clock, &mut enter, |_| {
+            assert!(alice.uptime() >= Duration::from_secs(10));
+
+            let ping_req = Packet::PingRequest(PingRequest::new(&precomp, &bob_pk, &PingRequestPayload { id: 42 }));
+            alice.handle_packet(ping_req, addr).wait().unwrap();
+
+            assert_eq!(alice.last_packet_at(), Some(now));
         });
+    }
 
-        alice.handle_packet(packet, addr).wait().unwrap();
+    #[test]
+    fn dht_main_loop_sends_health_snapshot() {
+        let (mut alice, _precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
 
-        let (received, _rx) = rx.into_future().wait().unwrap();
-        let (packet, addr_to_send) = received.unwrap();
+        alice.set_health_interval(Duration::from_secs(10));
+        let health_rx = alice.health_subscribe();
 
-        assert_eq!(addr_to_send, ip_port.to_saddr());
+        assert!(alice.try_add_to_close_nodes(&PackedNode::new(addr, &bob_pk)));
+        alice.add_friend(gen_keypair().0);
 
-        let next_packet = unpack!(packet, Packet::OnionResponse1);
+        let now = Instant::now();
+        let mut enter = tokio_executor::enter().unwrap();
+        for &secs in &[0, 5, 10] {
+            let clock = Clock::new_with_now(ConstNow(now + Duration::from_secs(secs)));
+            with_default(&clock, &mut enter, |_| {
+                alice.dht_main_loop().wait().unwrap();
+            });
+        }
 
-        assert_eq!(next_packet.payload, payload);
-        assert_eq!(next_packet.onion_return, next_onion_return);
+        // Necessary to drop health tx so that health_rx.collect() can be
+        // finished
+        drop(alice);
+
+        let snapshots = health_rx.collect().wait().unwrap();
+        // the snapshot is not sent at 5th second since the interval is not
+        // passed yet
+        assert_eq!(snapshots.len(), 2);
+        assert_eq!(snapshots[0].close_nodes, 1);
+        assert_eq!(snapshots[0].friends, 1);
+        assert!(snapshots[1].uptime >= Duration::from_secs(10));
+        assert_eq!(snapshots[1].last_packet_at, None);
     }
 
     #[test]
-    fn handle_onion_response_2_invalid_onion_return() {
-        let (alice, _precomp, _bob_pk, _bob_sk, rx, addr) = create_node();
+    fn dht_main_loop_clears_timed_out_announces() {
+        let (mut alice, precomp, bob_pk, _bob_sk, rx, addr) = create_node();
+
+        alice.set_onion_announce_timeout(Duration::from_secs(10));
 
         let onion_return = OnionReturn {
             nonce: secretbox::gen_nonce(),
-            payload: vec![42; ONION_RETURN_2_PAYLOAD_SIZE] // not encrypted with onion_symmetric_key
+            payload: vec![42; ONION_RETURN_3_PAYLOAD_SIZE]
         };
-        let payload = InnerOnionResponse::OnionAnnounceResponse(OnionAnnounceResponse {
-            sendback_data: 12345,
-            nonce: gen_nonce(),
-            payload: vec![42; 123]
-        });
-        let packet = Packet::OnionResponse2(OnionResponse2 {
-            onion_return,
-            payload
-        });
 
-        alice.handle_packet(packet, addr).wait().unwrap();
+        // get ping id
 
-        // Necessary to drop tx so that rx.collect() can be finished
-        drop(alice);
+        let payload = OnionAnnounceRequestPayload {
+            ping_id: initial_ping_id(),
+            search_pk: gen_keypair().0,
+            data_pk: gen_keypair().0,
+            sendback_data: 42
+        };
+        let inner = InnerOnionAnnounceRequest::new(&precomp, &bob_pk, &payload);
+        let packet = Packet::OnionAnnounceRequest(OnionAnnounceRequest {
+            inner,
+            onion_return: onion_return.clone()
+        });
 
-        assert!(rx.collect().wait().unwrap().is_empty());
-    }
+        alice.handle_packet(packet, addr).wait().unwrap();
 
-    #[test]
-    fn handle_onion_response_2_invalid_next_onion_return() {
-        let (alice, _precomp, _bob_pk, _bob_sk, _rx, addr) = create_node();
+        let (received, _rx) = rx.into_future().wait().unwrap();
+        let (packet, _addr_to_send) = received.unwrap();
+        let response = unpack!(packet, Packet::OnionResponse3);
+        let response = unpack!(response.payload, InnerOnionResponse::OnionAnnounceResponse);
+        let payload = response.get_payload(&precomp).unwrap();
+        let ping_id = payload.ping_id_or_pk;
 
-        let onion_symmetric_key = alice.onion_symmetric_key.read();
+        // announce node
 
-        let ip_port = IpPort {
-            protocol: ProtocolType::UDP,
-            ip_addr: "5.6.7.8".parse().unwrap(),
-            port: 12345
-        };
-        let onion_return = OnionReturn::new(&onion_symmetric_key, &ip_port, None);
-        let inner = OnionDataResponse {
-            nonce: gen_nonce(),
-            temporary_pk: gen_keypair().0,
-            payload: vec![42; 123]
+        let payload = OnionAnnounceRequestPayload {
+            ping_id,
+            search_pk: gen_keypair().0,
+            data_pk: gen_keypair().0,
+            sendback_data: 42
         };
-        let packet = Packet::OnionResponse2(OnionResponse2 {
-            onion_return,
-            payload: InnerOnionResponse::OnionDataResponse(inner.clone())
+        let inner = InnerOnionAnnounceRequest::new(&precomp, &bob_pk, &payload);
+        let packet = Packet::OnionAnnounceRequest(OnionAnnounceRequest {
+            inner,
+            onion_return
         });
 
-        assert!(alice.handle_packet(packet, addr).wait().is_err());
-    }
+        alice.handle_packet(packet, addr).wait().unwrap();
 
-    // handle_onion_response_1
-    #[test]
-    fn handle_onion_response_1_with_onion_announce_response() {
-        let (alice, _precomp, _bob_pk, _bob_sk, rx, addr) = create_node();
+        assert_eq!(alice.onion_announce.read().entries_count(), 1);
+        assert_eq!(alice.onion_announced_keys(), vec![bob_pk]);
 
-        let onion_symmetric_key = alice.onion_symmetric_key.read();
+        let time = Instant::now() + Duration::from_secs(10);
 
-        let ip_port = IpPort {
-            protocol: ProtocolType::UDP,
-            ip_addr: "5.6.7.8".parse().unwrap(),
-            port: 12345
-        };
-        let onion_return = OnionReturn::new(&onion_symmetric_key, &ip_port, None);
-        let inner = OnionAnnounceResponse {
-            sendback_data: 12345,
-            nonce: gen_nonce(),
-            payload: vec![42; 123]
-        };
-        let packet = Packet::OnionResponse1(OnionResponse1 {
-            onion_return,
-            payload: InnerOnionResponse::OnionAnnounceResponse(inner.clone())
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(time));
+
+        with_default(&clock, &mut enter, |_| {
+            alice.dht_main_loop().wait().unwrap();
         });
 
-        alice.handle_packet(packet, addr).wait().unwrap();
+        assert_eq!(alice.onion_announce.read().entries_count(), 0);
+    }
+
+    #[test]
+    fn dropped_receiver_closes_tx() {
+        let (mut alice, _precomp, bob_pk, _bob_sk, rx, addr) = create_node();
 
-        let (received, _rx) = rx.into_future().wait().unwrap();
-        let (packet, addr_to_send) = received.unwrap();
+        let (event_tx, event_rx) = mpsc::unbounded();
+        alice.set_event_sink(event_tx);
 
-        assert_eq!(addr_to_send, ip_port.to_saddr());
+        drop(rx);
 
-        let next_packet = unpack!(packet, Packet::OnionAnnounceResponse);
+        let packet = Packet::LanDiscovery(LanDiscovery { pk: bob_pk });
+        for _ in 0 .. MAX_CONSECUTIVE_SEND_FAILURES - 1 {
+            assert!(alice.send_to_direct(addr, packet.clone()).wait().is_err());
+        }
+        assert!(!alice.is_tx_closed());
+        for _ in 0 .. 2 {
+            assert!(alice.send_to_direct(addr, packet.clone()).wait().is_err());
+        }
+        assert!(alice.is_tx_closed());
 
-        assert_eq!(next_packet, inner);
+        // periodical tasks are stopped
+        let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+        let error = runtime.block_on(alice.clone().run()).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::BrokenPipe);
+
+        // event is sent only once
+        drop(alice);
+        assert_eq!(event_rx.collect().wait().unwrap(), vec![DhtEvent::OutgoingChannelClosed]);
     }
 
     #[test]
-    fn server_handle_onion_response_1_with_onion_data_response_test() {
-        let (alice, _precomp, _bob_pk, _bob_sk, rx, addr) = create_node();
+    fn replace_tx_reopens_tx() {
+        let (alice, _precomp, bob_pk, _bob_sk, rx, addr) = create_node();
 
-        let onion_symmetric_key = alice.onion_symmetric_key.read();
+        drop(rx);
 
-        let ip_port = IpPort {
-            protocol: ProtocolType::UDP,
-            ip_addr: "5.6.7.8".parse().unwrap(),
-            port: 12345
-        };
-        let onion_return = OnionReturn::new(&onion_symmetric_key, &ip_port, None);
-        let inner = OnionDataResponse {
-            nonce: gen_nonce(),
-            temporary_pk: gen_keypair().0,
-            payload: vec![42; 123]
-        };
-        let packet = Packet::OnionResponse1(OnionResponse1 {
-            onion_return,
-            payload: InnerOnionResponse::OnionDataResponse(inner.clone())
-        });
+        let packet = Packet::LanDiscovery(LanDiscovery { pk: bob_pk });
+        for _ in 0 .. MAX_CONSECUTIVE_SEND_FAILURES {
+            assert!(alice.send_to_direct(addr, packet.clone()).wait().is_err());
+        }
+        assert!(alice.is_tx_closed());
 
-        alice.handle_packet(packet, addr).wait().unwrap();
+        let (tx, _rx) = mpsc::channel(32);
+        alice.replace_tx(tx);
 
-        let (received, _rx) = rx.into_future().wait().unwrap();
-        let (packet, addr_to_send) = received.unwrap();
+        assert!(!alice.is_tx_closed());
+    }
 
-        assert_eq!(addr_to_send, ip_port.to_saddr());
+    #[test]
+    fn replace_tx() {
+        let (alice, precomp, bob_pk, _bob_sk, rx, addr) = create_node();
 
-        let next_packet = unpack!(packet, Packet::OnionDataResponse);
+        *alice.random_requests_count.write() = MAX_BOOTSTRAP_TIMES;
 
-        assert_eq!(next_packet, inner);
+        let node_pk = gen_keypair().0;
+        assert!(alice.try_add_to_close_nodes(&PackedNode::new("1.2.3.4:12345".parse().unwrap(), &node_pk)));
+        alice.close_nodes.write().get_node_mut(&node_pk).unwrap()
+            .update_returned_addr("5.6.7.8:33445".parse().unwrap());
+
+        let (tx, new_rx) = mpsc::channel(32);
+        alice.replace_tx(tx);
+
+        assert_eq!(*alice.random_requests_count.read(), 0);
+        // our external address is learned again
+        assert_eq!(alice.close_nodes.read().get_node(&node_pk).unwrap().assoc4.ret_saddr, None);
+
+        let ping_req = Packet::PingRequest(PingRequest::new(&precomp, &bob_pk, &PingRequestPayload { id: 42 }));
+        alice.handle_packet(ping_req, addr).wait().unwrap();
+
+        // Necessary to drop tx so that rx.collect() can be finished
+        drop(alice);
+
+        assert!(rx.collect().wait().unwrap().is_empty());
+
+        let packets = new_rx.collect().wait().unwrap();
+        assert_eq!(packets.len(), 1);
+        unpack!(packets[0].0.clone(), Packet::PingResponse);
     }
 
     #[test]
-    fn handle_onion_response_1_redirect_to_tcp() {
-        let (mut alice, _precomp, _bob_pk, _bob_sk, _rx, _addr) = create_node();
-        let (tcp_onion_tx, tcp_onion_rx) = mpsc::channel(1);
-        alice.set_tcp_onion_sink(tcp_onion_tx);
+    fn drain() {
+        crypto_init().unwrap();
 
+        // sends are pending until the receiver takes packets from the channel
+        let (tx, rx) = mpsc::channel(0);
+        let (pk, sk) = gen_keypair();
+        let alice = Server::new(tx, pk, sk);
+        let (bob_pk, bob_sk) = gen_keypair();
+        let precomp = precompute(&alice.pk, &bob_sk);
         let addr: SocketAddr = "127.0.0.1:12346".parse().unwrap();
 
-        let onion_symmetric_key = alice.onion_symmetric_key.read();
+        let ping_req = |id| Packet::PingRequest(PingRequest::new(&precomp, &bob_pk, &PingRequestPayload { id }));
 
-        let ip_port = IpPort {
-            protocol: ProtocolType::TCP,
-            ip_addr: "5.6.7.8".parse().unwrap(),
-            port: 12345
-        };
-        let onion_return = OnionReturn::new(&onion_symmetric_key, &ip_port, None);
-        let inner = InnerOnionResponse::OnionAnnounceResponse(OnionAnnounceResponse {
-            sendback_data: 12345,
-            nonce: gen_nonce(),
-            payload: vec![42; 123]
-        });
-        let packet = Packet::OnionResponse1(OnionResponse1 {
-            onion_return,
-            payload: inner.clone()
-        });
+        let sends = alice.handle_packet(ping_req(1), addr).join3(
+            alice.handle_packet(ping_req(2), addr),
+            alice.handle_packet(ping_req(3), addr)
+        );
+        let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
 
-        alice.handle_packet(packet, addr).wait().unwrap();
+        // drain is not ready while packets are not passed to the channel
+        let pending_sends = alice.pending_sends.clone();
+        let drain = runtime.block_on(future::lazy(|| {
+            let mut drain = alice.drain();
+            assert_eq!(drain.poll().unwrap(), Async::NotReady);
+            assert_eq!(pending_sends.load(Ordering::SeqCst), 3);
+            future::ok::<_, Error>(drain)
+        })).unwrap();
 
-        let (received, _tcp_onion_rx) = tcp_onion_rx.into_future().wait().unwrap();
-        let (packet, addr_to_send) = received.unwrap();
+        let receiver = rx.take(3).collect().map_err(|()| Error::from(ErrorKind::UnexpectedEof));
+        let (_, (), packets) = runtime.block_on(sends.join3(drain, receiver)).unwrap();
+        assert_eq!(packets.len(), 3);
+        assert_eq!(alice.pending_sends.load(Ordering::SeqCst), 0);
 
-        assert_eq!(addr_to_send, ip_port.to_saddr());
-        assert_eq!(packet, inner);
+        // new packets are not sent after draining
+        assert!(alice.handle_packet(ping_req(4), addr).wait().is_err());
+        let send_filter = alice.send_filter();
+        assert!(!send_filter(&ping_req(5), addr));
+
+        // until draining is undone
+        alice.undrain();
+        assert!(send_filter(&ping_req(5), addr));
     }
 
     #[test]
-    fn handle_onion_response_1_can_not_redirect_to_tcp() {
-        let (alice, _precomp, _bob_pk, _bob_sk, _rx, addr) = create_node();
-
-        let onion_symmetric_key = alice.onion_symmetric_key.read();
+    fn drain_without_pending_sends() {
+        let (alice, _precomp, _bob_pk, _bob_sk, _rx, _addr) = create_node();
 
-        let ip_port = IpPort {
-            protocol: ProtocolType::TCP,
-            ip_addr: "5.6.7.8".parse().unwrap(),
-            port: 12345
-        };
-        let onion_return = OnionReturn::new(&onion_symmetric_key, &ip_port, None);
-        let inner = OnionAnnounceResponse {
-            sendback_data: 12345,
-            nonce: gen_nonce(),
-            payload: vec![42; 123]
-        };
-        let packet = Packet::OnionResponse1(OnionResponse1 {
-            onion_return,
-            payload: InnerOnionResponse::OnionAnnounceResponse(inner.clone())
-        });
+        alice.drain().wait().unwrap();
+        alice.drain().wait().unwrap();
 
-        assert!(alice.handle_packet(packet, addr).wait().is_err());
+        // completed drains don't leave their tasks registered
+        assert!(alice.drain_tasks.read().is_empty());
     }
 
     #[test]
-    fn handle_onion_response_1_invalid_onion_return() {
-        let (alice, _precomp, _bob_pk, _bob_sk, rx, addr) = create_node();
+    fn dht_main_loop_paused() {
+        let (alice, precomp, bob_pk, bob_sk, rx, addr) = create_node();
 
-        let onion_return = OnionReturn {
-            nonce: secretbox::gen_nonce(),
-            payload: vec![42; ONION_RETURN_1_PAYLOAD_SIZE] // not encrypted with onion_symmetric_key
-        };
-        let payload = InnerOnionResponse::OnionAnnounceResponse(OnionAnnounceResponse {
-            sendback_data: 12345,
-            nonce: gen_nonce(),
-            payload: vec![42; 123]
-        });
-        let packet = Packet::OnionResponse1(OnionResponse1 {
-            onion_return,
-            payload
-        });
+        let packed_node = PackedNode::new("127.1.1.1:12345".parse().unwrap(), &gen_keypair().0);
+        assert!(alice.try_add_to_close_nodes(&packed_node));
 
-        alice.handle_packet(packet, addr).wait().unwrap();
+        alice.pause();
+        assert!(alice.is_paused());
+
+        alice.dht_main_loop().wait().unwrap();
+
+        // incoming requests are still handled
+        let req_payload = PingRequestPayload { id: 42 };
+        let ping_req = Packet::PingRequest(PingRequest::new(&precomp, &bob_pk, &req_payload));
+
+        alice.handle_packet(ping_req, addr).wait().unwrap();
 
-        // Necessary to drop tx so that rx.collect() can be finished
         drop(alice);
+        let packets = rx.collect().wait().unwrap();
 
-        assert!(rx.collect().wait().unwrap().is_empty());
+        // only PingResponse is sent
+        assert_eq!(packets.len(), 1);
+        let (packet, addr_to_send) = packets[0].clone();
+
+        assert_eq!(addr_to_send, addr);
+
+        let ping_resp = unpack!(packet, Packet::PingResponse);
+        let precomputed_key = precompute(&ping_resp.pk, &bob_sk);
+        let ping_resp_payload = ping_resp.get_payload(&precomputed_key).unwrap();
+
+        assert_eq!(ping_resp_payload.id, req_payload.id);
     }
 
     #[test]
-    fn handle_onion_response_1_invalid_next_onion_return() {
-        let (alice, _precomp, _bob_pk, _bob_sk, _rx, addr) = create_node();
+    fn dht_main_loop_resumed() {
+        let (alice, _precomp, _bob_pk, _bob_sk, rx, _addr) = create_node();
 
-        let onion_symmetric_key = alice.onion_symmetric_key.read();
+        let packed_node = PackedNode::new("127.1.1.1:12345".parse().unwrap(), &gen_keypair().0);
+        assert!(alice.try_add_to_close_nodes(&packed_node));
 
-        let ip_port = IpPort {
-            protocol: ProtocolType::UDP,
-            ip_addr: "5.6.7.8".parse().unwrap(),
-            port: 12345
-        };
-        let next_onion_return = OnionReturn {
-            nonce: secretbox::gen_nonce(),
-            payload: vec![42; ONION_RETURN_1_PAYLOAD_SIZE]
-        };
-        let onion_return = OnionReturn::new(&onion_symmetric_key, &ip_port, Some(&next_onion_return));
-        let inner = OnionDataResponse {
-            nonce: gen_nonce(),
-            temporary_pk: gen_keypair().0,
-            payload: vec![42; 123]
-        };
-        let packet = Packet::OnionResponse1(OnionResponse1 {
-            onion_return,
-            payload: InnerOnionResponse::OnionDataResponse(inner.clone())
-        });
+        alice.pause();
+        alice.resume();
+        assert!(!alice.is_paused());
 
-        assert!(alice.handle_packet(packet, addr).wait().is_err());
+        alice.dht_main_loop().wait().unwrap();
+
+        drop(alice);
+        assert!(!rx.collect().wait().unwrap().is_empty());
     }
 
-    // send_nat_ping_req()
     #[test]
-    fn send_nat_ping_req() {
-        let (alice, _precomp, _bob_pk, _bob_sk, mut rx, _addr) = create_node();
+    fn dht_main_loop_max_friends_per_tick() {
+        let (mut alice, _precomp, bob_pk, _bob_sk, _rx, _addr) = create_node();
 
-        let (friend_pk, friend_sk) = gen_keypair();
+        let packed_node = PackedNode::new("211.192.153.67:33445".parse().unwrap(), &bob_pk);
+        assert!(alice.try_add_to_close_nodes(&packed_node));
 
-        let nodes = [
-            PackedNode::new("127.1.1.1:12345".parse().unwrap(), &gen_keypair().0),
-            PackedNode::new("127.1.1.2:12345".parse().unwrap(), &gen_keypair().0),
-            PackedNode::new("127.1.1.3:12345".parse().unwrap(), &gen_keypair().0),
-            PackedNode::new("127.1.1.4:12345".parse().unwrap(), &gen_keypair().0),
-        ];
-        alice.add_friend(friend_pk);
-        {
-            let friends = &mut alice.friends.write();
-            for node in &nodes {
-                friends[FAKE_FRIENDS_NUMBER].try_add_to_close(&node);
-                let dht_node = friends[FAKE_FRIENDS_NUMBER].close_nodes.get_node_mut(&friend_pk, &node.pk).unwrap();
-                dht_node.update_returned_addr(node.saddr);
-            }
+        // 2 fake friends + 6 real friends
+        for _ in 0 .. 6 {
+            alice.add_friend(gen_keypair().0);
         }
 
+        alice.set_max_friends_per_tick(Some(4));
+
+        let not_processed = |alice: &Server| alice.friends.read().iter()
+            .filter(|friend| !friend.nodes_to_bootstrap.is_empty())
+            .count();
+
+        assert_eq!(not_processed(&alice), 6);
+
+        // the first iteration processes 2 fake friends and 2 real friends
         alice.dht_main_loop().wait().unwrap();
+        assert_eq!(not_processed(&alice), 4);
 
-        loop {
-            let (received, rx1) = rx.into_future().wait().unwrap();
-            let (packet, _addr_to_send) = received.unwrap();
+        // the second iteration processes the rest 4 real friends
+        alice.dht_main_loop().wait().unwrap();
+        assert_eq!(not_processed(&alice), 0);
+    }
 
-            if let Packet::DhtRequest(nat_ping_req) = packet {
-                let precomputed_key = precompute(&nat_ping_req.spk, &friend_sk);
-                let nat_ping_req_payload = nat_ping_req.get_payload(&precomputed_key).unwrap();
-                let nat_ping_req_payload = unpack!(nat_ping_req_payload, DhtRequestPayload::NatPingRequest);
+    #[test]
+    fn set_max_friends_per_tick_zero() {
+        let (mut alice, _precomp, _bob_pk, _bob_sk, _rx, _addr) = create_node();
 
-                assert_eq!(alice.friends.read()[FAKE_FRIENDS_NUMBER].hole_punch.ping_id, nat_ping_req_payload.id);
-                break;
-            }
-            rx = rx1;
-        }
+        alice.set_max_friends_per_tick(Some(0));
+
+        assert_eq!(alice.max_friends_per_tick, None);
     }
 
     // handle_lan_discovery
@@ -2863,7 +8410,97 @@ mod tests {
         // Necessary to drop tx so that rx.collect() can be finished
         drop(alice);
 
-        assert!(rx.collect().wait().unwrap().is_empty());
+        assert!(rx.collect().wait().unwrap().is_empty());
+    }
+
+    #[test]
+    fn trigger_lan_discovery() {
+        crypto_init().unwrap();
+        let (tx, rx) = mpsc::channel(256);
+        let (pk, sk) = gen_keypair();
+        let alice = Server::new(tx, pk, sk);
+
+        alice.trigger_lan_discovery().wait().unwrap();
+        // the next trigger continues from the next ports window
+        alice.trigger_lan_discovery().wait().unwrap();
+
+        drop(alice);
+        let packets = rx.collect().wait().unwrap();
+        assert!(!packets.is_empty());
+        assert!(packets.iter().any(|&(_, addr)| addr.port() >= START_PORT + PORTS_PER_DISCOVERY));
+        for (packet, addr) in packets {
+            let lan_discovery = unpack!(packet, Packet::LanDiscovery);
+            assert_eq!(lan_discovery.pk, pk);
+            let port = addr.port();
+            assert!(port == DEFAULT_PORT || port >= START_PORT && port < START_PORT + 2 * PORTS_PER_DISCOVERY);
+        }
+    }
+
+    #[test]
+    fn trigger_lan_discovery_when_paused() {
+        let (alice, _precomp, _bob_pk, _bob_sk, rx, _addr) = create_node();
+
+        alice.pause();
+        alice.trigger_lan_discovery().wait().unwrap();
+
+        drop(alice);
+        assert!(rx.collect().wait().unwrap().is_empty());
+    }
+
+    #[test]
+    fn trigger_lan_discovery_to_allowed_networks() {
+        crypto_init().unwrap();
+        let (tx, rx) = mpsc::channel(256);
+        let (pk, sk) = gen_keypair();
+        let alice = Server::new(tx, pk, sk);
+
+        alice.set_allowed_networks(vec!["255.255.255.255/32".parse().unwrap()]);
+        alice.trigger_lan_discovery().wait().unwrap();
+
+        drop(alice);
+        let packets = rx.collect().wait().unwrap();
+        assert!(!packets.is_empty());
+        assert!(packets.iter().all(|&(_, addr)| addr.ip() == "255.255.255.255".parse::<IpAddr>().unwrap()));
+    }
+
+    #[test]
+    fn trigger_lan_discovery_when_disabled() {
+        let (mut alice, _precomp, _bob_pk, _bob_sk, rx, _addr) = create_node();
+
+        alice.enable_lan_discovery(false);
+        alice.trigger_lan_discovery().wait().unwrap();
+
+        drop(alice);
+        assert!(rx.collect().wait().unwrap().is_empty());
+    }
+
+    #[test]
+    fn handle_lan_discovery_when_suppressed() {
+        let (alice, _precomp, bob_pk, _bob_sk, rx, addr) = create_node();
+
+        let now = Instant::now();
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(now));
+        with_default(&clock, &mut enter, |_| {
+            alice.suppress_lan_discovery(Duration::from_secs(10));
+        });
+
+        for &(secs, is_suppressed) in &[(5, true), (10, false)] {
+            let clock = Clock::new_with_now(ConstNow(now + Duration::from_secs(secs)));
+            with_default(&clock, &mut enter, |_| {
+                let lan = Packet::LanDiscovery(LanDiscovery { pk: bob_pk });
+                alice.handle_packet(lan, addr).wait().unwrap();
+                assert_eq!(alice.lan_discovery_quiet_period().is_active(), is_suppressed);
+            });
+        }
+
+        // Necessary to drop tx so that rx.collect() can be finished
+        drop(alice);
+
+        // only the packet received after the quiet period is handled
+        let packets = rx.collect().wait().unwrap();
+        assert_eq!(packets.len(), 1);
+        unpack!(packets[0].0.clone(), Packet::NodesRequest);
     }
 
     #[test]
@@ -2896,6 +8533,37 @@ mod tests {
         assert_ne!(onion_symmetric_key_1, onion_symmetric_key_2)
     }
 
+    #[test]
+    fn onion_key_age() {
+        let (alice, _precomp, _bob_pk, _bob_sk, _rx, _addr) = create_node();
+
+        let now = Instant::now();
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(now));
+        with_default(&clock, &mut enter, |_| {
+            alice.refresh_onion_key();
+            assert_eq!(alice.onion_key_age(), Duration::from_secs(0));
+        });
+
+        let clock = Clock::new_with_now(ConstNow(now + Duration::from_secs(100)));
+        with_default(&clock, &mut enter, |_| {
+            assert_eq!(alice.onion_key_age(), Duration::from_secs(100));
+        });
+    }
+
+    #[test]
+    fn refresh_onion_key_sends_event() {
+        let (mut alice, _precomp, _bob_pk, _bob_sk, _rx, _addr) = create_node();
+
+        let (event_tx, event_rx) = mpsc::unbounded();
+        alice.set_event_sink(event_tx);
+
+        alice.refresh_onion_key();
+
+        drop(alice);
+        assert_eq!(event_rx.collect().wait().unwrap(), vec![DhtEvent::OnionKeyRotated]);
+    }
+
     #[test]
     fn handle_tcp_onion_request() {
         let (alice, _precomp, _bob_pk, _bob_sk, rx, addr) = create_node();
@@ -2932,6 +8600,29 @@ mod tests {
         assert_eq!(onion_return_payload.0, IpPort::from_tcp_saddr(addr));
     }
 
+    #[test]
+    fn handle_tcp_onion_request_own_temporary_pk() {
+        let (alice, _precomp, _bob_pk, _bob_sk, rx, addr) = create_node();
+
+        let packet = OnionRequest {
+            nonce: gen_nonce(),
+            ip_port: IpPort {
+                protocol: ProtocolType::UDP,
+                ip_addr: "5.6.7.8".parse().unwrap(),
+                port: 12345
+            },
+            temporary_pk: alice.pk,
+            payload: vec![42; 123]
+        };
+
+        assert!(alice.handle_tcp_onion_request(packet, addr).wait().is_err());
+
+        // Necessary to drop tx so that rx.collect() can be finished
+        drop(alice);
+
+        assert!(rx.collect().wait().unwrap().is_empty());
+    }
+
     #[test]
     fn ping_nodes_to_bootstrap() {
         let (alice, _precomp, bob_pk, bob_sk, rx, _addr) = create_node();
@@ -2963,6 +8654,30 @@ mod tests {
         }).collect().wait().unwrap();
     }
 
+    #[test]
+    fn ping_nodes_to_bootstrap_closest_first() {
+        let (alice, _precomp, _bob_pk, _bob_sk, rx, _addr) = create_node();
+
+        let mut nodes = (0 .. 4).map(|i| {
+            let saddr = SocketAddr::new("127.1.1.1".parse().unwrap(), 12345 + i);
+            PackedNode::new(saddr, &gen_keypair().0)
+        }).collect::<Vec<_>>();
+
+        let mut nodes_to_bootstrap = NodesQueue::new(MAX_TO_BOOTSTRAP);
+        for node in &nodes {
+            assert!(nodes_to_bootstrap.try_add(&alice.pk, node));
+        }
+
+        let alice_pk = alice.pk;
+        alice.ping_nodes_to_bootstrap(&mut alice.request_queue.write(), &mut nodes_to_bootstrap, alice_pk).wait().unwrap();
+
+        drop(alice);
+
+        let addrs = rx.collect().wait().unwrap().into_iter().map(|(_packet, addr)| addr).collect::<Vec<_>>();
+        nodes.sort_by(|a, b| alice_pk.distance(&a.pk, &b.pk));
+        assert_eq!(addrs, nodes.iter().map(|node| node.saddr).collect::<Vec<_>>());
+    }
+
     #[test]
     fn ping_nodes_from_nodes_to_ping_list() {
         let (alice, _precomp, bob_pk, bob_sk, rx, _addr) = create_node();
@@ -3004,6 +8719,35 @@ mod tests {
         assert!(rx.collect().wait().unwrap().is_empty());
     }
 
+    #[test]
+    fn clear_nodes_to_ping() {
+        let (alice, precomp, bob_pk, _bob_sk, rx, addr) = create_node();
+
+        let req_payload = PingRequestPayload { id: 42 };
+        let ping_req = Packet::PingRequest(PingRequest::new(&precomp, &bob_pk, &req_payload));
+        alice.handle_packet(ping_req, addr).wait().unwrap();
+
+        let node = PackedNode::new("127.1.1.1:12345".parse().unwrap(), &gen_keypair().0);
+        alice.ping_add(&node).wait().unwrap();
+
+        assert_eq!(alice.nodes_to_ping_len(), 2);
+
+        alice.clear_nodes_to_ping();
+
+        assert_eq!(alice.nodes_to_ping_len(), 0);
+
+        // no PingRequest is sent to cleared nodes
+        alice.send_pings().wait().unwrap();
+
+        drop(alice);
+
+        let packets = rx.collect().wait().unwrap();
+        assert!(packets.iter().all(|(packet, _addr)| match packet {
+            Packet::PingRequest(_) => false,
+            _ => true,
+        }));
+    }
+
     #[test]
     fn ping_close_nodes() {
         let (alice, _precomp, bob_pk, bob_sk, rx, _addr) = create_node();
@@ -3019,8 +8763,9 @@ mod tests {
 
         let mut request_queue = alice.request_queue.write();
 
-        // 3 = 2 packets sent by ping_close_nodes + 1 packet sent by send_nodes_req_random
-        rx.take(3).map(|(packet, addr)| {
+        // 2 packets sent by ping_close_nodes, the request sent by
+        // send_nodes_req_random duplicates one of them and is coalesced
+        rx.take(2).map(|(packet, addr)| {
             let nodes_req = unpack!(packet, Packet::NodesRequest);
             if addr == "127.0.0.1:33445".parse().unwrap() {
                 let precomputed_key = precompute(&nodes_req.pk, &bob_sk);
@@ -3084,6 +8829,65 @@ mod tests {
         assert!(rx.collect().wait().unwrap().is_empty());
     }
 
+    #[test]
+    fn dht_main_loop_deduplicates_nodes_requests() {
+        let (alice, _precomp, bob_pk, _bob_sk, rx, _addr) = create_node();
+
+        // the same node is in the bootstrap list and in close nodes list so
+        // that it's requested by every loop stage
+        let pn = PackedNode::new("127.1.1.1:12345".parse().unwrap(), &bob_pk);
+        assert!(alice.close_nodes.write().try_add(&pn));
+        assert!(alice.nodes_to_bootstrap.write().try_add(&alice.pk, &pn));
+
+        alice.dht_main_loop().wait().unwrap();
+
+        // requests sent outside of the iteration are not deduplicated
+        assert!(alice.tick_nodes_reqs.read().is_none());
+
+        drop(alice);
+        let packets = rx.collect().wait().unwrap();
+        assert_eq!(packets.len(), 1);
+        let (packet, addr) = packets[0].clone();
+        unpack!(packet, Packet::NodesRequest);
+        assert_eq!(addr, pn.saddr);
+    }
+
+    #[test]
+    fn send_nodes_req_random_max_bootstrap_times() {
+        let (mut alice, _precomp, bob_pk, _bob_sk, rx, _addr) = create_node();
+
+        alice.set_max_bootstrap_times(1);
+
+        {
+            let mut close_nodes = alice.close_nodes.write();
+            let pn = PackedNode::new("127.1.1.1:12345".parse().unwrap(), &bob_pk);
+            assert!(close_nodes.try_add(&pn));
+            let node = close_nodes.get_node_mut(&bob_pk).unwrap();
+            // Set last_ping_req_time so that only random request will be sent
+            node.assoc4.last_ping_req_time = Some(clock_now());
+            node.assoc6.last_ping_req_time = Some(clock_now());
+        }
+
+        let now = Instant::now();
+        let mut enter = tokio_executor::enter().unwrap();
+
+        // Only the first random request should be sent every second
+        for i in 0 .. 3 {
+            let clock = Clock::new_with_now(ConstNow(now + Duration::from_secs(i)));
+            with_default(&clock, &mut enter, |_| {
+                alice.dht_main_loop().wait().unwrap();
+            });
+        }
+
+        // Necessary to drop tx so that rx.collect() can be finished
+        drop(alice);
+
+        let packets = rx.collect().wait().unwrap();
+        assert_eq!(packets.len(), 1);
+        let (packet, _) = packets[0].clone();
+        unpack!(packet, Packet::NodesRequest);
+    }
+
     #[test]
     fn ping_nodes_to_bootstrap_of_friend() {
         let (alice, _precomp, bob_pk, bob_sk, rx, _addr) = create_node();
@@ -3136,66 +8940,291 @@ mod tests {
 
         alice.dht_main_loop().wait().unwrap();
 
-        let mut request_queue = alice.request_queue.write();
+        let mut request_queue = alice.request_queue.write();
+
+        // 2 packets sent by ping_close_nodes, the request sent by
+        // send_nodes_req_random duplicates one of them and is coalesced
+        rx.take(2).map(|(packet, addr)| {
+            let nodes_req = unpack!(packet, Packet::NodesRequest);
+            if addr == "127.0.0.1:33445".parse().unwrap() {
+                let precomputed_key = precompute(&nodes_req.pk, &bob_sk);
+                let nodes_req_payload = nodes_req.get_payload(&precomputed_key).unwrap();
+                assert!(request_queue.check_ping_id(bob_pk, nodes_req_payload.id));
+                assert_eq!(nodes_req_payload.pk, friend_pk);
+            } else {
+                let precomputed_key = precompute(&nodes_req.pk, &node_sk);
+                let nodes_req_payload = nodes_req.get_payload(&precomputed_key).unwrap();
+                assert!(request_queue.check_ping_id(node_pk, nodes_req_payload.id));
+                assert_eq!(nodes_req_payload.pk, friend_pk);
+            }
+        }).collect().wait().unwrap();
+    }
+
+    #[test]
+    fn ping_close_nodes_of_friend_across_ticks() {
+        let (alice, _precomp, _bob_pk, _bob_sk, _rx, _addr) = create_node();
+        let node_pk = gen_keypair().0;
+
+        let friend_pk = gen_keypair().0;
+
+        alice.add_friend(friend_pk);
+
+        let pn = PackedNode::new("127.1.1.1:12345".parse().unwrap(), &node_pk);
+        assert!(alice.friends.write()[FAKE_FRIENDS_NUMBER].try_add_to_close(&pn));
+
+        let now = Instant::now();
+        let tick = |secs: u64| {
+            let mut enter = tokio_executor::enter().unwrap();
+            let clock = Clock::new_with_now(ConstNow(now + Duration::from_secs(secs)));
+            with_default(&clock, &mut enter, |_| {
+                alice.dht_main_loop().wait().unwrap();
+            });
+            alice.friends.read()[FAKE_FRIENDS_NUMBER].close_nodes
+                .get_node(&friend_pk, &node_pk).unwrap()
+                .assoc4.last_ping_req_time.unwrap()
+        };
+
+        assert_eq!(tick(0), now);
+        // ping interval is not passed yet
+        assert_eq!(tick(PING_INTERVAL - 1), now);
+        // the node didn't answer so ping interval is doubled
+        assert_eq!(tick(PING_INTERVAL), now);
+        // node is pinged again for liveness
+        assert_eq!(tick(PING_INTERVAL * 2), now + Duration::from_secs(PING_INTERVAL * 2));
+    }
+
+    #[test]
+    fn ping_close_nodes_backoff() {
+        let (alice, _precomp, _bob_pk, _bob_sk, _rx, _addr) = create_node();
+        let (node_pk, node_sk) = gen_keypair();
+
+        let pn = PackedNode::new("127.1.1.1:12345".parse().unwrap(), &node_pk);
+        assert!(alice.close_nodes.write().try_add(&pn));
+
+        let now = Instant::now();
+        let tick = |secs: u64| {
+            let mut enter = tokio_executor::enter().unwrap();
+            let clock = Clock::new_with_now(ConstNow(now + Duration::from_secs(secs)));
+            with_default(&clock, &mut enter, |_| {
+                alice.dht_main_loop().wait().unwrap();
+            });
+            alice.close_nodes.read().get_node(&node_pk).unwrap().assoc4.last_ping_req_time.unwrap()
+        };
+
+        assert_eq!(tick(0), now);
+        // the request times out
+        assert_eq!(tick(PING_TIMEOUT + 1), now);
+        assert_eq!(alice.unanswered_pings.read().get(&node_pk), Some(&1));
+        assert_eq!(tick(PING_INTERVAL), now);
+        assert_eq!(tick(PING_INTERVAL * 2), now + Duration::from_secs(PING_INTERVAL * 2));
+
+        // response resets the counter
+        let ping_id = alice.request_queue.write().new_ping_id(node_pk);
+        let precomp = precompute(&alice.pk, &node_sk);
+        let resp_payload = PingResponsePayload { id: ping_id };
+        let ping_resp = Packet::PingResponse(PingResponse::new(&precomp, &node_pk, &resp_payload));
+        alice.handle_packet(ping_resp, pn.saddr).wait().unwrap();
+
+        assert!(alice.unanswered_pings.read().is_empty());
+    }
+
+    #[test]
+    fn send_nodes_req_random_friend_periodicity() {
+        let (alice, _precomp, bob_pk, _bob_sk, mut rx, _addr) = create_node();
+
+        let friend_pk = gen_keypair().0;
+        alice.add_friend(friend_pk);
+
+        let pn = PackedNode::new("127.0.0.1:33445".parse().unwrap(), &bob_pk);
+        assert!(alice.friends.write()[FAKE_FRIENDS_NUMBER].try_add_to_close(&pn));
+        // Set last_ping_req_time so that only random request will be sent
+        alice.friends.write()[FAKE_FRIENDS_NUMBER].close_nodes.nodes[0].assoc4.last_ping_req_time = Some(clock_now());
+        alice.friends.write()[FAKE_FRIENDS_NUMBER].close_nodes.nodes[0].assoc6.last_ping_req_time = Some(clock_now());
+
+        let now = Instant::now();
+        let mut enter = tokio_executor::enter().unwrap();
+
+        // Random request should be sent every second MAX_BOOTSTRAP_TIMES times
+        // This loop will produce MAX_BOOTSTRAP_TIMES random packets
+        for i in 0 .. MAX_BOOTSTRAP_TIMES {
+            let clock = Clock::new_with_now(ConstNow(now + Duration::from_secs(u64::from(i))));
+
+            with_default(&clock, &mut enter, |_| {
+                alice.friends.write()[FAKE_FRIENDS_NUMBER].hole_punch.last_send_ping_time = Some(clock_now());
+                alice.dht_main_loop().wait().unwrap();
+            });
+
+            let (received, rx1) = rx.into_future().wait().unwrap();
+            let (packet, _) = received.unwrap();
+
+            unpack!(packet, Packet::NodesRequest);
+
+            rx = rx1;
+        }
+
+        // Random packet won't be sent anymore if NODES_REQ_INTERVAL is not passed
+        let clock = Clock::new_with_now(ConstNow(
+            now + Duration::from_secs(u64::from(MAX_BOOTSTRAP_TIMES))
+        ));
+        with_default(&clock, &mut enter, |_| {
+            alice.dht_main_loop().wait().unwrap();
+        });
+
+        // Necessary to drop tx so that rx.collect() can be finished
+        drop(alice);
+
+        assert!(rx.collect().wait().unwrap().is_empty());
+    }
+
+    #[test]
+    fn send_nodes_req_random_tapering() {
+        let (mut alice, _precomp, _bob_pk, _bob_sk, _rx, _addr) = create_node();
+
+        alice.enable_random_requests_tapering(true);
+        *alice.random_requests_count.write() = MAX_BOOTSTRAP_TIMES;
+
+        for i in 0 .. KBUCKET_DEFAULT_SIZE {
+            let saddr = SocketAddr::new("1.2.3.4".parse().unwrap(), 12345 + u16::from(i));
+            assert!(alice.try_add_to_close_nodes(&PackedNode::new(saddr, &gen_keypair().0)));
+        }
+
+        let now = Instant::now();
+        *alice.last_nodes_req_time.write() = now;
+        let mut enter = tokio_executor::enter().unwrap();
+        let mut tick = |secs: u64, bad: bool| {
+            let clock = Clock::new_with_now(ConstNow(now + Duration::from_secs(secs)));
+            with_default(&clock, &mut enter, |_| {
+                // keep nodes alive without sending pings to them
+                for node in alice.close_nodes.write().iter_mut() {
+                    node.assoc4.last_resp_time = if bad { None } else { Some(clock_now()) };
+                    node.assoc4.last_ping_req_time = Some(clock_now());
+                }
+                alice.dht_main_loop().wait().unwrap();
+            });
+            *alice.last_nodes_req_time.read() == now + Duration::from_secs(secs)
+        };
+
+        // random request is sent and the interval is doubled
+        assert!(tick(NODES_REQ_INTERVAL + 1, false));
+        assert!(!tick(NODES_REQ_INTERVAL * 2 + 2, false));
+        assert!(tick(NODES_REQ_INTERVAL * 3 + 2, false));
+        // the interval is doubled again
+        assert!(!tick(NODES_REQ_INTERVAL * 6 + 2, false));
+        assert!(tick(NODES_REQ_INTERVAL * 7 + 3, false));
+        // nodes become bad so requests resume with default interval
+        assert!(tick(NODES_REQ_INTERVAL * 8 + 4, true));
+    }
+
+    #[test]
+    fn set_friend_search_interval() {
+        let (alice, _precomp, _bob_pk, _bob_sk, _rx, _addr) = create_node();
+
+        let friend_pk_1 = gen_keypair().0;
+        let friend_pk_2 = gen_keypair().0;
+        alice.add_friend(friend_pk_1);
+        alice.add_friend(friend_pk_2);
+
+        assert!(alice.set_friend_search_interval(friend_pk_2, Duration::from_secs(NODES_REQ_INTERVAL * 5)));
+        // unknown friend is reported
+        assert!(!alice.set_friend_search_interval(gen_keypair().0, Duration::from_secs(NODES_REQ_INTERVAL * 5)));
+
+        let now = Instant::now();
+        let mut enter = tokio_executor::enter().unwrap();
+        let mut tick = |secs: u64| {
+            let clock = Clock::new_with_now(ConstNow(now + Duration::from_secs(secs)));
+            with_default(&clock, &mut enter, |_| {
+                alice.dht_main_loop().wait().unwrap();
+            });
+            let friends = alice.friends.read();
+            (friends[FAKE_FRIENDS_NUMBER].random_requests_count, friends[FAKE_FRIENDS_NUMBER + 1].random_requests_count)
+        };
+
+        for i in 0 .. MAX_BOOTSTRAP_TIMES {
+            tick(u64::from(i));
+        }
+
+        let last_burst = u64::from(MAX_BOOTSTRAP_TIMES - 1);
+        let burst_count = MAX_BOOTSTRAP_TIMES;
+
+        // friend with default interval is searched while the other one is not
+        assert_eq!(tick(last_burst + NODES_REQ_INTERVAL + 1), (burst_count + 1, burst_count));
+        // friend with long interval is searched after its interval is passed
+        assert_eq!(tick(last_burst + NODES_REQ_INTERVAL * 5 + 1), (burst_count + 2, burst_count + 1));
+    }
+
+    #[test]
+    fn send_nodes_req_random_to_good_node_only() {
+        let (alice, _precomp, bob_pk, bob_sk, rx, _addr) = create_node();
+
+        let search_pk = gen_keypair().0;
+        let mut bad_node = DhtNode::new(PackedNode::new("127.1.1.1:12345".parse().unwrap(), &gen_keypair().0));
+        bad_node.assoc4.last_resp_time = None;
+
+        alice.send_nodes_req_random(&mut alice.request_queue.write(), iter::once(&bad_node), search_pk).wait().unwrap();
+
+        let good_node = DhtNode::new(PackedNode::new("127.0.0.1:33445".parse().unwrap(), &bob_pk));
+        alice.send_nodes_req_random(&mut alice.request_queue.write(), iter::once(&good_node), search_pk).wait().unwrap();
+
+        let mut request_queue = alice.request_queue.write();
+
+        let (received, _rx) = rx.into_future().wait().unwrap();
+        let (packet, addr_to_send) = received.unwrap();
+
+        assert_eq!(addr_to_send, "127.0.0.1:33445".parse().unwrap());
+
+        let nodes_req = unpack!(packet, Packet::NodesRequest);
+        let precomputed_key = precompute(&nodes_req.pk, &bob_sk);
+        let nodes_req_payload = nodes_req.get_payload(&precomputed_key).unwrap();
 
-        // 3 = 2 packets sent by ping_close_nodes + 1 packet sent by send_nodes_req_random
-        rx.take(3).map(|(packet, addr)| {
-            let nodes_req = unpack!(packet, Packet::NodesRequest);
-            if addr == "127.0.0.1:33445".parse().unwrap() {
-                let precomputed_key = precompute(&nodes_req.pk, &bob_sk);
-                let nodes_req_payload = nodes_req.get_payload(&precomputed_key).unwrap();
-                assert!(request_queue.check_ping_id(bob_pk, nodes_req_payload.id));
-                assert_eq!(nodes_req_payload.pk, friend_pk);
-            } else {
-                let precomputed_key = precompute(&nodes_req.pk, &node_sk);
-                let nodes_req_payload = nodes_req.get_payload(&precomputed_key).unwrap();
-                assert!(request_queue.check_ping_id(node_pk, nodes_req_payload.id));
-                assert_eq!(nodes_req_payload.pk, friend_pk);
-            }
-        }).collect().wait().unwrap();
+        assert!(request_queue.check_ping_id(bob_pk, nodes_req_payload.id));
+        assert_eq!(nodes_req_payload.pk, search_pk);
     }
 
     #[test]
-    fn send_nodes_req_random_friend_periodicity() {
-        let (alice, _precomp, bob_pk, _bob_sk, mut rx, _addr) = create_node();
+    fn send_nat_ping_req_to_friend() {
+        let (alice, _precomp, _bob_pk, _bob_sk, rx, _addr) = create_node();
 
-        let friend_pk = gen_keypair().0;
-        alice.add_friend(friend_pk);
+        let (friend_pk, friend_sk) = gen_keypair();
+        let mut friend = DhtFriend::new(friend_pk);
+        for i in 0 .. FRIEND_CLOSE_NODES_COUNT / 2 {
+            let node = PackedNode::new(SocketAddr::new("127.1.1.1".parse().unwrap(), 12345 + u16::from(i)), &gen_keypair().0);
+            assert!(friend.try_add_to_close(&node));
+            let dht_node = friend.close_nodes.get_node_mut(&friend_pk, &node.pk).unwrap();
+            dht_node.update_returned_addr(node.saddr);
+        }
 
-        let pn = PackedNode::new("127.0.0.1:33445".parse().unwrap(), &bob_pk);
-        assert!(alice.friends.write()[FAKE_FRIENDS_NUMBER].try_add_to_close(&pn));
-        // Set last_ping_req_time so that only random request will be sent
-        alice.friends.write()[FAKE_FRIENDS_NUMBER].close_nodes.nodes[0].assoc4.last_ping_req_time = Some(clock_now());
-        alice.friends.write()[FAKE_FRIENDS_NUMBER].close_nodes.nodes[0].assoc6.last_ping_req_time = Some(clock_now());
+        alice.send_nat_ping_req(&mut alice.request_queue.write(), iter::once(&mut friend)).wait().unwrap();
 
-        let now = Instant::now();
-        let mut enter = tokio_executor::enter().unwrap();
+        assert!(friend.hole_punch.last_send_ping_time.is_some());
 
-        // Random request should be sent every second MAX_BOOTSTRAP_TIMES times
-        // This loop will produce MAX_BOOTSTRAP_TIMES random packets
-        for i in 0 .. MAX_BOOTSTRAP_TIMES {
-            let clock = Clock::new_with_now(ConstNow(now + Duration::from_secs(u64::from(i))));
+        // Necessary to drop tx so that rx.collect() can be finished
+        drop(alice);
 
-            with_default(&clock, &mut enter, |_| {
-                alice.friends.write()[FAKE_FRIENDS_NUMBER].hole_punch.last_send_ping_time = Some(clock_now());
-                alice.dht_main_loop().wait().unwrap();
-            });
+        let nat_ping_reqs = rx.collect().wait().unwrap().into_iter()
+            .filter_map(|(packet, _addr)| match packet {
+                Packet::DhtRequest(dht_request) => Some(dht_request),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
 
-            let (received, rx1) = rx.into_future().wait().unwrap();
-            let (packet, _) = received.unwrap();
+        // NatPingRequest is sent through all close nodes of the friend
+        assert_eq!(nat_ping_reqs.len(), FRIEND_CLOSE_NODES_COUNT as usize / 2);
+        for nat_ping_req in nat_ping_reqs {
+            let precomputed_key = precompute(&nat_ping_req.spk, &friend_sk);
+            let nat_ping_req_payload = nat_ping_req.get_payload(&precomputed_key).unwrap();
+            let nat_ping_req_payload = unpack!(nat_ping_req_payload, DhtRequestPayload::NatPingRequest);
+            assert_eq!(nat_ping_req_payload.id, friend.hole_punch.ping_id);
+        }
+    }
 
-            unpack!(packet, Packet::NodesRequest);
+    #[test]
+    fn send_nodes_req_to_ourselves() {
+        let (alice, _precomp, _bob_pk, _bob_sk, rx, addr) = create_node();
 
-            rx = rx1;
-        }
+        let node = PackedNode::new(addr, &alice.pk);
+        let res = alice.send_nodes_req(&node, &mut alice.request_queue.write(), alice.pk).wait();
 
-        // Random packet won't be sent anymore if NODES_REQ_INTERVAL is not passed
-        let clock = Clock::new_with_now(ConstNow(
-            now + Duration::from_secs(u64::from(MAX_BOOTSTRAP_TIMES))
-        ));
-        with_default(&clock, &mut enter, |_| {
-            alice.dht_main_loop().wait().unwrap();
-        });
+        assert_eq!(*res.err().unwrap().kind(), SendErrorKind::TargetIsSelf);
 
         // Necessary to drop tx so that rx.collect() can be finished
         drop(alice);
@@ -3242,6 +9271,105 @@ mod tests {
         }).collect().wait().unwrap();
     }
 
+    #[test]
+    fn needs_bootstrap() {
+        let (mut alice, _precomp, bob_pk, _bob_sk, _rx, _addr) = create_node();
+
+        assert!(alice.needs_bootstrap());
+
+        let pn = PackedNode::new("127.1.1.1:12345".parse().unwrap(), &bob_pk);
+        alice.add_initial_bootstrap(pn);
+
+        assert!(!alice.needs_bootstrap());
+    }
+
+    #[test]
+    fn send_bootstrap_requests_without_bootstrap_nodes() {
+        let (alice, _precomp, _bob_pk, _bob_sk, rx, _addr) = create_node();
+
+        alice.send_bootstrap_requests().wait().unwrap();
+        assert!(alice.last_needs_bootstrap_warn_time.read().is_some());
+
+        drop(alice);
+        assert!(rx.collect().wait().unwrap().is_empty());
+    }
+
+    #[test]
+    fn replace_bootstrap_nodes() {
+        let (mut alice, _precomp, _bob_pk, _bob_sk, rx, _addr) = create_node();
+
+        let old_node = PackedNode::new("127.1.1.1:12345".parse().unwrap(), &gen_keypair().0);
+        alice.add_initial_bootstrap(old_node);
+
+        let new_nodes = vec![
+            PackedNode::new("127.1.1.2:12345".parse().unwrap(), &gen_keypair().0),
+            PackedNode::new("127.1.1.3:12345".parse().unwrap(), &gen_keypair().0),
+        ];
+        alice.replace_bootstrap_nodes(new_nodes.clone());
+
+        assert_eq!(*alice.initial_bootstrap.read(), new_nodes);
+
+        alice.send_bootstrap_requests().wait().unwrap();
+
+        drop(alice);
+
+        let addrs = rx.collect().wait().unwrap().into_iter()
+            .map(|(packet, addr)| {
+                unpack!(packet, Packet::NodesRequest);
+                addr
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(addrs.len(), 2);
+        assert!(new_nodes.iter().all(|node| addrs.contains(&node.saddr)));
+        assert!(!addrs.contains(&old_node.saddr));
+    }
+
+    #[test]
+    fn fallback_bootstrap() {
+        let (mut alice, _precomp, bob_pk, bob_sk, rx, _addr) = create_node();
+
+        let addr = "127.1.1.1:12345".parse().unwrap();
+        alice.set_fallback_bootstrap_node(Some(PackedNode::new(addr, &bob_pk)));
+
+        alice.fallback_bootstrap().wait().unwrap();
+
+        let (received, rx) = rx.into_future().wait().unwrap();
+        let (packet, addr_to_send) = received.unwrap();
+
+        assert_eq!(addr_to_send, addr);
+
+        let nodes_req = unpack!(packet, Packet::NodesRequest);
+        let precomputed_key = precompute(&nodes_req.pk, &bob_sk);
+        let nodes_req_payload = nodes_req.get_payload(&precomputed_key).unwrap();
+        assert!(alice.request_queue.write().check_ping_id(bob_pk, nodes_req_payload.id));
+
+        // fallback node is not queried when there are close nodes
+        let (node_pk, _node_sk) = gen_keypair();
+        let pn = PackedNode::new("127.0.0.1:33445".parse().unwrap(), &node_pk);
+        alice.try_add_to_close_nodes(&pn);
+
+        alice.fallback_bootstrap().wait().unwrap();
+
+        drop(alice);
+        assert!(rx.collect().wait().unwrap().is_empty());
+    }
+
+    #[test]
+    fn send_bootstrap_requests_with_fallback_bootstrap_node() {
+        let (mut alice, _precomp, bob_pk, _bob_sk, rx, _addr) = create_node();
+
+        let addr = "127.1.1.1:12345".parse().unwrap();
+        alice.set_fallback_bootstrap_node(Some(PackedNode::new(addr, &bob_pk)));
+
+        alice.send_bootstrap_requests().wait().unwrap();
+
+        drop(alice);
+        let packets = rx.collect().wait().unwrap();
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].1, addr);
+        unpack!(packets[0].0.clone(), Packet::NodesRequest);
+    }
+
     #[test]
     fn send_bootstrap_requests() {
         let (mut alice, _precomp, bob_pk, bob_sk, rx, _addr) = create_node();
@@ -3273,6 +9401,130 @@ mod tests {
         }).collect().wait().unwrap();
     }
 
+    #[test]
+    fn is_well_connected() {
+        let (alice, _precomp, _bob_pk, _bob_sk, _rx, _addr) = create_node();
+
+        assert!(!alice.is_connected());
+        assert!(alice.is_well_connected(0));
+
+        for i in 0 .. 2 {
+            let pn = PackedNode::new(format!("127.1.1.{}:12345", i + 1).parse().unwrap(), &gen_keypair().0);
+            assert!(alice.close_nodes.write().try_add(&pn));
+        }
+
+        assert!(alice.is_connected());
+        assert!(alice.is_well_connected(2));
+        assert!(!alice.is_well_connected(3));
+
+        // bad nodes are not counted
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(
+            Instant::now() + Duration::from_secs(BAD_NODE_TIMEOUT + 1)
+        ));
+        with_default(&clock, &mut enter, |_| {
+            assert!(!alice.is_connected());
+        });
+    }
+
+    #[test]
+    fn set_port_prediction_range() {
+        let (mut alice, _precomp, _bob_pk, _bob_sk, _rx, _addr) = create_node();
+
+        alice.add_friend(gen_keypair().0);
+        alice.set_port_prediction_range(10);
+        alice.add_friend(gen_keypair().0);
+
+        assert!(alice.friends.read().iter().all(|friend| friend.hole_punch.port_prediction_range == 10));
+    }
+
+    #[test]
+    fn set_addr_preference() {
+        let (alice, _precomp, bob_pk, _bob_sk, _rx, _addr) = create_node();
+
+        let addr4 = "1.2.3.4:12345".parse().unwrap();
+        let addr6 = "[2001:db8::1]:12345".parse().unwrap();
+        assert!(alice.close_nodes.write().try_add(&PackedNode::new(addr4, &bob_pk)));
+        alice.close_nodes.write().get_node_mut(&bob_pk).unwrap().update_addr(addr6);
+
+        alice.set_addr_preference(AddrPreference::Ipv4);
+        let nodes: Vec<PackedNode> = alice.get_closest(&bob_pk, false, false).into();
+        assert_eq!(nodes, vec![PackedNode::new(addr4, &bob_pk)]);
+
+        alice.set_addr_preference(AddrPreference::Ipv6);
+        let nodes: Vec<PackedNode> = alice.get_closest(&bob_pk, false, false).into();
+        assert_eq!(nodes, vec![PackedNode::new(addr6, &bob_pk)]);
+    }
+
+    #[test]
+    fn close_list_subnet_diversity() {
+        let (alice, _precomp, _bob_pk, _bob_sk, _rx, _addr) = create_node();
+
+        for i in 0 .. 8 {
+            let addr = SocketAddr::new("1.2.3.4".parse().unwrap(), 12345 + i);
+            alice.close_nodes.write().try_add(&PackedNode::new(addr, &gen_keypair().0));
+        }
+        let nodes_count = alice.close_nodes.read().iter().count();
+        assert!(nodes_count > 1);
+
+        // clustered nodes
+        assert_eq!(alice.close_list_subnet_diversity(), 1);
+
+        for i in 0 .. 8 {
+            let addr = SocketAddr::new(format!("1.2.{}.4", 4 + i).parse().unwrap(), 12345);
+            alice.close_nodes.write().try_add(&PackedNode::new(addr, &gen_keypair().0));
+        }
+
+        // spread out nodes
+        let new_nodes_count = alice.close_nodes.read().iter().count();
+        assert_eq!(alice.close_list_subnet_diversity(), 1 + new_nodes_count - nodes_count);
+    }
+
+    #[test]
+    fn wait_connected() {
+        let (alice, _precomp, _bob_pk, _bob_sk, _rx, _addr) = create_node();
+
+        let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+
+        let pn = PackedNode::new("127.1.1.1:12345".parse().unwrap(), &gen_keypair().0);
+        assert!(alice.close_nodes.write().try_add(&pn));
+
+        // the threshold is not reached yet
+        let future = alice.wait_connected(2).timeout(Duration::from_millis(100));
+        assert!(runtime.block_on(future).unwrap_err().is_elapsed());
+
+        let pn = PackedNode::new("127.1.1.2:12345".parse().unwrap(), &gen_keypair().0);
+        assert!(alice.close_nodes.write().try_add(&pn));
+
+        runtime.block_on(alice.wait_connected(2)).unwrap();
+    }
+
+    #[test]
+    fn kick_bootstrap() {
+        let (mut alice, _precomp, bob_pk, _bob_sk, rx, _addr) = create_node();
+
+        let initial_addrs: Vec<SocketAddr> = vec!["127.1.1.1:12345".parse().unwrap(), "127.1.1.2:12345".parse().unwrap()];
+        for &addr in &initial_addrs {
+            alice.add_initial_bootstrap(PackedNode::new(addr, &gen_keypair().0));
+        }
+        let queued_addr = "127.1.1.3:12345".parse().unwrap();
+        assert!(alice.nodes_to_bootstrap.write().try_add(&alice.pk, &PackedNode::new(queued_addr, &bob_pk)));
+
+        alice.kick_bootstrap().wait().unwrap();
+
+        assert!(alice.nodes_to_bootstrap.read().is_empty());
+
+        drop(alice);
+        let mut addrs = rx.collect().wait().unwrap().into_iter()
+            .map(|(packet, addr)| {
+                unpack!(packet, Packet::NodesRequest);
+                addr
+            })
+            .collect::<Vec<_>>();
+        addrs.sort();
+        assert_eq!(addrs, vec![initial_addrs[0], initial_addrs[1], queued_addr]);
+    }
+
     #[test]
     fn send_bootstrap_requests_when_ktree_has_good_node() {
         let (mut alice, _precomp, bob_pk, _bob_sk, rx, _addr) = create_node();
@@ -3377,4 +9629,137 @@ mod tests {
 
         assert!(alice.handle_packet(data, addr).wait().is_err());
     }
+
+    #[test]
+    fn handle_onion_data_response_for_local_client() {
+        let (mut alice, _precomp, _bob_pk, _bob_sk, _rx, addr) = create_node();
+        let (onion_response_tx, onion_response_rx) = mpsc::unbounded();
+        alice.set_onion_response_sink(onion_response_tx);
+
+        let inner = OnionDataResponse {
+            nonce: gen_nonce(),
+            temporary_pk: gen_keypair().0,
+            payload: vec![42; 123]
+        };
+        let data = Packet::OnionDataResponse(inner.clone());
+
+        alice.handle_packet(data, addr).wait().unwrap();
+
+        drop(alice);
+        assert_eq!(onion_response_rx.collect().wait().unwrap(), vec![InnerOnionResponse::OnionDataResponse(inner)]);
+    }
+
+    #[test]
+    fn handle_onion_announce_response_for_local_client() {
+        let (mut alice, precomp, _bob_pk, _bob_sk, _rx, addr) = create_node();
+        let (onion_response_tx, onion_response_rx) = mpsc::unbounded();
+        alice.set_onion_response_sink(onion_response_tx);
+
+        let payload = OnionAnnounceResponsePayload {
+            announce_status: AnnounceStatus::Found,
+            ping_id_or_pk: sha256::hash(&[1, 2, 3]),
+            nodes: vec![
+                PackedNode::new(SocketAddr::V4("5.6.7.8:12345".parse().unwrap()), &gen_keypair().0)
+            ]
+        };
+        let inner = OnionAnnounceResponse::new(&precomp, 12345, &payload);
+        let data = Packet::OnionAnnounceResponse(inner.clone());
+
+        alice.handle_packet(data, addr).wait().unwrap();
+
+        drop(alice);
+        assert_eq!(onion_response_rx.collect().wait().unwrap(), vec![InnerOnionResponse::OnionAnnounceResponse(inner)]);
+    }
+
+    #[test]
+    fn bucket_fill() {
+        let (alice, _precomp, _bob_pk, _bob_sk, _rx, _addr) = create_node();
+
+        assert!(alice.try_add_to_close_nodes(&PackedNode::new("127.0.0.1:33445".parse().unwrap(), &pk_in_kbucket(&alice.pk, 0, 0))));
+        assert!(alice.try_add_to_close_nodes(&PackedNode::new("127.0.0.2:33445".parse().unwrap(), &pk_in_kbucket(&alice.pk, 5, 0))));
+        assert!(alice.try_add_to_close_nodes(&PackedNode::new("127.0.0.3:33445".parse().unwrap(), &pk_in_kbucket(&alice.pk, 5, 1))));
+
+        let fill = alice.bucket_fill();
+
+        assert_eq!(fill.len(), KBUCKET_MAX_ENTRIES as usize);
+        assert_eq!(fill[0], (0, 1));
+        assert_eq!(fill[5], (5, 2));
+        assert_eq!(fill.iter().map(|&(_, count)| count).sum::<usize>(), 3);
+    }
+
+    #[test]
+    fn eviction_tracking() {
+        let (mut alice, _precomp, _bob_pk, _bob_sk, _rx, _addr) = create_node();
+
+        let (event_tx, event_rx) = mpsc::unbounded();
+        alice.set_event_sink(event_tx);
+        alice.enable_eviction_tracking(true);
+
+        for i in 0 .. KBUCKET_DEFAULT_SIZE {
+            let addr = format!("127.0.0.{}:33445", i + 1).parse().unwrap();
+            assert!(alice.try_add_to_close_nodes(&PackedNode::new(addr, &pk_in_kbucket(&alice.pk, 0, i + 1))));
+        }
+
+        let evicted_pk = alice.close_nodes.read().kbuckets[0].nodes.last().unwrap().pk;
+
+        // trusted node replaces the farthest untrusted one in the full kbucket
+        let trusted_node = PackedNode::new("127.0.1.1:33445".parse().unwrap(), &pk_in_kbucket(&alice.pk, 0, 0x7f));
+        alice.add_initial_bootstrap(trusted_node);
+        assert!(alice.try_add_to_close(&mut alice.close_nodes.write(), &trusted_node));
+
+        assert!(!alice.close_nodes.read().contains(&evicted_pk));
+        assert_eq!(alice.evicted_close_nodes(), 1);
+
+        drop(alice);
+        assert_eq!(event_rx.collect().wait().unwrap(), vec![DhtEvent::NodeEvicted(evicted_pk)]);
+    }
+
+    #[test]
+    fn coverage_score() {
+        let (alice, _precomp, _bob_pk, _bob_sk, _rx, _addr) = create_node();
+
+        assert_eq!(alice.coverage_score(), 0.0);
+
+        for bit in 0 .. 4 {
+            for i in 0 .. KBUCKET_DEFAULT_SIZE {
+                let addr = format!("127.0.{}.{}:33445", bit, i).parse().unwrap();
+                assert!(alice.try_add_to_close_nodes(&PackedNode::new(addr, &pk_in_kbucket(&alice.pk, bit, i + 1))));
+            }
+        }
+
+        assert_eq!(alice.coverage_score(), 1.0);
+
+        // half filled kbucket lowers the score
+        for i in 0 .. KBUCKET_DEFAULT_SIZE / 2 {
+            let addr = format!("127.0.4.{}:33445", i).parse().unwrap();
+            assert!(alice.try_add_to_close_nodes(&PackedNode::new(addr, &pk_in_kbucket(&alice.pk, 4, i + 1))));
+        }
+
+        assert!((alice.coverage_score() - 0.9).abs() < 1e-6);
+
+        // bad nodes don't count
+        let time = Instant::now() + Duration::from_secs(BAD_NODE_TIMEOUT + 1);
+
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(time));
+
+        with_default(&clock, &mut enter, |_| {
+            assert_eq!(alice.coverage_score(), 0.0);
+        });
+    }
+
+    #[test]
+    fn routing_coverage() {
+        let (alice, _precomp, _bob_pk, _bob_sk, _rx, _addr) = create_node();
+
+        assert!(alice.routing_coverage().is_empty());
+
+        for (i, &(index, last_byte)) in [(0, 1), (0, 2), (0, 3), (5, 1), (42, 1)].iter().enumerate() {
+            let addr = SocketAddr::new("1.2.3.4".parse().unwrap(), 12345 + i as u16);
+            let pn = PackedNode::new(addr, &pk_in_kbucket(&alice.pk, index, last_byte));
+            assert!(alice.try_add_to_close_nodes(&pn));
+        }
+
+        assert_eq!(alice.routing_coverage(), vec![(0, 3), (5, 1), (42, 1)]);
+    }
 }
@@ -189,6 +189,27 @@ impl NetCrypto {
         self.connections.read().get(&pk).cloned()
     }
 
+    /// Check if there is a connection to the peer with given UDP address, i.e.
+    /// handshake packets from this address are expected.
+    pub fn has_connection_by_addr(&self, addr: SocketAddr) -> bool {
+        self.key_by_addr(addr).map_or(false, |pk| self.connections.read().contains_key(&pk))
+    }
+
+    /// Check if there is an established connection to the peer with given DHT
+    /// `PublicKey`.
+    pub fn is_established_by_dht_pk(&self, peer_dht_pk: &PublicKey) -> bool {
+        self.connections.read().values().any(|connection| {
+            let connection = connection.read();
+            connection.peer_dht_pk == *peer_dht_pk && connection.is_established()
+        })
+    }
+
+    /// Add crypto connection. Used in tests to simulate connected peers.
+    #[cfg(test)]
+    pub(crate) fn add_connection(&self, connection: CryptoConnection) {
+        self.connections.write().insert(connection.peer_real_pk, Arc::new(RwLock::new(connection)));
+    }
+
     /// Create `CookieResponse` packet with `Cookie` requested by `CookieRequest` packet
     fn handle_cookie_request(&self, packet: &CookieRequest) -> Result<CookieResponse, Error> {
         let payload = packet.get_payload(&self.precomputed_keys.get(packet.pk))?;
@@ -1156,6 +1177,41 @@ mod tests {
         assert_eq!(payload.cookie_hash, cookie.hash());
     }
 
+    #[test]
+    fn has_connection_by_addr() {
+        crypto_init().unwrap();
+        let (udp_tx, _udp_rx) = mpsc::channel(1);
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            precomputed_keys,
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let connection = CryptoConnection::new(&dht_sk, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        assert!(!net_crypto.has_connection_by_addr(addr));
+
+        net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
+        net_crypto.keys_by_addr.write().insert((addr.ip(), addr.port()), peer_real_pk);
+
+        assert!(net_crypto.has_connection_by_addr(addr));
+        assert!(!net_crypto.has_connection_by_addr("127.0.0.1:12346".parse().unwrap()));
+    }
+
     #[test]
     fn handle_udp_cookie_response_no_connection() {
         crypto_init().unwrap();
@@ -3192,4 +3248,47 @@ mod tests {
 
         assert_eq!(udp_rx.collect().wait().unwrap().len(), 2);
     }
+
+    #[test]
+    fn is_established_by_dht_pk() {
+        crypto_init().unwrap();
+        let (udp_tx, _udp_rx) = mpsc::channel(1);
+        let (dht_pk_tx, _dht_pk_rx) = mpsc::unbounded();
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            dht_pk_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            precomputed_keys,
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let connection = CryptoConnection::new(&dht_sk, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+        net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
+
+        // connection is not established yet
+        assert!(!net_crypto.is_established_by_dht_pk(&peer_dht_pk));
+
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        net_crypto.connections.read()[&peer_real_pk].write().status = ConnectionStatus::Established {
+            sent_nonce: gen_nonce(),
+            received_nonce: gen_nonce(),
+            peer_session_pk,
+            session_precomputed_key,
+        };
+
+        assert!(net_crypto.is_established_by_dht_pk(&peer_dht_pk));
+        assert!(!net_crypto.is_established_by_dht_pk(&gen_keypair().0));
+    }
 }
@@ -42,4 +42,5 @@ onion return addresses.
 */
 
 pub mod onion_announce;
+pub mod replay_cache;
 pub mod packet;
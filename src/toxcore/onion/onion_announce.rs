@@ -23,8 +23,8 @@ pub const ONION_ANNOUNCE_MAX_ENTRIES: usize = 160;
 /// 2 * `PING_ID_TIMEOUT` seconds.
 pub const PING_ID_TIMEOUT: u64 = 300;
 
-/// Number of seconds that announce entry can be stored in onion announce list
-/// without re-announcing.
+/// Default number of seconds that announce entry can be stored in onion
+/// announce list without re-announcing.
 pub const ONION_ANNOUNCE_TIMEOUT: u64 = 300;
 
 /// Create onion ping id filled with zeros.
@@ -36,7 +36,8 @@ pub fn initial_ping_id() -> sha256::Digest {
 /** Entry that corresponds to announced onion node.
 
 When node successfully announce itself this entry is added to announced nodes
-list. It's considered expired after `ONION_ANNOUNCE_TIMEOUT` seconds.
+list. It's considered expired after the timeout of announced nodes list which
+is `ONION_ANNOUNCE_TIMEOUT` seconds by default.
 
 */
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -70,12 +71,11 @@ impl OnionAnnounceEntry {
 
     /** Check if this entry is timed out.
 
-    Entry considered timed out after `ONION_ANNOUNCE_TIMEOUT` seconds since it
-    was created.
+    Entry considered timed out after `timeout` since it was created.
 
     */
-    pub fn is_timed_out(&self) -> bool {
-        clock_elapsed(self.time) >= Duration::from_secs(ONION_ANNOUNCE_TIMEOUT)
+    pub fn is_timed_out(&self, timeout: Duration) -> bool {
+        clock_elapsed(self.time) >= timeout
     }
 }
 
@@ -157,7 +157,10 @@ pub struct OnionAnnounce {
     /// List of announced onion nodes
     entries: Vec<OnionAnnounceEntry>,
     /// Short term DHT `PublicKey`
-    dht_pk: PublicKey
+    dht_pk: PublicKey,
+    /// Time after which announced node is removed from the list if it didn't
+    /// re-announce itself
+    entry_timeout: Duration,
 }
 
 impl OnionAnnounce {
@@ -168,10 +171,43 @@ impl OnionAnnounce {
         OnionAnnounce {
             secret_bytes,
             entries: Vec::with_capacity(ONION_ANNOUNCE_MAX_ENTRIES),
-            dht_pk
+            dht_pk,
+            entry_timeout: Duration::from_secs(ONION_ANNOUNCE_TIMEOUT),
         }
     }
 
+    /// Set time after which announced node is removed from the list if it
+    /// didn't re-announce itself.
+    pub fn set_entry_timeout(&mut self, timeout: Duration) {
+        self.entry_timeout = timeout;
+    }
+
+    /// Get number of announced nodes including timed out ones that weren't
+    /// removed yet.
+    pub fn entries_count(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Get `PublicKey`s of announced nodes ignoring timed out entries.
+    pub fn announced_keys(&self) -> Vec<PublicKey> {
+        self.entries.iter()
+            .filter(|e| !e.is_timed_out(self.entry_timeout))
+            .map(|e| e.pk)
+            .collect()
+    }
+
+    /// Check if node with given `PublicKey` is announced and its entry is not
+    /// timed out.
+    pub fn is_announced(&self, pk: PublicKey) -> bool {
+        self.find_in_entries(pk).is_some()
+    }
+
+    /// Remove timed out entries from onion announce list.
+    pub fn clear_timed_out(&mut self) {
+        let timeout = self.entry_timeout;
+        self.entries.retain(|e| !e.is_timed_out(timeout));
+    }
+
     /** Calculate onion ping id using sha256 hash of arguments together with
     secret bytes stored in this struct.
 
@@ -194,7 +230,7 @@ impl OnionAnnounce {
     fn find_in_entries(&self, pk: PublicKey) -> Option<&OnionAnnounceEntry> {
         match self.entries.binary_search_by(|e| self.dht_pk.distance(&e.pk, &pk)) {
             //TODO: use Option::filter when it's stabilized
-            Ok(idx) => if self.entries[idx].is_timed_out() { None } else { self.entries.get(idx) },
+            Ok(idx) => if self.entries[idx].is_timed_out(self.entry_timeout) { None } else { self.entries.get(idx) },
             Err(_) => None
         }
     }
@@ -214,8 +250,7 @@ impl OnionAnnounce {
 
     */
     fn add_to_entries(&mut self, entry: OnionAnnounceEntry) -> Option<&OnionAnnounceEntry> {
-        //TODO: remove timed out entries by timer?
-        self.entries.retain(|e| !e.is_timed_out());
+        self.clear_timed_out();
         match self.entries.binary_search_by(|e| self.dht_pk.distance(&e.pk, &entry.pk)) {
             Ok(idx) => {
                 // node with such pk already announced - just update the entry
@@ -358,7 +393,7 @@ mod tests {
             },
             gen_keypair().0
         );
-        assert!(!entry.is_timed_out());
+        assert!(!entry.is_timed_out(Duration::from_secs(ONION_ANNOUNCE_TIMEOUT)));
     }
 
     #[test]
@@ -382,7 +417,7 @@ mod tests {
         ));
 
         with_default(&clock, &mut enter, |_| {
-            assert!(entry.is_timed_out());
+            assert!(entry.is_timed_out(Duration::from_secs(ONION_ANNOUNCE_TIMEOUT)));
         });
     }
 
@@ -484,6 +519,91 @@ mod tests {
         });
     }
 
+    #[test]
+    fn announced_keys() {
+        crypto_init().unwrap();
+        let dht_pk = gen_keypair().0;
+        let mut onion_announce = OnionAnnounce::new(dht_pk);
+        onion_announce.set_entry_timeout(Duration::from_secs(10));
+
+        let entry = create_random_entry("1.2.3.4:12345".parse().unwrap());
+        let entry_pk = entry.pk;
+        let entry_time = entry.time;
+
+        assert!(onion_announce.add_to_entries(entry).is_some());
+        assert_eq!(onion_announce.announced_keys(), vec![entry_pk]);
+
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(
+            entry_time + Duration::from_secs(10)
+        ));
+
+        with_default(&clock, &mut enter, |_| {
+            assert!(onion_announce.announced_keys().is_empty());
+        });
+    }
+
+    #[test]
+    fn is_announced() {
+        crypto_init().unwrap();
+        let dht_pk = gen_keypair().0;
+        let mut onion_announce = OnionAnnounce::new(dht_pk);
+        onion_announce.set_entry_timeout(Duration::from_secs(10));
+
+        let entry = create_random_entry("1.2.3.4:12345".parse().unwrap());
+        let entry_pk = entry.pk;
+        let entry_time = entry.time;
+
+        assert!(!onion_announce.is_announced(entry_pk));
+        assert!(onion_announce.add_to_entries(entry).is_some());
+        assert!(onion_announce.is_announced(entry_pk));
+
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(
+            entry_time + Duration::from_secs(10)
+        ));
+
+        with_default(&clock, &mut enter, |_| {
+            assert!(!onion_announce.is_announced(entry_pk));
+        });
+    }
+
+    #[test]
+    fn clear_timed_out() {
+        crypto_init().unwrap();
+        let dht_pk = gen_keypair().0;
+        let mut onion_announce = OnionAnnounce::new(dht_pk);
+        onion_announce.set_entry_timeout(Duration::from_secs(10));
+
+        let entry = create_random_entry("1.2.3.4:12345".parse().unwrap());
+        let entry_time = entry.time;
+
+        assert!(onion_announce.add_to_entries(entry).is_some());
+        assert_eq!(onion_announce.entries_count(), 1);
+
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(
+            entry_time + Duration::from_secs(9)
+        ));
+
+        with_default(&clock, &mut enter, |_| {
+            onion_announce.clear_timed_out();
+        });
+
+        assert_eq!(onion_announce.entries_count(), 1);
+
+        // time when entry is timed out
+        let clock = Clock::new_with_now(ConstNow(
+            entry_time + Duration::from_secs(10)
+        ));
+
+        with_default(&clock, &mut enter, |_| {
+            onion_announce.clear_timed_out();
+        });
+
+        assert_eq!(onion_announce.entries_count(), 0);
+    }
+
     ////////////////////////////////////////////////////////////////////////////////////////
     // Tests for OnionAnnounce::add_to_entries
     #[test]
@@ -13,6 +13,10 @@ use std::io::{Error, ErrorKind};
 /// payload that should contain at least `IpPort` struct.
 const ONION_REQUEST_0_MIN_PAYLOAD_SIZE: usize = (SIZE_IPPORT + MACBYTES) * 2 + PUBLICKEYBYTES;
 
+/// Maximum size of encrypted payload so that the packet doesn't exceed
+/// `ONION_MAX_PACKET_SIZE`.
+pub const ONION_REQUEST_0_MAX_PAYLOAD_SIZE: usize = ONION_MAX_PACKET_SIZE - (1 + NONCEBYTES + PUBLICKEYBYTES);
+
 /** First onion request packet. It's sent from DHT node to the first node from
 onion chain. Payload can be encrypted with either temporary generated
 `SecretKey` or DHT `SecretKey` of sender and with DHT `PublicKey` of receiver.
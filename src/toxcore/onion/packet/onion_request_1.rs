@@ -12,6 +12,10 @@ use std::io::{Error, ErrorKind};
 /// Encrypted payload should contain at least `IpPort` struct.
 const ONION_REQUEST_1_MIN_PAYLOAD_SIZE: usize = SIZE_IPPORT + MACBYTES;
 
+/// Maximum size of encrypted payload so that the packet doesn't exceed
+/// `ONION_MAX_PACKET_SIZE`.
+pub const ONION_REQUEST_1_MAX_PAYLOAD_SIZE: usize = ONION_MAX_PACKET_SIZE - (1 + NONCEBYTES + PUBLICKEYBYTES + ONION_RETURN_1_SIZE);
+
 /** Second onion request packet. It's sent from the first to the second node from
 onion chain. Payload should be encrypted with temporary generated `SecretKey` and
 with DHT `PublicKey` of receiver.
@@ -8,6 +8,10 @@ use crate::toxcore::crypto_core::*;
 
 use std::io::{Error, ErrorKind};
 
+/// Maximum size of encrypted payload so that the packet doesn't exceed
+/// `ONION_MAX_PACKET_SIZE`.
+pub const ONION_REQUEST_2_MAX_PAYLOAD_SIZE: usize = ONION_MAX_PACKET_SIZE - (1 + NONCEBYTES + PUBLICKEYBYTES + ONION_RETURN_2_SIZE);
+
 /** Third onion request packet. It's sent from the second to the third node from
 onion chain. Payload should be encrypted with temporary generated `SecretKey` and
 with DHT `PublicKey` of receiver.
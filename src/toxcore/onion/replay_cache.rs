@@ -0,0 +1,138 @@
+//! Cache of recently forwarded onion requests used to detect replayed onion
+//! requests.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::toxcore::crypto_core::*;
+use crate::toxcore::onion::packet::OnionReturn;
+use crate::toxcore::time::*;
+
+/// Time in seconds during which an onion request with the same `OnionReturn`
+/// is considered to be a replay.
+pub const ONION_REPLAY_CACHE_TIMEOUT: u64 = 2;
+
+/** Struct that remembers fingerprints of `OnionReturn`s of forwarded onion
+requests.
+
+An `OnionReturn` is encrypted by the previous hop with a fresh nonce for every
+request, so a request that comes with the same `OnionReturn` again is a copy of
+the request we already forwarded and it can be dropped. Note that it doesn't
+detect onion paths that form a cycle since every hop wraps `OnionReturn` anew.
+*/
+#[derive(Clone, Debug)]
+pub struct OnionReplayCache {
+    /// Map that stores fingerprints of `OnionReturn`s with time when they were
+    /// seen.
+    forwards: HashMap<sha256::Digest, Instant>,
+    /// Time during which a fingerprint is stored.
+    timeout: Duration,
+}
+
+impl OnionReplayCache {
+    /// Create new `OnionReplayCache`.
+    pub fn new(timeout: Duration) -> OnionReplayCache {
+        OnionReplayCache {
+            forwards: HashMap::new(),
+            timeout,
+        }
+    }
+
+    /// Calculate fingerprint of `OnionReturn`.
+    fn fingerprint(onion_return: &OnionReturn) -> sha256::Digest {
+        let mut state = sha256::State::new();
+        state.update(onion_return.nonce.as_ref());
+        state.update(&onion_return.payload);
+        state.finalize()
+    }
+
+    /// Remember the `OnionReturn` of the request that is going to be forwarded.
+    /// Returns `false` if the same `OnionReturn` was already seen during the
+    /// timeout and the request should be dropped as a replay.
+    pub fn check(&mut self, onion_return: &OnionReturn) -> bool {
+        let timeout = self.timeout;
+        match self.forwards.insert(OnionReplayCache::fingerprint(onion_return), clock_now()) {
+            Some(time) => clock_elapsed(time) >= timeout,
+            None => true,
+        }
+    }
+
+    /// Remove fingerprints which timeout is over.
+    pub fn clear_expired(&mut self) {
+        let timeout = self.timeout;
+        self.forwards.retain(|_, &mut time|
+            clock_elapsed(time) < timeout
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio_executor;
+    use tokio_timer::clock::*;
+
+    use crate::toxcore::time::ConstNow;
+
+    fn onion_return() -> OnionReturn {
+        OnionReturn {
+            nonce: secretbox::gen_nonce(),
+            payload: vec![42; 123]
+        }
+    }
+
+    #[test]
+    fn clone() {
+        let cache = OnionReplayCache::new(Duration::from_secs(1));
+        let _ = cache.clone();
+    }
+
+    #[test]
+    fn check() {
+        let mut cache = OnionReplayCache::new(Duration::from_secs(1));
+        let onion_return_1 = onion_return();
+        let onion_return_2 = onion_return();
+
+        assert!(cache.check(&onion_return_1));
+        assert!(!cache.check(&onion_return_1));
+
+        // other onion returns are not affected
+        assert!(cache.check(&onion_return_2));
+    }
+
+    #[test]
+    fn check_after_timeout() {
+        let mut cache = OnionReplayCache::new(Duration::from_secs(1));
+        let onion_return = onion_return();
+
+        assert!(cache.check(&onion_return));
+
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(
+            Instant::now() + Duration::from_secs(1)
+        ));
+
+        with_default(&clock, &mut enter, |_| {
+            assert!(cache.check(&onion_return));
+        });
+    }
+
+    #[test]
+    fn clear_expired() {
+        let mut cache = OnionReplayCache::new(Duration::from_secs(1));
+
+        assert!(cache.check(&onion_return()));
+
+        let mut enter = tokio_executor::enter().unwrap();
+        let clock = Clock::new_with_now(ConstNow(
+            Instant::now() + Duration::from_secs(1)
+        ));
+
+        with_default(&clock, &mut enter, |_| {
+            cache.clear_expired();
+        });
+
+        assert!(cache.forwards.is_empty());
+    }
+}